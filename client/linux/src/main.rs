@@ -2,93 +2,62 @@
 // by Christopher R. Curzio <ccurzio@luminum.net>
 
 use clap::{Arg, App};
-use colored::Colorize;
-use chrono::Local;
-use chrono::format::strftime::StrftimeItems;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::process;
-use std::process::{Command, Stdio, Child};
+use std::process::{Child, Command, Stdio};
 use std::thread;
-use tokio::task::spawn;
+use tokio::runtime::Handle;
 use tokio::time;
 use gethostname::gethostname;
 use etc_os_release::OsRelease;
 use local_ip_address::local_ip;
-use std::net::{TcpListener, SocketAddr, ToSocketAddrs, TcpStream};
-use native_tls::{TlsConnector, TlsStream};
+use std::net::{IpAddr, TcpListener, SocketAddr, ToSocketAddrs, TcpStream};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::{X509NameBuilder, X509Req};
 use std::str;
 use std::error::Error;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::fs::{self, File};
-use std::io::{self, Read, Write, BufWriter, BufReader, BufRead};
-use std::time::Duration;
+use std::sync::Mutex;
+use std::fs;
+use std::io::{self, Write, BufWriter, BufReader};
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{Duration, Instant};
 use regex::Regex;
 use rusqlite::{params, Connection, Result};
-use serde::{Serialize, Deserialize};
-use rmp_serde::{from_read, Deserializer, Serializer, to_vec_named};
-use rmp_serde::decode::from_slice;
+use luminum_log::{Format, LogConfig, LogError, Output, Rotation};
+use luminum_proto::{DEFAULT_MAX_FRAME, read_message, write_message};
+use tracing::{debug, error, info, warn};
+use session::Session;
+use shutdown::Signals;
+use luminum_proto::{ClientMessage, ServerMessage, Request, Response, RegisterRequest, IntegrityConfigRequest, IntegrityEvents, Heartbeat, LumyMessage, LumyContent, Lumy, Status, UID_NONE};
+
+mod push;
+mod session;
+mod shutdown;
 
 const VER: &str = "0.0.1";
 const CFGPATH: &str = "/opt/Luminum/LuminumClient/config/client.conf.db";
 const CRTPATH: &str = "/opt/Luminum/LuminumClient/config/server.crt";
+const CCRTPATH: &str = "/opt/Luminum/LuminumClient/config/client.crt";
+const CKEYPATH: &str = "/opt/Luminum/LuminumClient/config/client.key";
 const MODPATH: &str = "/opt/Luminum/LuminumClient/modules";
-const IPORT: u16 = 10704;
 const DPORT: u16 = 10465;
 const LPORT: u16 = 10461;
+const DEFAULT_HEARTBEAT: u64 = 300;
+const MIN_HEARTBEAT: u64 = 10;
+// How long Lumys get to exit after SIGTERM before they are killed
+const LUMY_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Lumys started by this client, stopped with it
+static LUMY_PROCESSES: Mutex<Vec<(String, Child)>> = Mutex::new(Vec::new());
 
 struct Config {
 	key: String,
 	value: String
 	}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ServerMessage {
-	version: String,
-	content: MessageContent
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct ClientMessage {
-	uid: String,
-	product: String,
-	version: String,
-	content: MessageContent
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct MessageContent {
-	lumy: String,
-	status: String,
-	action: String,
-	data: Option<MessageData>
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct MessageData {
-	serverkey: Option<String>,
-	hostname: Option<String>,
-	uid: Option<String>,
-	osplat: Option<String>,
-	osver: Option<String>,
-	ipv4: Option<String>,
-	ipv6: Option<String>,
-	info: Option<Vec<String>>
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct LumyMessage {
-	lumy: String,
-	version: String,
-	content: LumyContent
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct LumyContent {
-	action: String,
-	data: Option<Vec<String>>
-	}
-
 #[tokio::main]
 async fn main() {
 	let endpointname = gethostname().to_string_lossy().into_owned();
@@ -112,33 +81,29 @@ async fn main() {
 	let setup = matches.is_present("setup");
 	let debug = matches.is_present("debug");
 
-	let mut clientconfig: HashMap<String, String> = HashMap::new();
-	let mut lumys: HashMap<String, String> = HashMap::new();
+	let log_guard = match log_config(debug).and_then(|logconfig| luminum_log::init(&logconfig)) {
+		Ok(guard) => guard,
+		Err(err) => {
+			eprintln!("Unable to start logging: {}", err);
+			process::exit(1);
+			}
+		};
 
-        // Set up break handler
-	let running = Arc::new(AtomicBool::new(true));
-	let r = running.clone();
-	ctrlc::set_handler(move || {
-		r.store(false, Ordering::SeqCst);
-		print!("\r\x1B[K");
-		io::stdout().flush().unwrap_or(());
-		dbout(debug,0,"Received BREAK signal.");
-		dbout(debug,0,"Luminum Client Terminated.");
-		process::exit(1);
-		}).expect("Error creating break handler");
+	let _clientconfig: HashMap<String, String> = HashMap::new();
+	let mut lumys: HashMap<String, String> = HashMap::new();
 
 	// Check if setup routine needs to run
 	if setup {
-		dbout(debug,4,format!("Starting client setup...").as_str());
+		debug!("Starting client setup...");
 		if fs::metadata(CFGPATH).is_err() { clientsetup(); }
 		else {
-			dbout(debug,1,format!("Client configuration already exists. Aborting.").as_str());
+			error!("Client configuration already exists. Aborting.");
 			process::exit(1);
 			}
 		}
 
 	// Client Startup
-	dbout(debug,0,format!("Starting Luminum Client v{}...", VER).as_str());
+	info!("Starting Luminum Client v{}...", VER);
 
 	// Get machine IP address information
 	let ip_address = local_ip().unwrap().to_string();
@@ -149,11 +114,11 @@ async fn main() {
 	if ipv4_regex.is_match(&ip_address) { ipv4_address = ip_address.clone(); }
 	if ipv6_regex.is_match(&ip_address) { ipv6_address = ip_address.clone(); }
 
-	dbout(debug,4,format!("Endpoint IP address: {}", ip_address).as_str());
+	debug!("Endpoint IP address: {}", ip_address);
 
-	dbout(debug,3,format!("Using configuration: {}",CFGPATH).as_str());
-	let clientconfig = parse_clientconfig(debug);
-	let clientconfig_clone = clientconfig.clone();
+	info!("Using configuration: {}",CFGPATH);
+	let clientconfig = parse_clientconfig();
+	let _clientconfig_clone = clientconfig.clone();
 
 	// Set up local IPC listener
 	let addr_str = format!("127.0.0.1:{}", LPORT);
@@ -161,26 +126,11 @@ async fn main() {
 
 	let ipclistener = match TcpListener::bind(addr) {
 		Ok(ipclistener) => { 
-			dbout(debug,3,format!("Local IPC configured on port {}", LPORT).as_str());
+			info!("Local IPC configured on port {}", LPORT);
 			ipclistener
 			},
 		Err(err) => {
-			dbout(debug,1,format!("Unable to configure local IPC: {}", err).as_str());
-			process::exit(1);
-			}
-		};
-
-	// Set up local data connection
-	let sladdr_str = format!("{}:{}",ip_address,IPORT);
-	let sladdr: SocketAddr = sladdr_str.parse().expect("Invalid socket address for server data connection");
-
-	let serverlistener = match TcpListener::bind(sladdr) {
-		Ok(serverlistener) => {
-			dbout(debug,3,format!("Incoming data connection configured on port {}", IPORT).as_str());
-			serverlistener
-			},
-		Err(err) => {
-			dbout(debug,1,format!("Unable to configure incoming data connection: {}", err).as_str());
+			error!("Unable to configure local IPC: {}", err);
 			process::exit(1);
 			}
 		};
@@ -189,7 +139,7 @@ async fn main() {
 	let server_host = clientconfig.get("SHOST").unwrap();
 	let server_port = clientconfig.get("SPORT").unwrap();
 	let server_addr_str = format!("{}:{}", server_host, server_port);
-	let server_addr: SocketAddr = match server_addr_str.to_socket_addrs() {
+	let _server_addr: SocketAddr = match server_addr_str.to_socket_addrs() {
 		Ok(mut addrs) => {
 			if let Some(addr) = addrs.next() { addr }
 			else {
@@ -204,104 +154,147 @@ async fn main() {
 		};
 
 	// Check client registration status and register with server if necessary
-	let mut uid = String::new();
-	if clientconfig.get("UID").is_none() {
-		dbout(debug,4,format!("Endpoint is not registered with the Luminum server. Sending registration request...").as_str());
-		let msgdata = MessageData {
-			hostname: Some(String::from(endpointname.clone())),
-			serverkey: Some(String::from(clientconfig.get("SVRKEY").unwrap())),
-			uid: Some(String::from("NONE")),
-			osplat: Some(String::from("Linux")),
-			osver: Some(String::from(get_os_release())),
-			ipv4: Some(String::from(ip_address)),
-			ipv6: None,
-			info: None
-			};
-		let msgcontent = MessageContent {
-			lumy: String::from("Client Core"),
-			status: String::from("noreg"),
-			action: String::from("register"),
-			data: Some(msgdata)
-			};
-			let clientmsg = ClientMessage {
-			product: String::from("Luminum Client"),
-			version: String::from(VER),
-			uid: String::from("NONE"),
-			content: msgcontent
-			};
-		let servermsg: Option<ServerMessage> = match server_send(server_host, server_port, CRTPATH, clientmsg, debug) {
-			Ok(response) => { Some(response) },
+	if !clientconfig.contains_key("UID") {
+		debug!("Endpoint is not registered with the Luminum server. Sending registration request...");
+		let csr = match generate_csr(&endpointname) {
+			Ok(csr) => csr,
 			Err(err) => {
-				dbout(debug,2,format!("Unable to send message to server: {}", err).as_str());
-				None
+				error!("Unable to generate client certificate request: {}", err);
+				process::exit(1);
 				}
 			};
-
-		let response = servermsg.unwrap();
-		if response.content.action == "register" {
-			if response.content.status == "OK" {
-				let response_data: MessageData = response.content.data.expect("Error: Unable to parse response data");
-				let new_uid = response_data.uid.expect("Error: Unable to parse UID in response data");
+		let request = RegisterRequest {
+			serverkey: clientconfig.get("TOKEN").or(clientconfig.get("SVRKEY")).cloned(),
+			hostname: endpointname.clone(),
+			osplat: String::from("Linux"),
+			osver: get_os_release(),
+			ipv4: Some(ipv4_address).filter(|address| !address.is_empty()),
+			ipv6: Some(ipv6_address).filter(|address| !address.is_empty()),
+			csr: Some(csr)
+			};
+		let clientmsg = ClientMessage::new(UID_NONE,VER,Lumy::ClientCore,Status::NoReg,Request::Register(request));
+		// The endpoint can't do anything until it is registered, so keep trying until the server answers
+		let response = session::exchange_retrying(&clientmsg).await;
+		match response.content.response {
+			Response::Register(registration) if response.content.status == Status::Ok => {
+				let new_uid = registration.uid;
+				match registration.certificate {
+					Some(certificate) => {
+						if let Err(err) = fs::write(CCRTPATH, certificate) {
+							error!("Unable to save client certificate: {}", err);
+							process::exit(1);
+							}
+						debug!("Client certificate written to {}", CCRTPATH);
+						},
+					None => {
+						error!("Luminum server did not issue a client certificate");
+						process::exit(1);
+						}
+					}
 				let confconn = Connection::open(CFGPATH).expect("Error: Could not open configuration database.");
-				confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",&[&"UID",new_uid.to_string().as_str()]).expect("Error: Could not insert UID into CONFIG table.");
+				confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["UID",new_uid.as_str()]).expect("Error: Could not insert UID into CONFIG table.");
+				// The enrollment token is no longer needed once the endpoint has its certificate
+				confconn.execute("delete from CONFIG where KEY in ('TOKEN','SVRKEY')",[]).expect("Error: Could not remove enrollment token from CONFIG table.");
 				confconn.close().unwrap();
-				dbout(debug,3,format!("Registration successful. (UID: {})", new_uid).as_str());
-				let dbg = debug;
-				tokio::spawn(async move {
-					let mut interval = time::interval(Duration::from_secs(300));
-					loop {
-						interval.tick().await;
-						heartbeat(dbg).await;
-						}
-					});
+				info!("Registration successful. (UID: {})", new_uid);
+				},
+			Response::Error(err) => {
+				error!("Registration rejected by Luminum server: {}", err.message);
+				process::exit(1);
+				},
+			_ => {
+				error!("Unexpected registration response from Luminum server");
+				process::exit(1);
 				}
-			}		
+			}
 		}
 	else {
 		let uid = clientconfig.get("UID").unwrap();
-		dbout(debug,3,format!("Endpoint is registered with UID {}", uid).as_str());
-		let dbg = debug;
-		tokio::spawn(async move {
-			let mut interval = time::interval(Duration::from_secs(300));
-			loop {
-				interval.tick().await;
-				heartbeat(dbg).await;
-				}
-			});
+		info!("Endpoint is registered with UID {}", uid);
 		}
 
+	// Stop cleanly on SIGTERM or SIGINT from here on
+	let mut signals = match Signals::install() {
+		Ok(signals) => signals,
+		Err(err) => {
+			error!("Unable to install shutdown signal handlers: {}", err);
+			process::exit(1);
+			}
+		};
+
+	// Keep a session open to the server for heartbeats, Lumy requests and pushed commands
+	let session = Session::start();
+	tokio::spawn(heartbeat_loop(session.clone()));
+
 	// Review installed Lumys
 	if file_exists(MODPATH) {
 		let mut integrity_path = String::from(MODPATH);
 		integrity_path.push_str("/integrity/Lumy_Integrity");
 
 		if file_exists(&integrity_path) {
-			dbout(debug,4,format!("Found Lumy: Integrity").as_str());
-			lumys.insert(String::from("Integrity"),String::from(integrity_path));
+			debug!("Found Lumy: Integrity");
+			lumys.insert(String::from("Integrity"),integrity_path);
 			}
 		}
 
 	for (lumy, lpath) in &lumys {
-		start_lumy(&lumy, &lpath, debug);
+		start_lumy(lumy, lpath);
 		if lumys.len() > 1 { thread::sleep(Duration::from_secs(2)); }
 		}
 
-	// Start IPC listener
-	let dbc = debug.clone();
-	for stream in ipclistener.incoming() {
-		match stream {
+	// Start IPC listener. Connections are accepted on the runtime and handled on their own threads.
+	let ipclistener = match ipclistener.set_nonblocking(true).and_then(|_| tokio::net::TcpListener::from_std(ipclistener)) {
+		Ok(ipclistener) => ipclistener,
+		Err(err) => {
+			error!("Unable to configure local IPC: {}", err);
+			process::exit(1);
+			}
+		};
+	info!("Luminum Client started.");
+	shutdown::notify(luminum_log::systemd::READY);
+	tokio::spawn(shutdown::watchdog());
+
+	let runtime = Handle::current();
+	let signal_name = loop {
+		let accepted = tokio::select! {
+			accepted = ipclistener.accept() => accepted,
+			signal_name = signals.recv() => { break signal_name; }
+			};
+		match accepted.and_then(|(stream, _)| stream.into_std()).and_then(|stream| stream.set_nonblocking(false).map(|_| stream)) {
 			Ok(stream) => {
+				let session = session.clone();
+				let runtime = runtime.clone();
+				let activity = shutdown::track();
 				thread::spawn(move || {
-					if let Err(err) = handle_ipc(stream,dbc) {
-						dbout(debug,2,format!("Error in IPC stream data: {}", err).as_str());
+					if let Err(err) = handle_ipc(stream,&session,&runtime) {
+						warn!("Error in IPC stream data: {}", err);
 						}
+					drop(activity);
 					});
 				}
 			Err(err) => {
-				dbout(debug,2,format!("Error establishing IPC connection: {}", err).as_str());
+				warn!("Error establishing IPC connection: {}", err);
 				}
 			}
+		};
+
+	// Stop accepting IPC, let work in progress finish, then stop the Lumys
+	info!("Received {}. Stopping Luminum Client...", signal_name);
+	shutdown::notify(luminum_log::systemd::STOPPING);
+	tokio::spawn(signals.exit_on_next());
+	drop(ipclistener);
+	if shutdown::active() > 0 {
+		info!("Waiting up to {} seconds for Lumy requests and commands to finish ({} remaining)...", shutdown::DRAIN_TIMEOUT.as_secs(), shutdown::active());
 		}
+	if !shutdown::drained(shutdown::DRAIN_TIMEOUT).await {
+		warn!("Abandoning Lumy requests and commands after {} seconds ({} remaining).", shutdown::DRAIN_TIMEOUT.as_secs(), shutdown::active());
+		}
+	if let Err(err) = tokio::task::spawn_blocking(stop_lumys).await {
+		error!("Unable to stop Lumys: {}", err);
+		}
+	info!("Luminum Client stopped.");
+	drop(log_guard);
+	process::exit(0);
 
 
 
@@ -331,7 +324,7 @@ async fn main() {
 		let servermsg: Option<ServerMessage> = match server_send(server_host, server_port, server_cert_path, clientmsg, debug) {
 			Ok(response) => { Some(response) },
 			Err(err) => {
-				dbout(debug,2,format!("Failed to send message to server: {}", err).as_str());
+				warn!("Failed to send message to server: {}", err);
 				None
 				}
 			};
@@ -356,50 +349,52 @@ async fn main() {
 */
 	}
 
-async fn heartbeat(debug: bool) {
-	let ccfg = parse_clientconfig(debug);
-	let uid = ccfg.get("UID").unwrap();
-	let endpointname = gethostname().to_string_lossy().into_owned();
-	let server_host = ccfg.get("SHOST").unwrap();
-	let server_port = ccfg.get("SPORT").unwrap();
-
-	let msgdata = MessageData {
-		hostname: None,
-		serverkey: None,
-		uid: None,
-		osplat: None,
-		osver: None,
-		ipv4: None,
-		ipv6: None,
-		info: None
-		};
-	let msgcontent = MessageContent {
-		lumy: String::from("Client Core"),
-		status: String::from("online"),
-		action: String::from("heartbeat"),
-		data: Some(msgdata)
-		};
-	let clientmsg = ClientMessage {
-		product: String::from("Luminum Client"),
-		version: String::from(VER),
-		uid: String::from(uid),
-		content: msgcontent
+// Send heartbeats at the configured interval
+async fn heartbeat_loop(session: Session) {
+	loop {
+		debug!("Sending heartbeat to Luminum server");
+		if let Err(err) = heartbeat(&session).await {
+			warn!("Failed to send heartbeat: {}", err);
+			}
+		time::sleep(heartbeat_interval(&parse_clientconfig())).await;
+		}
+	}
+
+async fn heartbeat(session: &Session) -> Result<ServerMessage, Box<dyn Error>> {
+	let ccfg = parse_clientconfig();
+	let uid = ccfg.get("UID").ok_or("Endpoint is not registered")?;
+
+	// Report current attributes so the server can pick up changes since registration
+	let mut attributes = Heartbeat {
+		hostname: Some(gethostname().to_string_lossy().into_owned()),
+		osplat: Some(String::from("Linux")),
+		osver: Some(get_os_release()),
+		..Heartbeat::default()
 		};
+	match local_ip() {
+		Ok(IpAddr::V4(address)) => { attributes.ipv4 = Some(address.to_string()); },
+		Ok(IpAddr::V6(address)) => { attributes.ipv6 = Some(address.to_string()); },
+		Err(err) => { warn!("Unable to determine endpoint IP address: {}", err); }
+		}
 
-	dbout(debug,4,format!("Sending heartbeat to Luminum server").as_str());
-	let _ = server_send(server_host, server_port, CRTPATH, clientmsg, debug);
-	thread::sleep(Duration::from_secs(5));
+	let clientmsg = ClientMessage::new(uid,VER,Lumy::ClientCore,Status::Online,Request::Heartbeat(attributes));
+	let response = session.request(clientmsg).await?;
+	if let Response::Heartbeat(Heartbeat { server_certificates: Some(bundle), .. }) = &response.content.response {
+		if let Err(err) = session::update_server_certificates(bundle) {
+			warn!("Unable to update trusted server certificates: {}", err);
+			}
+		}
+	Ok(response)
 	}
 
-
-fn parse_clientconfig(debug: bool) -> HashMap<String, String> {
+fn parse_clientconfig() -> HashMap<String, String> {
 	let mut clientconfig: HashMap<String, String> = HashMap::new();
 
 	if fs::metadata(CFGPATH).is_ok() {
 		let confconn = match Connection::open(CFGPATH) {
 			Ok(confconn) => { confconn }
 			Err(err) => {
-				dbout(debug,1,format!("Could not open client configuration database: {}",err).as_str());
+				error!("Could not open client configuration database: {}",err);
 				process::exit(1);
 				}
 			};
@@ -410,141 +405,178 @@ fn parse_clientconfig(debug: bool) -> HashMap<String, String> {
 				value: row.get(1)?
 				})
 			}).expect("Error: Unable to parse configuration values");
-		for cfg_result in cfg_iter {
-			if let Ok(cfg) = cfg_result {
-				clientconfig.insert(cfg.key.to_string(),cfg.value.to_string());
-				}
+		for cfg in cfg_iter.flatten() {
+			clientconfig.insert(cfg.key.to_string(),cfg.value.to_string());
 			}
 		}
 	else {
-		dbout(debug,1,format!("Configuration database not found.").as_str());
+		error!("Configuration database not found.");
 		process::exit(1);
 		}
-	return clientconfig
+	clientconfig
+	}
+
+// Logging settings from the configuration database (LOGLEVEL, LOGFORMAT, LOGOUTPUT, LOGFILE and
+// LOGROTATE). --debug overrides the level.
+fn log_config(debug: bool) -> Result<LogConfig, LogError> {
+	let clientconfig = if file_exists(CFGPATH) { parse_clientconfig() } else { HashMap::new() };
+	let level = if debug { String::from("debug") } else { clientconfig.get("LOGLEVEL").cloned().unwrap_or_else(|| String::from(luminum_log::DEFAULT_LEVEL)) };
+	luminum_log::validate_level(&level)?;
+	let rotation = clientconfig.get("LOGROTATE").map_or(Ok(Rotation::Daily), |value| value.parse())?;
+	Ok(LogConfig {
+		level,
+		format: clientconfig.get("LOGFORMAT").map_or(Ok(Format::Text), |value| value.parse())?,
+		output: Output::parse(clientconfig.get("LOGOUTPUT").map_or("stdout", String::as_str), clientconfig.get("LOGFILE").map(String::as_str), rotation)?
+		})
 	}
 
-fn handle_ipc(stream: TcpStream, debug: bool) -> Result<(), Box<dyn std::error::Error>> {
+fn handle_ipc(stream: TcpStream, session: &Session, runtime: &Handle) -> Result<(), Box<dyn std::error::Error>> {
 	let mut reader = BufReader::new(&stream);
 	let mut writer = BufWriter::new(&stream);
 
-	let ccfg = parse_clientconfig(debug);
+	let ccfg = parse_clientconfig();
 	let uid = ccfg.get("UID").unwrap();
 	let endpointname = gethostname().to_string_lossy().into_owned();
-	let server_host = ccfg.get("SHOST").unwrap();
-	let server_port = ccfg.get("SPORT").unwrap();
-
-	let mut buffer = [0; 1024];
-	let bytes_read = reader.read(&mut buffer)?;
-	let mut deserializer = Deserializer::new(&buffer[..]);
-	let lumymsg: LumyMessage = Deserialize::deserialize(&mut deserializer)?;
-
-	if lumymsg.lumy == "Integrity" {
-		if lumymsg.content.action == "newconfig" {
-			dbout(debug,4,format!("Received new configuration request from Integrity Lumy").as_str());
-			let msgdata = MessageData {
-				hostname: Some(String::from(endpointname)),
-				serverkey: None,
-				uid: Some(String::from(uid)),
-				osplat: Some(String::from("Linux")),
-				osver: None,
-				ipv4: None,
-				ipv6: None,
-				info: None
-				};
-			let msgcontent = MessageContent {
-				lumy: String::from("Integrity"),
-				status: String::from("new"),
-				action: String::from("newconfig"),
-				data: Some(msgdata)
-				};
-			let clientmsg = ClientMessage {
-				product: String::from("Luminum Client"),
-				version: String::from(VER),
-				uid: String::from(uid),
-				content: msgcontent
-				};
-			let servermsg: Option<ServerMessage> = match server_send(server_host, server_port, CRTPATH, clientmsg, debug) {
-				Ok(response) => {
-					dbout(debug,4,format!("Sent Integrity configuration request to Luminum server").as_str());
-					Some(response)
-					},
-				Err(err) => {
-					dbout(debug,2,format!("Failed to send Integrity configuration request to server: {}", err).as_str());
-					None
-					}
-				};
-
-			if let serverdata = servermsg.unwrap().content.data.unwrap().info {
-				let tolumycontent = LumyContent {
-					action: String::from("setconfig"),
-					data: serverdata
-					};
-				let tolumymsg = LumyMessage {
-					lumy: String::from("Luminum Client"),
-					version: String::from(VER),
-					content: tolumycontent
-					};
-				let serialized_data = to_vec_named(&tolumymsg).expect("Error: Unable to serialize IPC response");
-				writer.write_all(&serialized_data).expect("Error: Unable to send message to Luminum Client process");
-				dbout(debug,3,format!("New configuration sent to Integrity Lumy").as_str());
+
+	let max_frame = max_frame(&ccfg);
+	let lumymsg: LumyMessage = match read_message(&mut reader, max_frame)? {
+		Some(lumymsg) => lumymsg,
+		None => { return Ok(()); }
+		};
+
+	if lumymsg.lumy == Lumy::Integrity && lumymsg.content == LumyContent::NewConfig {
+		debug!("Received new configuration request from Integrity Lumy");
+		let request = IntegrityConfigRequest {
+			hostname: Some(endpointname),
+			osplat: Some(String::from("Linux"))
+			};
+		let clientmsg = ClientMessage::new(uid,VER,Lumy::Integrity,Status::New,Request::IntegrityConfig(request));
+		let servermsg = match runtime.block_on(session.request(clientmsg)) {
+			Ok(response) => {
+				debug!("Sent Integrity configuration request to Luminum server");
+				response
+				},
+			Err(err) => {
+				warn!("Failed to send Integrity configuration request to server: {}", err);
+				return Err(err.into());
+				}
+			};
+
+		match servermsg.content.response {
+			Response::IntegrityConfig(config) => {
+				let tolumymsg = LumyMessage::new(Lumy::Client,VER,LumyContent::SetConfig(config.paths));
+				write_message(&mut writer, &tolumymsg, max_frame)?;
+				info!("New configuration sent to Integrity Lumy");
+				},
+			Response::Error(err) => {
+				warn!("Luminum server rejected Integrity configuration request: {}", err.message);
+				},
+			_ => {
+				warn!("Unexpected response to Integrity configuration request");
 				}
 			}
 		}
-	Ok(())
-	}
-
-fn server_send(server_host: &str, server_port: &str, cert_path: &str, message: ClientMessage, debug: bool) -> Result<ServerMessage, Box<dyn Error>> {
-	let server_addr_str = format!("{}:{}", server_host, server_port); 
-	let server_addr = server_addr_str.to_socket_addrs()?.next().ok_or("No addresses found for hostname")?; 
-	let mut server_cert = File::open(cert_path)?;
-	let mut cert_buffer = Vec::new();
-	server_cert.read_to_end(&mut cert_buffer)?;
-
-	let sconn = loop {
-		match TcpStream::connect(server_addr) {
-			Ok(sconn) => break sconn,
+	else if let (Lumy::Integrity, LumyContent::Events(events)) = (lumymsg.lumy, lumymsg.content) {
+		// The Lumy doesn't wait for a reply, so failures are only logged
+		let count = events.len();
+		let clientmsg = ClientMessage::new(uid,VER,Lumy::Integrity,Status::Ok,Request::IntegrityEvents(IntegrityEvents { events }));
+		match runtime.block_on(session.request(clientmsg)) {
+			Ok(servermsg) => match servermsg.content.response {
+				Response::IntegrityEvents(receipt) => { debug!("Luminum server accepted {} of {} Integrity events", receipt.accepted, count); },
+				Response::Error(err) => { warn!("Luminum server rejected Integrity events: {}", err.message); },
+				_ => { warn!("Unexpected response to Integrity events"); }
+				},
 			Err(err) => {
-				dbout(debug,2,format!("Connection to Luminum server failed: {}", err).as_str());
-				dbout(debug,4,format!("Retrying connection in 30 seconds...",).as_str());
-				thread::sleep(Duration::from_secs(30));
+				warn!("Failed to send {} Integrity events to server: {}", count, err);
+				return Err(err.into());
 				}
 			}
-		};
-
-	let mut builder = TlsConnector::builder();
-	builder.add_root_certificate(native_tls::Certificate::from_pem(&cert_buffer)?);
-	let connector = builder.build()?;
-	let mut server_stream = connector.connect(server_host, sconn)?;
-
-	let mut stream = Arc::new(Mutex::new(server_stream));
-	let stream_clone = Arc::clone(&stream);
+		}
+	Ok(())
+	}
 
-	let serialized_data = to_vec_named(&message)?;
-	let mut stream = stream.lock().unwrap();
-	stream.write_all(&serialized_data)?;
-	stream.flush()?;
+// Generate the endpoint's private key and a certificate signing request for registration.
+// The server sets the certificate subject, so the CSR only needs to prove possession of the key.
+fn generate_csr(hostname: &str) -> Result<String, Box<dyn Error>> {
+	let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+	let mut keyfile = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(CKEYPATH)?;
+	keyfile.write_all(&key.private_key_to_pem_pkcs8()?)?;
+
+	let mut name = X509NameBuilder::new()?;
+	name.append_entry_by_nid(Nid::COMMONNAME, hostname)?;
+	let name = name.build();
+
+	let mut req = X509Req::builder()?;
+	req.set_subject_name(&name)?;
+	req.set_pubkey(&key)?;
+	req.sign(&key, MessageDigest::sha256())?;
+	Ok(String::from_utf8(req.build().to_pem()?)?)
+	}
 
-	let mut buffer = Vec::new();
-	stream.read_to_end(&mut buffer)?;
+// Time between heartbeats, from client configuration or default
+fn heartbeat_interval(clientconfig: &HashMap<String, String>) -> Duration {
+	let seconds = clientconfig.get("HEARTBEAT").and_then(|value| value.parse::<u64>().ok()).unwrap_or(DEFAULT_HEARTBEAT);
+	Duration::from_secs(seconds.max(MIN_HEARTBEAT))
+	}
 
-	let mut deserializer = Deserializer::new(&buffer[..]);
-	let response: ServerMessage = Deserialize::deserialize(&mut deserializer)?;
-	Ok(response)
+// Maximum size of a single framed message, from client configuration or default
+fn max_frame(clientconfig: &HashMap<String, String>) -> usize {
+	match clientconfig.get("MAXFRAME") {
+		Some(value) => value.parse::<usize>().unwrap_or(DEFAULT_MAX_FRAME),
+		None => DEFAULT_MAX_FRAME
+		}
 	}
 
-fn start_lumy(lumy: &str, cmd: &str, debug: bool) {
-	dbout(debug,0,format!("Starting \"{}\" Lumy", lumy).as_str());
-	let mut child = Command::new(cmd)
+fn start_lumy(lumy: &str, cmd: &str) {
+	info!("Starting \"{}\" Lumy", lumy);
+	let child = Command::new(cmd)
 	.stdout(Stdio::null())
 	.stderr(Stdio::null())
 	.spawn();
 
 	match child {
-		Ok(mut _child) => {
-			dbout(debug,3,format!("Successfully started \"{}\" Lumy", lumy).as_str());
+		Ok(child) => {
+			info!("Successfully started \"{}\" Lumy", lumy);
+			let mut processes = LUMY_PROCESSES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+			// Reap Lumys that have already exited
+			processes.retain_mut(|(_, child)| !matches!(child.try_wait(), Ok(Some(_))));
+			processes.push((lumy.to_string(), child));
 			},
 		Err(err) => {
-			dbout(debug,1,format!("Unable to start \"{}\" Lumy: {}", lumy, err).as_str());
+			error!("Unable to start \"{}\" Lumy: {}", lumy, err);
+			}
+		}
+	}
+
+// Ask every running Lumy to stop with SIGTERM, killing those still running after LUMY_STOP_TIMEOUT
+fn stop_lumys() {
+	let mut processes = std::mem::take(&mut *LUMY_PROCESSES.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+	for (lumy, child) in &processes {
+		debug!("Stopping \"{}\" Lumy", lumy);
+		// SAFETY: the child hasn't been waited for, so its PID still belongs to it
+		if unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } != 0 {
+			warn!("Unable to signal \"{}\" Lumy: {}", lumy, io::Error::last_os_error());
+			}
+		}
+	let deadline = Instant::now() + LUMY_STOP_TIMEOUT;
+	while !processes.is_empty() && Instant::now() < deadline {
+		processes.retain_mut(|(lumy, child)| match child.try_wait() {
+			Ok(Some(status)) => {
+				info!("\"{}\" Lumy stopped ({})", lumy, status);
+				false
+				},
+			Ok(None) => true,
+			Err(err) => {
+				warn!("Unable to check on \"{}\" Lumy: {}", lumy, err);
+				false
+				}
+			});
+		thread::sleep(Duration::from_millis(100));
+		}
+	for (lumy, mut child) in processes {
+		warn!("\"{}\" Lumy did not stop within {} seconds. Killing it.", lumy, LUMY_STOP_TIMEOUT.as_secs());
+		if let Err(err) = child.kill().and_then(|_| child.wait().map(|_| ())) {
+			warn!("Unable to kill \"{}\" Lumy: {}", lumy, err);
 			}
 		}
 	}
@@ -553,10 +585,9 @@ fn clientsetup() {
 	println!("Luminum Client (Linux)\nby Christopher R. Curzio <ccurzio@luminum.net>\n");
 	println!("Client Configuration\n--------------------");
 
-	let mut server = String::new();
 	let mut ui_server = String::new();
-	let mut ui_server_key = String::new();
-	let mut port: u16 = 10465;
+	let mut ui_token = String::new();
+	let port: u16;
 
 	print!("Enter Luminum server hostname or IP address: ");
 	io::stdout().flush().unwrap();
@@ -564,7 +595,7 @@ fn clientsetup() {
 		.read_line(&mut ui_server)
 		.expect("Error reading user input");
 	let ui_server = ui_server.trim();
-	server = ui_server.to_string();
+	let server = ui_server.to_string();
 
 	loop {
 		let mut ui_port = String::new();
@@ -587,19 +618,19 @@ fn clientsetup() {
 		break;
 		}
 
-	print!("Enter Luminum server key: ");
+	print!("Enter Luminum enrollment token: ");
 	io::stdout().flush().unwrap();
 	io::stdin()
-		.read_line(&mut ui_server_key)
+		.read_line(&mut ui_token)
 		.expect("Error reading user input");
-	let ui_server_key = ui_server_key.trim();
-	let server_key = ui_server_key.to_string();
+	let ui_token = ui_token.trim();
+	let token = ui_token.to_string();
 
 	let confconn = Connection::open(CFGPATH).expect("Error: Could not initialize configuration database");
 	confconn.execute("create table if not exists CONFIG ( KEY text not null, VALUE text not null )",[]).expect("Error: Could not create CONFIG table in configuration database");
-	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",&[&"SHOST",server.as_str()]).expect("Error: Could not insert SHOST into CONFIG table.");
-	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",&[&"SPORT",port.to_string().as_str()]).expect("Error: Could not insert SPORT into CONFIG table.");
-	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",&[&"SVRKEY",server_key.as_str()]).expect("Error: Could not insert SKEY into CONFIG table.");
+	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["SHOST",server.as_str()]).expect("Error: Could not insert SHOST into CONFIG table.");
+	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["SPORT",port.to_string().as_str()]).expect("Error: Could not insert SPORT into CONFIG table.");
+	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["TOKEN",token.as_str()]).expect("Error: Could not insert TOKEN into CONFIG table.");
 	confconn.close().unwrap();
 
 	println!("\nLuminum Server: {}",server);
@@ -610,33 +641,12 @@ fn clientsetup() {
 	}
 
 fn get_os_release() -> String {
-	let mut os_release = String::new();
 	match OsRelease::open() {
-		Ok(result) => { os_release = result.pretty_name().to_string(); }
-		Err(_) => { os_release = "Unknown".to_string(); }
+		Ok(result) => result.pretty_name().to_string(),
+		Err(_) => "Unknown".to_string()
 		}
-	return os_release;
 	}
 
 fn file_exists(path: &str) -> bool {
 	fs::metadata(path).is_ok()
 	}
-
-fn dbout(debug: bool, outlvl: i32, output: &str) {
-	let dateformat = StrftimeItems::new("%Y-%m-%d %H:%M:%S");
-	let current_datetime = Local::now();
-	let formatted_datetime = current_datetime.format_with_items(dateformat).to_string();
-	let mut etype = String::new();
-
-	if debug {
-		if outlvl == 0 { etype = "PROC".cyan().to_string(); }
-		else if outlvl == 1 { etype = "FAIL".red().to_string(); }
-		else if outlvl == 2 { etype = "WARN".yellow().to_string(); }
-		else if outlvl == 3 { etype = " OK ".green().to_string(); }
-		else if outlvl == 4 { etype = "INFO".to_string(); }
-		println!("{} [{}] {}",formatted_datetime,etype,output);
-		}
-	else {
-		if outlvl == 1 { println!("{}",output); }
-		}
-	}
@@ -0,0 +1,117 @@
+// Pushed Commands
+//
+// Commands arrive over the server session (see session.rs) without a request ID. Each one is
+// run on its own task and its result is sent back as a request on the same session.
+
+use std::fs;
+use std::path::Path;
+use gethostname::gethostname;
+use local_ip_address::local_ip;
+use rusqlite::Connection;
+use luminum_proto::{ClientMessage, Command, CommandKind, CommandResult, Request, Response, Lumy, Status};
+use crate::session::Session;
+use crate::shutdown::Activity;
+use tracing::{debug, warn};
+use crate::{CFGPATH, MODPATH, VER, file_exists, get_os_release, heartbeat, start_lumy};
+
+// Client configuration values the server is allowed to change
+const SETTABLE_KEYS: [&str; 3] = ["HEARTBEAT", "MAXFRAME", "LOGLEVEL"];
+
+// Run a pushed command and report its result to the server. The client waits for the
+// activity to end before it stops.
+pub async fn handle(session: Session, uid: String, command: Command, _activity: Activity) {
+	debug!("Received command {} from Luminum server", command.id);
+	let (success, output) = match run_command(&session, command.kind).await {
+		Ok(output) => (true, output),
+		Err(output) => (false, output)
+		};
+	let status = if success { Status::Ok } else { Status::Error };
+	let result = CommandResult { id: command.id.clone(), success, output };
+	match session.request(ClientMessage::new(&uid,VER,Lumy::ClientCore,status,Request::CommandResult(result))).await {
+		Ok(response) => {
+			if let Response::Error(err) = response.content.response {
+				warn!("Luminum server rejected result of command {}: {}", command.id, err.message);
+				}
+			},
+		Err(err) => {
+			warn!("Unable to send result of command {}: {}", command.id, err);
+			}
+		}
+	}
+
+// Run a pushed command, returning its output or the reason it failed
+async fn run_command(session: &Session, kind: CommandKind) -> Result<String, String> {
+	match kind {
+		CommandKind::Action { name, .. } if name == "heartbeat" => {
+			heartbeat(session).await.map(|_| String::from("Heartbeat sent")).map_err(|err| err.to_string())
+			},
+		// Everything else touches the filesystem or configuration database
+		kind => tokio::task::spawn_blocking(move || match kind {
+			CommandKind::Question(question) => answer(&question),
+			CommandKind::SetConfig { key, value } => set_config(&key, &value),
+			CommandKind::Action { name, args } => action(&name, &args)
+			}).await.unwrap_or_else(|err| Err(err.to_string()))
+		}
+	}
+
+fn answer(question: &str) -> Result<String, String> {
+	match question {
+		"hostname" => Ok(gethostname().to_string_lossy().into_owned()),
+		"osver" => Ok(get_os_release()),
+		"ipaddress" => local_ip().map(|address| address.to_string()).map_err(|err| err.to_string()),
+		"uptime" => {
+			let uptime = fs::read_to_string("/proc/uptime").map_err(|err| err.to_string())?;
+			let seconds = uptime.split_whitespace().next().and_then(|value| value.parse::<f64>().ok()).ok_or("Unable to parse /proc/uptime")?;
+			Ok(format!("{}", seconds as u64))
+			},
+		"lumys" => {
+			let mut lumys: Vec<String> = match fs::read_dir(MODPATH) {
+				Ok(entries) => entries.flatten().map(|entry| entry.file_name().to_string_lossy().into_owned()).collect(),
+				Err(_) => Vec::new()
+				};
+			lumys.sort();
+			Ok(lumys.join(","))
+			},
+		_ => Err(format!("Unknown question: {}", question))
+		}
+	}
+
+fn set_config(key: &str, value: &str) -> Result<String, String> {
+	if !SETTABLE_KEYS.contains(&key) {
+		return Err(format!("Configuration key {} cannot be changed remotely", key));
+		}
+	let valid = match key {
+		"LOGLEVEL" => luminum_log::validate_level(value).is_ok(),
+		_ => value.parse::<u64>().is_ok_and(|number| number != 0)
+		};
+	if !valid {
+		return Err(format!("Invalid value for {}: {}", key, value));
+		}
+	let confconn = Connection::open(CFGPATH).map_err(|err| err.to_string())?;
+	confconn.execute("delete from CONFIG where KEY = ?1", [key]).map_err(|err| err.to_string())?;
+	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)", [key, value]).map_err(|err| err.to_string())?;
+	// The log level applies straight away; other values are read when they're next needed
+	if key == "LOGLEVEL" {
+		luminum_log::set_level(value).map_err(|err| err.to_string())?;
+		}
+	Ok(format!("{} set to {}", key, value))
+	}
+
+fn action(name: &str, args: &[String]) -> Result<String, String> {
+	match name {
+		"start-lumy" => {
+			let lumy = args.first().ok_or("start-lumy requires a Lumy name")?;
+			if !lumy.chars().all(|c| c.is_ascii_alphanumeric()) {
+				return Err(format!("Invalid Lumy name: {}", lumy));
+				}
+			let path = Path::new(MODPATH).join(lumy.to_lowercase()).join(format!("Lumy_{}", lumy));
+			let path = path.to_string_lossy();
+			if !file_exists(&path) {
+				return Err(format!("Lumy {} is not installed", lumy));
+				}
+			start_lumy(lumy, &path);
+			Ok(format!("Started {} Lumy", lumy))
+			},
+		_ => Err(format!("Unknown action: {}", name))
+		}
+	}
@@ -0,0 +1,307 @@
+// Server Session
+//
+// The client keeps a single long-lived TLS session to the Luminum server. Heartbeats, Lumy
+// requests and command results share it: every request carries a request ID and waits for
+// the response with the same ID, so several requests can be outstanding at once. Messages
+// without a request ID are pushed by the server. When the session drops, the client
+// reconnects with exponential backoff.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{sleep, timeout};
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::ssl::{SslConnector, SslMethod};
+use openssl::x509::X509;
+use tokio_openssl::SslStream;
+use luminum_proto::{ClientMessage, ServerMessage, Request, Response, ListenRequest, Lumy, Status, read_message_async, write_message_async};
+use tracing::{debug, info, warn};
+use crate::{CCRTPATH, CKEYPATH, CRTPATH, VER, file_exists, max_frame, parse_clientconfig, push, shutdown};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// Requests waiting to be written to the session
+const OUTGOING_DEPTH: usize = 64;
+
+type ServerStream = SslStream<TcpStream>;
+
+#[derive(Debug)]
+pub enum SessionError {
+	// No session to the server could be established in time
+	Unavailable,
+	TimedOut,
+	// The session closed before the response arrived
+	Closed
+	}
+
+impl fmt::Display for SessionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SessionError::Unavailable => write!(f, "not connected to Luminum server"),
+			SessionError::TimedOut => write!(f, "timed out waiting for Luminum server"),
+			SessionError::Closed => write!(f, "session with Luminum server closed")
+			}
+		}
+	}
+
+impl Error for SessionError {}
+
+#[derive(Clone)]
+pub struct Session {
+	inner: Arc<Inner>
+	}
+
+struct Inner {
+	next_id: AtomicU64,
+	// Requests waiting for a response, by request ID
+	pending: Mutex<HashMap<u64, oneshot::Sender<ServerMessage>>>,
+	// Queue for the current connection, if there is one
+	outgoing: watch::Sender<Option<mpsc::Sender<ClientMessage>>>
+	}
+
+impl Session {
+	// Start maintaining a session in the background
+	pub fn start() -> Session {
+		let session = Session {
+			inner: Arc::new(Inner {
+				next_id: AtomicU64::new(1),
+				pending: Mutex::new(HashMap::new()),
+				outgoing: watch::channel(None).0
+				})
+			};
+		tokio::spawn(session.clone().maintain());
+		session
+		}
+
+	// Send a request and wait for its response
+	pub async fn request(&self, mut msg: ClientMessage) -> Result<ServerMessage, SessionError> {
+		let mut connection = self.inner.outgoing.subscribe();
+		let outgoing = match timeout(REQUEST_TIMEOUT, connection.wait_for(Option::is_some)).await {
+			Ok(Ok(outgoing)) => outgoing.clone().ok_or(SessionError::Unavailable)?,
+			_ => { return Err(SessionError::Unavailable); }
+			};
+
+		let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+		msg.request_id = Some(id);
+		let (waiter, response) = oneshot::channel();
+		self.pending().insert(id, waiter);
+		if outgoing.send(msg).await.is_err() {
+			self.pending().remove(&id);
+			return Err(SessionError::Closed);
+			}
+		match timeout(REQUEST_TIMEOUT, response).await {
+			Ok(Ok(response)) => Ok(response),
+			Ok(Err(_)) => Err(SessionError::Closed),
+			Err(_) => {
+				self.pending().remove(&id);
+				Err(SessionError::TimedOut)
+				}
+			}
+		}
+
+	fn pending(&self) -> MutexGuard<'_, HashMap<u64, oneshot::Sender<ServerMessage>>> {
+		self.inner.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+		}
+
+	// Connect, serve the session until it drops, and reconnect with exponential backoff
+	async fn maintain(self) {
+		let mut backoff = INITIAL_BACKOFF;
+		loop {
+			match self.open().await {
+				Ok((stream, uid, keepalive)) => {
+					info!("Session established with Luminum server");
+					backoff = INITIAL_BACKOFF;
+					let reason = self.serve(stream, &uid, keepalive).await;
+					warn!("Session with Luminum server ended: {}", reason);
+					},
+				Err(err) => {
+					warn!("Unable to establish session with Luminum server: {}", err);
+					}
+				}
+			let delay = with_jitter(backoff);
+			debug!("Reconnecting to Luminum server in {} seconds", delay.as_secs());
+			sleep(delay).await;
+			backoff = (backoff * 2).min(MAX_BACKOFF);
+			}
+		}
+
+	// Connect and ask to listen for pushed commands. Returns the stream, the endpoint's UID and
+	// the server's keepalive interval.
+	async fn open(&self) -> Result<(ServerStream, String, u64), Box<dyn Error + Send + Sync>> {
+		let ccfg = parse_clientconfig();
+		let uid = ccfg.get("UID").ok_or("Endpoint is not registered")?.clone();
+		let max_frame = max_frame(&ccfg);
+		let mut stream = connect().await?;
+
+		let listen = ClientMessage::new(&uid,VER,Lumy::ClientCore,Status::Online,Request::Listen(ListenRequest {}));
+		write_message_async(&mut stream, &listen, max_frame).await?;
+		let response: ServerMessage = match timeout(REQUEST_TIMEOUT, read_message_async(&mut stream, max_frame)).await {
+			Ok(response) => response?.ok_or("Server closed the connection without responding")?,
+			Err(_) => { return Err(SessionError::TimedOut.into()); }
+			};
+		match response.content.response {
+			Response::Listen(listen) => Ok((stream, uid, listen.keepalive)),
+			Response::Error(err) => Err(format!("Server refused session: {}", err.message).into()),
+			_ => Err("Unexpected response to listen request".into())
+			}
+		}
+
+	// Route messages from the server until the session drops. Returns the reason it ended.
+	async fn serve(&self, stream: ServerStream, uid: &str, keepalive: u64) -> String {
+		let max_frame = max_frame(&parse_clientconfig());
+		let (mut reader, writer) = tokio::io::split(stream);
+		let (outgoing, queue) = mpsc::channel(OUTGOING_DEPTH);
+		let mut writer_task = tokio::spawn(write_messages(writer, queue, max_frame));
+		self.inner.outgoing.send_replace(Some(outgoing));
+
+		// The server sends a keepalive on idle sessions, so missing several means the session is dead
+		let silence = Duration::from_secs(keepalive.max(1) * 3);
+		let reason = loop {
+			let msg = tokio::select! {
+				written = &mut writer_task => {
+					break match written {
+						Ok(Err(err)) => format!("write failed: {}", err),
+						_ => String::from("writer stopped")
+						};
+					},
+				read = timeout(silence, read_message_async::<ReadHalf<ServerStream>, ServerMessage>(&mut reader, max_frame)) => match read {
+					Ok(Ok(Some(msg))) => msg,
+					Ok(Ok(None)) => { break String::from("server closed the session"); },
+					Ok(Err(err)) => { break format!("read failed: {}", err); },
+					Err(_) => { break format!("no keepalive for {} seconds", silence.as_secs()); }
+					}
+				};
+			match msg.request_id {
+				Some(id) => {
+					match self.pending().remove(&id) {
+						Some(waiter) => { let _ = waiter.send(msg); },
+						None => { warn!("Response to unknown request {} from Luminum server", id); }
+						}
+					},
+				None => match msg.content.response {
+					Response::Command(command) => {
+						tokio::spawn(push::handle(self.clone(), uid.to_string(), command, shutdown::track()));
+						},
+					// Keepalive
+					Response::Heartbeat(_) => {},
+					Response::Error(err) => {
+						warn!("Luminum server reported an error: {}", err.message);
+						},
+					_ => {
+						warn!("Unexpected message from Luminum server");
+						}
+					}
+				}
+			};
+
+		// Fail anything still waiting on this connection
+		self.inner.outgoing.send_replace(None);
+		writer_task.abort();
+		self.pending().clear();
+		reason
+		}
+	}
+
+async fn write_messages(mut writer: WriteHalf<ServerStream>, mut queue: mpsc::Receiver<ClientMessage>, max_frame: usize) -> Result<(), luminum_proto::FrameError> {
+	while let Some(msg) = queue.recv().await {
+		write_message_async(&mut writer, &msg, max_frame).await?;
+		}
+	Ok(())
+	}
+
+// Open a TLS connection to the Luminum server. The server is verified against the
+// certificates in server.crt, and the client certificate is presented once the endpoint has
+// been registered.
+pub async fn connect() -> Result<ServerStream, Box<dyn Error + Send + Sync>> {
+	let ccfg = parse_clientconfig();
+	let server_host = ccfg.get("SHOST").ok_or("Server hostname is not configured")?;
+	let server_port = ccfg.get("SPORT").ok_or("Server port is not configured")?;
+
+	let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+	for certificate in X509::stack_from_pem(&std::fs::read(CRTPATH)?)? {
+		builder.cert_store_mut().add_cert(certificate)?;
+		}
+	if file_exists(CCRTPATH) && file_exists(CKEYPATH) {
+		let certificate = X509::from_pem(&std::fs::read(CCRTPATH)?)?;
+		let key = PKey::private_key_from_pem(&std::fs::read(CKEYPATH)?)?;
+		builder.set_certificate(&certificate)?;
+		builder.set_private_key(&key)?;
+		}
+	let ssl = builder.build().configure()?.into_ssl(server_host)?;
+
+	let tcp = match timeout(CONNECT_TIMEOUT, TcpStream::connect(format!("{}:{}", server_host, server_port))).await {
+		Ok(tcp) => tcp?,
+		Err(_) => { return Err(SessionError::TimedOut.into()); }
+		};
+	let mut stream = SslStream::new(ssl, tcp)?;
+	match timeout(CONNECT_TIMEOUT, Pin::new(&mut stream).connect()).await {
+		Ok(result) => result?,
+		Err(_) => { return Err(SessionError::TimedOut.into()); }
+		}
+	Ok(stream)
+	}
+
+// Replace server.crt with the certificates the server says to trust. They arrive over a
+// session already verified against server.crt, so a renewed server certificate is picked up
+// before the server starts presenting it.
+pub fn update_server_certificates(bundle: &str) -> Result<(), Box<dyn Error>> {
+	if std::fs::read_to_string(CRTPATH).map(|current| current == bundle).unwrap_or(false) {
+		return Ok(());
+		}
+	if X509::stack_from_pem(bundle.as_bytes())?.is_empty() {
+		return Err("Server sent no certificates".into());
+		}
+	let staging = format!("{}.tmp", CRTPATH);
+	std::fs::write(&staging, bundle)?;
+	std::fs::rename(&staging, CRTPATH)?;
+	info!("Updated trusted server certificates in {}", CRTPATH);
+	Ok(())
+	}
+
+// Send a single message on its own connection, for requests made before the endpoint has a
+// client certificate
+pub async fn exchange(msg: &ClientMessage) -> Result<ServerMessage, Box<dyn Error + Send + Sync>> {
+	let max_frame = max_frame(&parse_clientconfig());
+	let mut stream = connect().await?;
+	write_message_async(&mut stream, msg, max_frame).await?;
+	match timeout(REQUEST_TIMEOUT, read_message_async(&mut stream, max_frame)).await {
+		Ok(response) => Ok(response?.ok_or("Server closed the connection without responding")?),
+		Err(_) => Err(SessionError::TimedOut.into())
+		}
+	}
+
+// Send a single message like exchange, retrying with exponential backoff until the server
+// can be reached
+pub async fn exchange_retrying(msg: &ClientMessage) -> ServerMessage {
+	let mut backoff = INITIAL_BACKOFF;
+	loop {
+		match exchange(msg).await {
+			Ok(response) => { return response; },
+			Err(err) => { warn!("Unable to send message to server: {}", err); }
+			}
+		let delay = with_jitter(backoff);
+		debug!("Retrying in {} seconds", delay.as_secs());
+		sleep(delay).await;
+		backoff = (backoff * 2).min(MAX_BACKOFF);
+		}
+	}
+
+// Spread reconnects out so a server restart isn't followed by every endpoint at once
+fn with_jitter(delay: Duration) -> Duration {
+	let mut bytes = [0u8; 2];
+	let fraction = match rand_bytes(&mut bytes) {
+		Ok(()) => u16::from_le_bytes(bytes) as u32,
+		Err(_) => 0
+		};
+	delay + delay / 4 * fraction / u16::MAX as u32
+	}
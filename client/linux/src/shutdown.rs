@@ -0,0 +1,103 @@
+// Shutdown
+//
+// SIGTERM and SIGINT stop the client in stages. The IPC listener stops accepting, IPC
+// requests and pushed commands already being handled get DRAIN_TIMEOUT to finish, and then
+// the Lumys are stopped (see stop_lumys in main.rs). A second signal exits at once.
+
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::time::sleep;
+use luminum_log::systemd;
+use tracing::warn;
+
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(15);
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+// IPC requests and pushed commands being handled
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+// Held while an IPC request or pushed command is handled
+pub struct Activity;
+
+impl Drop for Activity {
+	fn drop(&mut self) {
+		ACTIVE.fetch_sub(1, Ordering::SeqCst);
+		}
+	}
+
+pub fn track() -> Activity {
+	ACTIVE.fetch_add(1, Ordering::SeqCst);
+	Activity
+	}
+
+pub fn active() -> usize {
+	ACTIVE.load(Ordering::SeqCst)
+	}
+
+// Wait for the work in progress to finish, returning false if some is left at the deadline
+pub async fn drained(deadline: Duration) -> bool {
+	let until = Instant::now() + deadline;
+	while active() > 0 {
+		if Instant::now() >= until {
+			return false;
+			}
+		sleep(DRAIN_POLL).await;
+		}
+	true
+	}
+
+pub struct Signals {
+	terminate: Signal,
+	interrupt: Signal
+	}
+
+impl Signals {
+	// From here on SIGTERM and SIGINT no longer end the process by themselves
+	pub fn install() -> io::Result<Signals> {
+		Ok(Signals { terminate: signal(SignalKind::terminate())?, interrupt: signal(SignalKind::interrupt())? })
+		}
+
+	// Wait for a stop signal, returning its name
+	pub async fn recv(&mut self) -> &'static str {
+		tokio::select! {
+			_ = self.terminate.recv() => "SIGTERM",
+			_ = self.interrupt.recv() => "SIGINT"
+			}
+		}
+
+	// Exit without waiting on a second signal
+	pub async fn exit_on_next(mut self) {
+		self.recv().await;
+		warn!("Received a second signal. Exiting without waiting for work in progress.");
+		process::exit(1);
+		}
+	}
+
+// Ping the service manager's watchdog for as long as the async workers are responsive
+pub async fn watchdog() {
+	let Some(interval) = systemd::watchdog_interval() else { return; };
+	let mut ticks = tokio::time::interval(interval);
+	let mut failing = false;
+	loop {
+		ticks.tick().await;
+		// Only the first of a run of failures is worth reporting
+		match systemd::notify(systemd::WATCHDOG) {
+			Err(err) if !failing => {
+				warn!("Unable to ping the service manager's watchdog: {}", err);
+				failing = true;
+				},
+			Err(_) => {},
+			Ok(_) => { failing = false; }
+			}
+		}
+	}
+
+// Tell the service manager about a state change, if there is one
+pub fn notify(state: &str) {
+	if let Err(err) = systemd::notify(state) {
+		warn!("Unable to notify the service manager ({}): {}", state, err);
+		}
+	}
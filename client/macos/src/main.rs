@@ -2,13 +2,12 @@
 // by Christopher R. Curzio <ccurzio@luminum.net>
 
 use clap::{Arg, App};
-use colored::Colorize;
-use chrono::Local;
-use chrono::format::strftime::StrftimeItems;
 use std::process;
 use rusqlite::{params, Connection, Result};
 use std::collections::HashMap;
 use std::fs::{self, File};
+use luminum_log::LogConfig;
+use tracing::error;
 
 const VER: &str = "0.0.1";
 const CFGPATH: &str = "/Library/luminum/LuminumClient/conf/luminum.conf.db";
@@ -26,6 +25,14 @@ fn main() {
 	.get_matches();
 
 	let debug = matches.is_present("debug");
+	let logconfig = LogConfig { level: String::from(if debug { "debug" } else { luminum_log::DEFAULT_LEVEL }), ..LogConfig::default() };
+	let _log_guard = match luminum_log::init(&logconfig) {
+		Ok(guard) => guard,
+		Err(err) => {
+			eprintln!("Unable to start logging: {}", err);
+			process::exit(1);
+			}
+		};
 
 	let mut clientconfig: HashMap<String, String> = HashMap::new();
 
@@ -33,26 +40,7 @@ fn main() {
 		// Read Client Config
 		}
 	else {
-		dbout(debug,1,format!("Configuration database not found.").as_str());
+		error!("Configuration database not found.");
 		process::exit(1);
 		}
 	}
-
-fn dbout(debug: bool, outlvl: i32, output: &str) {
-	let dateformat = StrftimeItems::new("%Y-%m-%d %H:%M:%S");
-	let current_datetime = Local::now();
-	let formatted_datetime = current_datetime.format_with_items(dateformat).to_string();
-	let mut etype = String::new();
-
-	if debug {
-		if outlvl == 0 { etype = "PROC".cyan().to_string(); }
-		else if outlvl == 1 { etype = "FAIL".red().to_string(); }
-		else if outlvl == 2 { etype = "WARN".yellow().to_string(); }
-		else if outlvl == 3 { etype = " OK ".green().to_string(); }
-		else if outlvl == 4 { etype = "INFO".to_string(); }
-		println!("{} [{}] {}",formatted_datetime,etype,output);
-		}
-	else {
-		if outlvl == 1 { println!("{}",output); }
-		}
-	}
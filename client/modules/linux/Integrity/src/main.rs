@@ -1,106 +1,32 @@
-use std::fs::{self, File};
+use std::fs;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::fs::PermissionsExt;
 use users::get_group_by_gid;
 use users::get_user_by_uid;
-use std::env;
-use std::error::Error;
-use std::process;
 use std::path::Path;
-use std::net::{TcpStream, Shutdown};
-use std::io::{self, BufRead, BufReader, Read, Write, Seek};
-use std::thread;
-use std::collections::HashMap;
-use rusqlite::{params, Connection, Result};
-use notify::{RecommendedWatcher, RecursiveMode, Watcher, Event, Config, Result as NotifyResult};
-use notify::event::{EventKind, MetadataKind};
+use std::net::TcpStream;
+use rusqlite::Connection;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher, EventKind, Config};
 use std::sync::mpsc::channel;
-use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
-use serde::{Serialize, Deserialize};
-use serde_json::{json, Value};
-use rmp_serde::{from_read, Deserializer, Serializer, to_vec_named};
-use rmp_serde::decode::from_slice;
-
-#[derive(Serialize)]
-struct NotifyEvent {
-	kind: String,
-	paths: Vec<String>
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct LumyMessage {
-	lumy: String,
-	version: String,
-	content: LumyContent
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct LumyContent {
-	action: String,
-	data: Option<Vec<String>>
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct ClientMessage {
-	uid: String,
-	product: String,
-	version: String,
-	content: MessageContent
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct MessageContent {
-	lumy: String,
-	status: String,
-	action: String,
-	data: Option<MessageData>
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct MessageData {
-	serverkey: Option<String>,
-	hostname: Option<String>,
-	uid: Option<String>,
-	osplat: Option<String>,
-	osver: Option<String>,
-	ipv4: Option<String>,
-	ipv6: Option<String>,
-	info: Option<Vec<String>>
-	}
-
-impl From<Event> for NotifyEvent {
-	fn from(event: Event) -> Self {
-		NotifyEvent {
-			kind: format!("{:?}", event.kind),
-			paths: event.paths.into_iter().map(|p| p.to_string_lossy().into_owned()).collect()
-			}
-		}
-	}
+use luminum_proto::{DEFAULT_MAX_FRAME, read_message, write_message, LumyMessage, LumyContent, Lumy, IntegrityEvent, FileEventKind, FileType};
 
 const VER: &str = "0.0.1";
 const CFGPATH: &str = "/opt/Luminum/LuminumClient/modules/integrity/integrity.conf.db";
+#[allow(dead_code)]
 const IMLOGS: &str = "/opt/Luminum/LuminumClient/modules/integrity/imlogs.db";
 
 fn main() {
-	let mut stream = TcpStream::connect("127.0.0.1:10461").expect("Error: Could not connect to Luminum Client process");
+	let stream = TcpStream::connect("127.0.0.1:10461").expect("Error: Could not connect to Luminum Client process");
 	
 	if !file_exists(CFGPATH) {
-		let lumycontent = LumyContent {
-			action: String::from("newconfig"),
-			data: None
-			};
-		let lumymsg = LumyMessage {
-			lumy: String::from("Integrity"),
-			version: String::from(VER),
-			content: lumycontent
-			};
+		let lumymsg = LumyMessage::new(Lumy::Integrity,VER,LumyContent::NewConfig);
 		let response: LumyMessage = client_send(&stream, lumymsg);
 
-		if response.content.action == "setconfig" {
+		if let LumyContent::SetConfig(paths) = response.content {
 			let confconn = Connection::open(CFGPATH).expect("Error: Could not open configuration database.");
 			confconn.execute("create table if not exists WATCH ( PATH text not null )",[]).expect("Error: Could not create WATCH table in Integrity Lumy configuration database");
-			for value in response.content.data.unwrap() { confconn.execute("insert into WATCH (PATH) values (?)", [&value]).expect("Error: Could not insert watch list values into Lumy configuration"); }
+			for value in paths { confconn.execute("insert into WATCH (PATH) values (?)", [&value]).expect("Error: Could not insert watch list values into Lumy configuration"); }
 			confconn.close().unwrap();
 			}
 		}
@@ -110,10 +36,12 @@ fn main() {
 		let mut watcher: RecommendedWatcher = RecommendedWatcher::new(tx, Config::default()).expect("Error: Could not set up watcher.");
 
 		let watchpaths = get_config("watch");
-		let ignorepaths = get_config("ignore");
+		let _ignorepaths = get_config("ignore");
 
 		for watchpath in watchpaths {
-			watcher.watch(Path::new(&watchpath), RecursiveMode::Recursive);
+			if let Err(e) = watcher.watch(Path::new(&watchpath), RecursiveMode::Recursive) {
+				println!("Watch error: {:?}", e);
+				}
 			}
 
 		//for ignorepath in ignorepaths {
@@ -122,30 +50,9 @@ fn main() {
 			match rx.recv() {
 				Ok(Ok(event)) => {
 					println!("{:?}", event);
-					let notify_event: NotifyEvent = event.clone().into();
-					let mut combined_json = json!({});
-					combined_json["notify_event"] = serde_json::to_value(&notify_event).unwrap();
-					let json_event = serde_json::to_string(&combined_json).unwrap();
-					if let Ok(metadata) = std::fs::metadata(&event.paths[0].clone()) {
-						println!("{:?}",event.kind);
-						println!("{:?}",metadata);
-						println!("{:?}",event.paths);
-                                        	let mut ftype = String::new();
-						if metadata.is_dir() { ftype = "Directory".to_string() }
-						else if metadata.is_file() {ftype = "File".to_string() }
-						println!("{}",String::from(ftype));
-						let permissions = metadata.permissions();
-						let mode = permissions.mode();
-						println!("{}",octal_to_symbolic(mode));
-						let user = get_user_by_uid(metadata.uid()).map_or("Unknown".to_string(), |u| u.name().to_string_lossy().into_owned());
-						let group = get_group_by_gid(metadata.gid()).map_or("Unknown".to_string(), |g| g.name().to_string_lossy().into_owned());
-						println!("{}:{}",user,group);
-						let file_size = metadata.len();
-						println!("{} bytes",file_size);
-						}
-					else {
-						println!("{:?}",event.kind);
-						println!("{:?}",event.paths);
+					let events: Vec<IntegrityEvent> = event.paths.iter().map(|path| integrity_event(&event.kind, path)).collect();
+					if let Err(e) = report_events(events) {
+						println!("Error: Could not report events to Luminum Client process: {}", e);
 						}
 					}
 				Ok(Err(e)) => println!("Watch error: {:?}", e),
@@ -157,28 +64,51 @@ fn main() {
 		}
 	}
 
-fn client_send(mut stream: &TcpStream, message: LumyMessage) -> LumyMessage {
-	let serialized_data = to_vec_named(&message).expect("Error: Unable to serialize data to Luminum Client");
-	stream.write_all(&serialized_data).expect("Error: Unable to send message to Luminum Client process");
-	stream.flush();
+// Describe a change to a path, with its current metadata if it still exists
+fn integrity_event(kind: &EventKind, path: &Path) -> IntegrityEvent {
+	let kind = match kind {
+		EventKind::Create(_) => FileEventKind::Create,
+		EventKind::Modify(_) => FileEventKind::Modify,
+		EventKind::Remove(_) => FileEventKind::Remove,
+		EventKind::Access(_) => FileEventKind::Access,
+		_ => FileEventKind::Other
+		};
+	let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs() as i64).unwrap_or_default();
+	let mut event = IntegrityEvent { path: path.to_string_lossy().into_owned(), kind, at, ..IntegrityEvent::default() };
+	if let Ok(metadata) = fs::symlink_metadata(path) {
+		let ftype = if metadata.is_dir() { FileType::Directory } else if metadata.is_file() { FileType::File } else { FileType::Other };
+		let user = get_user_by_uid(metadata.uid()).map_or("Unknown".to_string(), |u| u.name().to_string_lossy().into_owned());
+		let group = get_group_by_gid(metadata.gid()).map_or("Unknown".to_string(), |g| g.name().to_string_lossy().into_owned());
+		event.file_type = Some(ftype);
+		event.permissions = Some(octal_to_symbolic(metadata.permissions().mode()));
+		event.owner = Some(format!("{}:{}", user, group));
+		event.size = Some(metadata.len());
+		}
+	event
+	}
 
-	let mut buffer = Vec::new();
-	stream.read_to_end(&mut buffer);
+// The client handles one message per connection and doesn't reply to events
+fn report_events(events: Vec<IntegrityEvent>) -> Result<(), luminum_proto::FrameError> {
+	let mut stream = TcpStream::connect("127.0.0.1:10461")?;
+	write_message(&mut stream, &LumyMessage::new(Lumy::Integrity,VER,LumyContent::Events(events)), DEFAULT_MAX_FRAME)
+	}
 
-	let mut deserializer = Deserializer::new(&buffer[..]);
-	let response: LumyMessage = Deserialize::deserialize(&mut deserializer).expect("Error: Unable to parse client response");
-	return response
+fn client_send(mut stream: &TcpStream, message: LumyMessage) -> LumyMessage {
+	write_message(&mut stream, &message, DEFAULT_MAX_FRAME).expect("Error: Unable to send message to Luminum Client process");
+	read_message(&mut stream, DEFAULT_MAX_FRAME)
+		.expect("Error: Unable to parse client response")
+		.expect("Error: Luminum Client process closed the connection")
 	}
 
 fn get_config(list: &str) -> Vec<String> {
-	if list.to_string() == "watch" {
+	if list == "watch" {
 		let confconn = Connection::open(CFGPATH).expect("Error: Could not open configuration database.");
 		let mut stmt = confconn.prepare("select PATH from WATCH").expect("Failed to prepare query");
 		let watchpaths: Vec<String> = stmt.query_map([], |row| row.get(0)).expect("Failed to execute query").map(|result| result.expect("Error retrieving column value")).collect();
 		//confconn.close().unwrap();
 		watchpaths
 		}
-	else if list.to_string() == "ignore" {
+	else if list == "ignore" {
 		let ignorepaths: Vec<String> = Vec::new();
 
 		ignorepaths
@@ -188,6 +118,7 @@ fn get_config(list: &str) -> Vec<String> {
 		}
 	}
 
+#[allow(dead_code)]
 fn save_event() {
 	let imlogsconn = Connection::open(IMLOGS).expect("Error: Could not open events database.");
 	imlogsconn.execute("create table if not exists EVENTS (`DATE` datetime not null, `TYPE` tinytext not null, `PATH` text not null, `PERMS` tinytext not null, `CHANGE` tinytext not null)",[]).expect("Error: Could not create EVENTS table in Integrity Lumy database");
@@ -0,0 +1,122 @@
+// Admin API client
+//
+// Blocking HTTPS client for the server's admin API. The server certificate is self-signed,
+// so it is trusted from a CA file rather than the system roots.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use native_tls::{Certificate, TlsConnector};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use ureq::{Agent, AgentBuilder, Request};
+use luminum_proto::admin::{API_PREFIX, ApiError};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum ClientError {
+	// The server answered with an error
+	Api(u16, String),
+	Transport(String),
+	Setup(String)
+	}
+
+impl fmt::Display for ClientError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ClientError::Api(401, message) => write!(f, "{} (check the API key)", message),
+			ClientError::Api(_, message) => write!(f, "{}", message),
+			ClientError::Transport(message) => write!(f, "unable to reach the server: {}", message),
+			ClientError::Setup(message) => write!(f, "{}", message)
+			}
+		}
+	}
+
+impl Error for ClientError {}
+
+impl From<ureq::Error> for ClientError {
+	fn from(err: ureq::Error) -> Self {
+		match err {
+			ureq::Error::Status(code, response) => {
+				let status = response.status_text().to_string();
+				let message = response.into_json::<ApiError>().map(|body| body.error).unwrap_or(status);
+				ClientError::Api(code, message)
+				},
+			ureq::Error::Transport(err) => ClientError::Transport(err.to_string())
+			}
+		}
+	}
+
+pub struct Client {
+	agent: Agent,
+	base: String,
+	authorization: String
+	}
+
+impl Client {
+	// Trust the certificates in the given PEM files. With verify_hostname off, the server
+	// only has to present one of them, whatever name it was reached by.
+	pub fn new(server: &str, api_key: &str, ca_files: &[String], verify_hostname: bool) -> Result<Client, ClientError> {
+		let mut builder = TlsConnector::builder();
+		builder.disable_built_in_roots(true);
+		builder.danger_accept_invalid_hostnames(!verify_hostname);
+		for path in ca_files {
+			let pem = fs::read(path).map_err(|err| ClientError::Setup(format!("unable to read CA file {}: {}", path, err)))?;
+			let certificates = Certificate::stack_from_pem(&pem).map_err(|err| ClientError::Setup(format!("invalid CA file {}: {}", path, err)))?;
+			for certificate in certificates {
+				builder.add_root_certificate(certificate);
+				}
+			}
+		let connector = builder.build().map_err(|err| ClientError::Setup(err.to_string()))?;
+		Ok(Client {
+			agent: AgentBuilder::new().tls_connector(Arc::new(connector)).timeout(TIMEOUT).build(),
+			base: format!("{}{}", server.trim_end_matches('/'), API_PREFIX),
+			authorization: format!("Bearer {}", api_key.trim())
+			})
+		}
+
+	fn request(&self, method: &str, path: &str) -> Request {
+		self.agent.request(method, &format!("{}{}", self.base, path)).set("Authorization", &self.authorization)
+		}
+
+	pub fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T, ClientError> {
+		let response = self.request("GET", path).query_pairs(query.iter().copied()).call()?;
+		response.into_json().map_err(|err| ClientError::Transport(err.to_string()))
+		}
+
+	pub fn send<B: Serialize, T: DeserializeOwned>(&self, method: &str, path: &str, body: &B) -> Result<T, ClientError> {
+		let response = self.request(method, path).send_json(body)?;
+		response.into_json().map_err(|err| ClientError::Transport(err.to_string()))
+		}
+
+	// For requests answered without a body
+	pub fn call(&self, method: &str, path: &str) -> Result<(), ClientError> {
+		self.request(method, path).call()?;
+		Ok(())
+		}
+
+	// Copy a response body to the output as it arrives
+	pub fn download<W: Write>(&self, path: &str, output: &mut W) -> Result<(), ClientError> {
+		let response = self.request("GET", path).call()?;
+		io::copy(&mut response.into_reader(), output).map_err(|err| ClientError::Transport(err.to_string()))?;
+		Ok(())
+		}
+
+	// For requests with a body that are answered without one
+	pub fn submit<B: Serialize>(&self, method: &str, path: &str, body: &B) -> Result<(), ClientError> {
+		self.request(method, path).send_json(body)?;
+		Ok(())
+		}
+	}
+
+// Percent-encode a value used as a path segment
+pub fn segment(value: &str) -> String {
+	value.bytes().map(|byte| match byte {
+		b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+		_ => format!("%{:02X}", byte)
+		}).collect()
+	}
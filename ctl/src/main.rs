@@ -0,0 +1,800 @@
+// Luminum Control
+// by Christopher R. Curzio <ccurzio@luminum.net>
+//
+// Command-line administration of a Luminum Server through its admin API.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::process;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches};
+use luminum_proto::CommandKind;
+use luminum_proto::admin::{AnswerInfo, ApiKeyInfo, CommandInfo, CreatedApiKey, CreatedToken, EndpointDetail, EndpointGroups, EndpointInfo, FileEventInfo, GroupInfo, NewApiKey, NewOperator, NewQuestion, NewToken,
+	OperatorInfo, OperatorUpdate, Page, PasswordChange, PrincipalInfo, QuestionInfo, ServerConfig, TokenInfo, Watchlist};
+use luminum_proto::audit::{AuditRecord, AuditVerification, ChainVerifier};
+use client::{Client, ClientError, segment};
+
+mod client;
+mod output;
+
+const VER: &str = "0.0.1";
+const DEFAULT_SERVER: &str = "https://127.0.0.1:10467";
+// The server certificate, and the one it replaces while a renewal rolls out
+const DCPATH: &str = "/opt/Luminum/LuminumServer/config/luminum.crt";
+const DCPREVPATH: &str = "/opt/Luminum/LuminumServer/config/luminum.crt.prev";
+// Audit events, as the server names them
+const AUDIT_EVENTS: [&str; 14] = ["registration", "enrollment-denied", "identity-mismatch", "sign-in", "sign-in-failed", "sign-out", "question", "action",
+	"configuration", "endpoint", "enrollment", "access", "server", "audit"];
+
+fn main() {
+	let uid = || Arg::with_name("uid").value_name("UID").help("Endpoint UID").required(true);
+	let token_id = || Arg::with_name("id").value_name("TOKEN_ID").help("Enrollment token ID").required(true);
+	let paths = || Arg::with_name("paths").value_name("PATH").help("Watched path").required(true).multiple_values(true);
+	let question_id = || Arg::with_name("id").value_name("QUESTION_ID").help("Question ID").required(true);
+	let username = || Arg::with_name("username").value_name("USERNAME").help("Operator username").required(true);
+	let role = || Arg::with_name("role").long("role").value_name("ROLE").help("Operator role").possible_values(["viewer", "operator", "administrator"]).takes_value(true);
+	let since = || Arg::with_name("since").long("since").value_name("TIME").help("Only at or after TIME, as Unix seconds or an age such as 30m or 2d").takes_value(true);
+	let until = || Arg::with_name("until").long("until").value_name("TIME").help("Only at or before TIME, as Unix seconds or an age such as 30m or 2d").takes_value(true);
+	let password_file = || Arg::with_name("password-file").long("password-file").value_name("FILE").help("File containing the password, instead of prompting for it").takes_value(true);
+	let matches = App::new("Luminum Control")
+		.version(VER)
+		.author("Christopher R. Curzio <ccurzio@accipiter.org>")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+	.arg(Arg::with_name("server")
+		.long("server")
+		.value_name("URL")
+		.help("Admin API address of the server, also read from LUMINUM_SERVER [default: https://127.0.0.1:10467]")
+		.takes_value(true)
+		.global(true))
+	.arg(Arg::with_name("api-key-file")
+		.long("api-key-file")
+		.value_name("FILE")
+		.help("File containing the API key; otherwise it is read from LUMINUM_API_KEY")
+		.takes_value(true)
+		.global(true))
+	.arg(Arg::with_name("ca-file")
+		.long("ca-file")
+		.value_name("FILE")
+		.help("PEM certificates to trust for the server [default: the server certificate on this host]")
+		.takes_value(true)
+		.multiple_occurrences(true)
+		.global(true))
+	.arg(Arg::with_name("no-verify-hostname")
+		.long("no-verify-hostname")
+		.help("Accept a trusted certificate whatever name the server was reached by")
+		.takes_value(false)
+		.global(true))
+	.arg(Arg::with_name("json")
+		.long("json")
+		.help("Print results as JSON")
+		.takes_value(false)
+		.global(true))
+	.arg(Arg::with_name("limit")
+		.long("limit")
+		.value_name("COUNT")
+		.help("Largest number of items to list [default: 100]")
+		.takes_value(true)
+		.global(true))
+	.arg(Arg::with_name("offset")
+		.long("offset")
+		.value_name("COUNT")
+		.help("Number of items to skip when listing")
+		.takes_value(true)
+		.global(true))
+	.subcommand(App::new("endpoints")
+		.about("List and manage registered endpoints")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(App::new("list")
+			.about("List endpoints")
+			.arg(Arg::with_name("search")
+				.long("search")
+				.value_name("TEXT")
+				.help("Only endpoints whose UID, hostname or address contains TEXT")
+				.takes_value(true))
+			.arg(Arg::with_name("presence")
+				.long("presence")
+				.value_name("STATE")
+				.help("Only endpoints in this presence state")
+				.possible_values(["online", "stale", "offline"])
+				.takes_value(true))
+			.arg(Arg::with_name("group")
+				.long("group")
+				.value_name("GROUP")
+				.help("Only endpoints in this group")
+				.takes_value(true)))
+		.subcommand(App::new("show").about("Show an endpoint and its commands").arg(uid()))
+		.subcommand(App::new("history").about("Show an endpoint's attribute changes and presence events").arg(uid()))
+		.subcommand(App::new("revoke").about("Revoke an endpoint").arg(uid()))
+		.subcommand(App::new("delete")
+			.about("Delete an endpoint and everything stored about it")
+			.arg(uid())
+			.arg(Arg::with_name("yes")
+				.long("yes")
+				.help("Confirm the deletion")
+				.takes_value(false))))
+	.subcommand(App::new("tokens")
+		.about("Manage enrollment tokens")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(App::new("list").about("List enrollment tokens"))
+		.subcommand(App::new("create")
+			.about("Create an enrollment token")
+			.arg(Arg::with_name("description")
+				.long("description")
+				.value_name("TEXT")
+				.help("Description of the token")
+				.takes_value(true))
+			.arg(Arg::with_name("groups")
+				.long("groups")
+				.value_name("GROUPS")
+				.help("Comma-separated groups assigned to endpoints enrolled with the token")
+				.takes_value(true))
+			.arg(Arg::with_name("expires")
+				.long("expires")
+				.value_name("LIFETIME")
+				.help("Lifetime of the token, e.g. 12h or 30d, or \"never\" [default: 7d]")
+				.takes_value(true))
+			.arg(Arg::with_name("max-uses")
+				.long("max-uses")
+				.value_name("COUNT")
+				.help("Number of endpoints the token may enroll [default: unlimited]")
+				.takes_value(true)))
+		.subcommand(App::new("revoke").about("Revoke an enrollment token").arg(token_id()))
+		.subcommand(App::new("endpoints").about("List endpoints enrolled with a token").arg(token_id())))
+	.subcommand(App::new("watchlist")
+		.about("View and edit endpoint Integrity watchlists")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(App::new("show").about("Show an endpoint's watchlist").arg(uid()))
+		.subcommand(App::new("set").about("Replace an endpoint's watchlist").arg(uid()).arg(paths()))
+		.subcommand(App::new("add").about("Add paths to an endpoint's watchlist").arg(uid()).arg(paths()))
+		.subcommand(App::new("remove").about("Remove paths from an endpoint's watchlist").arg(uid()).arg(paths())))
+	.subcommand(App::new("groups")
+		.about("List endpoint groups and change an endpoint's groups")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(App::new("list").about("List groups and how many endpoints each has"))
+		.subcommand(App::new("endpoints")
+			.about("List the endpoints in a group")
+			.arg(Arg::with_name("name").value_name("GROUP").help("Group name").required(true)))
+		.subcommand(App::new("set")
+			.about("Replace an endpoint's groups")
+			.arg(uid())
+			.arg(Arg::with_name("groups").value_name("GROUP").help("Group name; give none to remove the endpoint from every group").multiple_values(true))))
+	.subcommand(App::new("commands")
+		.about("List and queue endpoint commands")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(App::new("list").about("List the commands queued for an endpoint").arg(uid()))
+		.subcommand(App::new("queue")
+			.about("Queue a command for an endpoint")
+			.arg(uid())
+			.arg(Arg::with_name("question")
+				.long("question")
+				.value_name("QUESTION")
+				.help("Ask the endpoint a question, such as hostname or uptime")
+				.takes_value(true))
+			.arg(Arg::with_name("config")
+				.long("config")
+				.value_name("KEY=VALUE")
+				.help("Change a client configuration value")
+				.takes_value(true))
+			.arg(Arg::with_name("action")
+				.long("action")
+				.value_name("NAME")
+				.help("Run a built-in client action")
+				.takes_value(true))
+			.arg(Arg::with_name("args").value_name("ARG").help("Action argument").multiple_values(true).requires("action"))
+			.group(ArgGroup::with_name("command").args(&["question", "config", "action"]).required(true))))
+	.subcommand(App::new("questions")
+		.about("Ask questions of several endpoints and read their answers")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(App::new("ask")
+			.about("Ask a question of every endpoint, a group, or the given endpoints")
+			.arg(Arg::with_name("question").value_name("QUESTION").help("Question, such as hostname or uptime").required(true))
+			.arg(Arg::with_name("group")
+				.long("group")
+				.value_name("GROUP")
+				.help("Ask the endpoints in this group")
+				.takes_value(true)
+				.conflicts_with("uid"))
+			.arg(Arg::with_name("uid")
+				.long("uid")
+				.value_name("UID")
+				.help("Ask this endpoint; may be given more than once")
+				.takes_value(true)
+				.multiple_occurrences(true)))
+		.subcommand(App::new("list").about("List questions, newest first"))
+		.subcommand(App::new("show").about("Show a question and how many endpoints have answered").arg(question_id()))
+		.subcommand(App::new("results")
+			.about("Show the answers to a question")
+			.arg(question_id())
+			.arg(Arg::with_name("state")
+				.long("state")
+				.value_name("STATE")
+				.help("Only answers in this state")
+				.possible_values(["queued", "sent", "succeeded", "failed", "cancelled"])
+				.takes_value(true))))
+	.subcommand(App::new("events")
+		.about("List Integrity file events, newest first")
+		.arg(Arg::with_name("uid")
+			.long("uid")
+			.value_name("UID")
+			.help("Only events from this endpoint")
+			.takes_value(true))
+		.arg(Arg::with_name("path")
+			.long("path")
+			.value_name("PREFIX")
+			.help("Only events for paths starting with PREFIX")
+			.takes_value(true))
+		.arg(Arg::with_name("kind")
+			.long("kind")
+			.value_name("KIND")
+			.help("Only events of this kind")
+			.possible_values(["create", "modify", "remove", "access", "other"])
+			.takes_value(true))
+		.arg(Arg::with_name("since")
+			.long("since")
+			.value_name("TIME")
+			.help("Only events at or after TIME, as Unix seconds or an age such as 30m or 2d")
+			.takes_value(true))
+		.arg(Arg::with_name("until")
+			.long("until")
+			.value_name("TIME")
+			.help("Only events at or before TIME, as Unix seconds or an age such as 30m or 2d")
+			.takes_value(true)))
+	.subcommand(App::new("config")
+		.about("Show the server's running configuration")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(App::new("show").about("Show the server's running configuration")))
+	.subcommand(App::new("whoami")
+		.about("Show the operator, scopes and groups of the API key in use"))
+	.subcommand(App::new("password")
+		.about("Change your operator password")
+		.arg(password_file()))
+	.subcommand(App::new("operators")
+		.about("Manage operator accounts (administrators only)")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(App::new("list").about("List operators"))
+		.subcommand(App::new("show").about("Show an operator").arg(username()))
+		.subcommand(App::new("create")
+			.about("Create an operator")
+			.arg(username())
+			.arg(role().help("Operator role [default: viewer]"))
+			.arg(Arg::with_name("groups")
+				.long("groups")
+				.value_name("GROUPS")
+				.help("Comma-separated endpoint groups the operator is limited to [default: every endpoint]")
+				.takes_value(true))
+			.arg(password_file()))
+		.subcommand(App::new("update")
+			.about("Change an operator's role, groups, state or password")
+			.arg(username())
+			.arg(role())
+			.arg(Arg::with_name("groups")
+				.long("groups")
+				.value_name("GROUPS")
+				.help("Comma-separated endpoint groups the operator is limited to; an empty list for every endpoint")
+				.takes_value(true))
+			.arg(Arg::with_name("disable")
+				.long("disable")
+				.help("Stop the operator signing in or using their API keys")
+				.takes_value(false))
+			.arg(Arg::with_name("enable")
+				.long("enable")
+				.help("Re-enable a disabled operator")
+				.conflicts_with("disable")
+				.takes_value(false))
+			.arg(Arg::with_name("reset-password")
+				.long("reset-password")
+				.help("Set a new password for the operator")
+				.takes_value(false))
+			.arg(password_file().requires("reset-password")))
+		.subcommand(App::new("delete")
+			.about("Delete an operator and revoke their API keys")
+			.arg(username())
+			.arg(Arg::with_name("yes")
+				.long("yes")
+				.help("Confirm the deletion")
+				.takes_value(false))))
+	.subcommand(App::new("api-keys")
+		.about("Manage API keys. Administrators see every key; others see their own.")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(App::new("list").about("List API keys"))
+		.subcommand(App::new("create")
+			.about("Create an API key acting for you, with some or all of your permissions")
+			.arg(Arg::with_name("description")
+				.long("description")
+				.value_name("TEXT")
+				.help("Description of the key")
+				.takes_value(true))
+			.arg(Arg::with_name("scopes")
+				.long("scopes")
+				.value_name("SCOPES")
+				.help("Comma-separated permissions: read, ask, configure, deploy, endpoints, enrollment, admin [default: all of yours]")
+				.takes_value(true))
+			.arg(Arg::with_name("expires")
+				.long("expires")
+				.value_name("LIFETIME")
+				.help("Lifetime of the key, e.g. 12h or 90d, or \"never\" [default: never]")
+				.takes_value(true)))
+		.subcommand(App::new("revoke").about("Revoke an API key").arg(Arg::with_name("id").value_name("KEY_ID").help("API key ID").required(true))))
+	.subcommand(App::new("audit")
+		.about("Read, export and check the server's audit log (administrators only)")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(App::new("list")
+			.about("List audit records, newest first")
+			.arg(Arg::with_name("event")
+				.long("event")
+				.value_name("EVENT")
+				.help("Only records of this event")
+				.possible_values(AUDIT_EVENTS)
+				.takes_value(true))
+			.arg(Arg::with_name("actor")
+				.long("actor")
+				.value_name("TEXT")
+				.help("Only records whose actor contains TEXT")
+				.takes_value(true))
+			.arg(Arg::with_name("source")
+				.long("source")
+				.value_name("ADDRESS")
+				.help("Only records from this IP address, or \"local\" for the server host")
+				.takes_value(true))
+			.arg(since())
+			.arg(until()))
+		.subcommand(App::new("export")
+			.about("Export the whole audit log as JSON lines, oldest first")
+			.arg(Arg::with_name("output")
+				.long("output")
+				.value_name("FILE")
+				.help("File to write the export to [default: standard output]")
+				.takes_value(true)))
+		.subcommand(App::new("verify")
+			.about("Check the audit log's hash chain on the server, or in an export")
+			.arg(Arg::with_name("file")
+				.long("file")
+				.value_name("FILE")
+				.help("Check this export instead of asking the server")
+				.takes_value(true))))
+	.get_matches();
+
+	// Checking an export needs no server
+	if let Some(("audit", matches)) = matches.subcommand() {
+		if let Some(("verify", verify)) = matches.subcommand() {
+			if let Some(path) = verify.value_of("file") {
+				match verify_export(path) {
+					Ok(verification) => report_verification(&verification, matches.is_present("json")),
+					Err(err) => {
+						eprintln!("Error: {}", err);
+						process::exit(1);
+						}
+					}
+				}
+			}
+		}
+
+	let client = match connect(&matches) {
+		Ok(client) => client,
+		Err(err) => {
+			eprintln!("Error: {}", err);
+			process::exit(1);
+			}
+		};
+	let json = matches.is_present("json");
+	let result = match matches.subcommand() {
+		Some(("endpoints", matches)) => endpoints(&client, matches, json),
+		Some(("tokens", matches)) => tokens(&client, matches, json),
+		Some(("watchlist", matches)) => watchlist(&client, matches, json),
+		Some(("groups", matches)) => groups(&client, matches, json),
+		Some(("commands", matches)) => commands(&client, matches, json),
+		Some(("questions", matches)) => questions(&client, matches, json),
+		Some(("events", matches)) => events(&client, matches, json),
+		Some(("config", _)) => config(&client, json),
+		Some(("whoami", _)) => whoami(&client, json),
+		Some(("password", matches)) => password(&client, matches),
+		Some(("operators", matches)) => operators(&client, matches, json),
+		Some(("api-keys", matches)) => api_keys(&client, matches, json),
+		Some(("audit", matches)) => audit(&client, matches, json),
+		_ => Ok(())
+		};
+	if let Err(err) = result {
+		eprintln!("Error: {}", err);
+		process::exit(1);
+		}
+	}
+
+fn connect(matches: &ArgMatches) -> Result<Client, ClientError> {
+	let server = matches.value_of("server").map(String::from).or_else(|| env::var("LUMINUM_SERVER").ok()).unwrap_or_else(|| DEFAULT_SERVER.to_string());
+	let api_key = match matches.value_of("api-key-file") {
+		Some(path) => fs::read_to_string(path).map_err(|err| ClientError::Setup(format!("unable to read API key file {}: {}", path, err)))?,
+		None => env::var("LUMINUM_API_KEY").map_err(|_| ClientError::Setup(String::from("no API key given. Use --api-key-file or set LUMINUM_API_KEY.")))?
+		};
+	let ca_files: Vec<String> = match matches.values_of("ca-file") {
+		Some(files) => files.map(String::from).collect(),
+		None => [DCPATH, DCPREVPATH].iter().filter(|path| fs::metadata(path).is_ok()).map(|path| path.to_string()).collect()
+		};
+	if ca_files.is_empty() {
+		return Err(ClientError::Setup(format!("{} not found. Use --ca-file to name the server certificate.", DCPATH)));
+		}
+	Client::new(&server, &api_key, &ca_files, !matches.is_present("no-verify-hostname"))
+	}
+
+// Query parameters for a listing: the named filters that were given, and the page wanted
+fn query<'a>(matches: &'a ArgMatches, filters: &[&'static str]) -> Vec<(&'static str, &'a str)> {
+	filters.iter().chain(&["limit", "offset"]).filter_map(|name| matches.value_of(name).map(|value| (*name, value))).collect()
+	}
+
+fn endpoints(client: &Client, matches: &ArgMatches, json: bool) -> Result<(), ClientError> {
+	match matches.subcommand() {
+		Some(("list", matches)) => {
+			let endpoints: Page<EndpointInfo> = client.get("/endpoints", &query(matches, &["search", "presence", "group"]))?;
+			if json { output::json(&endpoints); } else { output::endpoints(&endpoints.items); output::more(&endpoints); }
+			},
+		Some((command @ ("show" | "history"), matches)) => {
+			let detail: EndpointDetail = client.get(&format!("/endpoints/{}", segment(matches.value_of("uid").unwrap_or_default())), &[])?;
+			if json { output::json(&detail); }
+			else if command == "show" { output::endpoint(&detail); }
+			else { output::history(&detail); }
+			},
+		Some(("revoke", matches)) => {
+			let uid = matches.value_of("uid").unwrap_or_default();
+			client.call("POST", &format!("/endpoints/{}/revoke", segment(uid)))?;
+			if !json { println!("Endpoint {} revoked.", uid); }
+			},
+		Some(("delete", matches)) => {
+			let uid = matches.value_of("uid").unwrap_or_default();
+			if !matches.is_present("yes") {
+				return Err(ClientError::Setup(format!("deleting endpoint {} removes its history and watchlist. Add --yes to confirm.", uid)));
+				}
+			client.call("DELETE", &format!("/endpoints/{}", segment(uid)))?;
+			if !json { println!("Endpoint {} deleted.", uid); }
+			},
+		_ => {}
+		}
+	Ok(())
+	}
+
+fn tokens(client: &Client, matches: &ArgMatches, json: bool) -> Result<(), ClientError> {
+	match matches.subcommand() {
+		Some(("list", matches)) => {
+			let tokens: Page<TokenInfo> = client.get("/tokens", &query(matches, &[]))?;
+			if json { output::json(&tokens); } else { output::tokens(&tokens.items); output::more(&tokens); }
+			},
+		Some(("create", matches)) => {
+			let max_uses = match matches.value_of("max-uses") {
+				Some(count) => Some(count.parse::<u32>().map_err(|_| ClientError::Setup(format!("invalid maximum use count: {}", count)))?),
+				None => None
+				};
+			let request = NewToken {
+				description: matches.value_of("description").unwrap_or_default().to_string(),
+				groups: matches.value_of("groups").unwrap_or_default().split(',').map(str::trim).filter(|group| !group.is_empty()).map(String::from).collect(),
+				expires: matches.value_of("expires").map(String::from),
+				max_uses
+				};
+			let created: CreatedToken = client.send("POST", "/tokens", &request)?;
+			if json { output::json(&created); }
+			else {
+				println!("Enrollment token {} created.", created.info.id);
+				println!("Expires: {}", output::format_timestamp(created.info.expires));
+				println!("Maximum uses: {}", created.info.max_uses.map(|count| count.to_string()).unwrap_or(String::from("unlimited")));
+				println!("Groups: {}", created.info.groups.join(", "));
+				println!("\nToken: {}\n", created.token);
+				println!("NOTE: This will be the only time this token will be made available. Please make a note of it!");
+				}
+			},
+		Some(("revoke", matches)) => {
+			let id = matches.value_of("id").unwrap_or_default();
+			client.call("POST", &format!("/tokens/{}/revoke", segment(id)))?;
+			if !json { println!("Enrollment token {} revoked.", id); }
+			},
+		Some(("endpoints", matches)) => {
+			let endpoints: Page<EndpointInfo> = client.get(&format!("/tokens/{}/endpoints", segment(matches.value_of("id").unwrap_or_default())), &query(matches, &[]))?;
+			if json { output::json(&endpoints); } else { output::endpoints(&endpoints.items); output::more(&endpoints); }
+			},
+		_ => {}
+		}
+	Ok(())
+	}
+
+fn watchlist(client: &Client, matches: &ArgMatches, json: bool) -> Result<(), ClientError> {
+	let Some((command, matches)) = matches.subcommand() else { return Ok(()); };
+	let path = format!("/endpoints/{}/watchlist", segment(matches.value_of("uid").unwrap_or_default()));
+	let given: Vec<String> = matches.values_of("paths").map(|paths| paths.map(String::from).collect()).unwrap_or_default();
+	let watchlist: Watchlist = match command {
+		"show" => client.get(&path, &[])?,
+		"set" => client.send("PUT", &path, &Watchlist { paths: given })?,
+		_ => {
+			// Edit the current watchlist and save it whole
+			let mut current: Watchlist = client.get(&path, &[])?;
+			if command == "add" {
+				current.paths.extend(given);
+				}
+			else {
+				current.paths.retain(|path| !given.contains(path));
+				}
+			client.send("PUT", &path, &current)?
+			}
+		};
+	if json { output::json(&watchlist); } else { output::watchlist(&watchlist); }
+	Ok(())
+	}
+
+fn groups(client: &Client, matches: &ArgMatches, json: bool) -> Result<(), ClientError> {
+	match matches.subcommand() {
+		Some(("list", matches)) => {
+			let groups: Page<GroupInfo> = client.get("/groups", &query(matches, &[]))?;
+			if json { output::json(&groups); } else { output::groups(&groups.items); output::more(&groups); }
+			},
+		Some(("endpoints", matches)) => {
+			let endpoints: Page<EndpointInfo> = client.get(&format!("/groups/{}/endpoints", segment(matches.value_of("name").unwrap_or_default())), &query(matches, &[]))?;
+			if json { output::json(&endpoints); } else { output::endpoints(&endpoints.items); output::more(&endpoints); }
+			},
+		Some(("set", matches)) => {
+			let uid = matches.value_of("uid").unwrap_or_default();
+			let request = EndpointGroups { groups: matches.values_of("groups").map(|groups| groups.map(String::from).collect()).unwrap_or_default() };
+			let saved: EndpointGroups = client.send("PUT", &format!("/endpoints/{}/groups", segment(uid)), &request)?;
+			if json { output::json(&saved); }
+			else if saved.groups.is_empty() { println!("Endpoint {} is in no groups.", uid); }
+			else { println!("Endpoint {} is in groups: {}", uid, saved.groups.join(", ")); }
+			},
+		_ => {}
+		}
+	Ok(())
+	}
+
+fn commands(client: &Client, matches: &ArgMatches, json: bool) -> Result<(), ClientError> {
+	match matches.subcommand() {
+		Some(("list", matches)) => {
+			let commands: Page<CommandInfo> = client.get(&format!("/endpoints/{}/commands", segment(matches.value_of("uid").unwrap_or_default())), &query(matches, &[]))?;
+			if json { output::json(&commands); } else { output::commands(&commands.items); output::more(&commands); }
+			},
+		Some(("queue", matches)) => {
+			let uid = matches.value_of("uid").unwrap_or_default();
+			let kind = if let Some(question) = matches.value_of("question") {
+				CommandKind::Question(question.to_string())
+				}
+			else if let Some(setting) = matches.value_of("config") {
+				let (key, value) = setting.split_once('=').ok_or_else(|| ClientError::Setup(format!("invalid configuration setting: {} (expected KEY=VALUE)", setting)))?;
+				CommandKind::SetConfig { key: key.to_string(), value: value.to_string() }
+				}
+			else {
+				CommandKind::Action {
+					name: matches.value_of("action").unwrap_or_default().to_string(),
+					args: matches.values_of("args").map(|args| args.map(String::from).collect()).unwrap_or_default()
+					}
+				};
+			let command: CommandInfo = client.send("POST", &format!("/endpoints/{}/commands", segment(uid)), &kind)?;
+			if json { output::json(&command); } else { println!("Command {} queued for endpoint {}.", command.id, uid); }
+			},
+		_ => {}
+		}
+	Ok(())
+	}
+
+fn questions(client: &Client, matches: &ArgMatches, json: bool) -> Result<(), ClientError> {
+	match matches.subcommand() {
+		Some(("ask", matches)) => {
+			let request = NewQuestion {
+				question: matches.value_of("question").unwrap_or_default().to_string(),
+				uids: matches.values_of("uid").map(|uids| uids.map(String::from).collect()).unwrap_or_default(),
+				group: matches.value_of("group").map(String::from)
+				};
+			let question: QuestionInfo = client.send("POST", "/questions", &request)?;
+			if json { output::json(&question); }
+			else { println!("Question {} asked of {} ({} endpoints).", question.id, question.target, question.endpoints); }
+			},
+		Some(("list", matches)) => {
+			let questions: Page<QuestionInfo> = client.get("/questions", &query(matches, &[]))?;
+			if json { output::json(&questions); } else { output::questions(&questions.items); output::more(&questions); }
+			},
+		Some(("show", matches)) => {
+			let question: QuestionInfo = client.get(&format!("/questions/{}", segment(matches.value_of("id").unwrap_or_default())), &[])?;
+			if json { output::json(&question); } else { output::question(&question); }
+			},
+		Some(("results", matches)) => {
+			let answers: Page<AnswerInfo> = client.get(&format!("/questions/{}/results", segment(matches.value_of("id").unwrap_or_default())), &query(matches, &["state"]))?;
+			if json { output::json(&answers); } else { output::answers(&answers.items); output::more(&answers); }
+			},
+		_ => {}
+		}
+	Ok(())
+	}
+
+fn events(client: &Client, matches: &ArgMatches, json: bool) -> Result<(), ClientError> {
+	let times = times(matches)?;
+	let mut query = query(matches, &["uid", "path", "kind"]);
+	query.extend(times.iter().map(|(name, value)| (*name, value.as_str())));
+	let events: Page<FileEventInfo> = client.get("/integrity/events", &query)?;
+	if json { output::json(&events); } else { output::events(&events.items); output::more(&events); }
+	Ok(())
+	}
+
+// The --since and --until filters given, as Unix seconds
+fn times(matches: &ArgMatches) -> Result<Vec<(&'static str, String)>, ClientError> {
+	let mut times = Vec::new();
+	for name in ["since", "until"] {
+		if let Some(value) = matches.value_of(name) {
+			times.push((name, parse_time(value).ok_or_else(|| ClientError::Setup(format!("invalid time: {} (expected Unix seconds or an age such as 30m or 2d)", value)))?.to_string()));
+			}
+		}
+	Ok(times)
+	}
+
+// Unix seconds, or an age counted back from now such as 90s, 30m, 12h or 2d
+fn parse_time(value: &str) -> Option<i64> {
+	if let Ok(secs) = value.parse::<i64>() {
+		return Some(secs);
+		}
+	let unit = match value.chars().last()? {
+		's' => 1,
+		'm' => 60,
+		'h' => 3600,
+		'd' => 86400,
+		_ => { return None; }
+		};
+	let count = value[..value.len() - 1].parse::<i64>().ok()?;
+	let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?.as_secs() as i64;
+	Some(now - count.checked_mul(unit)?)
+	}
+
+fn config(client: &Client, json: bool) -> Result<(), ClientError> {
+	let config: ServerConfig = client.get("/config", &[])?;
+	if json { output::json(&config); } else { output::config(&config); }
+	Ok(())
+	}
+
+fn whoami(client: &Client, json: bool) -> Result<(), ClientError> {
+	let principal: PrincipalInfo = client.get("/me", &[])?;
+	if json { output::json(&principal); } else { output::principal(&principal); }
+	Ok(())
+	}
+
+fn password(client: &Client, matches: &ArgMatches) -> Result<(), ClientError> {
+	let current_password = rpassword::read_password_from_tty(Some("Current password: ")).map_err(|err| ClientError::Setup(err.to_string()))?;
+	let new_password = new_password(matches)?;
+	client.submit("PUT", "/me/password", &PasswordChange { current_password, new_password })?;
+	println!("Password changed.");
+	Ok(())
+	}
+
+fn operators(client: &Client, matches: &ArgMatches, json: bool) -> Result<(), ClientError> {
+	match matches.subcommand() {
+		Some(("list", matches)) => {
+			let operators: Page<OperatorInfo> = client.get("/operators", &query(matches, &[]))?;
+			if json { output::json(&operators); } else { output::operators(&operators.items); output::more(&operators); }
+			},
+		Some(("show", matches)) => {
+			let operator: OperatorInfo = client.get(&format!("/operators/{}", segment(matches.value_of("username").unwrap_or_default())), &[])?;
+			if json { output::json(&operator); } else { output::operator(&operator); }
+			},
+		Some(("create", matches)) => {
+			let request = NewOperator {
+				username: matches.value_of("username").unwrap_or_default().to_string(),
+				password: new_password(matches)?,
+				role: matches.value_of("role").map(String::from),
+				groups: split_list(matches.value_of("groups").unwrap_or_default())
+				};
+			let operator: OperatorInfo = client.send("POST", "/operators", &request)?;
+			if json { output::json(&operator); } else { println!("Operator {} created as {}.", operator.username, operator.role); }
+			},
+		Some(("update", matches)) => {
+			let request = OperatorUpdate {
+				role: matches.value_of("role").map(String::from),
+				groups: matches.value_of("groups").map(split_list),
+				disabled: if matches.is_present("disable") { Some(true) } else if matches.is_present("enable") { Some(false) } else { None },
+				password: if matches.is_present("reset-password") { Some(new_password(matches)?) } else { None }
+				};
+			if request == OperatorUpdate::default() {
+				return Err(ClientError::Setup(String::from("nothing to change. Give --role, --groups, --disable, --enable or --reset-password.")));
+				}
+			let operator: OperatorInfo = client.send("PUT", &format!("/operators/{}", segment(matches.value_of("username").unwrap_or_default())), &request)?;
+			if json { output::json(&operator); } else { println!("Operator {} updated.", operator.username); }
+			},
+		Some(("delete", matches)) => {
+			let username = matches.value_of("username").unwrap_or_default();
+			if !matches.is_present("yes") {
+				return Err(ClientError::Setup(format!("deleting operator {} also revokes their API keys. Add --yes to confirm.", username)));
+				}
+			client.call("DELETE", &format!("/operators/{}", segment(username)))?;
+			if !json { println!("Operator {} deleted.", username); }
+			},
+		_ => {}
+		}
+	Ok(())
+	}
+
+fn api_keys(client: &Client, matches: &ArgMatches, json: bool) -> Result<(), ClientError> {
+	match matches.subcommand() {
+		Some(("list", matches)) => {
+			let keys: Page<ApiKeyInfo> = client.get("/api-keys", &query(matches, &[]))?;
+			if json { output::json(&keys); } else { output::api_keys(&keys.items); output::more(&keys); }
+			},
+		Some(("create", matches)) => {
+			let request = NewApiKey {
+				description: matches.value_of("description").unwrap_or_default().to_string(),
+				scopes: split_list(matches.value_of("scopes").unwrap_or_default()),
+				expires: matches.value_of("expires").map(String::from)
+				};
+			let created: CreatedApiKey = client.send("POST", "/api-keys", &request)?;
+			if json { output::json(&created); }
+			else {
+				println!("API key {} created.", created.info.id);
+				println!("Scopes: {}", created.info.scopes.join(", "));
+				println!("Expires: {}", output::format_timestamp(created.info.expires));
+				println!("\nKey: {}\n", created.key);
+				println!("NOTE: This will be the only time this key will be made available. Please make a note of it!");
+				}
+			},
+		Some(("revoke", matches)) => {
+			let id = matches.value_of("id").unwrap_or_default();
+			client.call("POST", &format!("/api-keys/{}/revoke", segment(id)))?;
+			if !json { println!("API key {} revoked.", id); }
+			},
+		_ => {}
+		}
+	Ok(())
+	}
+
+fn audit(client: &Client, matches: &ArgMatches, json: bool) -> Result<(), ClientError> {
+	match matches.subcommand() {
+		Some(("list", matches)) => {
+			let times = times(matches)?;
+			let mut query = query(matches, &["event", "actor", "source"]);
+			query.extend(times.iter().map(|(name, value)| (*name, value.as_str())));
+			let records: Page<AuditRecord> = client.get("/audit", &query)?;
+			if json { output::json(&records); } else { output::audit_records(&records.items); output::more(&records); }
+			},
+		Some(("export", matches)) => match matches.value_of("output") {
+			Some(path) => {
+				let mut file = File::create(path).map_err(|err| ClientError::Setup(format!("unable to create {}: {}", path, err)))?;
+				client.download("/audit/export", &mut file)?;
+				if !json { println!("Audit log exported to {}.", path); }
+				},
+			None => { client.download("/audit/export", &mut io::stdout().lock())?; }
+			},
+		Some(("verify", _)) => {
+			let verification: AuditVerification = client.get("/audit/verify", &[])?;
+			report_verification(&verification, json);
+			},
+		_ => {}
+		}
+	Ok(())
+	}
+
+// Check the chain in an export, which holds one JSON record per line
+fn verify_export(path: &str) -> Result<AuditVerification, ClientError> {
+	let file = File::open(path).map_err(|err| ClientError::Setup(format!("unable to read {}: {}", path, err)))?;
+	let mut verifier = ChainVerifier::new();
+	for (number, line) in BufReader::new(file).lines().enumerate() {
+		let line = line.map_err(|err| ClientError::Setup(format!("unable to read {}: {}", path, err)))?;
+		if line.trim().is_empty() {
+			continue;
+			}
+		let record: AuditRecord = serde_json::from_str(&line).map_err(|err| ClientError::Setup(format!("{} line {} is not an audit record: {}", path, number + 1, err)))?;
+		if let Err(broken) = verifier.check(&record) {
+			return Ok(AuditVerification { records: verifier.count(), head: verifier.head().to_string(), broken: Some(broken) });
+			}
+		}
+	Ok(AuditVerification { records: verifier.count(), head: verifier.head().to_string(), broken: None })
+	}
+
+// Print the result of a check and exit, failing if the chain is broken
+fn report_verification(verification: &AuditVerification, json: bool) -> ! {
+	if json { output::json(verification); } else { output::verification(verification); }
+	process::exit(if verification.broken.is_some() { 1 } else { 0 });
+	}
+
+// A comma-separated list, without empty entries
+fn split_list(value: &str) -> Vec<String> {
+	value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+	}
+
+// Read a new password from --password-file, or prompt for it twice
+fn new_password(matches: &ArgMatches) -> Result<String, ClientError> {
+	if let Some(path) = matches.value_of("password-file") {
+		let password = fs::read_to_string(path).map_err(|err| ClientError::Setup(format!("unable to read password file {}: {}", path, err)))?;
+		return Ok(password.trim_end_matches(&['\r', '\n'][..]).to_string());
+		}
+	let password = rpassword::read_password_from_tty(Some("New password: ")).map_err(|err| ClientError::Setup(err.to_string()))?;
+	let verify = rpassword::read_password_from_tty(Some("Verify new password: ")).map_err(|err| ClientError::Setup(err.to_string()))?;
+	if password != verify {
+		return Err(ClientError::Setup(String::from("passwords do not match")));
+		}
+	Ok(password)
+	}
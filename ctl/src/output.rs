@@ -0,0 +1,216 @@
+// Output
+//
+// Everything is printed either as a table for people or as JSON for scripts.
+
+use chrono::Local;
+use serde::Serialize;
+use luminum_proto::{CommandKind, FileType};
+use luminum_proto::admin::{AnswerInfo, ApiKeyInfo, CommandInfo, EndpointDetail, EndpointInfo, FileEventInfo, GroupInfo, OperatorInfo, Page, PrincipalInfo, QuestionInfo, ServerConfig, TokenInfo, Watchlist};
+use luminum_proto::audit::{AuditRecord, AuditVerification};
+
+// Print a value as pretty JSON
+pub fn json<T: Serialize>(value: &T) {
+	match serde_json::to_string_pretty(value) {
+		Ok(json) => println!("{}", json),
+		Err(err) => eprintln!("Error: {}", err)
+		}
+	}
+
+pub fn format_timestamp(timestamp: Option<i64>) -> String {
+	match timestamp.and_then(|secs| chrono::DateTime::from_timestamp(secs, 0)) {
+		Some(time) => time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string(),
+		None => String::from("never")
+		}
+	}
+
+// Note when a listing holds only part of what matched
+pub fn more<T>(page: &Page<T>) {
+	let shown = page.items.len() as u64;
+	if shown < page.total {
+		let first = page.offset as u64 + 1;
+		println!("\nShowing {}-{} of {}. Use --offset {} for more.", first.min(page.total), page.offset as u64 + shown, page.total, page.offset as u64 + shown);
+		}
+	}
+
+fn address(endpoint: &EndpointInfo) -> &str {
+	if endpoint.ipv4.is_empty() { &endpoint.ipv6 } else { &endpoint.ipv4 }
+	}
+
+pub fn endpoints(endpoints: &[EndpointInfo]) {
+	println!("{:<36} {:<32} {:<8} {:<8} {:<19} {:<24} IP ADDRESS", "UID", "HOSTNAME", "OS", "STATE", "LAST SEEN", "GROUPS");
+	for endpoint in endpoints {
+		let state = if endpoint.revoked { "revoked" } else { &endpoint.presence };
+		println!("{:<36} {:<32} {:<8} {:<8} {:<19} {:<24} {}", endpoint.uid, endpoint.hostname, endpoint.osplat, state, format_timestamp(Some(endpoint.last_seen)), endpoint.groups.join(","), address(endpoint));
+		}
+	}
+
+pub fn endpoint(detail: &EndpointDetail) {
+	let endpoint = &detail.endpoint;
+	println!("UID:          {}", endpoint.uid);
+	println!("Hostname:     {}", endpoint.hostname);
+	println!("OS:           {} {}", endpoint.osplat, endpoint.osver);
+	println!("IPv4 address: {}", endpoint.ipv4);
+	println!("IPv6 address: {}", endpoint.ipv6);
+	println!("Groups:       {}", endpoint.groups.join(", "));
+	println!("Token:        {}", endpoint.token_id.as_deref().unwrap_or("none"));
+	println!("Certificate:  {}", endpoint.cert_fingerprint.as_deref().unwrap_or("none"));
+	println!("State:        {}{}", endpoint.presence, if endpoint.revoked { " (revoked)" } else { "" });
+	println!("Last seen:    {}", format_timestamp(Some(endpoint.last_seen)));
+	if !detail.commands.is_empty() {
+		println!();
+		commands(&detail.commands);
+		}
+	}
+
+pub fn commands(commands: &[CommandInfo]) {
+	println!("{:<36} {:<19} {:<10} {:<32} OUTPUT", "COMMAND", "CREATED", "STATE", "REQUEST");
+	for command in commands {
+		let description = match &command.kind {
+			CommandKind::Question(question) => format!("question {}", question),
+			CommandKind::SetConfig { key, value } => format!("config {}={}", key, value),
+			CommandKind::Action { name, args } => format!("action {} {}", name, args.join(" ")).trim_end().to_string()
+			};
+		println!("{:<36} {:<19} {:<10} {:<32} {}", command.id, format_timestamp(Some(command.created)), command.state, description, command.output.as_deref().unwrap_or_default());
+		}
+	}
+
+pub fn history(detail: &EndpointDetail) {
+	println!("{:<19} {:<10} {:<32} NEW VALUE", "CHANGED", "ATTRIBUTE", "OLD VALUE");
+	for change in &detail.attribute_changes {
+		println!("{:<19} {:<10} {:<32} {}", format_timestamp(Some(change.changed)), change.attribute, change.old_value, change.new_value);
+		}
+	println!("\n{:<19} {:<10} NEW STATE", "AT", "OLD STATE");
+	for event in &detail.presence_events {
+		println!("{:<19} {:<10} {}", format_timestamp(Some(event.at)), event.old, event.new);
+		}
+	}
+
+pub fn tokens(tokens: &[TokenInfo]) {
+	println!("{:<10} {:<19} {:<19} {:>9} {:<10} {:<24} DESCRIPTION", "ID", "CREATED", "EXPIRES", "USES", "STATUS", "GROUPS");
+	for token in tokens {
+		let uses = match token.max_uses {
+			Some(max_uses) => format!("{}/{}", token.uses, max_uses),
+			None => token.uses.to_string()
+			};
+		println!("{:<10} {:<19} {:<19} {:>9} {:<10} {:<24} {}", token.id, format_timestamp(Some(token.created)), format_timestamp(token.expires), uses, token.status, token.groups.join(","), token.description);
+		}
+	}
+
+pub fn watchlist(watchlist: &Watchlist) {
+	for path in &watchlist.paths {
+		println!("{}", path);
+		}
+	}
+
+pub fn groups(groups: &[GroupInfo]) {
+	println!("{:<64} ENDPOINTS", "GROUP");
+	for group in groups {
+		println!("{:<64} {}", group.name, group.endpoints);
+		}
+	}
+
+pub fn questions(questions: &[QuestionInfo]) {
+	println!("{:<36} {:<19} {:>9} {:<24} QUESTION", "ID", "ASKED", "ANSWERED", "TARGET");
+	for question in questions {
+		println!("{:<36} {:<19} {:>9} {:<24} {}", question.id, format_timestamp(Some(question.created)), format!("{}/{}", question.answered, question.endpoints), question.target, question.question);
+		}
+	}
+
+pub fn question(question: &QuestionInfo) {
+	println!("ID:       {}", question.id);
+	println!("Question: {}", question.question);
+	println!("Asked of: {}", question.target);
+	println!("Asked by: {}", question.asked_by);
+	println!("Asked:    {}", format_timestamp(Some(question.created)));
+	println!("Answered: {} of {} endpoints", question.answered, question.endpoints);
+	}
+
+pub fn answers(answers: &[AnswerInfo]) {
+	println!("{:<36} {:<10} {:<19} ANSWER", "UID", "STATE", "COMPLETED");
+	for answer in answers {
+		let completed = answer.completed.map(|completed| format_timestamp(Some(completed))).unwrap_or_default();
+		println!("{:<36} {:<10} {:<19} {}", answer.uid, answer.state, completed, answer.answer.as_deref().unwrap_or_default());
+		}
+	}
+
+pub fn events(events: &[FileEventInfo]) {
+	println!("{:<19} {:<36} {:<7} {:<10} {:<10} {:<20} {:>12} PATH", "AT", "UID", "KIND", "TYPE", "MODE", "OWNER", "SIZE");
+	for event in events {
+		let size = event.size.map(|size| size.to_string()).unwrap_or_default();
+		println!("{:<19} {:<36} {:<7} {:<10} {:<10} {:<20} {:>12} {}", format_timestamp(Some(event.at)), event.uid, event.kind, event.file_type.as_ref().map(FileType::as_str).unwrap_or_default(),
+			event.permissions.as_deref().unwrap_or_default(), event.owner.as_deref().unwrap_or_default(), size, event.path);
+		}
+	}
+
+pub fn config(config: &ServerConfig) {
+	let (certificate, logging, limits) = (&config.certificate, &config.logging, &config.limits);
+	println!("Version:             {}", config.version);
+	println!("Schema version:      {}", config.schema_version);
+	println!("Listening on:        {}", config.listen.join(", "));
+	println!("Admin listener:      {}", if config.admin.is_empty() { String::from("disabled") } else { config.admin.join(", ") });
+	println!("API listener:        {}", if config.api.is_empty() { String::from("disabled") } else { config.api.join(", ") });
+	println!("Storage:             {}", config.storage);
+	println!("Certificate key:     {}", certificate.key_type);
+	println!("Certificate names:   {}", certificate.hostnames.iter().chain(&certificate.addresses).cloned().collect::<Vec<String>>().join(", "));
+	println!("Certificate days:    {} valid, previous presented {} after renewal, warning at {} before expiry", certificate.validity, certificate.rollover, certificate.expiry_warning);
+	println!("Logging:             {} as {} to {}", logging.level, logging.format, logging.output);
+	println!("Maximum frame:       {} bytes", limits.max_frame);
+	println!("Maximum connections: {}", limits.max_connections);
+	println!("Maximum in flight:   {}", limits.max_in_flight);
+	println!("Timeouts:            {}s handshake, {}s read, {}s idle, {}s drain", limits.handshake_timeout, limits.read_timeout, limits.idle_timeout, limits.drain_timeout);
+	println!("Presence:            stale after {}s, offline after {}s", config.presence.stale_after, config.presence.offline_after);
+	println!("Integrity module:    {}", if config.modules.integrity { "enabled" } else { "disabled" });
+	let protection = &config.protection;
+	println!("Per source address:  {} connections ({} a minute), {} handshakes, {} registrations ({} a minute)", protection.max_connections_per_ip,
+		protection.connections_per_minute, protection.max_handshakes_per_ip, protection.max_registrations_per_ip, protection.registrations_per_minute);
+	println!("Bans:                {}s after {} failures within {}s", protection.ban_duration, protection.ban_after_failures, protection.failure_window);
+	println!("Sign-ins:            {} a minute per source address", protection.sign_ins_per_minute);
+	}
+
+pub fn principal(principal: &PrincipalInfo) {
+	println!("Operator: {}", principal.operator.as_deref().unwrap_or("none"));
+	println!("Role:     {}", principal.role.as_deref().unwrap_or("none"));
+	println!("API key:  {}{}", principal.key_id, if principal.session { " (session)" } else { "" });
+	println!("Scopes:   {}", principal.scopes.join(", "));
+	println!("Groups:   {}", if principal.groups.is_empty() { String::from("all endpoints") } else { principal.groups.join(", ") });
+	}
+
+pub fn operators(operators: &[OperatorInfo]) {
+	println!("{:<24} {:<14} {:<19} {:<19} {:<9} GROUPS", "USERNAME", "ROLE", "CREATED", "LAST LOGIN", "STATUS");
+	for operator in operators {
+		let status = if operator.disabled { "disabled" } else { "active" };
+		println!("{:<24} {:<14} {:<19} {:<19} {:<9} {}", operator.username, operator.role, format_timestamp(Some(operator.created)), format_timestamp(operator.last_login), status, operator.groups.join(","));
+		}
+	}
+
+pub fn operator(operator: &OperatorInfo) {
+	println!("Username:   {}", operator.username);
+	println!("Role:       {}", operator.role);
+	println!("Groups:     {}", if operator.groups.is_empty() { String::from("all endpoints") } else { operator.groups.join(", ") });
+	println!("Created:    {}", format_timestamp(Some(operator.created)));
+	println!("Last login: {}", format_timestamp(operator.last_login));
+	println!("Status:     {}", if operator.disabled { "disabled" } else { "active" });
+	}
+
+pub fn api_keys(keys: &[ApiKeyInfo]) {
+	println!("{:<10} {:<19} {:<19} {:<19} {:<10} {:<16} {:<24} DESCRIPTION", "ID", "CREATED", "EXPIRES", "LAST USED", "STATUS", "OWNER", "SCOPES");
+	for key in keys {
+		println!("{:<10} {:<19} {:<19} {:<19} {:<10} {:<16} {:<24} {}", key.id, format_timestamp(Some(key.created)), format_timestamp(key.expires), format_timestamp(key.last_used),
+			key.status, key.owner.as_deref().unwrap_or("-"), key.scopes.join(","), key.description);
+		}
+	}
+
+pub fn audit_records(records: &[AuditRecord]) {
+	println!("{:>8} {:<19} {:<17} {:<40} {:<16} DETAIL", "SEQ", "AT", "EVENT", "ACTOR", "SOURCE");
+	for record in records {
+		println!("{:>8} {:<19} {:<17} {:<40} {:<16} {}", record.seq, format_timestamp(Some(record.at)), record.event, record.actor, record.source, record.detail);
+		}
+	}
+
+pub fn verification(verification: &AuditVerification) {
+	match &verification.broken {
+		None => println!("Audit log intact: {} records.", verification.records),
+		Some(broken) => println!("Audit log broken at {}. The {} records before it are intact.", broken, verification.records)
+		}
+	println!("Head: {}", verification.head);
+	}
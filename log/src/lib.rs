@@ -0,0 +1,204 @@
+// Luminum Logging
+//
+// Shared logging for the Luminum server and clients. Log messages are tracing events with a
+// level, a module target and structured fields such as uid, peer and lumy. They are written
+// to stdout, a rotated log file or the systemd journal, as text or JSON.
+//
+// The level filter takes tracing directives ("info", "warn,luminum_server::listener=debug")
+// and can be changed while the program runs. Events with the "security" target are always
+// logged, whatever the level.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation as FileRotation};
+use tracing_subscriber::{fmt as format, reload, EnvFilter, Layer, Registry};
+use tracing_subscriber::fmt::time::ChronoLocal;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+pub mod systemd;
+
+// Target for security events
+pub const SECURITY: &str = "security";
+pub const DEFAULT_LEVEL: &str = "info";
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum LogError {
+	InvalidLevel(String),
+	InvalidSetting(String),
+	Io(String, io::Error),
+	AlreadyInitialized,
+	NotInitialized,
+	Unsupported(&'static str)
+	}
+
+impl fmt::Display for LogError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			LogError::InvalidLevel(level) => write!(f, "invalid log level: {}", level),
+			LogError::InvalidSetting(reason) => write!(f, "{}", reason),
+			LogError::Io(path, err) => write!(f, "unable to open log {}: {}", path, err),
+			LogError::AlreadyInitialized => write!(f, "logging is already initialized"),
+			LogError::NotInitialized => write!(f, "logging is not initialized"),
+			LogError::Unsupported(output) => write!(f, "{} output is not supported on this system", output)
+			}
+		}
+	}
+
+impl Error for LogError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+	Text,
+	Json
+	}
+
+impl FromStr for Format {
+	type Err = LogError;
+
+	fn from_str(input: &str) -> Result<Format, LogError> {
+		match input {
+			"text" => Ok(Format::Text),
+			"json" => Ok(Format::Json),
+			other => Err(LogError::InvalidSetting(format!("Unknown log format: {} (expected text or json)", other)))
+			}
+		}
+	}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+	Hourly,
+	Daily,
+	Never
+	}
+
+impl FromStr for Rotation {
+	type Err = LogError;
+
+	fn from_str(input: &str) -> Result<Rotation, LogError> {
+		match input {
+			"hourly" => Ok(Rotation::Hourly),
+			"daily" => Ok(Rotation::Daily),
+			"never" => Ok(Rotation::Never),
+			other => Err(LogError::InvalidSetting(format!("Unknown log rotation: {} (expected hourly, daily or never)", other)))
+			}
+		}
+	}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+	Stdout,
+	// Rotated files are named after the path, with the date appended
+	File { path: String, rotation: Rotation },
+	Journald
+	}
+
+impl Output {
+	// Parse an output name. File output needs the path of the log file.
+	pub fn parse(name: &str, path: Option<&str>, rotation: Rotation) -> Result<Output, LogError> {
+		match name {
+			"stdout" => Ok(Output::Stdout),
+			"file" => match path {
+				Some(path) if !path.is_empty() => Ok(Output::File { path: path.to_string(), rotation }),
+				_ => Err(LogError::InvalidSetting(String::from("File log output needs a log file path")))
+				},
+			"journald" => Ok(Output::Journald),
+			other => Err(LogError::InvalidSetting(format!("Unknown log output: {} (expected stdout, file or journald)", other)))
+			}
+		}
+	}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogConfig {
+	pub level: String,
+	pub format: Format,
+	pub output: Output
+	}
+
+impl Default for LogConfig {
+	fn default() -> Self {
+		LogConfig { level: String::from(DEFAULT_LEVEL), format: Format::Text, output: Output::Stdout }
+		}
+	}
+
+// Keeps buffered file output flowing. Dropping it flushes the log.
+pub struct LogGuard {
+	_worker: Option<WorkerGuard>
+	}
+
+// Check a level filter without applying it
+pub fn validate_level(level: &str) -> Result<(), LogError> {
+	filter(level).map(|_| ())
+	}
+
+// Security events are logged at any level
+fn filter(level: &str) -> Result<EnvFilter, LogError> {
+	EnvFilter::try_new(format!("{},{}=info", level, SECURITY)).map_err(|_| LogError::InvalidLevel(level.to_string()))
+	}
+
+type Boxed = Box<dyn Layer<tracing_subscriber::layer::Layered<reload::Layer<EnvFilter, Registry>, Registry>> + Send + Sync>;
+
+// Install the global logger. Only the first call succeeds.
+pub fn init(config: &LogConfig) -> Result<LogGuard, LogError> {
+	let (filter_layer, handle) = reload::Layer::new(filter(&config.level)?);
+	let mut worker = None;
+	let output: Boxed = match &config.output {
+		Output::Stdout => {
+			let layer = format::layer().with_timer(ChronoLocal::new(String::from(TIME_FORMAT))).with_ansi(io::stdout().is_terminal());
+			match config.format {
+				Format::Text => layer.boxed(),
+				Format::Json => layer.json().flatten_event(true).boxed()
+				}
+			},
+		Output::File { path, rotation } => {
+			let path = Path::new(path);
+			let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+			let prefix = path.file_name().ok_or_else(|| LogError::InvalidSetting(format!("Invalid log file path: {}", path.display())))?;
+			let rotation = match rotation {
+				Rotation::Hourly => FileRotation::HOURLY,
+				Rotation::Daily => FileRotation::DAILY,
+				Rotation::Never => FileRotation::NEVER
+				};
+			let appender = RollingFileAppender::builder().rotation(rotation).filename_prefix(prefix.to_string_lossy()).build(directory)
+				.map_err(|err| LogError::Io(path.display().to_string(), io::Error::other(err)))?;
+			let (writer, guard) = tracing_appender::non_blocking(appender);
+			worker = Some(guard);
+			let layer = format::layer().with_timer(ChronoLocal::new(String::from(TIME_FORMAT))).with_ansi(false).with_writer(writer);
+			match config.format {
+				Format::Text => layer.boxed(),
+				Format::Json => layer.json().flatten_event(true).boxed()
+				}
+			},
+		Output::Journald => journald()?
+		};
+
+	tracing_subscriber::registry().with(filter_layer).with(output).try_init().map_err(|_| LogError::AlreadyInitialized)?;
+	let _ = FILTER.set(handle);
+	Ok(LogGuard { _worker: worker })
+	}
+
+// The journal keeps its own timestamps and structured fields
+#[cfg(feature = "journald")]
+fn journald() -> Result<Boxed, LogError> {
+	let layer = tracing_journald::layer().map_err(|err| LogError::Io(String::from("journald"), err))?;
+	Ok(layer.boxed())
+	}
+
+#[cfg(not(feature = "journald"))]
+fn journald() -> Result<Boxed, LogError> {
+	Err(LogError::Unsupported("journald"))
+	}
+
+// Change the level filter of the running logger
+pub fn set_level(level: &str) -> Result<(), LogError> {
+	let filter = filter(level)?;
+	FILTER.get().ok_or(LogError::NotInitialized)?.reload(filter).map_err(|_| LogError::NotInitialized)
+	}
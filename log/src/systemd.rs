@@ -0,0 +1,57 @@
+// Service Manager Notifications
+//
+// Tells systemd about a daemon's state through the socket named by NOTIFY_SOCKET, for units
+// of Type=notify: READY=1 once it is serving, STOPPING=1 when it starts shutting down, and
+// WATCHDOG=1 at least twice per WatchdogSec. Outside systemd there is no socket and nothing
+// is sent. Like journald output, this is the daemons' only other tie to systemd.
+
+use std::env;
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::Duration;
+
+pub const READY: &str = "READY=1";
+pub const STOPPING: &str = "STOPPING=1";
+pub const WATCHDOG: &str = "WATCHDOG=1";
+
+// Send a state to the service manager. Returns false when there isn't one.
+pub fn notify(state: &str) -> io::Result<bool> {
+	match env::var("NOTIFY_SOCKET") {
+		Ok(socket) if !socket.is_empty() => send(&socket, state).map(|_| true),
+		_ => Ok(false)
+		}
+	}
+
+// Send a state to a notification socket. Names starting with @ are in the abstract namespace.
+pub fn send(socket: &str, state: &str) -> io::Result<()> {
+	let address = match socket.strip_prefix('@') {
+		Some(name) => abstract_address(name)?,
+		None => SocketAddr::from_pathname(socket)?
+		};
+	UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+	Ok(())
+	}
+
+#[cfg(target_os = "linux")]
+fn abstract_address(name: &str) -> io::Result<SocketAddr> {
+	use std::os::linux::net::SocketAddrExt;
+	SocketAddr::from_abstract_name(name)
+	}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_address(_name: &str) -> io::Result<SocketAddr> {
+	Err(io::Error::new(io::ErrorKind::Unsupported, "abstract sockets are only available on Linux"))
+	}
+
+// How often to send WATCHDOG=1 when the service manager expects it from this process: half
+// the watchdog timeout, so one late ping doesn't get the daemon restarted
+pub fn watchdog_interval() -> Option<Duration> {
+	let timeout = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+	if let Ok(pid) = env::var("WATCHDOG_PID") {
+		if pid.parse::<u32>().ok() != Some(process::id()) {
+			return None;
+			}
+		}
+	Some(Duration::from_micros(timeout / 2))
+	}
@@ -0,0 +1,34 @@
+// Parsing of logging settings
+
+use luminum_log::*;
+
+#[test]
+fn levels_accept_directives() {
+	assert!(validate_level("info").is_ok());
+	assert!(validate_level("warn,LuminumServer::listener=debug").is_ok());
+	assert!(matches!(validate_level("loud=="), Err(LogError::InvalidLevel(_))));
+	}
+
+#[test]
+fn formats_and_rotations_parse() {
+	assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+	assert_eq!("text".parse::<Format>().unwrap(), Format::Text);
+	assert!("xml".parse::<Format>().is_err());
+	assert_eq!("hourly".parse::<Rotation>().unwrap(), Rotation::Hourly);
+	assert!("weekly".parse::<Rotation>().is_err());
+	}
+
+#[test]
+fn file_output_needs_a_path() {
+	assert_eq!(Output::parse("file", Some("/var/log/luminum/server.log"), Rotation::Daily).unwrap(),
+		Output::File { path: String::from("/var/log/luminum/server.log"), rotation: Rotation::Daily });
+	assert!(Output::parse("file", None, Rotation::Daily).is_err());
+	assert!(Output::parse("file", Some(""), Rotation::Never).is_err());
+	assert_eq!(Output::parse("journald", None, Rotation::Daily).unwrap(), Output::Journald);
+	assert!(Output::parse("syslog", None, Rotation::Daily).is_err());
+	}
+
+#[test]
+fn level_changes_need_a_logger() {
+	assert!(matches!(set_level("debug"), Err(LogError::NotInitialized)));
+	}
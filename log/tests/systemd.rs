@@ -0,0 +1,40 @@
+// Service manager notifications
+
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use luminum_log::systemd;
+
+#[test]
+fn states_reach_the_socket() {
+	let path = std::env::temp_dir().join(format!("luminum-notify-{}.sock", process::id()));
+	let _ = std::fs::remove_file(&path);
+	let socket = UnixDatagram::bind(&path).unwrap();
+	systemd::send(path.to_str().unwrap(), systemd::READY).unwrap();
+	systemd::send(path.to_str().unwrap(), systemd::STOPPING).unwrap();
+	let mut buffer = [0; 64];
+	let received = socket.recv(&mut buffer).unwrap();
+	assert_eq!(&buffer[..received], b"READY=1");
+	let received = socket.recv(&mut buffer).unwrap();
+	assert_eq!(&buffer[..received], b"STOPPING=1");
+	std::fs::remove_file(&path).unwrap();
+	}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn abstract_names_start_with_at() {
+	let name = format!("luminum-notify-{}", process::id());
+	let address = {
+		use std::os::linux::net::SocketAddrExt;
+		std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap()
+		};
+	let socket = UnixDatagram::bind_addr(&address).unwrap();
+	systemd::send(&format!("@{}", name), systemd::WATCHDOG).unwrap();
+	let mut buffer = [0; 64];
+	let received = socket.recv(&mut buffer).unwrap();
+	assert_eq!(&buffer[..received], b"WATCHDOG=1");
+	}
+
+#[test]
+fn missing_sockets_fail() {
+	assert!(systemd::send("/nonexistent/luminum-notify.sock", systemd::READY).is_err());
+	}
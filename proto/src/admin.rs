@@ -0,0 +1,394 @@
+// Admin API
+//
+// JSON bodies exchanged between the server's admin API and its clients, luminumctl among
+// them. Requests are authenticated with an API key sent as "Authorization: Bearer
+// <id>.<secret>"; operators signing in with a password are given a short-lived key the same
+// way. Timestamps are Unix seconds. The server publishes an OpenAPI description
+// of the API at API_PREFIX/openapi.json.
+
+use serde::{Deserialize, Serialize};
+use crate::message::{CommandKind, FileEventKind, FileType};
+
+pub const API_PREFIX: &str = "/api/v1";
+// Page size used when a request gives none, and the largest accepted
+pub const DEFAULT_PAGE: u32 = 100;
+pub const MAX_PAGE: u32 = 1000;
+
+// One page of a collection. Collections take "limit" and "offset" query parameters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Page<T> {
+	pub items: Vec<T>,
+	// Items matched before paging
+	pub total: u64,
+	pub limit: u32,
+	pub offset: u32
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct EndpointInfo {
+	pub uid: String,
+	pub hostname: String,
+	pub ipv4: String,
+	pub ipv6: String,
+	pub osplat: String,
+	pub osver: String,
+	#[serde(default)]
+	pub cert_fingerprint: Option<String>,
+	#[serde(default)]
+	pub token_id: Option<String>,
+	#[serde(default)]
+	pub groups: Vec<String>,
+	pub revoked: bool,
+	pub last_seen: i64,
+	// "online", "stale" or "offline"
+	pub presence: String
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttributeChangeInfo {
+	pub attribute: String,
+	pub old_value: String,
+	pub new_value: String,
+	pub changed: i64
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PresenceEventInfo {
+	pub old: String,
+	pub new: String,
+	pub at: i64
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommandInfo {
+	pub id: String,
+	pub kind: CommandKind,
+	// "queued", "sent", "succeeded", "failed" or "cancelled"
+	pub state: String,
+	pub created: i64,
+	#[serde(default)]
+	pub sent: Option<i64>,
+	#[serde(default)]
+	pub completed: Option<i64>,
+	#[serde(default)]
+	pub output: Option<String>,
+	// Question the command asks on behalf of, if any
+	#[serde(default)]
+	pub question_id: Option<String>
+	}
+
+// An endpoint with its history, as returned for a single endpoint
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct EndpointDetail {
+	pub endpoint: EndpointInfo,
+	#[serde(default)]
+	pub attribute_changes: Vec<AttributeChangeInfo>,
+	#[serde(default)]
+	pub presence_events: Vec<PresenceEventInfo>,
+	#[serde(default)]
+	pub commands: Vec<CommandInfo>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct TokenInfo {
+	pub id: String,
+	pub description: String,
+	#[serde(default)]
+	pub groups: Vec<String>,
+	pub created: i64,
+	#[serde(default)]
+	pub expires: Option<i64>,
+	#[serde(default)]
+	pub max_uses: Option<u32>,
+	pub uses: u32,
+	// "active", "revoked", "used" or "expired"
+	pub status: String
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct NewToken {
+	#[serde(default)]
+	pub description: String,
+	#[serde(default)]
+	pub groups: Vec<String>,
+	// Lifetime such as "12h" or "30d", or "never". Defaults to seven days.
+	#[serde(default)]
+	pub expires: Option<String>,
+	#[serde(default)]
+	pub max_uses: Option<u32>
+	}
+
+// A newly created enrollment token. The token string is only ever returned here.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CreatedToken {
+	pub info: TokenInfo,
+	pub token: String
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Watchlist {
+	pub paths: Vec<String>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct EndpointGroups {
+	pub groups: Vec<String>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct GroupInfo {
+	pub name: String,
+	pub endpoints: u32
+	}
+
+// Ask a question of the given endpoints, the members of a group, or every unrevoked
+// endpoint when neither is given
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct NewQuestion {
+	pub question: String,
+	#[serde(default)]
+	pub uids: Vec<String>,
+	#[serde(default)]
+	pub group: Option<String>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct QuestionInfo {
+	pub id: String,
+	pub question: String,
+	// Description of the endpoints asked, such as "group web"
+	pub target: String,
+	// Operator, or ownerless API key, the question was asked by
+	pub asked_by: String,
+	pub created: i64,
+	// Endpoints asked, and how many have answered
+	pub endpoints: u32,
+	pub answered: u32
+	}
+
+// One endpoint's answer to a question
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct AnswerInfo {
+	pub uid: String,
+	pub command_id: String,
+	// "queued", "sent", "succeeded", "failed" or "cancelled"
+	pub state: String,
+	#[serde(default)]
+	pub completed: Option<i64>,
+	#[serde(default)]
+	pub answer: Option<String>
+	}
+
+// A change to a path on an endpoint's Integrity watchlist
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FileEventInfo {
+	pub uid: String,
+	pub path: String,
+	pub kind: FileEventKind,
+	#[serde(default)]
+	pub file_type: Option<FileType>,
+	#[serde(default)]
+	pub permissions: Option<String>,
+	#[serde(default)]
+	pub owner: Option<String>,
+	#[serde(default)]
+	pub size: Option<u64>,
+	// When the endpoint saw the change, and when the server received it
+	pub at: i64,
+	pub received: i64
+	}
+
+// The server's running configuration. Secrets and key material are never included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ServerConfig {
+	pub version: String,
+	pub schema_version: u32,
+	pub listen: Vec<String>,
+	pub admin: Vec<String>,
+	pub api: Vec<String>,
+	// Storage backend, such as "sqlite (/path/to/db)"
+	pub storage: String,
+	pub certificate: CertificateConfig,
+	pub logging: LoggingConfig,
+	pub limits: LimitsConfig,
+	pub presence: PresenceConfig,
+	pub modules: ModulesConfig,
+	pub protection: ProtectionConfig
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CertificateConfig {
+	pub key_type: String,
+	pub hostnames: Vec<String>,
+	pub addresses: Vec<String>,
+	// Days
+	pub validity: u32,
+	pub rollover: u32,
+	pub expiry_warning: u32
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct LoggingConfig {
+	pub level: String,
+	pub format: String,
+	pub output: String
+	}
+
+// Timeouts are in seconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct LimitsConfig {
+	pub max_frame: usize,
+	pub max_connections: usize,
+	pub max_in_flight: usize,
+	pub handshake_timeout: u64,
+	pub read_timeout: u64,
+	pub idle_timeout: u64,
+	pub drain_timeout: u64
+	}
+
+// Seconds without a message before an endpoint is stale, then offline
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PresenceConfig {
+	pub stale_after: i64,
+	pub offline_after: i64
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ModulesConfig {
+	pub integrity: bool
+	}
+
+// Limits per source address on the data port, and on admin API sign-ins. Sources with
+// ban_after_failures failures within failure_window seconds are banned for ban_duration seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ProtectionConfig {
+	pub max_connections_per_ip: usize,
+	pub max_handshakes_per_ip: usize,
+	pub connections_per_minute: u32,
+	pub max_registrations_per_ip: usize,
+	pub registrations_per_minute: u32,
+	pub ban_after_failures: u32,
+	pub failure_window: u64,
+	pub ban_duration: u64,
+	pub sign_ins_per_minute: u32
+	}
+
+// Role names are "viewer", "operator" and "administrator"; scope names are "read", "ask",
+// "configure", "deploy", "endpoints", "enrollment" and "admin"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct OperatorInfo {
+	pub username: String,
+	pub role: String,
+	// Endpoint groups the operator is limited to; empty for every endpoint
+	#[serde(default)]
+	pub groups: Vec<String>,
+	pub created: i64,
+	#[serde(default)]
+	pub last_login: Option<i64>,
+	pub disabled: bool
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct NewOperator {
+	pub username: String,
+	pub password: String,
+	// Defaults to "viewer"
+	#[serde(default)]
+	pub role: Option<String>,
+	#[serde(default)]
+	pub groups: Vec<String>
+	}
+
+// Changes to an operator. Fields left out are unchanged.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct OperatorUpdate {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub role: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub groups: Option<Vec<String>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub disabled: Option<bool>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub password: Option<String>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ApiKeyInfo {
+	pub id: String,
+	pub description: String,
+	// Operator the key acts for; keys created on the server host have none
+	#[serde(default)]
+	pub owner: Option<String>,
+	pub scopes: Vec<String>,
+	pub created: i64,
+	#[serde(default)]
+	pub expires: Option<i64>,
+	#[serde(default)]
+	pub last_used: Option<i64>,
+	// "active", "revoked" or "expired"
+	pub status: String,
+	// Issued by signing in rather than created as a key
+	#[serde(default)]
+	pub session: bool
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct NewApiKey {
+	#[serde(default)]
+	pub description: String,
+	// Defaults to every scope the caller holds
+	#[serde(default)]
+	pub scopes: Vec<String>,
+	// Lifetime such as "12h" or "90d", or "never". Defaults to never.
+	#[serde(default)]
+	pub expires: Option<String>
+	}
+
+// A newly created API key. The key string is only ever returned here.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CreatedApiKey {
+	pub info: ApiKeyInfo,
+	pub key: String
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Login {
+	pub username: String,
+	pub password: String
+	}
+
+// Who a request is made by, and what they may do
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PrincipalInfo {
+	pub key_id: String,
+	#[serde(default)]
+	pub operator: Option<String>,
+	#[serde(default)]
+	pub role: Option<String>,
+	pub scopes: Vec<String>,
+	// Endpoint groups the principal is limited to; empty for every endpoint
+	#[serde(default)]
+	pub groups: Vec<String>,
+	#[serde(default)]
+	pub session: bool
+	}
+
+// A signed-in operator's session. The key is used like any other API key until it expires
+// or the operator signs out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+	pub key: String,
+	pub expires: i64,
+	pub principal: PrincipalInfo
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PasswordChange {
+	pub current_password: String,
+	pub new_password: String
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiError {
+	pub error: String
+	}
@@ -0,0 +1,126 @@
+// Audit Log
+//
+// Records of administrative and security-relevant actions on a Luminum Server. Records are
+// numbered from 1 and hash-chained: each record's hash covers its own fields and the hash of
+// the record before it, so a record that is changed, removed or inserted breaks the chain from
+// that point on. The server, luminumctl and anything else holding an export can check a chain
+// with ChainVerifier.
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Previous hash of the first record
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+// Distinguishes this chain format from any later one
+const DOMAIN: &[u8] = b"luminum-audit-v1";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct AuditRecord {
+	pub seq: u64,
+	// Unix seconds
+	pub at: i64,
+	// What happened, such as "registration" or "sign-in"
+	pub event: String,
+	// Who did it: an operator, an API key, an endpoint or the server host
+	pub actor: String,
+	// IP address the action came from, or "local" on the server host
+	pub source: String,
+	pub detail: String,
+	pub prev_hash: String,
+	pub hash: String
+	}
+
+impl AuditRecord {
+	// Hex SHA-256 over the record's fields and the previous hash. Each field is length
+	// prefixed, so no choice of field contents can make two records hash the same way.
+	pub fn digest(&self) -> String {
+		let mut hasher = Sha256::new();
+		hasher.update(DOMAIN);
+		let seq = self.seq.to_string();
+		let at = self.at.to_string();
+		for field in [seq.as_str(), at.as_str(), &self.event, &self.actor, &self.source, &self.detail, &self.prev_hash] {
+			hasher.update((field.len() as u64).to_be_bytes());
+			hasher.update(field.as_bytes());
+			}
+		hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+		}
+
+	// Link the record to the one before it, or start a chain when there is none
+	pub fn seal(mut self, previous: Option<&AuditRecord>) -> AuditRecord {
+		self.seq = previous.map_or(1, |previous| previous.seq + 1);
+		self.prev_hash = previous.map_or(GENESIS_HASH.to_string(), |previous| previous.hash.clone());
+		self.hash = self.digest();
+		self
+		}
+	}
+
+// Where and why a chain stops verifying
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditBreak {
+	pub seq: u64,
+	pub reason: String
+	}
+
+impl fmt::Display for AuditBreak {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "record {}: {}", self.seq, self.reason)
+		}
+	}
+
+// Checks records one at a time, in order, from the start of a chain
+#[derive(Debug, Clone)]
+pub struct ChainVerifier {
+	next_seq: u64,
+	head: String,
+	count: u64
+	}
+
+impl Default for ChainVerifier {
+	fn default() -> Self {
+		ChainVerifier { next_seq: 1, head: GENESIS_HASH.to_string(), count: 0 }
+		}
+	}
+
+impl ChainVerifier {
+	pub fn new() -> ChainVerifier {
+		ChainVerifier::default()
+		}
+
+	pub fn check(&mut self, record: &AuditRecord) -> Result<(), AuditBreak> {
+		let broken = |reason: String| Err(AuditBreak { seq: record.seq, reason });
+		if record.seq != self.next_seq {
+			return broken(format!("expected record {}", self.next_seq));
+			}
+		if record.prev_hash != self.head {
+			return broken(String::from("does not follow the record before it"));
+			}
+		if record.digest() != record.hash {
+			return broken(String::from("contents do not match its hash"));
+			}
+		self.next_seq += 1;
+		self.head = record.hash.clone();
+		self.count += 1;
+		Ok(())
+		}
+
+	// Records checked so far
+	pub fn count(&self) -> u64 {
+		self.count
+		}
+
+	// Hash of the last record checked. Noting it somewhere safe lets a later check show the
+	// chain wasn't rewritten from the start.
+	pub fn head(&self) -> &str {
+		&self.head
+		}
+	}
+
+// Result of checking a whole chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct AuditVerification {
+	pub records: u64,
+	pub head: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub broken: Option<AuditBreak>
+	}
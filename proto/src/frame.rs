@@ -0,0 +1,187 @@
+// Luminum wire framing
+//
+// Every message exchanged between a Luminum Client and the Luminum Server (and between
+// the client and its Lumys) is wrapped in a frame:
+//
+//   +-------------+-----------+--------------------+--------------------+
+//   | magic (4)   | ver (1)   | length (4, BE u32) | payload (length)   |
+//   +-------------+-----------+--------------------+--------------------+
+//
+// The payload is a MessagePack body. Frames are self-delimiting, so a single TLS session
+// can carry any number of request/response exchanges.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use rmp_serde::to_vec_named;
+
+pub const MAGIC: [u8; 4] = *b"LUMN";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 9;
+pub const DEFAULT_MAX_FRAME: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum FrameError {
+	Io(io::Error),
+	BadMagic([u8; 4]),
+	BadVersion(u8),
+	TooLarge(usize, usize),
+	Encode(rmp_serde::encode::Error),
+	Decode(rmp_serde::decode::Error)
+	}
+
+impl fmt::Display for FrameError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			FrameError::Io(err) => write!(f, "I/O error: {}", err),
+			FrameError::BadMagic(magic) => write!(f, "invalid frame magic: {:02x?}", magic),
+			FrameError::BadVersion(ver) => write!(f, "unsupported frame version: {}", ver),
+			FrameError::TooLarge(len, max) => write!(f, "frame length {} exceeds maximum of {} bytes", len, max),
+			FrameError::Encode(err) => write!(f, "unable to encode message: {}", err),
+			FrameError::Decode(err) => write!(f, "unable to decode message: {}", err)
+			}
+		}
+	}
+
+impl Error for FrameError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			FrameError::Io(err) => Some(err),
+			FrameError::Encode(err) => Some(err),
+			FrameError::Decode(err) => Some(err),
+			_ => None
+			}
+		}
+	}
+
+impl From<io::Error> for FrameError {
+	fn from(err: io::Error) -> Self { FrameError::Io(err) }
+	}
+
+impl From<rmp_serde::encode::Error> for FrameError {
+	fn from(err: rmp_serde::encode::Error) -> Self { FrameError::Encode(err) }
+	}
+
+impl From<rmp_serde::decode::Error> for FrameError {
+	fn from(err: rmp_serde::decode::Error) -> Self { FrameError::Decode(err) }
+	}
+
+// Build the frame header for a payload of the given length
+pub fn encode_header(len: usize, max_len: usize) -> Result<[u8; HEADER_LEN], FrameError> {
+	if len > max_len || len > u32::MAX as usize {
+		return Err(FrameError::TooLarge(len, max_len));
+		}
+	let mut header = [0u8; HEADER_LEN];
+	header[..4].copy_from_slice(&MAGIC);
+	header[4] = VERSION;
+	header[5..].copy_from_slice(&(len as u32).to_be_bytes());
+	Ok(header)
+	}
+
+// Validate a frame header and return the payload length it announces
+pub fn decode_header(header: &[u8; HEADER_LEN], max_len: usize) -> Result<usize, FrameError> {
+	let mut magic = [0u8; 4];
+	magic.copy_from_slice(&header[..4]);
+	if magic != MAGIC {
+		return Err(FrameError::BadMagic(magic));
+		}
+	if header[4] != VERSION {
+		return Err(FrameError::BadVersion(header[4]));
+		}
+	let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+	if len > max_len {
+		return Err(FrameError::TooLarge(len, max_len));
+		}
+	Ok(len)
+	}
+
+// Write a single frame containing the given payload
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8], max_len: usize) -> Result<(), FrameError> {
+	let header = encode_header(payload.len(), max_len)?;
+	writer.write_all(&header)?;
+	writer.write_all(payload)?;
+	writer.flush()?;
+	Ok(())
+	}
+
+// Read a single frame. Returns Ok(None) if the peer closed the connection cleanly between frames.
+pub fn read_frame<R: Read>(reader: &mut R, max_len: usize) -> Result<Option<Vec<u8>>, FrameError> {
+	let mut header = [0u8; HEADER_LEN];
+	let mut filled = 0;
+	while filled < HEADER_LEN {
+		match reader.read(&mut header[filled..]) {
+			Ok(0) if filled == 0 => return Ok(None),
+			Ok(0) => return Err(FrameError::Io(io::Error::from(io::ErrorKind::UnexpectedEof))),
+			Ok(n) => filled += n,
+			Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+			Err(err) => return Err(FrameError::Io(err))
+			}
+		}
+	let len = decode_header(&header, max_len)?;
+	let mut payload = vec![0u8; len];
+	reader.read_exact(&mut payload)?;
+	Ok(Some(payload))
+	}
+
+// Serialize a message as MessagePack and send it as one frame
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T, max_len: usize) -> Result<(), FrameError> {
+	let payload = to_vec_named(message)?;
+	write_frame(writer, &payload, max_len)
+	}
+
+// Read one frame and deserialize its MessagePack payload
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R, max_len: usize) -> Result<Option<T>, FrameError> {
+	match read_frame(reader, max_len)? {
+		Some(payload) => Ok(Some(rmp_serde::from_slice(&payload)?)),
+		None => Ok(None)
+		}
+	}
+
+#[cfg(feature = "tokio")]
+pub use self::nonblocking::{read_frame_async, write_frame_async, read_message_async, write_message_async};
+
+#[cfg(feature = "tokio")]
+mod nonblocking {
+	use super::*;
+	use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+	// Async equivalent of write_frame
+	pub async fn write_frame_async<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8], max_len: usize) -> Result<(), FrameError> {
+		let header = encode_header(payload.len(), max_len)?;
+		writer.write_all(&header).await?;
+		writer.write_all(payload).await?;
+		writer.flush().await?;
+		Ok(())
+		}
+
+	// Async equivalent of read_frame
+	pub async fn read_frame_async<R: AsyncRead + Unpin>(reader: &mut R, max_len: usize) -> Result<Option<Vec<u8>>, FrameError> {
+		let mut header = [0u8; HEADER_LEN];
+		let mut filled = 0;
+		while filled < HEADER_LEN {
+			match reader.read(&mut header[filled..]).await? {
+				0 if filled == 0 => return Ok(None),
+				0 => return Err(FrameError::Io(io::Error::from(io::ErrorKind::UnexpectedEof))),
+				n => filled += n
+				}
+			}
+		let len = decode_header(&header, max_len)?;
+		let mut payload = vec![0u8; len];
+		reader.read_exact(&mut payload).await?;
+		Ok(Some(payload))
+		}
+
+	pub async fn write_message_async<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T, max_len: usize) -> Result<(), FrameError> {
+		let payload = to_vec_named(message)?;
+		write_frame_async(writer, &payload, max_len).await
+		}
+
+	pub async fn read_message_async<R: AsyncRead + Unpin, T: DeserializeOwned>(reader: &mut R, max_len: usize) -> Result<Option<T>, FrameError> {
+		match read_frame_async(reader, max_len).await? {
+			Some(payload) => Ok(Some(rmp_serde::from_slice(&payload)?)),
+			None => Ok(None)
+			}
+		}
+	}
@@ -0,0 +1,17 @@
+// Luminum Protocol
+// by Christopher R. Curzio <ccurzio@luminum.net>
+//
+// Wire protocol shared by the Luminum Server, the Luminum Client and the client Lumys.
+
+pub mod admin;
+pub mod audit;
+pub mod frame;
+pub mod message;
+pub mod lumy;
+
+pub use frame::{FrameError, DEFAULT_MAX_FRAME, read_frame, write_frame, read_message, write_message};
+pub use message::*;
+pub use lumy::{LumyMessage, LumyContent};
+
+#[cfg(feature = "tokio")]
+pub use frame::{read_frame_async, write_frame_async, read_message_async, write_message_async};
@@ -0,0 +1,31 @@
+// Local IPC messages between the Luminum Client and its Lumys
+
+use serde::{Deserialize, Serialize};
+use crate::message::{IntegrityEvent, Lumy};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LumyMessage {
+	pub lumy: Lumy,
+	pub version: String,
+	pub content: LumyContent
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", content = "data")]
+pub enum LumyContent {
+	// Lumy has no saved configuration and asks the client to fetch one
+	#[serde(rename = "newconfig")]
+	NewConfig,
+	// Client hands the Lumy its configuration (Integrity: watch paths)
+	#[serde(rename = "setconfig")]
+	SetConfig(Vec<String>),
+	// Integrity Lumy reports changes to watched paths, for the client to forward to the server
+	#[serde(rename = "events")]
+	Events(Vec<IntegrityEvent>)
+	}
+
+impl LumyMessage {
+	pub fn new(lumy: Lumy, version: &str, content: LumyContent) -> Self {
+		LumyMessage { lumy, version: version.to_string(), content }
+		}
+	}
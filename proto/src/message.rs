@@ -0,0 +1,349 @@
+// Client/server messages
+//
+// The wire layout matches the original untyped messages (lumy/status/action/data maps),
+// so the typed enums below decode messages from older clients and servers unchanged.
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+pub const PRODUCT_CLIENT: &str = "Luminum Client";
+pub const UID_NONE: &str = "NONE";
+
+// Component a message originates from or is addressed to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lumy {
+	#[serde(rename = "Client Core")]
+	ClientCore,
+	#[serde(rename = "Luminum Core")]
+	ServerCore,
+	#[serde(rename = "Luminum Client")]
+	Client,
+	#[serde(rename = "Integrity")]
+	Integrity
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+	#[serde(rename = "noreg")]
+	NoReg,
+	#[serde(rename = "online")]
+	Online,
+	#[serde(rename = "new")]
+	New,
+	#[serde(rename = "OK")]
+	Ok,
+	#[serde(rename = "denied")]
+	Denied,
+	#[serde(rename = "error")]
+	Error
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientMessage {
+	pub uid: String,
+	pub product: String,
+	pub version: String,
+	pub content: ClientContent,
+	// Set by clients that send several requests over one session. The server echoes it in
+	// the response so replies can be matched up when they complete out of order.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub request_id: Option<u64>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientContent {
+	pub lumy: Lumy,
+	pub status: Status,
+	#[serde(flatten)]
+	pub request: Request
+	}
+
+// Client requests, tagged by the "action" field with the payload under "data"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", content = "data")]
+pub enum Request {
+	#[serde(rename = "register")]
+	Register(RegisterRequest),
+	#[serde(rename = "heartbeat")]
+	Heartbeat(Heartbeat),
+	#[serde(rename = "newconfig")]
+	IntegrityConfig(IntegrityConfigRequest),
+	// Keep the session open so the server can push commands over it
+	#[serde(rename = "listen")]
+	Listen(ListenRequest),
+	#[serde(rename = "result")]
+	CommandResult(CommandResult),
+	// File changes reported by the Integrity Lumy
+	#[serde(rename = "events")]
+	IntegrityEvents(IntegrityEvents)
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct RegisterRequest {
+	// Enrollment token. The field keeps its original name for wire compatibility.
+	pub serverkey: Option<String>,
+	pub hostname: String,
+	pub osplat: String,
+	pub osver: String,
+	pub ipv4: Option<String>,
+	pub ipv6: Option<String>,
+	// PEM certificate signing request for the endpoint's client certificate
+	pub csr: Option<String>
+	}
+
+// Heartbeats carry the endpoint's current attributes so the server can pick up changes.
+// Attributes are optional; acknowledgements from the server leave them empty.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Heartbeat {
+	pub hostname: Option<String>,
+	pub osplat: Option<String>,
+	pub osver: Option<String>,
+	pub ipv4: Option<String>,
+	pub ipv6: Option<String>,
+	// PEM server certificates the endpoint should trust, sent by the server so endpoints
+	// keep working across a certificate renewal
+	pub server_certificates: Option<String>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct IntegrityConfigRequest {
+	pub hostname: Option<String>,
+	pub osplat: Option<String>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ListenRequest {}
+
+// Outcome of a pushed command, sent back on the session it arrived on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CommandResult {
+	pub id: String,
+	pub success: bool,
+	pub output: String
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct IntegrityEvents {
+	pub events: Vec<IntegrityEvent>
+	}
+
+// A change to a watched path. File details are unset once the path no longer exists.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct IntegrityEvent {
+	pub path: String,
+	pub kind: FileEventKind,
+	pub file_type: Option<FileType>,
+	// Symbolic mode, such as "rw-r--r--"
+	pub permissions: Option<String>,
+	// "user:group"
+	pub owner: Option<String>,
+	pub size: Option<u64>,
+	// Unix seconds
+	pub at: i64
+	}
+
+// What happened to a watched path. Kinds this version doesn't know decode as Other.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FileEventKind {
+	#[serde(rename = "create")]
+	Create,
+	#[serde(rename = "modify")]
+	Modify,
+	#[serde(rename = "remove")]
+	Remove,
+	#[serde(rename = "access")]
+	Access,
+	#[default]
+	#[serde(rename = "other", other)]
+	Other
+	}
+
+impl FileEventKind {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			FileEventKind::Create => "create",
+			FileEventKind::Modify => "modify",
+			FileEventKind::Remove => "remove",
+			FileEventKind::Access => "access",
+			FileEventKind::Other => "other"
+			}
+		}
+
+	pub fn parse(value: &str) -> Option<FileEventKind> {
+		match value {
+			"create" => Some(FileEventKind::Create),
+			"modify" => Some(FileEventKind::Modify),
+			"remove" => Some(FileEventKind::Remove),
+			"access" => Some(FileEventKind::Access),
+			"other" => Some(FileEventKind::Other),
+			_ => None
+			}
+		}
+	}
+
+impl fmt::Display for FileEventKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.as_str())
+		}
+	}
+
+// Type of a watched path. Types this version doesn't know decode as Other.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FileType {
+	#[serde(rename = "file")]
+	File,
+	#[serde(rename = "directory")]
+	Directory,
+	#[default]
+	#[serde(rename = "other", other)]
+	Other
+	}
+
+impl FileType {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			FileType::File => "file",
+			FileType::Directory => "directory",
+			FileType::Other => "other"
+			}
+		}
+
+	pub fn parse(value: &str) -> Option<FileType> {
+		match value {
+			"file" => Some(FileType::File),
+			"directory" => Some(FileType::Directory),
+			"other" => Some(FileType::Other),
+			_ => None
+			}
+		}
+	}
+
+impl fmt::Display for FileType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.as_str())
+		}
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerMessage {
+	pub version: String,
+	pub content: ServerContent,
+	// Request this message answers. Pushed commands and keepalives have none.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub request_id: Option<u64>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerContent {
+	pub lumy: Lumy,
+	pub status: Status,
+	#[serde(flatten)]
+	pub response: Response
+	}
+
+// Server responses, tagged the same way as requests
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", content = "data")]
+pub enum Response {
+	#[serde(rename = "register")]
+	Register(RegisterResponse),
+	#[serde(rename = "heartbeat")]
+	Heartbeat(Heartbeat),
+	#[serde(rename = "newconfig")]
+	IntegrityConfig(IntegrityConfigResponse),
+	#[serde(rename = "listen")]
+	Listen(ListenResponse),
+	// Pushed by the server on a listening session
+	#[serde(rename = "command")]
+	Command(Command),
+	#[serde(rename = "result")]
+	CommandResult(CommandReceipt),
+	#[serde(rename = "events")]
+	IntegrityEvents(IntegrityEventReceipt),
+	#[serde(rename = "error")]
+	Error(ErrorResponse)
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct RegisterResponse {
+	pub uid: String,
+	// PEM client certificate signed by the server's client CA
+	pub certificate: Option<String>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct IntegrityConfigResponse {
+	#[serde(rename = "info")]
+	pub paths: Vec<String>
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ListenResponse {
+	// The server sends a heartbeat on idle sessions at this interval, in seconds
+	pub keepalive: u64
+	}
+
+// Command pushed to an endpoint. The ID is echoed back in the CommandResult.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Command {
+	pub id: String,
+	#[serde(flatten)]
+	pub kind: CommandKind
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "args")]
+pub enum CommandKind {
+	// Ask the endpoint for a piece of information, such as "hostname" or "uptime"
+	#[serde(rename = "question")]
+	Question(String),
+	// Change a client configuration value
+	#[serde(rename = "config")]
+	SetConfig { key: String, value: String },
+	// Run a built-in client action
+	#[serde(rename = "action")]
+	Action { name: String, args: Vec<String> }
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CommandReceipt {
+	pub id: String
+	}
+
+// Number of events the server stored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct IntegrityEventReceipt {
+	pub accepted: u32
+	}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ErrorResponse {
+	pub message: String
+	}
+
+impl ClientMessage {
+	pub fn new(uid: &str, version: &str, lumy: Lumy, status: Status, request: Request) -> Self {
+		ClientMessage {
+			uid: uid.to_string(),
+			product: PRODUCT_CLIENT.to_string(),
+			version: version.to_string(),
+			content: ClientContent { lumy, status, request },
+			request_id: None
+			}
+		}
+	}
+
+impl ServerMessage {
+	pub fn new(version: &str, lumy: Lumy, status: Status, response: Response) -> Self {
+		ServerMessage {
+			version: version.to_string(),
+			content: ServerContent { lumy, status, response },
+			request_id: None
+			}
+		}
+
+	// Error reply to a request that could not be fulfilled
+	pub fn error(version: &str, status: Status, message: &str) -> Self {
+		ServerMessage::new(version, Lumy::ServerCore, status, Response::Error(ErrorResponse { message: message.to_string() }))
+		}
+	}
@@ -0,0 +1,87 @@
+use luminum_proto::{CommandKind, FileEventKind, FileType};
+use luminum_proto::admin::*;
+
+#[test]
+fn endpoint_detail_roundtrip() {
+	let detail = EndpointDetail {
+		endpoint: EndpointInfo {
+			uid: "f3c1".to_string(),
+			hostname: "host01".to_string(),
+			ipv4: "192.168.1.20".to_string(),
+			osplat: "Linux".to_string(),
+			groups: vec!["web".to_string()],
+			last_seen: 1700000000,
+			presence: "online".to_string(),
+			..Default::default()
+			},
+		attribute_changes: vec![AttributeChangeInfo { attribute: "ipv4".to_string(), old_value: "192.168.1.19".to_string(), new_value: "192.168.1.20".to_string(), changed: 1700000000 }],
+		presence_events: vec![PresenceEventInfo { old: "stale".to_string(), new: "online".to_string(), at: 1700000000 }],
+		commands: vec![CommandInfo { id: "c1".to_string(), kind: CommandKind::Question("uptime".to_string()), state: "queued".to_string(), created: 1700000000, sent: None, completed: None, output: None, question_id: None }]
+		};
+	let json = serde_json::to_string(&detail).unwrap();
+	assert_eq!(serde_json::from_str::<EndpointDetail>(&json).unwrap(), detail);
+	}
+
+#[test]
+fn new_token_fields_are_optional() {
+	let token: NewToken = serde_json::from_str("{}").unwrap();
+	assert_eq!(token, NewToken::default());
+	let token: NewToken = serde_json::from_str(r#"{"groups":["web"],"expires":"30d","max_uses":5}"#).unwrap();
+	assert_eq!(token.groups, vec!["web".to_string()]);
+	assert_eq!(token.expires.as_deref(), Some("30d"));
+	assert_eq!(token.max_uses, Some(5));
+	}
+
+#[test]
+fn pages_roundtrip() {
+	let page = Page {
+		items: vec![FileEventInfo { uid: "f3c1".to_string(), path: "/etc/passwd".to_string(), kind: FileEventKind::Modify, file_type: Some(FileType::File), size: Some(1822), at: 1700000000, received: 1700000002, ..Default::default() }],
+		total: 41,
+		limit: 1,
+		offset: 40
+		};
+	let json = serde_json::to_string(&page).unwrap();
+	assert_eq!(serde_json::from_str::<Page<FileEventInfo>>(&json).unwrap(), page);
+	}
+
+#[test]
+fn new_question_targets_are_optional() {
+	let question: NewQuestion = serde_json::from_str(r#"{"question":"uptime"}"#).unwrap();
+	assert_eq!(question, NewQuestion { question: "uptime".to_string(), ..Default::default() });
+	assert!(serde_json::from_str::<NewQuestion>(r#"{"group":"web"}"#).is_err());
+	}
+
+#[test]
+fn operator_updates_only_carry_changes() {
+	let update = OperatorUpdate { disabled: Some(true), ..Default::default() };
+	assert_eq!(serde_json::to_string(&update).unwrap(), r#"{"disabled":true}"#);
+	let update: OperatorUpdate = serde_json::from_str(r#"{"role":"operator","groups":[]}"#).unwrap();
+	assert_eq!(update, OperatorUpdate { role: Some("operator".to_string()), groups: Some(Vec::new()), ..Default::default() });
+	}
+
+#[test]
+fn session_roundtrip() {
+	let session = Session {
+		key: "c4f155a1.df7f".to_string(),
+		expires: 1700043200,
+		principal: PrincipalInfo {
+			key_id: "c4f155a1".to_string(),
+			operator: Some("alice".to_string()),
+			role: Some("operator".to_string()),
+			scopes: vec!["read".to_string(), "ask".to_string()],
+			groups: vec!["web".to_string()],
+			session: true
+			}
+		};
+	let json = serde_json::to_string(&session).unwrap();
+	assert_eq!(serde_json::from_str::<Session>(&json).unwrap(), session);
+	}
+
+#[test]
+fn new_api_key_fields_are_optional() {
+	let key: NewApiKey = serde_json::from_str("{}").unwrap();
+	assert_eq!(key, NewApiKey::default());
+	let operator: NewOperator = serde_json::from_str(r#"{"username":"alice","password":"correct horse battery"}"#).unwrap();
+	assert_eq!(operator.role, None);
+	assert!(operator.groups.is_empty());
+	}
@@ -0,0 +1,61 @@
+use luminum_proto::audit::*;
+
+fn chain(count: u64) -> Vec<AuditRecord> {
+	let mut records: Vec<AuditRecord> = Vec::new();
+	for n in 0..count {
+		let record = AuditRecord {
+			at: 1700000000 + n as i64,
+			event: "sign-in".to_string(),
+			actor: "operator alice".to_string(),
+			source: "192.0.2.10".to_string(),
+			detail: format!("Signed in {}", n),
+			..Default::default()
+			}.seal(records.last());
+		records.push(record);
+		}
+	records
+	}
+
+fn verify(records: &[AuditRecord]) -> Result<u64, AuditBreak> {
+	let mut verifier = ChainVerifier::new();
+	for record in records {
+		verifier.check(record)?;
+		}
+	Ok(verifier.count())
+	}
+
+#[test]
+fn sealed_records_chain() {
+	let records = chain(3);
+	assert_eq!(records[0].seq, 1);
+	assert_eq!(records[0].prev_hash, GENESIS_HASH);
+	assert_eq!(records[2].prev_hash, records[1].hash);
+	assert_eq!(verify(&records), Ok(3));
+	}
+
+#[test]
+fn changed_records_break_the_chain() {
+	let mut records = chain(3);
+	records[1].actor = "operator mallory".to_string();
+	assert_eq!(verify(&records).unwrap_err().seq, 2);
+
+	// Rehashing the changed record still breaks the link to the next one
+	let mut records = chain(3);
+	records[1].detail = "Nothing happened".to_string();
+	records[1].hash = records[1].digest();
+	assert_eq!(verify(&records).unwrap_err().seq, 3);
+	}
+
+#[test]
+fn removed_records_break_the_chain() {
+	let mut records = chain(4);
+	records.remove(2);
+	assert_eq!(verify(&records).unwrap_err(), AuditBreak { seq: 4, reason: "expected record 3".to_string() });
+	}
+
+#[test]
+fn fields_are_not_ambiguous() {
+	let first = AuditRecord { event: "ab".to_string(), actor: "c".to_string(), ..Default::default() };
+	let second = AuditRecord { event: "a".to_string(), actor: "bc".to_string(), ..Default::default() };
+	assert_ne!(first.digest(), second.digest());
+	}
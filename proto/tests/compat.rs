@@ -0,0 +1,259 @@
+// Backward compatibility with the untyped message structs used before luminum-proto
+
+use serde::{Deserialize, Serialize};
+use rmp_serde::{from_slice, to_vec_named};
+use luminum_proto::*;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LegacyClientMessage {
+	uid: String,
+	product: String,
+	version: String,
+	content: LegacyClientContent
+	}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LegacyClientContent {
+	lumy: String,
+	status: String,
+	action: String,
+	data: Option<LegacyMessageData>
+	}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LegacyServerMessage {
+	version: String,
+	content: LegacyServerContent
+	}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LegacyServerContent {
+	lumy: String,
+	status: String,
+	action: String,
+	data: LegacyMessageData
+	}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct LegacyMessageData {
+	serverkey: Option<String>,
+	hostname: Option<String>,
+	uid: Option<String>,
+	osplat: Option<String>,
+	osver: Option<String>,
+	ipv4: Option<String>,
+	ipv6: Option<String>,
+	info: Option<Vec<String>>
+	}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LegacyLumyMessage {
+	lumy: String,
+	version: String,
+	content: LegacyLumyContent
+	}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LegacyLumyContent {
+	action: String,
+	data: Option<Vec<String>>
+	}
+
+fn legacy_client(uid: &str, lumy: &str, status: &str, action: &str, data: LegacyMessageData) -> Vec<u8> {
+	to_vec_named(&LegacyClientMessage {
+		uid: uid.to_string(),
+		product: "Luminum Client".to_string(),
+		version: "0.0.1".to_string(),
+		content: LegacyClientContent {
+			lumy: lumy.to_string(),
+			status: status.to_string(),
+			action: action.to_string(),
+			data: Some(data)
+			}
+		}).unwrap()
+	}
+
+#[test]
+fn legacy_registration_decodes() {
+	let bytes = legacy_client("NONE", "Client Core", "noreg", "register", LegacyMessageData {
+		serverkey: Some("key".to_string()),
+		hostname: Some("host01".to_string()),
+		uid: Some("NONE".to_string()),
+		osplat: Some("Linux".to_string()),
+		osver: Some("Slackware 15.0".to_string()),
+		ipv4: Some("192.168.1.20".to_string()),
+		ipv6: None,
+		info: None
+		});
+	let msg: ClientMessage = from_slice(&bytes).unwrap();
+	assert_eq!(msg.uid, UID_NONE);
+	assert_eq!(msg.content.lumy, Lumy::ClientCore);
+	assert_eq!(msg.content.status, Status::NoReg);
+	match msg.content.request {
+		Request::Register(reg) => {
+			assert_eq!(reg.serverkey.as_deref(), Some("key"));
+			assert_eq!(reg.hostname, "host01");
+			assert_eq!(reg.osplat, "Linux");
+			assert_eq!(reg.osver, "Slackware 15.0");
+			assert_eq!(reg.ipv4.as_deref(), Some("192.168.1.20"));
+			assert_eq!(reg.ipv6, None);
+			},
+		other => panic!("unexpected request: {:?}", other)
+		}
+	}
+
+#[test]
+fn legacy_heartbeat_and_integrity_requests_decode() {
+	let bytes = legacy_client("f3c1", "Client Core", "online", "heartbeat", LegacyMessageData::default());
+	let msg: ClientMessage = from_slice(&bytes).unwrap();
+	assert_eq!(msg.content.request, Request::Heartbeat(Heartbeat::default()));
+
+	let bytes = legacy_client("f3c1", "Client Core", "online", "heartbeat", LegacyMessageData {
+		hostname: Some("host01".to_string()),
+		ipv4: Some("10.0.0.6".to_string()),
+		..Default::default()
+		});
+	let msg: ClientMessage = from_slice(&bytes).unwrap();
+	assert_eq!(msg.content.request, Request::Heartbeat(Heartbeat {
+		hostname: Some("host01".to_string()),
+		ipv4: Some("10.0.0.6".to_string()),
+		..Default::default()
+		}));
+
+	let bytes = legacy_client("f3c1", "Integrity", "new", "newconfig", LegacyMessageData {
+		hostname: Some("host01".to_string()),
+		uid: Some("f3c1".to_string()),
+		osplat: Some("Linux".to_string()),
+		..Default::default()
+		});
+	let msg: ClientMessage = from_slice(&bytes).unwrap();
+	assert_eq!(msg.content.lumy, Lumy::Integrity);
+	assert_eq!(msg.content.request, Request::IntegrityConfig(IntegrityConfigRequest {
+		hostname: Some("host01".to_string()),
+		osplat: Some("Linux".to_string())
+		}));
+	}
+
+#[test]
+fn typed_requests_decode_as_legacy() {
+	let msg = ClientMessage::new("NONE", "0.0.1", Lumy::ClientCore, Status::NoReg, Request::Register(RegisterRequest {
+		serverkey: Some("key".to_string()),
+		hostname: "host01".to_string(),
+		osplat: "Linux".to_string(),
+		osver: "Debian 12".to_string(),
+		ipv4: Some("10.0.0.5".to_string()),
+		ipv6: None,
+		csr: None
+		}));
+	let legacy: LegacyClientMessage = from_slice(&to_vec_named(&msg).unwrap()).unwrap();
+	assert_eq!(legacy.content.lumy, "Client Core");
+	assert_eq!(legacy.content.status, "noreg");
+	assert_eq!(legacy.content.action, "register");
+	let data = legacy.content.data.unwrap();
+	assert_eq!(data.serverkey.as_deref(), Some("key"));
+	assert_eq!(data.hostname.as_deref(), Some("host01"));
+	assert_eq!(data.ipv4.as_deref(), Some("10.0.0.5"));
+
+	let msg = ClientMessage::new("f3c1", "0.0.1", Lumy::ClientCore, Status::Online, Request::Heartbeat(Heartbeat::default()));
+	let legacy: LegacyClientMessage = from_slice(&to_vec_named(&msg).unwrap()).unwrap();
+	assert_eq!(legacy.content.action, "heartbeat");
+	assert_eq!(legacy.content.status, "online");
+
+	// Request IDs are ignored by peers that don't know about them
+	let mut msg = ClientMessage::new("f3c1", "0.0.1", Lumy::ClientCore, Status::Online, Request::Heartbeat(Heartbeat::default()));
+	msg.request_id = Some(42);
+	let legacy: LegacyClientMessage = from_slice(&to_vec_named(&msg).unwrap()).unwrap();
+	assert_eq!(legacy.uid, "f3c1");
+	assert_eq!(legacy.content.action, "heartbeat");
+	}
+
+#[test]
+fn legacy_server_responses_decode() {
+	let bytes = to_vec_named(&LegacyServerMessage {
+		version: "0.0.1".to_string(),
+		content: LegacyServerContent {
+			lumy: "Luminum Core".to_string(),
+			status: "OK".to_string(),
+			action: "register".to_string(),
+			data: LegacyMessageData { uid: Some("f3c1".to_string()), ..Default::default() }
+			}
+		}).unwrap();
+	let msg: ServerMessage = from_slice(&bytes).unwrap();
+	assert_eq!(msg.content.lumy, Lumy::ServerCore);
+	assert_eq!(msg.content.status, Status::Ok);
+	assert_eq!(msg.content.response, Response::Register(RegisterResponse { uid: "f3c1".to_string(), certificate: None }));
+	assert_eq!(msg.request_id, None);
+
+	let bytes = to_vec_named(&LegacyServerMessage {
+		version: "0.0.1".to_string(),
+		content: LegacyServerContent {
+			lumy: "Integrity".to_string(),
+			status: "OK".to_string(),
+			action: "newconfig".to_string(),
+			data: LegacyMessageData { info: Some(vec!["/etc".to_string()]), ..Default::default() }
+			}
+		}).unwrap();
+	let msg: ServerMessage = from_slice(&bytes).unwrap();
+	assert_eq!(msg.content.response, Response::IntegrityConfig(IntegrityConfigResponse { paths: vec!["/etc".to_string()] }));
+	}
+
+#[test]
+fn typed_responses_decode_as_legacy() {
+	let msg = ServerMessage::new("0.0.1", Lumy::Integrity, Status::Ok, Response::IntegrityConfig(IntegrityConfigResponse {
+		paths: vec!["/etc".to_string(), "/bin".to_string()]
+		}));
+	let legacy: LegacyServerMessage = from_slice(&to_vec_named(&msg).unwrap()).unwrap();
+	assert_eq!(legacy.content.action, "newconfig");
+	assert_eq!(legacy.content.status, "OK");
+	assert_eq!(legacy.content.data.info, Some(vec!["/etc".to_string(), "/bin".to_string()]));
+
+	let msg = ServerMessage::new("0.0.1", Lumy::ServerCore, Status::Ok, Response::Register(RegisterResponse { uid: "f3c1".to_string(), certificate: None }));
+	let legacy: LegacyServerMessage = from_slice(&to_vec_named(&msg).unwrap()).unwrap();
+	assert_eq!(legacy.content.data.uid.as_deref(), Some("f3c1"));
+	}
+
+#[test]
+fn legacy_lumy_messages_decode() {
+	let bytes = to_vec_named(&LegacyLumyMessage {
+		lumy: "Integrity".to_string(),
+		version: "0.0.1".to_string(),
+		content: LegacyLumyContent { action: "newconfig".to_string(), data: None }
+		}).unwrap();
+	let msg: LumyMessage = from_slice(&bytes).unwrap();
+	assert_eq!(msg, LumyMessage::new(Lumy::Integrity, "0.0.1", LumyContent::NewConfig));
+
+	let msg = LumyMessage::new(Lumy::Client, "0.0.1", LumyContent::SetConfig(vec!["/etc".to_string()]));
+	let legacy: LegacyLumyMessage = from_slice(&to_vec_named(&msg).unwrap()).unwrap();
+	assert_eq!(legacy.lumy, "Luminum Client");
+	assert_eq!(legacy.content.action, "setconfig");
+	assert_eq!(legacy.content.data, Some(vec!["/etc".to_string()]));
+	}
+
+#[test]
+fn unknown_actions_are_rejected() {
+	let bytes = legacy_client("f3c1", "Client Core", "online", "selfdestruct", LegacyMessageData::default());
+	assert!(from_slice::<ClientMessage>(&bytes).is_err());
+	}
+
+#[derive(Serialize)]
+struct UntypedEvent {
+	path: String,
+	kind: String,
+	file_type: Option<String>,
+	at: i64
+	}
+
+#[test]
+fn unknown_event_kinds_and_file_types_decode_as_other() {
+	let decode = |kind: &str, file_type: &str| -> IntegrityEvent {
+		from_slice(&to_vec_named(&UntypedEvent { path: "/etc/passwd".to_string(), kind: kind.to_string(), file_type: Some(file_type.to_string()), at: 1700000000 }).unwrap()).unwrap()
+		};
+	let event = decode("modify", "file");
+	assert_eq!((event.kind, event.file_type), (FileEventKind::Modify, Some(FileType::File)));
+	let event = decode("rename", "socket");
+	assert_eq!((event.kind, event.file_type), (FileEventKind::Other, Some(FileType::Other)));
+
+	let encoded: Vec<u8> = to_vec_named(&IntegrityEvent { kind: FileEventKind::Remove, file_type: Some(FileType::Directory), ..IntegrityEvent::default() }).unwrap();
+	let decoded: serde_json::Value = rmp_serde::from_slice(&encoded).unwrap();
+	assert_eq!((decoded["kind"].as_str(), decoded["file_type"].as_str()), (Some("remove"), Some("directory")));
+	}
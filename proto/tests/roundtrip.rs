@@ -0,0 +1,233 @@
+use std::io::Cursor;
+use luminum_proto::*;
+use luminum_proto::frame::{HEADER_LEN, encode_header};
+
+fn roundtrip_client(msg: ClientMessage) {
+	let mut buffer = Vec::new();
+	write_message(&mut buffer, &msg, DEFAULT_MAX_FRAME).unwrap();
+	let decoded: ClientMessage = read_message(&mut Cursor::new(buffer), DEFAULT_MAX_FRAME).unwrap().unwrap();
+	assert_eq!(decoded, msg);
+	}
+
+fn roundtrip_server(msg: ServerMessage) {
+	let mut buffer = Vec::new();
+	write_message(&mut buffer, &msg, DEFAULT_MAX_FRAME).unwrap();
+	let decoded: ServerMessage = read_message(&mut Cursor::new(buffer), DEFAULT_MAX_FRAME).unwrap().unwrap();
+	assert_eq!(decoded, msg);
+	}
+
+#[test]
+fn client_requests_roundtrip() {
+	roundtrip_client(ClientMessage::new(UID_NONE, "0.0.1", Lumy::ClientCore, Status::NoReg, Request::Register(RegisterRequest {
+		serverkey: Some("key".to_string()),
+		hostname: "host01".to_string(),
+		osplat: "Linux".to_string(),
+		osver: "Debian GNU/Linux 12 (bookworm)".to_string(),
+		ipv4: Some("192.168.1.20".to_string()),
+		ipv6: None,
+		csr: None
+		})));
+	roundtrip_client(ClientMessage::new("f3c1", "0.0.1", Lumy::ClientCore, Status::Online, Request::Heartbeat(Heartbeat::default())));
+	roundtrip_client(ClientMessage::new("f3c1", "0.0.1", Lumy::ClientCore, Status::Online, Request::Heartbeat(Heartbeat {
+		hostname: Some("host01".to_string()),
+		osplat: Some("Linux".to_string()),
+		osver: Some("Debian GNU/Linux 12 (bookworm)".to_string()),
+		ipv4: Some("192.168.1.21".to_string()),
+		ipv6: None,
+		server_certificates: None
+		})));
+	roundtrip_client(ClientMessage::new("f3c1", "0.0.1", Lumy::Integrity, Status::New, Request::IntegrityConfig(IntegrityConfigRequest {
+		hostname: Some("host01".to_string()),
+		osplat: Some("Linux".to_string())
+		})));
+	roundtrip_client(ClientMessage::new("f3c1", "0.0.1", Lumy::ClientCore, Status::Online, Request::Listen(ListenRequest {})));
+	roundtrip_client(ClientMessage::new("f3c1", "0.0.1", Lumy::ClientCore, Status::Ok, Request::CommandResult(CommandResult {
+		id: "9a0e".to_string(),
+		success: true,
+		output: "host01".to_string()
+		})));
+	roundtrip_client(ClientMessage::new("f3c1", "0.0.1", Lumy::Integrity, Status::Ok, Request::IntegrityEvents(IntegrityEvents {
+		events: vec![sample_event(), IntegrityEvent { path: "/etc/old.conf".to_string(), kind: FileEventKind::Remove, at: 1700000100, ..IntegrityEvent::default() }]
+		})));
+	}
+
+fn sample_event() -> IntegrityEvent {
+	IntegrityEvent {
+		path: "/etc/passwd".to_string(),
+		kind: FileEventKind::Modify,
+		file_type: Some(FileType::File),
+		permissions: Some("rw-r--r--".to_string()),
+		owner: Some("root:root".to_string()),
+		size: Some(1822),
+		at: 1700000000
+		}
+	}
+
+#[test]
+fn server_responses_roundtrip() {
+	roundtrip_server(ServerMessage::new("0.0.1", Lumy::ServerCore, Status::Ok, Response::Register(RegisterResponse { uid: "f3c1".to_string(), certificate: None })));
+	roundtrip_server(ServerMessage::new("0.0.1", Lumy::ServerCore, Status::Ok, Response::Heartbeat(Heartbeat::default())));
+	roundtrip_server(ServerMessage::new("0.0.1", Lumy::ServerCore, Status::Ok, Response::Heartbeat(Heartbeat {
+		server_certificates: Some("-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n".to_string()),
+		..Heartbeat::default()
+		})));
+	roundtrip_server(ServerMessage::new("0.0.1", Lumy::Integrity, Status::Ok, Response::IntegrityConfig(IntegrityConfigResponse {
+		paths: vec!["/etc".to_string(), "/usr/bin".to_string()]
+		})));
+	roundtrip_server(ServerMessage::error("0.0.1", Status::Denied, "Invalid server key"));
+	roundtrip_server(ServerMessage::new("0.0.1", Lumy::ServerCore, Status::Ok, Response::Listen(ListenResponse { keepalive: 60 })));
+	roundtrip_server(ServerMessage::new("0.0.1", Lumy::ServerCore, Status::Ok, Response::CommandResult(CommandReceipt { id: "9a0e".to_string() })));
+	roundtrip_server(ServerMessage::new("0.0.1", Lumy::Integrity, Status::Ok, Response::IntegrityEvents(IntegrityEventReceipt { accepted: 2 })));
+	}
+
+#[test]
+fn request_ids_roundtrip() {
+	let mut request = ClientMessage::new("f3c1", "0.0.1", Lumy::ClientCore, Status::Online, Request::Heartbeat(Heartbeat::default()));
+	request.request_id = Some(7);
+	roundtrip_client(request);
+	let mut response = ServerMessage::new("0.0.1", Lumy::ServerCore, Status::Ok, Response::Heartbeat(Heartbeat::default()));
+	response.request_id = Some(u64::MAX);
+	roundtrip_server(response);
+	}
+
+#[test]
+fn pushed_commands_roundtrip() {
+	for kind in [
+		CommandKind::Question("hostname".to_string()),
+		CommandKind::SetConfig { key: "HEARTBEAT".to_string(), value: "120".to_string() },
+		CommandKind::Action { name: "start-lumy".to_string(), args: vec!["Integrity".to_string()] }
+		] {
+		roundtrip_server(ServerMessage::new("0.0.1", Lumy::ServerCore, Status::Ok, Response::Command(Command { id: "9a0e".to_string(), kind })));
+		}
+	}
+
+#[test]
+fn lumy_messages_roundtrip() {
+	for msg in [
+		LumyMessage::new(Lumy::Integrity, "0.0.1", LumyContent::NewConfig),
+		LumyMessage::new(Lumy::Client, "0.0.1", LumyContent::SetConfig(vec!["/etc".to_string()])),
+		LumyMessage::new(Lumy::Integrity, "0.0.1", LumyContent::Events(vec![sample_event()]))
+		] {
+		let mut buffer = Vec::new();
+		write_message(&mut buffer, &msg, DEFAULT_MAX_FRAME).unwrap();
+		let decoded: LumyMessage = read_message(&mut Cursor::new(buffer), DEFAULT_MAX_FRAME).unwrap().unwrap();
+		assert_eq!(decoded, msg);
+		}
+	}
+
+#[test]
+fn several_messages_share_one_stream() {
+	let mut buffer = Vec::new();
+	for n in 0..3 {
+		let msg = ClientMessage::new(&format!("uid-{}", n), "0.0.1", Lumy::ClientCore, Status::Online, Request::Heartbeat(Heartbeat::default()));
+		write_message(&mut buffer, &msg, DEFAULT_MAX_FRAME).unwrap();
+		}
+	let mut reader = Cursor::new(buffer);
+	for n in 0..3 {
+		let msg: ClientMessage = read_message(&mut reader, DEFAULT_MAX_FRAME).unwrap().unwrap();
+		assert_eq!(msg.uid, format!("uid-{}", n));
+		}
+	assert!(read_message::<_, ClientMessage>(&mut reader, DEFAULT_MAX_FRAME).unwrap().is_none());
+	}
+
+#[test]
+fn large_payloads_are_not_truncated() {
+	let paths: Vec<String> = (0..10000).map(|n| format!("/var/lib/luminum/watch/{}", n)).collect();
+	let msg = ServerMessage::new("0.0.1", Lumy::Integrity, Status::Ok, Response::IntegrityConfig(IntegrityConfigResponse { paths }));
+	roundtrip_server(msg);
+	}
+
+#[test]
+fn oversized_frames_are_rejected() {
+	let mut buffer = Vec::new();
+	assert!(matches!(write_frame(&mut buffer, &[0u8; 64], 32), Err(FrameError::TooLarge(64, 32))));
+
+	write_frame(&mut buffer, &[0u8; 64], DEFAULT_MAX_FRAME).unwrap();
+	assert!(matches!(read_frame(&mut Cursor::new(buffer), 32), Err(FrameError::TooLarge(64, 32))));
+	}
+
+#[test]
+fn bad_headers_are_rejected() {
+	let mut buffer = Vec::new();
+	write_frame(&mut buffer, b"payload", DEFAULT_MAX_FRAME).unwrap();
+
+	let mut bad_magic = buffer.clone();
+	bad_magic[0] = b'X';
+	assert!(matches!(read_frame(&mut Cursor::new(bad_magic), DEFAULT_MAX_FRAME), Err(FrameError::BadMagic(_))));
+
+	let mut bad_version = buffer.clone();
+	bad_version[4] = 99;
+	assert!(matches!(read_frame(&mut Cursor::new(bad_version), DEFAULT_MAX_FRAME), Err(FrameError::BadVersion(99))));
+
+	let truncated = buffer[..buffer.len() - 2].to_vec();
+	assert!(matches!(read_frame(&mut Cursor::new(truncated), DEFAULT_MAX_FRAME), Err(FrameError::Io(_))));
+	}
+
+#[test]
+fn end_of_stream_is_clean_only_between_frames() {
+	assert!(read_frame(&mut Cursor::new(Vec::new()), DEFAULT_MAX_FRAME).unwrap().is_none());
+
+	let mut buffer = Vec::new();
+	write_frame(&mut buffer, b"payload", DEFAULT_MAX_FRAME).unwrap();
+	let mut reader = Cursor::new(buffer.clone());
+	assert_eq!(read_frame(&mut reader, DEFAULT_MAX_FRAME).unwrap().as_deref(), Some(&b"payload"[..]));
+	assert!(read_frame(&mut reader, DEFAULT_MAX_FRAME).unwrap().is_none());
+
+	// Closing partway through the header or the payload is an error
+	for cut in [1, HEADER_LEN - 1, HEADER_LEN, buffer.len() - 1] {
+		match read_frame(&mut Cursor::new(buffer[..cut].to_vec()), DEFAULT_MAX_FRAME) {
+			Err(FrameError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof),
+			other => panic!("frame cut at {} bytes read as {:?}", cut, other)
+			}
+		}
+	}
+
+#[test]
+fn announced_lengths_are_checked_before_reading_the_payload() {
+	// The header alone is enough to reject a frame, without waiting for a payload that never comes
+	let header = encode_header(0, DEFAULT_MAX_FRAME).unwrap();
+	let mut oversized = header;
+	oversized[5..].copy_from_slice(&u32::MAX.to_be_bytes());
+	assert!(matches!(read_frame(&mut Cursor::new(oversized.to_vec()), DEFAULT_MAX_FRAME), Err(FrameError::TooLarge(len, DEFAULT_MAX_FRAME)) if len == u32::MAX as usize));
+
+	let mut bad_magic = header;
+	bad_magic[..4].copy_from_slice(b"HTTP");
+	assert!(matches!(read_frame(&mut Cursor::new(bad_magic.to_vec()), DEFAULT_MAX_FRAME), Err(FrameError::BadMagic(magic)) if &magic == b"HTTP"));
+
+	// A payload of exactly the maximum length is allowed
+	let mut buffer = Vec::new();
+	write_frame(&mut buffer, &[7u8; 32], 32).unwrap();
+	assert_eq!(read_frame(&mut Cursor::new(buffer), 32).unwrap(), Some(vec![7u8; 32]));
+	}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_frames_are_checked_like_blocking_frames() {
+	let mut buffer = Vec::new();
+	write_frame(&mut buffer, b"payload", DEFAULT_MAX_FRAME).unwrap();
+	let mut reader = buffer.as_slice();
+	assert_eq!(read_frame_async(&mut reader, DEFAULT_MAX_FRAME).await.unwrap().as_deref(), Some(&b"payload"[..]));
+	assert!(read_frame_async(&mut reader, DEFAULT_MAX_FRAME).await.unwrap().is_none());
+	assert!(matches!(read_frame_async(&mut &buffer[..HEADER_LEN - 1], DEFAULT_MAX_FRAME).await, Err(FrameError::Io(_))));
+	assert!(matches!(read_frame_async(&mut &buffer[..buffer.len() - 1], DEFAULT_MAX_FRAME).await, Err(FrameError::Io(_))));
+	assert!(matches!(read_frame_async(&mut buffer.as_slice(), 4).await, Err(FrameError::TooLarge(7, 4))));
+
+	let mut bad_magic = buffer.clone();
+	bad_magic[0] = b'X';
+	assert!(matches!(read_frame_async(&mut bad_magic.as_slice(), DEFAULT_MAX_FRAME).await, Err(FrameError::BadMagic(_))));
+	}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_frames_interoperate_with_blocking_frames() {
+	let msg = ClientMessage::new("f3c1", "0.0.1", Lumy::ClientCore, Status::Online, Request::Heartbeat(Heartbeat::default()));
+	let mut buffer = Vec::new();
+	write_message(&mut buffer, &msg, DEFAULT_MAX_FRAME).unwrap();
+	let decoded: ClientMessage = read_message_async(&mut buffer.as_slice(), DEFAULT_MAX_FRAME).await.unwrap().unwrap();
+	assert_eq!(decoded, msg);
+
+	let mut buffer = Vec::new();
+	write_message_async(&mut buffer, &msg, DEFAULT_MAX_FRAME).await.unwrap();
+	let decoded: ClientMessage = read_message(&mut Cursor::new(buffer), DEFAULT_MAX_FRAME).unwrap().unwrap();
+	assert_eq!(decoded, msg);
+	}
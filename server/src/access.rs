@@ -0,0 +1,307 @@
+// Access control
+//
+// Operators sign in to the management API and web console with a password and hold one of
+// three roles. API keys carry scopes; a key owned by an operator is further limited to what
+// the operator's role allows, and to the operator's endpoint groups. Keys created on the
+// server host with --create-api-key have no owner and see every endpoint.
+
+use std::fmt;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use lazy_static::lazy_static;
+use openssl::rand::rand_bytes;
+use crate::storage::{ApiKey, Endpoint, Operator};
+
+pub const MIN_PASSWORD_LEN: usize = 12;
+pub const MAX_PASSWORD_LEN: usize = 1024;
+const SALT_BYTES: usize = 16;
+
+lazy_static! {
+	// Checked in place of a real hash when there is no such operator
+	static ref DECOY_HASH: String = hash_password("no such operator").unwrap_or_default();
+	}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+	// View endpoints, groups, questions, results, commands, watchlists and file events
+	Read,
+	// Ask questions
+	Ask,
+	// Edit watchlists and change client configuration
+	Configure,
+	// Run client actions
+	Deploy,
+	// Revoke and delete endpoints, and change their groups
+	Endpoints,
+	// Manage enrollment tokens
+	Enrollment,
+	// Manage operators and other operators' API keys, and view the server configuration
+	Admin
+	}
+
+impl Scope {
+	pub const ALL: [Scope; 7] = [Scope::Read, Scope::Ask, Scope::Configure, Scope::Deploy, Scope::Endpoints, Scope::Enrollment, Scope::Admin];
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Scope::Read => "read",
+			Scope::Ask => "ask",
+			Scope::Configure => "configure",
+			Scope::Deploy => "deploy",
+			Scope::Endpoints => "endpoints",
+			Scope::Enrollment => "enrollment",
+			Scope::Admin => "admin"
+			}
+		}
+
+	pub fn parse(value: &str) -> Option<Scope> {
+		Scope::ALL.into_iter().find(|scope| scope.as_str() == value)
+		}
+
+	// Enrollment tokens, operators and the server configuration aren't tied to endpoint
+	// groups, so only principals that see every endpoint may manage them
+	pub fn unrestricted_only(&self) -> bool {
+		matches!(self, Scope::Enrollment | Scope::Admin)
+		}
+	}
+
+impl fmt::Display for Scope {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.as_str())
+		}
+	}
+
+// Parse a list of scope names, rejecting unknown ones
+pub fn parse_scopes<S: AsRef<str>>(names: &[S]) -> Result<Vec<Scope>, String> {
+	let mut scopes = names.iter().map(|name| Scope::parse(name.as_ref().trim()).ok_or_else(|| {
+		format!("Unknown scope: {} (expected {})", name.as_ref(), Scope::ALL.map(|scope| scope.as_str()).join(", "))
+		})).collect::<Result<Vec<Scope>, String>>()?;
+	scopes.sort();
+	scopes.dedup();
+	Ok(scopes)
+	}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Role {
+	// Read-only access
+	#[default]
+	Viewer,
+	// Day-to-day work: questions, watchlists, client configuration and actions
+	Operator,
+	// Everything, including endpoint removal, enrollment and access management
+	Administrator
+	}
+
+impl Role {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Role::Viewer => "viewer",
+			Role::Operator => "operator",
+			Role::Administrator => "administrator"
+			}
+		}
+
+	pub fn parse(value: &str) -> Option<Role> {
+		match value {
+			"viewer" => Some(Role::Viewer),
+			"operator" => Some(Role::Operator),
+			"administrator" => Some(Role::Administrator),
+			_ => None
+			}
+		}
+
+	pub fn scopes(&self) -> &'static [Scope] {
+		match self {
+			Role::Viewer => &[Scope::Read],
+			Role::Operator => &[Scope::Read, Scope::Ask, Scope::Configure, Scope::Deploy],
+			Role::Administrator => &Scope::ALL
+			}
+		}
+	}
+
+impl fmt::Display for Role {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.as_str())
+		}
+	}
+
+// Letters, digits and . _ - @, so usernames can be email addresses
+pub fn valid_username(name: &str) -> bool {
+	!name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '@'))
+	}
+
+pub fn check_password(password: &str) -> Result<(), String> {
+	match password.chars().count() {
+		count if count < MIN_PASSWORD_LEN => Err(format!("Passwords must be at least {} characters", MIN_PASSWORD_LEN)),
+		_ if password.len() > MAX_PASSWORD_LEN => Err(format!("Passwords are limited to {} bytes", MAX_PASSWORD_LEN)),
+		_ => Ok(())
+		}
+	}
+
+// Hash a password with Argon2id, returning a PHC string that records the salt and parameters
+pub fn hash_password(password: &str) -> Result<String, String> {
+	let mut salt = [0u8; SALT_BYTES];
+	rand_bytes(&mut salt).map_err(|err| err.to_string())?;
+	let salt = SaltString::encode_b64(&salt).map_err(|err| err.to_string())?;
+	Argon2::default().hash_password(password.as_bytes(), &salt).map(|hash| hash.to_string()).map_err(|err| err.to_string())
+	}
+
+// Check a password against a stored hash. Without an operator, a decoy hash is checked so
+// unknown usernames take as long to reject as wrong passwords.
+pub fn verify_password(operator: Option<&Operator>, password: &str) -> bool {
+	let stored = operator.map_or(DECOY_HASH.as_str(), |operator| operator.password_hash.as_str());
+	let verified = PasswordHash::new(stored).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok());
+	verified && operator.is_some()
+	}
+
+// Who an API request is made by, and what they may do
+#[derive(Clone, Debug)]
+pub struct Principal {
+	pub key_id: String,
+	pub operator: Option<String>,
+	pub role: Option<Role>,
+	// The key's scopes, limited to its owner's role
+	pub scopes: Vec<Scope>,
+	// Endpoint groups the principal is limited to; empty for every endpoint
+	pub groups: Vec<String>,
+	// Signed in from the web console rather than holding an API key
+	pub session: bool
+	}
+
+impl Principal {
+	// The principal for a verified key and its owner, if it has one
+	pub fn new(key: &ApiKey, owner: Option<&Operator>) -> Principal {
+		match owner {
+			Some(operator) => Principal {
+				key_id: key.id.clone(),
+				operator: Some(operator.username.clone()),
+				role: Some(operator.role),
+				scopes: key.scopes.iter().copied().filter(|scope| operator.role.scopes().contains(scope)).collect(),
+				groups: operator.groups.clone(),
+				session: key.session
+				},
+			None => Principal {
+				key_id: key.id.clone(),
+				operator: None,
+				role: None,
+				scopes: key.scopes.clone(),
+				groups: Vec::new(),
+				session: key.session
+				}
+			}
+		}
+
+	pub fn restricted(&self) -> bool {
+		!self.groups.is_empty()
+		}
+
+	// Whether the principal may use a scope. Returns the reason when it may not.
+	pub fn check(&self, scope: Scope) -> Result<(), String> {
+		if !self.scopes.contains(&scope) {
+			return Err(format!("This requires the {} permission", scope));
+			}
+		if scope.unrestricted_only() && self.restricted() {
+			return Err(format!("The {} permission is only available to principals that are not limited to endpoint groups", scope));
+			}
+		Ok(())
+		}
+
+	pub fn sees_group(&self, group: &str) -> bool {
+		!self.restricted() || self.groups.iter().any(|allowed| allowed == group)
+		}
+
+	// Endpoints are visible when they share a group with the principal
+	pub fn sees(&self, endpoint: &Endpoint) -> bool {
+		!self.restricted() || endpoint.groups.iter().any(|group| self.sees_group(group))
+		}
+	}
+
+impl fmt::Display for Principal {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match &self.operator {
+			Some(operator) if self.session => write!(f, "operator {}", operator),
+			Some(operator) => write!(f, "operator {} with API key {}", operator, self.key_id),
+			None => write!(f, "API key {}", self.key_id)
+			}
+		}
+	}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn key(scopes: &[Scope]) -> ApiKey {
+		ApiKey { id: String::from("k1"), scopes: scopes.to_vec(), ..ApiKey::default() }
+		}
+
+	fn operator(role: Role, groups: &[&str]) -> Operator {
+		Operator { username: String::from("alice"), role, groups: groups.iter().map(|group| group.to_string()).collect(), ..Operator::default() }
+		}
+
+	fn endpoint(groups: &[&str]) -> Endpoint {
+		Endpoint { uid: String::from("e1"), groups: groups.iter().map(|group| group.to_string()).collect(), ..Endpoint::default() }
+		}
+
+	#[test]
+	fn limits_owned_keys_to_the_owners_role() {
+		let principal = Principal::new(&key(&Scope::ALL), Some(&operator(Role::Viewer, &[])));
+		assert_eq!(principal.scopes, [Scope::Read]);
+		assert_eq!(principal.role, Some(Role::Viewer));
+
+		let principal = Principal::new(&key(&Scope::ALL), Some(&operator(Role::Operator, &[])));
+		assert_eq!(principal.scopes, [Scope::Read, Scope::Ask, Scope::Configure, Scope::Deploy]);
+
+		// A role doesn't add scopes the key lacks
+		let principal = Principal::new(&key(&[Scope::Read]), Some(&operator(Role::Administrator, &[])));
+		assert_eq!(principal.scopes, [Scope::Read]);
+		}
+
+	#[test]
+	fn keeps_the_scopes_of_unowned_keys() {
+		let principal = Principal::new(&key(&[Scope::Read, Scope::Admin]), None);
+		assert_eq!(principal.scopes, [Scope::Read, Scope::Admin]);
+		assert!(!principal.restricted());
+		assert_eq!((principal.operator, principal.role), (None, None));
+		}
+
+	#[test]
+	fn checks_scopes() {
+		let principal = Principal::new(&key(&Scope::ALL), Some(&operator(Role::Operator, &[])));
+		assert!(principal.check(Scope::Ask).is_ok());
+		assert!(principal.check(Scope::Deploy).is_ok());
+		for scope in [Scope::Endpoints, Scope::Enrollment, Scope::Admin] {
+			assert!(principal.check(scope).is_err(), "{}", scope);
+			}
+		}
+
+	#[test]
+	fn keeps_enrollment_and_admin_from_restricted_principals() {
+		let principal = Principal::new(&key(&Scope::ALL), Some(&operator(Role::Administrator, &["web"])));
+		assert!(principal.restricted());
+		for scope in Scope::ALL {
+			assert_eq!(principal.check(scope).is_ok(), !scope.unrestricted_only(), "{}", scope);
+			}
+		let principal = Principal::new(&key(&Scope::ALL), Some(&operator(Role::Administrator, &[])));
+		assert!(Scope::ALL.iter().all(|scope| principal.check(*scope).is_ok()));
+		}
+
+	#[test]
+	fn sees_endpoints_in_its_groups() {
+		let restricted = Principal::new(&key(&[Scope::Read]), Some(&operator(Role::Viewer, &["web", "db"])));
+		assert!(restricted.sees_group("web"));
+		assert!(!restricted.sees_group("mail"));
+		assert!(restricted.sees(&endpoint(&["mail", "db"])));
+		assert!(!restricted.sees(&endpoint(&["mail"])));
+		assert!(!restricted.sees(&endpoint(&[])));
+
+		let unrestricted = Principal::new(&key(&[Scope::Read]), Some(&operator(Role::Viewer, &[])));
+		assert!(unrestricted.sees_group("mail"));
+		assert!(unrestricted.sees(&endpoint(&[])));
+		}
+
+	#[test]
+	fn parses_scopes() {
+		assert_eq!(parse_scopes(&["admin", " read", "read"]), Ok(vec![Scope::Read, Scope::Admin]));
+		assert!(parse_scopes(&["read", "root"]).is_err());
+		}
+	}
@@ -0,0 +1,305 @@
+// Operators, API keys and sessions
+
+use std::sync::Arc;
+use axum::{Extension, Json};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use luminum_log::SECURITY;
+use luminum_proto::admin::{ApiKeyInfo, CreatedApiKey, Login, NewApiKey, NewOperator, OperatorInfo, OperatorUpdate, Page, PasswordChange, PrincipalInfo, Session};
+use tracing::{error, info, warn};
+use crate::access::{self, Principal, Role, Scope};
+use crate::audit::Event;
+use crate::enroll::{self, now};
+use crate::listener::ServerState;
+use crate::storage::{self, ApiKey, Operator};
+use super::{Failure, Paging, Peer, audit, blocking, generate_key};
+
+// Console sessions last a working day
+const SESSION_LIFETIME: i64 = 12 * 60 * 60;
+
+// Sign in with a username and password, receiving a short-lived key for the session
+pub async fn login(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Json(request): Json<Login>) -> Result<Json<Session>, Failure> {
+	// Refused before the password is checked, so refusals cost next to nothing
+	if state.guard.sign_in(peer_addr.ip(), &state.protection()).is_err() {
+		return Err(Failure::new(StatusCode::TOO_MANY_REQUESTS, "Too many sign-in attempts. Try again later."));
+		}
+	let username = request.username.trim().to_string();
+	let attempted = username.clone();
+	// Argon2 is deliberately slow, so the password is checked off the async workers too
+	let session = blocking(&state, move |storage| {
+		let operator = storage.find_operator(&attempted)?;
+		if !access::verify_password(operator.as_ref(), &request.password) {
+			return Ok(Err("wrong username or password"));
+			}
+		let operator = match operator {
+			Some(operator) if operator.disabled => { return Ok(Err("operator is disabled")); },
+			Some(operator) => operator,
+			None => { return Ok(Err("wrong username or password")); }
+			};
+		let now = now();
+		storage.delete_stale_sessions(now)?;
+		let (mut key, secret) = generate_key("Console session", Some(&operator.username), operator.role.scopes().to_vec(), Some(now + SESSION_LIFETIME)).map_err(|err| {
+			error!("Could not generate session key: {}", err);
+			Failure::new(StatusCode::INTERNAL_SERVER_ERROR, "Could not start session")
+			})?;
+		key.session = true;
+		storage.add_api_key(&key)?;
+		storage.touch_operator(&operator.username, now)?;
+		Ok(Ok(Session { key: secret, expires: now + SESSION_LIFETIME, principal: principal_info(&Principal::new(&key, Some(&operator))) }))
+		}).await?;
+	match session {
+		Ok(session) => {
+			info!(target: SECURITY, peer = %peer_addr, "Operator {} signed in", username);
+			audit(&state, Event::SignIn, format!("operator {}", username), peer_addr, format!("Signed in with session key {}", session.principal.key_id)).await;
+			Ok(Json(session))
+			},
+		Err(reason) => {
+			warn!(target: SECURITY, peer = %peer_addr, "Rejected sign-in as {:?}: {}", username, reason);
+			// Repeated failures from one source are audited together
+			if let Some(unaudited) = state.guard.sign_in_failed(peer_addr.ip()) {
+				let detail = match unaudited {
+					0 => format!("Rejected sign-in: {}", reason),
+					count => format!("Rejected sign-in: {} ({} more rejected from this address since the last record)", reason, count)
+					};
+				audit(&state, Event::SignInFailed, format!("operator {}", username), peer_addr, detail).await;
+				}
+			Err(Failure::new(StatusCode::UNAUTHORIZED, "Invalid username or password"))
+			}
+		}
+	}
+
+pub async fn logout(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>) -> Result<StatusCode, Failure> {
+	if !principal.session {
+		return Err(Failure::bad_request("Only sessions can be signed out of; revoke API keys instead"));
+		}
+	let key_id = principal.key_id.clone();
+	blocking(&state, move |storage| Ok(storage.revoke_api_key(&key_id)?)).await?;
+	info!(target: SECURITY, "Sign-out by {}", principal);
+	audit(&state, Event::SignOut, &principal, peer_addr, String::from("Signed out")).await;
+	Ok(StatusCode::NO_CONTENT)
+	}
+
+pub async fn me(Extension(principal): Extension<Principal>) -> Json<PrincipalInfo> {
+	Json(principal_info(&principal))
+	}
+
+pub async fn change_password(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Json(request): Json<PasswordChange>) -> Result<StatusCode, Failure> {
+	let Some(username) = principal.operator.clone() else {
+		return Err(Failure::bad_request("Only operators have passwords"));
+		};
+	access::check_password(&request.new_password).map_err(Failure::bad_request)?;
+	blocking(&state, move |storage| {
+		let mut operator = find_operator(storage, &username)?;
+		if !access::verify_password(Some(&operator), &request.current_password) {
+			return Err(Failure::forbidden("The current password is wrong"));
+			}
+		operator.password_hash = hash(&request.new_password)?;
+		storage.update_operator(&operator)?;
+		Ok(())
+		}).await?;
+	info!(target: SECURITY, "Password changed by {}", principal);
+	audit(&state, Event::Access, &principal, peer_addr, String::from("Changed their password")).await;
+	Ok(StatusCode::NO_CONTENT)
+	}
+
+pub async fn list_operators(State(state): State<Arc<ServerState>>, Query(paging): Query<Paging>) -> Result<Json<Page<OperatorInfo>>, Failure> {
+	let operators = blocking(&state, |storage| Ok(storage.list_operators()?)).await?;
+	Ok(Json(paging.page(operators.iter().map(operator_info).collect())?))
+	}
+
+pub async fn show_operator(State(state): State<Arc<ServerState>>, Path(name): Path<String>) -> Result<Json<OperatorInfo>, Failure> {
+	blocking(&state, move |storage| Ok(Json(operator_info(&find_operator(storage, &name)?)))).await
+	}
+
+pub async fn create_operator(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Json(request): Json<NewOperator>) -> Result<(StatusCode, Json<OperatorInfo>), Failure> {
+	let username = request.username.trim().to_string();
+	if !access::valid_username(&username) {
+		return Err(Failure::bad_request(format!("Invalid username: {:?} (letters, digits and . _ - @, up to 64 characters)", username)));
+		}
+	access::check_password(&request.password).map_err(Failure::bad_request)?;
+	let role = parse_role(request.role.as_deref().unwrap_or("viewer"))?;
+	let groups = parse_groups(&request.groups)?;
+
+	let operator = blocking(&state, move |storage| {
+		if storage.find_operator(&username)?.is_some() {
+			return Err(Failure::new(StatusCode::CONFLICT, format!("Operator {} already exists", username)));
+			}
+		let operator = Operator { username, password_hash: hash(&request.password)?, role, groups, created: now(), last_login: None, disabled: false };
+		storage.add_operator(&operator)?;
+		Ok(operator)
+		}).await?;
+	info!(target: SECURITY, "Operator {} created as {} by {}", operator.username, operator.role, principal);
+	audit(&state, Event::Access, &principal, peer_addr, format!("Created operator {} as {}{}", operator.username, operator.role, groups_detail(&operator.groups))).await;
+	Ok((StatusCode::CREATED, Json(operator_info(&operator))))
+	}
+
+pub async fn update_operator(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Path(name): Path<String>, Json(request): Json<OperatorUpdate>) -> Result<Json<OperatorInfo>, Failure> {
+	let role = request.role.as_deref().map(parse_role).transpose()?;
+	let groups = request.groups.as_deref().map(parse_groups).transpose()?;
+	if let Some(password) = &request.password {
+		access::check_password(password).map_err(Failure::bad_request)?;
+		}
+	// An administrator locking themselves out could leave nobody able to manage access
+	if principal.operator.as_deref() == Some(name.as_str()) && (role.is_some_and(|role| role != Role::Administrator) || groups.as_ref().is_some_and(|groups| !groups.is_empty()) || request.disabled == Some(true)) {
+		return Err(Failure::bad_request("You can't limit or disable your own account"));
+		}
+	let changed: Vec<&str> = [("role", request.role.is_some()), ("groups", request.groups.is_some()), ("state", request.disabled.is_some()), ("password", request.password.is_some())]
+		.into_iter().filter(|(_, changed)| *changed).map(|(field, _)| field).collect();
+
+	let operator = blocking(&state, move |storage| {
+		let mut operator = find_operator(storage, &name)?;
+		if let Some(role) = role {
+			operator.role = role;
+			}
+		if let Some(groups) = groups {
+			operator.groups = groups;
+			}
+		if let Some(disabled) = request.disabled {
+			operator.disabled = disabled;
+			}
+		if let Some(password) = &request.password {
+			operator.password_hash = hash(password)?;
+			}
+		storage.update_operator(&operator)?;
+		Ok(operator)
+		}).await?;
+	info!(target: SECURITY, "Operator {} updated ({}) by {}", operator.username, changed.join(", "), principal);
+	let state_detail = if operator.disabled { "disabled" } else { "enabled" };
+	audit(&state, Event::Access, &principal, peer_addr, format!("Updated operator {} ({}): {}, {}{}", operator.username, changed.join(", "), operator.role, state_detail, groups_detail(&operator.groups))).await;
+	Ok(Json(operator_info(&operator)))
+	}
+
+// The operator's API keys are revoked with them
+pub async fn delete_operator(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Path(name): Path<String>) -> Result<StatusCode, Failure> {
+	if principal.operator.as_deref() == Some(name.as_str()) {
+		return Err(Failure::bad_request("You can't delete your own account"));
+		}
+	let deleted = name.clone();
+	blocking(&state, move |storage| match storage.delete_operator(&deleted)? {
+		true => Ok(()),
+		false => Err(Failure::not_found(format!("No operator named {}", deleted)))
+		}).await?;
+	info!(target: SECURITY, "Operator {} deleted by {}", name, principal);
+	audit(&state, Event::Access, &principal, peer_addr, format!("Deleted operator {}", name)).await;
+	Ok(StatusCode::NO_CONTENT)
+	}
+
+// Administrators see every key; everyone else sees their own
+pub async fn list_api_keys(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Query(paging): Query<Paging>) -> Result<Json<Page<ApiKeyInfo>>, Failure> {
+	let keys = blocking(&state, |storage| Ok(storage.list_api_keys()?)).await?;
+	let now = now();
+	Ok(Json(paging.page(keys.iter().filter(|key| manages(&principal, key)).map(|key| api_key_info(key, now)).collect())?))
+	}
+
+// Keys are owned by the operator creating them and can't hold scopes the creator lacks
+pub async fn create_api_key(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Json(request): Json<NewApiKey>) -> Result<(StatusCode, Json<CreatedApiKey>), Failure> {
+	let scopes = match request.scopes.is_empty() {
+		true => principal.scopes.clone(),
+		false => access::parse_scopes(&request.scopes).map_err(Failure::bad_request)?
+		};
+	if let Some(scope) = scopes.iter().find(|scope| !principal.scopes.contains(scope)) {
+		return Err(Failure::forbidden(format!("You can't grant the {} permission", scope)));
+		}
+	let expires = match request.expires.as_deref() {
+		Some("never") | None => None,
+		Some(lifetime) => Some(now().saturating_add(enroll::parse_duration(lifetime).ok_or_else(|| Failure::bad_request(format!("Invalid key lifetime: {}", lifetime)))?))
+		};
+
+	let owner = principal.operator.clone();
+	let (key, secret) = blocking(&state, move |storage| {
+		let (key, secret) = generate_key(&request.description, owner.as_deref(), scopes, expires).map_err(|err| {
+			error!("Could not generate API key: {}", err);
+			Failure::new(StatusCode::INTERNAL_SERVER_ERROR, "Could not generate API key")
+			})?;
+		storage.add_api_key(&key)?;
+		Ok((key, secret))
+		}).await?;
+	info!(target: SECURITY, "API key {} created with scopes {} by {}", key.id, storage::encode_scopes(&key.scopes), principal);
+	audit(&state, Event::Access, &principal, peer_addr, format!("Created API key {} with scopes {}", key.id, storage::encode_scopes(&key.scopes))).await;
+	Ok((StatusCode::CREATED, Json(CreatedApiKey { info: api_key_info(&key, now()), key: secret })))
+	}
+
+pub async fn revoke_api_key(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Path(id): Path<String>) -> Result<StatusCode, Failure> {
+	let revoked = id.clone();
+	let revoker = principal.clone();
+	blocking(&state, move |storage| match storage.find_api_key(&revoked)? {
+		Some(key) if manages(&revoker, &key) => Ok(storage.revoke_api_key(&revoked)?),
+		_ => Err(Failure::not_found(format!("No API key with ID {}", revoked)))
+		}).await?;
+	info!(target: SECURITY, "API key {} revoked by {}", id, principal);
+	audit(&state, Event::Access, &principal, peer_addr, format!("Revoked API key {}", id)).await;
+	Ok(StatusCode::NO_CONTENT)
+	}
+
+// Whether a principal may see and revoke a key
+fn manages(principal: &Principal, key: &ApiKey) -> bool {
+	principal.check(Scope::Admin).is_ok() || key.id == principal.key_id || (key.owner.is_some() && key.owner == principal.operator)
+	}
+
+fn groups_detail(groups: &[String]) -> String {
+	match groups.is_empty() {
+		true => String::new(),
+		false => format!(" in groups {}", groups.join(","))
+		}
+	}
+
+fn find_operator(storage: &dyn storage::Storage, username: &str) -> Result<Operator, Failure> {
+	storage.find_operator(username)?.ok_or_else(|| Failure::not_found(format!("No operator named {}", username)))
+	}
+
+fn parse_role(role: &str) -> Result<Role, Failure> {
+	Role::parse(role).ok_or_else(|| Failure::bad_request(format!("Unknown role: {} (expected viewer, operator or administrator)", role)))
+	}
+
+fn parse_groups(groups: &[String]) -> Result<Vec<String>, Failure> {
+	let mut groups = storage::split_groups(&groups.join(","));
+	if let Some(group) = groups.iter().find(|group| !enroll::valid_group(group)) {
+		return Err(Failure::bad_request(format!("Invalid group name: {}", group)));
+		}
+	groups.sort();
+	groups.dedup();
+	Ok(groups)
+	}
+
+fn hash(password: &str) -> Result<String, Failure> {
+	access::hash_password(password).map_err(|err| {
+		error!("Could not hash password: {}", err);
+		Failure::new(StatusCode::INTERNAL_SERVER_ERROR, "Could not hash password")
+		})
+	}
+
+fn operator_info(operator: &Operator) -> OperatorInfo {
+	OperatorInfo {
+		username: operator.username.clone(),
+		role: operator.role.to_string(),
+		groups: operator.groups.clone(),
+		created: operator.created,
+		last_login: operator.last_login,
+		disabled: operator.disabled
+		}
+	}
+
+fn api_key_info(key: &ApiKey, now: i64) -> ApiKeyInfo {
+	ApiKeyInfo {
+		id: key.id.clone(),
+		description: key.description.clone(),
+		owner: key.owner.clone(),
+		scopes: key.scopes.iter().map(ToString::to_string).collect(),
+		created: key.created,
+		expires: key.expires,
+		last_used: key.last_used,
+		status: key.status(now).to_string(),
+		session: key.session
+		}
+	}
+
+fn principal_info(principal: &Principal) -> PrincipalInfo {
+	PrincipalInfo {
+		key_id: principal.key_id.clone(),
+		operator: principal.operator.clone(),
+		role: principal.role.map(|role| role.to_string()),
+		scopes: principal.scopes.iter().map(ToString::to_string).collect(),
+		groups: principal.groups.clone(),
+		session: principal.session
+		}
+	}
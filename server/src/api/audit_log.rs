@@ -0,0 +1,70 @@
+// Audit log
+//
+// Records are listed newest first for browsing. Exports carry the whole chain oldest first,
+// one JSON record per line, so they can be checked away from the server.
+
+use std::sync::Arc;
+use axum::{Extension, Json};
+use axum::extract::{Query, State};
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use luminum_log::SECURITY;
+use luminum_proto::admin::Page;
+use luminum_proto::audit::{AuditRecord, AuditVerification};
+use tracing::{error, info};
+use crate::access::Principal;
+use crate::audit::{self, Event};
+use crate::listener::ServerState;
+use crate::storage::AuditQuery;
+use super::{Failure, Paging, Peer, blocking};
+
+#[derive(Deserialize)]
+pub struct AuditFilter {
+	event: Option<String>,
+	// Actors containing this text
+	actor: Option<String>,
+	source: Option<String>,
+	// Unix seconds, inclusive
+	since: Option<i64>,
+	until: Option<i64>
+	}
+
+pub async fn list(State(state): State<Arc<ServerState>>, Query(filter): Query<AuditFilter>, Query(paging): Query<Paging>) -> Result<Json<Page<AuditRecord>>, Failure> {
+	if let Some(event) = filter.event.as_deref().filter(|event| Event::parse(event).is_none()) {
+		let events: Vec<&str> = Event::ALL.iter().map(Event::as_str).collect();
+		return Err(Failure::bad_request(format!("Unknown audit event: {} (expected one of {})", event, events.join(", "))));
+		}
+	let query = AuditQuery {
+		event: filter.event,
+		actor: filter.actor.filter(|actor| !actor.is_empty()),
+		source: filter.source.filter(|source| !source.is_empty()),
+		since: filter.since,
+		until: filter.until,
+		limit: paging.limit()?,
+		offset: paging.offset()
+		};
+	let (limit, offset) = (query.limit, query.offset);
+	let (items, total) = blocking(&state, move |storage| Ok(storage.audit_records(&query)?)).await?;
+	Ok(Json(Page { items, total, limit, offset }))
+	}
+
+// The export is itself recorded, so the chain exported ends with it
+pub async fn export(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>) -> Result<impl IntoResponse, Failure> {
+	let (actor, source) = (principal.to_string(), peer_addr.ip().to_string());
+	let (body, count) = blocking(&state, move |storage| {
+		audit::record(storage, Event::Audit, &actor, &source, "Exported the audit log");
+		let mut body = Vec::new();
+		let count = audit::export(storage, &mut body).map_err(|err| {
+			error!("Could not export the audit log: {}", err);
+			Failure::new(StatusCode::INTERNAL_SERVER_ERROR, "Could not export the audit log")
+			})?;
+		Ok((body, count))
+		}).await?;
+	info!(target: SECURITY, "Audit log of {} records exported by {}", count, principal);
+	Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body))
+	}
+
+pub async fn verify(State(state): State<Arc<ServerState>>) -> Result<Json<AuditVerification>, Failure> {
+	blocking(&state, |storage| Ok(Json(audit::verify(storage)?))).await
+	}
@@ -0,0 +1,67 @@
+// Server configuration
+//
+// Reports the running configuration. Secrets are never included, nor are the paths to the
+// keys that hold them; the storage backend is described by type and location.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use axum::Json;
+use axum::extract::State;
+use luminum_log::{Format, Output};
+use luminum_proto::admin::{CertificateConfig, LimitsConfig, LoggingConfig, ModulesConfig, PresenceConfig, ProtectionConfig, ServerConfig};
+use crate::VER;
+use crate::listener::ServerState;
+use super::{Failure, blocking};
+
+pub async fn show(State(state): State<Arc<ServerState>>) -> Result<Json<ServerConfig>, Failure> {
+	let schema_version = blocking(&state, |storage| Ok(storage.schema_version()?)).await?;
+	let settings = state.settings();
+	let addresses = |addresses: &[SocketAddr]| addresses.iter().map(SocketAddr::to_string).collect();
+	let (certificate, logging, tunables) = (&settings.certificate, &settings.logging, &settings.tunables);
+	Ok(Json(ServerConfig {
+		version: VER.to_string(),
+		schema_version,
+		listen: addresses(&settings.listen),
+		admin: addresses(&settings.admin),
+		api: addresses(&settings.api),
+		storage: settings.backend.to_string(),
+		certificate: CertificateConfig {
+			key_type: certificate.key_type.to_string(),
+			hostnames: certificate.hostnames.clone(),
+			addresses: certificate.addresses.iter().map(ToString::to_string).collect(),
+			validity: certificate.validity,
+			rollover: certificate.rollover,
+			expiry_warning: certificate.expiry_warning
+			},
+		logging: LoggingConfig {
+			level: logging.level.clone(),
+			format: String::from(match logging.format { Format::Text => "text", Format::Json => "json" }),
+			output: String::from(match logging.output { Output::Stdout => "stdout", Output::File { .. } => "file", Output::Journald => "journald" })
+			},
+		limits: LimitsConfig {
+			max_frame: tunables.limits.max_frame,
+			max_connections: tunables.limits.max_connections,
+			max_in_flight: tunables.limits.max_in_flight,
+			handshake_timeout: tunables.limits.handshake_timeout.as_secs(),
+			read_timeout: tunables.limits.read_timeout.as_secs(),
+			idle_timeout: tunables.limits.idle_timeout.as_secs(),
+			drain_timeout: tunables.limits.drain_timeout.as_secs()
+			},
+		presence: PresenceConfig {
+			stale_after: tunables.presence.stale_after,
+			offline_after: tunables.presence.offline_after
+			},
+		modules: ModulesConfig { integrity: tunables.modules.integrity },
+		protection: ProtectionConfig {
+			max_connections_per_ip: tunables.protection.max_connections_per_ip,
+			max_handshakes_per_ip: tunables.protection.max_handshakes_per_ip,
+			connections_per_minute: tunables.protection.connections_per_minute,
+			max_registrations_per_ip: tunables.protection.max_registrations_per_ip,
+			registrations_per_minute: tunables.protection.registrations_per_minute,
+			ban_after_failures: tunables.protection.ban_after_failures,
+			failure_window: tunables.protection.failure_window.as_secs(),
+			ban_duration: tunables.protection.ban_duration.as_secs(),
+			sign_ins_per_minute: tunables.protection.sign_ins_per_minute
+			}
+		}))
+	}
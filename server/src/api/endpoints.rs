@@ -0,0 +1,229 @@
+// Endpoints, groups and commands
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use axum::{Extension, Json};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use serde::Deserialize;
+use luminum_log::SECURITY;
+use luminum_proto::CommandKind;
+use luminum_proto::admin::{AttributeChangeInfo, CommandInfo, EndpointDetail, EndpointGroups, EndpointInfo, GroupInfo, Page, PresenceEventInfo};
+use tracing::info;
+use crate::access::{Principal, Scope};
+use crate::audit::{Event, describe_command};
+use crate::enroll;
+use crate::listener::ServerState;
+use crate::push;
+use crate::storage::{self, AttributeChange, Endpoint, Presence, PresenceEvent, QueuedCommand};
+use super::{Failure, Paging, Peer, audit, blocking, find_endpoint, require};
+
+// Longest question, configuration value or action argument accepted in a command
+const MAX_COMMAND_FIELD: usize = 255;
+
+#[derive(Deserialize)]
+pub struct EndpointFilter {
+	// Matched against the UID, hostname and addresses, ignoring case
+	search: Option<String>,
+	presence: Option<String>,
+	group: Option<String>
+	}
+
+pub async fn list(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Query(filter): Query<EndpointFilter>, Query(paging): Query<Paging>) -> Result<Json<Page<EndpointInfo>>, Failure> {
+	let presence = match filter.presence.as_deref() {
+		None => None,
+		Some(value @ ("online" | "stale" | "offline")) => Some(Presence::parse(value)),
+		Some(value) => { return Err(Failure::bad_request(format!("Unknown presence state: {} (expected online, stale or offline)", value))); }
+		};
+	let search = filter.search.map(|search| search.to_lowercase());
+	let endpoints = blocking(&state, |storage| Ok(storage.list_endpoints()?)).await?;
+	let matched = endpoints.into_iter().filter(|endpoint| {
+		principal.sees(endpoint)
+			&& presence.is_none_or(|presence| endpoint.presence == presence)
+			&& filter.group.as_ref().is_none_or(|group| endpoint.groups.contains(group))
+			&& search.as_ref().is_none_or(|search| {
+				[&endpoint.uid, &endpoint.hostname, &endpoint.ipv4, &endpoint.ipv6].iter().any(|value| value.to_lowercase().contains(search))
+				})
+		}).map(endpoint_info).collect();
+	Ok(Json(paging.page(matched)?))
+	}
+
+pub async fn show(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Path(uid): Path<String>) -> Result<Json<EndpointDetail>, Failure> {
+	blocking(&state, move |storage| {
+		let endpoint = find_endpoint(storage, &principal, &uid)?;
+		Ok(Json(EndpointDetail {
+			endpoint: endpoint_info(endpoint),
+			attribute_changes: storage.attribute_history(&uid)?.into_iter().map(attribute_change_info).collect(),
+			presence_events: storage.presence_events(&uid)?.into_iter().map(presence_event_info).collect(),
+			commands: storage.endpoint_commands(&uid)?.into_iter().map(command_info).collect()
+			}))
+		}).await
+	}
+
+pub async fn revoke(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Path(uid): Path<String>) -> Result<StatusCode, Failure> {
+	let (revoked, revoker) = (uid.clone(), principal.clone());
+	blocking(&state, move |storage| {
+		find_endpoint(storage, &revoker, &revoked)?;
+		match storage.revoke_endpoint(&revoked)? {
+			true => Ok(()),
+			false => Err(Failure::not_found(format!("No endpoint with UID {}", revoked)))
+			}
+		}).await?;
+	state.channels.close_endpoint(&uid);
+	info!(target: SECURITY, uid = %uid, "Endpoint revoked by {}", principal);
+	audit(&state, Event::Endpoint, &principal, peer_addr, format!("Revoked endpoint {}", uid)).await;
+	Ok(StatusCode::NO_CONTENT)
+	}
+
+pub async fn delete(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Path(uid): Path<String>) -> Result<StatusCode, Failure> {
+	let (deleted, deleter) = (uid.clone(), principal.clone());
+	blocking(&state, move |storage| {
+		find_endpoint(storage, &deleter, &deleted)?;
+		match storage.delete_endpoint(&deleted)? {
+			true => Ok(()),
+			false => Err(Failure::not_found(format!("No endpoint with UID {}", deleted)))
+			}
+		}).await?;
+	info!(target: SECURITY, uid = %uid, "Endpoint deleted by {}", principal);
+	audit(&state, Event::Endpoint, &principal, peer_addr, format!("Deleted endpoint {}", uid)).await;
+	Ok(StatusCode::NO_CONTENT)
+	}
+
+pub async fn groups(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Path(uid): Path<String>) -> Result<Json<EndpointGroups>, Failure> {
+	blocking(&state, move |storage| Ok(Json(EndpointGroups { groups: find_endpoint(storage, &principal, &uid)?.groups }))).await
+	}
+
+pub async fn set_groups(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Path(uid): Path<String>, Json(request): Json<EndpointGroups>) -> Result<Json<EndpointGroups>, Failure> {
+	let mut groups = storage::split_groups(&request.groups.join(","));
+	if let Some(group) = groups.iter().find(|group| !enroll::valid_group(group)) {
+		return Err(Failure::bad_request(format!("Invalid group name: {}", group)));
+		}
+	if principal.restricted() {
+		if let Some(group) = groups.iter().find(|group| !principal.sees_group(group)) {
+			return Err(Failure::forbidden(format!("You can't add endpoints to group {}", group)));
+			}
+		if groups.is_empty() {
+			return Err(Failure::forbidden("Endpoints must stay in at least one of your groups"));
+			}
+		}
+	let (updated, updater) = (uid.clone(), principal.clone());
+	let groups = blocking(&state, move |storage| {
+		// Groups the principal can't see are left as they are
+		let endpoint = find_endpoint(storage, &updater, &updated)?;
+		groups.extend(endpoint.groups.into_iter().filter(|group| !updater.sees_group(group)));
+		groups.sort();
+		groups.dedup();
+		match storage.set_endpoint_groups(&updated, &groups)? {
+			true => Ok(groups),
+			false => Err(Failure::not_found(format!("No endpoint with UID {}", updated)))
+			}
+		}).await?;
+	info!(target: SECURITY, uid = %uid, "Endpoint groups set to \"{}\" by {}", groups.join(","), principal);
+	audit(&state, Event::Endpoint, &principal, peer_addr, format!("Set groups of endpoint {} to \"{}\"", uid, groups.join(","))).await;
+	Ok(Json(EndpointGroups { groups }))
+	}
+
+pub async fn commands(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Path(uid): Path<String>, Query(paging): Query<Paging>) -> Result<Json<Page<CommandInfo>>, Failure> {
+	let commands = blocking(&state, move |storage| {
+		find_endpoint(storage, &principal, &uid)?;
+		Ok(storage.endpoint_commands(&uid)?)
+		}).await?;
+	Ok(Json(paging.page(commands.into_iter().map(command_info).collect())?))
+	}
+
+// The command is delivered the next time the endpoint is listening for commands
+pub async fn queue_command(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Path(uid): Path<String>, Json(kind): Json<CommandKind>) -> Result<(StatusCode, Json<CommandInfo>), Failure> {
+	require(&principal, command_scope(&kind))?;
+	validate_command(&kind)?;
+	let command = push::new_command(&uid, kind);
+	let (queued, queuer) = (command.clone(), principal.clone());
+	blocking(&state, move |storage| {
+		let endpoint = find_endpoint(storage, &queuer, &queued.uid)?;
+		if endpoint.revoked {
+			return Err(Failure::new(StatusCode::CONFLICT, format!("Endpoint {} has been revoked", endpoint.uid)));
+			}
+		Ok(storage.queue_command(&queued)?)
+		}).await?;
+	info!(target: SECURITY, uid = %uid, "Command {} queued by {}", command.id, principal);
+	audit(&state, Event::command(&command.kind), &principal, peer_addr, format!("Queued {} for endpoint {} as command {}", describe_command(&command.kind), uid, command.id)).await;
+	Ok((StatusCode::CREATED, Json(command_info(command))))
+	}
+
+// Questions only read from an endpoint, while configuration changes and actions alter it
+fn command_scope(kind: &CommandKind) -> Scope {
+	match kind {
+		CommandKind::Question(_) => Scope::Ask,
+		CommandKind::SetConfig { .. } => Scope::Configure,
+		CommandKind::Action { .. } => Scope::Deploy
+		}
+	}
+
+pub fn validate_command(kind: &CommandKind) -> Result<(), Failure> {
+	let fields: Vec<&String> = match kind {
+		CommandKind::Question(question) => vec![question],
+		CommandKind::SetConfig { key, value } => vec![key, value],
+		CommandKind::Action { name, args } => std::iter::once(name).chain(args).collect()
+		};
+	match fields.first() {
+		Some(first) if first.trim().is_empty() => Err(Failure::bad_request("Command is missing its question, key or action name")),
+		_ if fields.iter().any(|field| field.len() > MAX_COMMAND_FIELD || field.chars().any(char::is_control)) => {
+			Err(Failure::bad_request(format!("Command fields are limited to {} printable characters", MAX_COMMAND_FIELD)))
+			},
+		_ => Ok(())
+		}
+	}
+
+pub async fn list_groups(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Query(paging): Query<Paging>) -> Result<Json<Page<GroupInfo>>, Failure> {
+	let endpoints = blocking(&state, |storage| Ok(storage.list_endpoints()?)).await?;
+	let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+	for group in endpoints.into_iter().flat_map(|endpoint| endpoint.groups).filter(|group| principal.sees_group(group)) {
+		*counts.entry(group).or_default() += 1;
+		}
+	Ok(Json(paging.page(counts.into_iter().map(|(name, endpoints)| GroupInfo { name, endpoints }).collect())?))
+	}
+
+// An unknown group has no members rather than being an error, since groups only exist
+// through their endpoints. Nor do groups the principal can't see.
+pub async fn group_endpoints(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Path(name): Path<String>, Query(paging): Query<Paging>) -> Result<Json<Page<EndpointInfo>>, Failure> {
+	let endpoints = blocking(&state, |storage| Ok(storage.list_endpoints()?)).await?;
+	let visible = principal.sees_group(&name);
+	Ok(Json(paging.page(endpoints.into_iter().filter(|endpoint| visible && endpoint.groups.contains(&name)).map(endpoint_info).collect())?))
+	}
+
+pub fn endpoint_info(endpoint: Endpoint) -> EndpointInfo {
+	EndpointInfo {
+		uid: endpoint.uid,
+		hostname: endpoint.hostname,
+		ipv4: endpoint.ipv4,
+		ipv6: endpoint.ipv6,
+		osplat: endpoint.osplat,
+		osver: endpoint.osver,
+		cert_fingerprint: endpoint.cert_fingerprint,
+		token_id: endpoint.token_id,
+		groups: endpoint.groups,
+		revoked: endpoint.revoked,
+		last_seen: endpoint.last_seen,
+		presence: endpoint.presence.to_string()
+		}
+	}
+
+fn attribute_change_info(change: AttributeChange) -> AttributeChangeInfo {
+	AttributeChangeInfo { attribute: change.attribute, old_value: change.old_value, new_value: change.new_value, changed: change.changed }
+	}
+
+fn presence_event_info(event: PresenceEvent) -> PresenceEventInfo {
+	PresenceEventInfo { old: event.old.to_string(), new: event.new.to_string(), at: event.at }
+	}
+
+fn command_info(command: QueuedCommand) -> CommandInfo {
+	CommandInfo {
+		id: command.id,
+		kind: command.kind,
+		state: command.state.to_string(),
+		created: command.created,
+		sent: command.sent,
+		completed: command.completed,
+		output: command.output,
+		question_id: command.question_id
+		}
+	}
@@ -0,0 +1,114 @@
+// Integrity watchlists and file events
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use axum::{Extension, Json};
+use axum::extract::{Path, Query, State};
+use serde::Deserialize;
+use luminum_proto::FileEventKind;
+use luminum_proto::admin::{FileEventInfo, Page, Watchlist};
+use tracing::info;
+use crate::access::Principal;
+use crate::audit::Event;
+use crate::listener::ServerState;
+use crate::storage::{FileEvent, FileEventQuery};
+use super::{Failure, Paging, Peer, audit, blocking, find_endpoint, visible_uids};
+
+const MAX_WATCHLIST: usize = 1024;
+const MAX_PATH: usize = 4096;
+
+pub async fn show_watchlist(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Path(uid): Path<String>) -> Result<Json<Watchlist>, Failure> {
+	blocking(&state, move |storage| {
+		find_endpoint(storage, &principal, &uid)?;
+		Ok(Json(Watchlist { paths: storage.watchlist(&uid)? }))
+		}).await
+	}
+
+// Endpoints pick up a changed watchlist the next time the Integrity Lumy asks for its configuration
+pub async fn set_watchlist(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Path(uid): Path<String>, Json(watchlist): Json<Watchlist>) -> Result<Json<Watchlist>, Failure> {
+	let mut seen = HashSet::new();
+	let mut paths = Vec::new();
+	for path in watchlist.paths {
+		let path = path.trim().to_string();
+		if path.is_empty() || path.len() > MAX_PATH || path.chars().any(char::is_control) {
+			return Err(Failure::bad_request(format!("Invalid watchlist path: {:?}", path)));
+			}
+		if seen.insert(path.clone()) {
+			paths.push(path);
+			}
+		}
+	if paths.len() > MAX_WATCHLIST {
+		return Err(Failure::bad_request(format!("Watchlists are limited to {} paths", MAX_WATCHLIST)));
+		}
+	let (updated, updater) = (uid.clone(), principal.clone());
+	let paths = blocking(&state, move |storage| {
+		find_endpoint(storage, &updater, &updated)?;
+		storage.set_watchlist(&updated, &paths)?;
+		Ok(paths)
+		}).await?;
+	info!(uid = %uid, "Integrity watchlist set to {} paths by {}", paths.len(), principal);
+	audit(&state, Event::Configuration, &principal, peer_addr, format!("Set the Integrity watchlist of endpoint {} to {} paths", uid, paths.len())).await;
+	Ok(Json(Watchlist { paths }))
+	}
+
+#[derive(Deserialize)]
+pub struct EventFilter {
+	uid: Option<String>,
+	// Paths starting with this prefix
+	path: Option<String>,
+	kind: Option<String>,
+	// Unix seconds, inclusive
+	since: Option<i64>,
+	until: Option<i64>
+	}
+
+impl EventFilter {
+	fn query(self, paging: &Paging) -> Result<FileEventQuery, Failure> {
+		let kind = self.kind.as_deref().map(|kind| FileEventKind::parse(kind).ok_or_else(|| Failure::bad_request(format!("Unknown event kind: {} (expected create, modify, remove, access or other)", kind)))).transpose()?;
+		Ok(FileEventQuery {
+			uid: self.uid,
+			uids: None,
+			path: self.path.filter(|path| !path.is_empty()),
+			kind,
+			since: self.since,
+			until: self.until,
+			limit: paging.limit()?,
+			offset: paging.offset()
+			})
+		}
+	}
+
+// File events from every endpoint the principal sees, newest first
+pub async fn events(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Query(filter): Query<EventFilter>, Query(paging): Query<Paging>) -> Result<Json<Page<FileEventInfo>>, Failure> {
+	let mut query = filter.query(&paging)?;
+	query.uids = blocking(&state, move |storage| visible_uids(storage, &principal)).await?.map(|uids| uids.into_iter().collect());
+	page_of_events(&state, query).await
+	}
+
+pub async fn endpoint_events(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Path(uid): Path<String>, Query(filter): Query<EventFilter>, Query(paging): Query<Paging>) -> Result<Json<Page<FileEventInfo>>, Failure> {
+	let mut query = filter.query(&paging)?;
+	let endpoint = uid.clone();
+	blocking(&state, move |storage| find_endpoint(storage, &principal, &endpoint)).await?;
+	query.uid = Some(uid);
+	page_of_events(&state, query).await
+	}
+
+async fn page_of_events(state: &Arc<ServerState>, query: FileEventQuery) -> Result<Json<Page<FileEventInfo>>, Failure> {
+	let (limit, offset) = (query.limit, query.offset);
+	let (events, total) = blocking(state, move |storage| Ok(storage.file_events(&query)?)).await?;
+	Ok(Json(Page { items: events.into_iter().map(file_event_info).collect(), total, limit, offset }))
+	}
+
+fn file_event_info(event: FileEvent) -> FileEventInfo {
+	FileEventInfo {
+		uid: event.uid,
+		path: event.path,
+		kind: event.kind,
+		file_type: event.file_type,
+		permissions: event.permissions,
+		owner: event.owner,
+		size: event.size,
+		at: event.at,
+		received: event.received
+		}
+	}
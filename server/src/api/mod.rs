@@ -0,0 +1,510 @@
+// Admin API
+//
+// Versioned JSON API for luminumctl, the web console and other management tools, served over
+// TLS with the server's identity on the configured API addresses. Every request except the
+// OpenAPI description and signing in carries an API key as "Authorization: Bearer <id>.<secret>".
+// Keys are created on the server host with --create-api-key, by operators through the API, or
+// by signing in; only a SHA-256 hash of the secret is stored, so a lost key has to be revoked
+// and replaced. Each route needs one of the key's scopes, as listed in requirement().
+// When the server stops, connections finish the request in progress and close.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::pin::{Pin, pin};
+use std::sync::Arc;
+use axum::{Extension, Json, Router};
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{Method, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use hyper::server::conn::http1;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use hyper_util::service::TowerToHyperService;
+use openssl::memcmp;
+use openssl::sha::sha256;
+use openssl::ssl::Ssl;
+use serde::Deserialize;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_openssl::SslStream;
+use luminum_log::SECURITY;
+use luminum_proto::admin::{API_PREFIX, ApiError, DEFAULT_PAGE, MAX_PAGE, Page};
+use tracing::{debug, error, info, warn};
+use crate::access::{Principal, Scope};
+use crate::audit::{self, Event};
+use crate::console::{self, CONSOLE_PATH};
+use crate::enroll::{self, now};
+use crate::listener::ServerState;
+use crate::shutdown::Shutdown;
+use crate::storage::{ApiKey, Endpoint, Storage, StorageError};
+
+mod access;
+mod audit_log;
+mod config;
+mod endpoints;
+mod integrity;
+mod questions;
+mod tokens;
+
+const ID_BYTES: usize = 4;
+const SECRET_BYTES: usize = 24;
+// Written by hand alongside the routes below; keep the two in step
+const OPENAPI: &str = include_str!("openapi.json");
+
+// Address of the client making a request
+#[derive(Clone, Copy)]
+struct Peer(SocketAddr);
+
+// What a route needs beyond a valid key
+enum Requirement {
+	Authenticated,
+	Scope(Scope)
+	}
+
+// An error returned to the API client
+pub struct Failure {
+	status: StatusCode,
+	message: String
+	}
+
+impl Failure {
+	fn new(status: StatusCode, message: impl Into<String>) -> Failure {
+		Failure { status, message: message.into() }
+		}
+
+	fn not_found(message: impl Into<String>) -> Failure {
+		Failure::new(StatusCode::NOT_FOUND, message)
+		}
+
+	fn bad_request(message: impl Into<String>) -> Failure {
+		Failure::new(StatusCode::BAD_REQUEST, message)
+		}
+
+	fn forbidden(message: impl Into<String>) -> Failure {
+		Failure::new(StatusCode::FORBIDDEN, message)
+		}
+	}
+
+// Storage errors are logged here and reported to the client without detail
+impl From<StorageError> for Failure {
+	fn from(err: StorageError) -> Failure {
+		error!("Admin API storage error: {}", err);
+		Failure::new(StatusCode::INTERNAL_SERVER_ERROR, "Storage error")
+		}
+	}
+
+impl IntoResponse for Failure {
+	fn into_response(self) -> Response {
+		(self.status, Json(ApiError { error: self.message })).into_response()
+		}
+	}
+
+// Paging parameters accepted by every collection
+#[derive(Deserialize)]
+struct Paging {
+	limit: Option<u32>,
+	offset: Option<u32>
+	}
+
+impl Paging {
+	fn limit(&self) -> Result<u32, Failure> {
+		match self.limit.unwrap_or(DEFAULT_PAGE) {
+			limit @ 1..=MAX_PAGE => Ok(limit),
+			limit => Err(Failure::bad_request(format!("Invalid limit: {} (expected 1 to {})", limit, MAX_PAGE)))
+			}
+		}
+
+	fn offset(&self) -> u32 {
+		self.offset.unwrap_or(0)
+		}
+
+	// Page through a collection that has already been read in full
+	fn page<T>(&self, items: Vec<T>) -> Result<Page<T>, Failure> {
+		let (limit, offset) = (self.limit()?, self.offset());
+		let total = items.len() as u64;
+		let items = items.into_iter().skip(offset as usize).take(limit as usize).collect();
+		Ok(Page { items, total, limit, offset })
+		}
+	}
+
+// Create a new API key acting for an operator, or for nobody in particular. Returns the
+// stored key and the key string to give to its holder.
+pub fn generate_key(description: &str, owner: Option<&str>, scopes: Vec<Scope>, expires: Option<i64>) -> Result<(ApiKey, String), String> {
+	let id = enroll::random_hex(ID_BYTES).map_err(|err| err.to_string())?;
+	let secret = enroll::random_hex(SECRET_BYTES).map_err(|err| err.to_string())?;
+	let key = ApiKey {
+		id: id.clone(),
+		hash: hash_secret(&secret),
+		description: description.to_string(),
+		owner: owner.map(str::to_string),
+		scopes,
+		created: now(),
+		expires,
+		last_used: None,
+		revoked: false,
+		session: false
+		};
+	Ok((key, format!("{}.{}", id, secret)))
+	}
+
+// Check a presented API key and find who it acts for. Returns the principal, or the reason
+// the key was rejected.
+fn verify_key(storage: &dyn Storage, presented: &str) -> Result<Result<Principal, String>, StorageError> {
+	let Some((id, secret)) = presented.split_once('.') else { return Ok(Err(String::from("malformed API key"))); };
+	let Some(key) = storage.find_api_key(id)? else { return Ok(Err(format!("unknown API key {}", id))); };
+	let hash = hash_secret(secret);
+	if hash.len() != key.hash.len() || !memcmp::eq(hash.as_bytes(), key.hash.as_bytes()) {
+		return Ok(Err(format!("invalid secret for API key {}", key.id)));
+		}
+	let now = now();
+	match key.status(now) {
+		"revoked" => { return Ok(Err(format!("API key {} has been revoked", key.id))); },
+		"expired" => { return Ok(Err(format!("API key {} has expired", key.id))); },
+		_ => {}
+		}
+	let owner = match &key.owner {
+		None => None,
+		Some(username) => match storage.find_operator(username)? {
+			Some(operator) if operator.disabled => { return Ok(Err(format!("operator {} owning API key {} is disabled", username, key.id))); },
+			Some(operator) => Some(operator),
+			None => { return Ok(Err(format!("operator {} owning API key {} no longer exists", username, key.id))); }
+			}
+		};
+	storage.touch_api_key(&key.id, now)?;
+	Ok(Ok(Principal::new(&key, owner.as_ref())))
+	}
+
+// API key secrets are random, so a plain hash is enough
+fn hash_secret(secret: &str) -> String {
+	enroll::hex(&sha256(secret.as_bytes()))
+	}
+
+// Run storage calls off the async worker threads
+async fn blocking<T, F>(state: &Arc<ServerState>, call: F) -> Result<T, Failure>
+	where T: Send + 'static, F: FnOnce(&dyn Storage) -> Result<T, Failure> + Send + 'static {
+	let state = state.clone();
+	match tokio::task::spawn_blocking(move || call(state.storage.as_ref())).await {
+		Ok(result) => result,
+		Err(err) => {
+			error!("Admin API handler failed: {}", err);
+			Err(Failure::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal error"))
+			}
+		}
+	}
+
+async fn authenticate(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, mut request: Request, next: Next) -> Result<Response, Failure> {
+	let presented = request.headers().get(header::AUTHORIZATION)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.strip_prefix("Bearer "))
+		.map(|value| value.trim().to_string());
+	let Some(presented) = presented else {
+		warn!(target: SECURITY, peer = %peer_addr, "Rejected admin API request: no API key");
+		return Err(Failure::new(StatusCode::UNAUTHORIZED, "API key required"));
+		};
+	let principal = match blocking(&state, move |storage| Ok(verify_key(storage, &presented)?)).await? {
+		Ok(principal) => principal,
+		Err(reason) => {
+			warn!(target: SECURITY, peer = %peer_addr, "Rejected admin API request: {}", reason);
+			return Err(Failure::new(StatusCode::UNAUTHORIZED, "Invalid API key"));
+			}
+		};
+
+	// Routes are matched under the API prefix
+	let route = request.extensions().get::<MatchedPath>().map(|route| route.as_str().strip_prefix(API_PREFIX).unwrap_or(route.as_str()).to_string());
+	if let Err(reason) = authorize(&principal, request.method(), route.as_deref()) {
+		warn!(target: SECURITY, peer = %peer_addr, "Refused admin API {} {} by {}: {}", request.method(), request.uri().path(), principal, reason);
+		return Err(Failure::forbidden(reason));
+		}
+	debug!(peer = %peer_addr, "Admin API {} {} by {}", request.method(), request.uri().path(), principal);
+	request.extensions_mut().insert(principal);
+	Ok(next.run(request).await)
+	}
+
+// Whether the principal may use a route. Returns the reason when it may not.
+fn authorize(principal: &Principal, method: &Method, route: Option<&str>) -> Result<(), String> {
+	match route.and_then(|route| requirement(method, route)) {
+		Some(Requirement::Authenticated) => Ok(()),
+		Some(Requirement::Scope(scope)) => principal.check(scope),
+		None => Err(String::from("No access rule covers this request"))
+		}
+	}
+
+// What each route needs. Routes missing from here are refused, so none can be added without
+// deciding who may use it.
+fn requirement(method: &Method, route: &str) -> Option<Requirement> {
+	let requirement = match (method.as_str(), route) {
+		("GET", "/endpoints" | "/endpoints/:uid" | "/endpoints/:uid/groups" | "/endpoints/:uid/commands" | "/endpoints/:uid/watchlist"
+			| "/endpoints/:uid/integrity/events" | "/groups" | "/groups/:name/endpoints" | "/questions" | "/questions/:id"
+			| "/questions/:id/results" | "/integrity/events") => Requirement::Scope(Scope::Read),
+		// The kind of command decides what else queueing it needs
+		("POST", "/endpoints/:uid/commands") => Requirement::Scope(Scope::Read),
+		("DELETE", "/endpoints/:uid") | ("POST", "/endpoints/:uid/revoke") | ("PUT", "/endpoints/:uid/groups") => Requirement::Scope(Scope::Endpoints),
+		("PUT", "/endpoints/:uid/watchlist") => Requirement::Scope(Scope::Configure),
+		("POST", "/questions") => Requirement::Scope(Scope::Ask),
+		(_, "/tokens" | "/tokens/:id/revoke" | "/tokens/:id/endpoints") => Requirement::Scope(Scope::Enrollment),
+		(_, "/config" | "/operators" | "/operators/:name" | "/audit" | "/audit/export" | "/audit/verify") => Requirement::Scope(Scope::Admin),
+		// Everyone may see and manage their own account and keys
+		(_, "/me" | "/me/password" | "/logout" | "/api-keys" | "/api-keys/:id/revoke") => Requirement::Authenticated,
+		_ => { return None; }
+		};
+	Some(requirement)
+	}
+
+// Record an action in the audit log, by the principal or whoever else is named
+async fn audit(state: &Arc<ServerState>, event: Event, actor: impl ToString, peer: SocketAddr, detail: String) {
+	let (actor, source) = (actor.to_string(), peer.ip().to_string());
+	let _ = blocking(state, move |storage| {
+		audit::record(storage, event, &actor, &source, &detail);
+		Ok(())
+		}).await;
+	}
+
+// Refuse a request the principal lacks a scope for
+fn require(principal: &Principal, scope: Scope) -> Result<(), Failure> {
+	principal.check(scope).map_err(|reason| {
+		warn!(target: SECURITY, "Refused admin API request by {}: {}", principal, reason);
+		Failure::forbidden(reason)
+		})
+	}
+
+fn router(state: Arc<ServerState>) -> Router {
+	let routes = Router::new()
+		.route("/endpoints", get(endpoints::list))
+		.route("/endpoints/:uid", get(endpoints::show).delete(endpoints::delete))
+		.route("/endpoints/:uid/revoke", post(endpoints::revoke))
+		.route("/endpoints/:uid/groups", get(endpoints::groups).put(endpoints::set_groups))
+		.route("/endpoints/:uid/commands", get(endpoints::commands).post(endpoints::queue_command))
+		.route("/endpoints/:uid/watchlist", get(integrity::show_watchlist).put(integrity::set_watchlist))
+		.route("/endpoints/:uid/integrity/events", get(integrity::endpoint_events))
+		.route("/groups", get(endpoints::list_groups))
+		.route("/groups/:name/endpoints", get(endpoints::group_endpoints))
+		.route("/questions", get(questions::list).post(questions::ask))
+		.route("/questions/:id", get(questions::show))
+		.route("/questions/:id/results", get(questions::results))
+		.route("/integrity/events", get(integrity::events))
+		.route("/tokens", get(tokens::list).post(tokens::create))
+		.route("/tokens/:id/revoke", post(tokens::revoke))
+		.route("/tokens/:id/endpoints", get(tokens::endpoints))
+		.route("/config", get(config::show))
+		.route("/audit", get(audit_log::list))
+		.route("/audit/export", get(audit_log::export))
+		.route("/audit/verify", get(audit_log::verify))
+		.route("/operators", get(access::list_operators).post(access::create_operator))
+		.route("/operators/:name", get(access::show_operator).put(access::update_operator).delete(access::delete_operator))
+		.route("/api-keys", get(access::list_api_keys).post(access::create_api_key))
+		.route("/api-keys/:id/revoke", post(access::revoke_api_key))
+		.route("/me", get(access::me))
+		.route("/me/password", put(access::change_password))
+		.route("/logout", post(access::logout))
+		.route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+		.route("/login", post(access::login))
+		.route("/openapi.json", get(openapi));
+	Router::new().nest(API_PREFIX, routes).merge(console::router()).with_state(state)
+	}
+
+// The API description is public so tools can be generated from it without a key
+async fn openapi() -> impl IntoResponse {
+	([(header::CONTENT_TYPE, "application/json")], OPENAPI)
+	}
+
+// Endpoints outside the principal's groups are reported as missing, so their UIDs aren't confirmed
+fn find_endpoint(storage: &dyn Storage, principal: &Principal, uid: &str) -> Result<Endpoint, Failure> {
+	match storage.find_endpoint(uid)? {
+		Some(endpoint) if principal.sees(&endpoint) => Ok(endpoint),
+		_ => Err(Failure::not_found(format!("No endpoint with UID {}", uid)))
+		}
+	}
+
+// UIDs of the endpoints a principal limited to groups may see; None when it sees every endpoint
+fn visible_uids(storage: &dyn Storage, principal: &Principal) -> Result<Option<HashSet<String>>, Failure> {
+	if !principal.restricted() {
+		return Ok(None);
+		}
+	Ok(Some(storage.list_endpoints()?.into_iter().filter(|endpoint| principal.sees(endpoint)).map(|endpoint| endpoint.uid).collect()))
+	}
+
+// Serve the admin API on the bound API listeners
+// The accept loops join the given tasks, which the server waits for when it stops
+pub async fn serve(listeners: Vec<TcpListener>, state: Arc<ServerState>, shutdown: Shutdown, tasks: &mut JoinSet<()>) {
+	let app = router(state.clone());
+	for listener in listeners {
+		let address = listener.local_addr().map(|address| address.to_string()).unwrap_or_default();
+		info!("Serving the admin API on https://{}{} and the web console on https://{}{}", address, API_PREFIX, address, CONSOLE_PATH);
+		tasks.spawn(accept_connections(listener, app.clone(), state.clone(), shutdown.clone()));
+		}
+	}
+
+async fn accept_connections(listener: TcpListener, app: Router, state: Arc<ServerState>, shutdown: Shutdown) {
+	loop {
+		let accepted = tokio::select! {
+			accepted = listener.accept() => accepted,
+			_ = shutdown.stopped() => { break; }
+			};
+		match accepted {
+			Ok((stream, peer_addr)) => {
+				tokio::spawn(serve_connection(stream, peer_addr, app.clone(), state.clone(), shutdown.clone()));
+				},
+			Err(err) => { warn!("Error accepting admin API connection: {}", err); }
+			}
+		}
+	}
+
+async fn serve_connection(stream: TcpStream, peer_addr: SocketAddr, app: Router, state: Arc<ServerState>, shutdown: Shutdown) {
+	let _activity = shutdown.track();
+	let limits = state.limits();
+	let mut tls_stream = match Ssl::new(state.identity.acceptor(now()).context()).and_then(|ssl| SslStream::new(ssl, stream)) {
+		Ok(tls_stream) => tls_stream,
+		Err(err) => {
+			warn!(peer = %peer_addr, "Error setting up admin API TLS session: {}", err);
+			return;
+			}
+		};
+	match timeout(limits.handshake_timeout, Pin::new(&mut tls_stream).accept()).await {
+		Ok(Ok(())) => {},
+		Ok(Err(err)) => {
+			debug!(peer = %peer_addr, "Error accepting admin API TLS connection: {}", err);
+			return;
+			},
+		Err(_) => {
+			debug!(peer = %peer_addr, "Admin API TLS handshake timed out");
+			return;
+			}
+		}
+
+	let service = TowerToHyperService::new(app.layer(Extension(Peer(peer_addr))));
+	let connection = http1::Builder::new()
+		.timer(TokioTimer::new())
+		.header_read_timeout(limits.read_timeout)
+		.serve_connection(TokioIo::new(tls_stream), service);
+	let mut connection = pin!(connection);
+	let served = tokio::select! {
+		served = connection.as_mut() => served,
+		_ = shutdown.stopped() => {
+			connection.as_mut().graceful_shutdown();
+			connection.await
+			}
+		};
+	if let Err(err) = served {
+		debug!(peer = %peer_addr, "Admin API connection closed: {}", err);
+		}
+	}
+
+#[cfg(test)]
+mod tests {
+	use crate::access::Role;
+	use crate::storage::Operator;
+	use crate::testing::Fixture;
+	use super::*;
+
+	fn principal(role: Role, groups: &[&str]) -> Principal {
+		let (key, _) = generate_key("test", Some("alice"), Scope::ALL.to_vec(), None).unwrap();
+		let operator = Operator { username: String::from("alice"), role, groups: groups.iter().map(|group| group.to_string()).collect(), ..Operator::default() };
+		Principal::new(&key, Some(&operator))
+		}
+
+	fn allowed(principal: &Principal, method: Method, route: &str) -> bool {
+		authorize(principal, &method, Some(route)).is_ok()
+		}
+
+	#[test]
+	fn covers_every_documented_route() {
+		let description: serde_json::Value = serde_json::from_str(OPENAPI).unwrap();
+		for (path, operations) in description["paths"].as_object().unwrap() {
+			// Signing in and the API description don't need a key
+			if path == "/login" || path == "/openapi.json" {
+				continue;
+				}
+			let route = path.replace('{', ":").replace('}', "");
+			for method in operations.as_object().unwrap().keys().filter(|method| *method != "parameters") {
+				let method = Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+				assert!(requirement(&method, &route).is_some(), "{} {}", method, route);
+				}
+			}
+		}
+
+	#[test]
+	fn refuses_routes_without_a_rule() {
+		let administrator = principal(Role::Administrator, &[]);
+		assert!(authorize(&administrator, &Method::GET, None).is_err());
+		assert!(!allowed(&administrator, Method::GET, "/unknown"));
+		assert!(!allowed(&administrator, Method::POST, "/endpoints"));
+		assert!(!allowed(&administrator, Method::DELETE, "/questions/:id"));
+		}
+
+	#[test]
+	fn authorizes_by_role() {
+		let viewer = principal(Role::Viewer, &[]);
+		assert!(allowed(&viewer, Method::GET, "/endpoints"));
+		assert!(allowed(&viewer, Method::GET, "/questions/:id/results"));
+		assert!(!allowed(&viewer, Method::POST, "/questions"));
+		assert!(!allowed(&viewer, Method::PUT, "/endpoints/:uid/watchlist"));
+
+		let operator = principal(Role::Operator, &[]);
+		assert!(allowed(&operator, Method::POST, "/questions"));
+		assert!(allowed(&operator, Method::PUT, "/endpoints/:uid/watchlist"));
+		assert!(!allowed(&operator, Method::POST, "/endpoints/:uid/revoke"));
+		assert!(!allowed(&operator, Method::DELETE, "/endpoints/:uid"));
+		assert!(!allowed(&operator, Method::GET, "/tokens"));
+		assert!(!allowed(&operator, Method::GET, "/audit"));
+
+		let administrator = principal(Role::Administrator, &[]);
+		for (method, route) in [(Method::POST, "/endpoints/:uid/revoke"), (Method::POST, "/tokens"), (Method::GET, "/config"), (Method::DELETE, "/operators/:name"), (Method::GET, "/audit/export")] {
+			assert!(allowed(&administrator, method.clone(), route), "{} {}", method, route);
+			}
+		}
+
+	#[test]
+	fn keeps_restricted_principals_from_enrollment_and_admin_routes() {
+		let administrator = principal(Role::Administrator, &["web"]);
+		assert!(allowed(&administrator, Method::POST, "/endpoints/:uid/revoke"));
+		assert!(allowed(&administrator, Method::PUT, "/endpoints/:uid/groups"));
+		for (method, route) in [(Method::GET, "/tokens"), (Method::POST, "/tokens/:id/revoke"), (Method::GET, "/config"), (Method::POST, "/operators"), (Method::GET, "/audit")] {
+			assert!(!allowed(&administrator, method.clone(), route), "{} {}", method, route);
+			}
+		}
+
+	#[test]
+	fn lets_everyone_manage_their_own_account() {
+		let (key, _) = generate_key("test", None, Vec::new(), None).unwrap();
+		let nobody = Principal::new(&key, None);
+		for (method, route) in [(Method::GET, "/me"), (Method::PUT, "/me/password"), (Method::POST, "/logout"), (Method::GET, "/api-keys"), (Method::POST, "/api-keys/:id/revoke")] {
+			assert!(allowed(&nobody, method.clone(), route), "{} {}", method, route);
+			}
+		assert!(!allowed(&nobody, Method::GET, "/endpoints"));
+		}
+
+	#[test]
+	fn verifies_api_keys() {
+		let fixture = Fixture::new();
+		let storage = fixture.storage();
+		storage.add_operator(&Operator { username: String::from("alice"), role: Role::Viewer, ..Operator::default() }).unwrap();
+		let add = |owner: Option<&str>, change: &dyn Fn(&mut ApiKey)| {
+			let (mut key, presented) = generate_key("test", owner, Scope::ALL.to_vec(), None).unwrap();
+			change(&mut key);
+			storage.add_api_key(&key).unwrap();
+			presented
+			};
+		let verify = |presented: &str| verify_key(storage, presented).unwrap();
+
+		let owned = verify(&add(Some("alice"), &|_| {})).unwrap();
+		assert_eq!(owned.scopes, [Scope::Read]);
+		let unowned = verify(&add(None, &|_| {})).unwrap();
+		assert_eq!(unowned.scopes, Scope::ALL);
+		assert!(storage.find_api_key(&unowned.key_id).unwrap().unwrap().last_used.is_some());
+
+		let presented = add(None, &|_| {});
+		let (id, _) = presented.split_once('.').unwrap();
+		assert!(verify(&format!("{}.wrong", id)).is_err());
+		assert!(verify(id).is_err());
+		assert!(verify("unknown.secret").is_err());
+		assert!(verify(&add(None, &|key| key.revoked = true)).is_err());
+		assert!(verify(&add(None, &|key| key.expires = Some(now() - 1))).is_err());
+		assert!(verify(&add(Some("bob"), &|_| {})).is_err());
+
+		let presented = add(Some("alice"), &|_| {});
+		storage.update_operator(&Operator { username: String::from("alice"), role: Role::Viewer, disabled: true, ..Operator::default() }).unwrap();
+		assert!(verify(&presented).is_err());
+		}
+	}
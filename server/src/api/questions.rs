@@ -0,0 +1,157 @@
+// Questions
+//
+// A question is sent to each targeted endpoint as its own question command. The answers
+// are the results of those commands, collected as the endpoints respond. Principals limited
+// to endpoint groups only see the questions, and answers, of endpoints in those groups.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use axum::{Extension, Json};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use serde::Deserialize;
+use uuid::Uuid;
+use luminum_log::SECURITY;
+use luminum_proto::CommandKind;
+use luminum_proto::admin::{AnswerInfo, NewQuestion, Page, QuestionInfo};
+use tracing::info;
+use crate::access::Principal;
+use crate::audit::Event;
+use crate::enroll::now;
+use crate::listener::ServerState;
+use crate::push;
+use crate::storage::{CommandState, Question, QueuedCommand, Storage};
+use super::endpoints::validate_command;
+use super::{Failure, Paging, Peer, audit, blocking, visible_uids};
+
+pub async fn list(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Query(paging): Query<Paging>) -> Result<Json<Page<QuestionInfo>>, Failure> {
+	blocking(&state, move |storage| {
+		let questions = storage.list_questions()?;
+		// Which questions a limited principal sees depends on their commands, so those are read for every question
+		if let Some(visible) = visible_uids(storage, &principal)? {
+			let mut items = Vec::new();
+			for question in questions {
+				let commands = visible_commands(storage, &question, Some(&visible))?;
+				if !commands.is_empty() {
+					items.push(question_info_from(question, &commands));
+					}
+				}
+			return Ok(Json(paging.page(items)?));
+			}
+		let page = paging.page(questions)?;
+		let items = page.items.into_iter().map(|question| {
+			let commands = storage.question_commands(&question.id)?;
+			Ok(question_info_from(question, &commands))
+			}).collect::<Result<Vec<QuestionInfo>, Failure>>()?;
+		Ok(Json(Page { items, total: page.total, limit: page.limit, offset: page.offset }))
+		}).await
+	}
+
+pub async fn show(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Path(id): Path<String>) -> Result<Json<QuestionInfo>, Failure> {
+	blocking(&state, move |storage| {
+		let (question, commands) = find_question(storage, &principal, &id)?;
+		Ok(Json(question_info_from(question, &commands)))
+		}).await
+	}
+
+pub async fn ask(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Json(request): Json<NewQuestion>) -> Result<(StatusCode, Json<QuestionInfo>), Failure> {
+	let text = request.question.trim().to_string();
+	validate_command(&CommandKind::Question(text.clone()))?;
+	if !request.uids.is_empty() && request.group.is_some() {
+		return Err(Failure::bad_request("Ask either a list of endpoints or a group, not both"));
+		}
+
+	let asker = principal.clone();
+	let (question, commands) = blocking(&state, move |storage| {
+		let endpoints: Vec<_> = storage.list_endpoints()?.into_iter().filter(|endpoint| asker.sees(endpoint)).collect();
+		let (target, uids): (String, Vec<String>) = match (&request.group, request.uids.is_empty()) {
+			(Some(group), _) => {
+				let members = endpoints.iter().filter(|endpoint| !endpoint.revoked && endpoint.groups.contains(group)).map(|endpoint| endpoint.uid.clone()).collect();
+				(format!("group {}", group), members)
+				},
+			(None, true) if asker.restricted() => (format!("all endpoints in {}", asker.groups.join(", ")), endpoints.iter().filter(|endpoint| !endpoint.revoked).map(|endpoint| endpoint.uid.clone()).collect()),
+			(None, true) => (String::from("all endpoints"), endpoints.iter().filter(|endpoint| !endpoint.revoked).map(|endpoint| endpoint.uid.clone()).collect()),
+			(None, false) => {
+				let mut uids = request.uids.clone();
+				uids.sort();
+				uids.dedup();
+				for uid in &uids {
+					match endpoints.iter().find(|endpoint| &endpoint.uid == uid) {
+						None => { return Err(Failure::not_found(format!("No endpoint with UID {}", uid))); },
+						Some(endpoint) if endpoint.revoked => { return Err(Failure::new(StatusCode::CONFLICT, format!("Endpoint {} has been revoked", uid))); },
+						Some(_) => {}
+						}
+					}
+				(format!("{} endpoint{}", uids.len(), if uids.len() == 1 { "" } else { "s" }), uids)
+				}
+			};
+		if uids.is_empty() {
+			return Err(Failure::bad_request(format!("No endpoints to ask in {}", target)));
+			}
+
+		let asked_by = asker.operator.clone().unwrap_or(asker.key_id.clone());
+		let question = Question { id: Uuid::new_v4().to_string(), question: text, target, asked_by, created: now() };
+		let commands: Vec<QueuedCommand> = uids.iter().map(|uid| QueuedCommand {
+			question_id: Some(question.id.clone()),
+			..push::new_command(uid, CommandKind::Question(question.question.clone()))
+			}).collect();
+		storage.add_question(&question, &commands)?;
+		Ok((question, commands))
+		}).await?;
+	info!(target: SECURITY, "Question {} ({}) asked of {} by {}", question.id, question.question, question.target, principal);
+	audit(&state, Event::Question, &principal, peer_addr, format!("Asked question {} ({}) of {}", question.id, question.question, question.target)).await;
+	Ok((StatusCode::CREATED, Json(question_info_from(question, &commands))))
+	}
+
+#[derive(Deserialize)]
+pub struct ResultFilter {
+	// "queued", "sent", "succeeded", "failed" or "cancelled"
+	state: Option<String>
+	}
+
+pub async fn results(State(state): State<Arc<ServerState>>, Extension(principal): Extension<Principal>, Path(id): Path<String>, Query(filter): Query<ResultFilter>, Query(paging): Query<Paging>) -> Result<Json<Page<AnswerInfo>>, Failure> {
+	let wanted = match filter.state.as_deref() {
+		None => None,
+		Some(value @ ("queued" | "sent" | "succeeded" | "failed" | "cancelled")) => Some(CommandState::parse(value)),
+		Some(value) => { return Err(Failure::bad_request(format!("Unknown command state: {} (expected queued, sent, succeeded, failed or cancelled)", value))); }
+		};
+	let (_, commands) = blocking(&state, move |storage| find_question(storage, &principal, &id)).await?;
+	let answers = commands.into_iter().filter(|command| wanted.is_none_or(|wanted| command.state == wanted)).map(|command| AnswerInfo {
+		uid: command.uid,
+		command_id: command.id,
+		state: command.state.to_string(),
+		completed: command.completed,
+		answer: command.output
+		}).collect();
+	Ok(Json(paging.page(answers)?))
+	}
+
+// A question and the commands that asked it of endpoints the principal sees. Questions that
+// reached none of them are reported as missing.
+fn find_question(storage: &dyn Storage, principal: &Principal, id: &str) -> Result<(Question, Vec<QueuedCommand>), Failure> {
+	let missing = || Failure::not_found(format!("No question with ID {}", id));
+	let question = storage.find_question(id)?.ok_or_else(missing)?;
+	let visible = visible_uids(storage, principal)?;
+	let commands = visible_commands(storage, &question, visible.as_ref())?;
+	match commands.is_empty() && visible.is_some() {
+		true => Err(missing()),
+		false => Ok((question, commands))
+		}
+	}
+
+fn visible_commands(storage: &dyn Storage, question: &Question, visible: Option<&HashSet<String>>) -> Result<Vec<QueuedCommand>, Failure> {
+	let commands = storage.question_commands(&question.id)?;
+	Ok(commands.into_iter().filter(|command| visible.is_none_or(|visible| visible.contains(&command.uid))).collect())
+	}
+
+fn question_info_from(question: Question, commands: &[QueuedCommand]) -> QuestionInfo {
+	QuestionInfo {
+		id: question.id,
+		question: question.question,
+		target: question.target,
+		asked_by: question.asked_by,
+		created: question.created,
+		endpoints: commands.len() as u32,
+		answered: commands.iter().filter(|command| matches!(command.state, CommandState::Succeeded | CommandState::Failed)).count() as u32
+		}
+	}
@@ -0,0 +1,84 @@
+// Enrollment tokens
+
+use std::sync::Arc;
+use axum::{Extension, Json};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use luminum_log::SECURITY;
+use luminum_proto::admin::{CreatedToken, EndpointInfo, NewToken, Page, TokenInfo};
+use tracing::{error, info};
+use crate::enroll::{self, now};
+use crate::listener::ServerState;
+use crate::access::Principal;
+use crate::audit::Event;
+use crate::storage::{self, EnrollmentToken};
+use super::endpoints::endpoint_info;
+use super::{Failure, Paging, Peer, audit, blocking};
+
+pub async fn list(State(state): State<Arc<ServerState>>, Query(paging): Query<Paging>) -> Result<Json<Page<TokenInfo>>, Failure> {
+	let tokens = blocking(&state, |storage| Ok(storage.list_tokens()?)).await?;
+	let now = now();
+	Ok(Json(paging.page(tokens.iter().map(|token| token_info(token, now)).collect())?))
+	}
+
+pub async fn create(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Json(request): Json<NewToken>) -> Result<(StatusCode, Json<CreatedToken>), Failure> {
+	let expires = match request.expires.as_deref() {
+		Some("never") => None,
+		Some(lifetime) => Some(enroll::parse_duration(lifetime).ok_or_else(|| Failure::bad_request(format!("Invalid token lifetime: {}", lifetime)))?),
+		None => Some(enroll::DEFAULT_EXPIRY)
+		};
+	if request.max_uses == Some(0) {
+		return Err(Failure::bad_request("Invalid maximum use count: 0"));
+		}
+	let groups = storage::split_groups(&request.groups.join(","));
+	if let Some(group) = groups.iter().find(|group| !enroll::valid_group(group)) {
+		return Err(Failure::bad_request(format!("Invalid group name: {}", group)));
+		}
+
+	let token_state = state.clone();
+	let (token, secret) = blocking(&state, move |storage| {
+		let (token, secret) = enroll::generate(storage, &token_state.master_key, &request.description, groups, expires, request.max_uses).map_err(|err| {
+			error!("Could not generate enrollment token: {}", err);
+			Failure::new(StatusCode::INTERNAL_SERVER_ERROR, "Could not generate enrollment token")
+			})?;
+		storage.add_token(&token)?;
+		Ok((token, secret))
+		}).await?;
+	info!(target: SECURITY, "Enrollment token {} created by {}", token.id, principal);
+	audit(&state, Event::Enrollment, &principal, peer_addr, format!("Created enrollment token {} ({})", token.id, token.description)).await;
+	Ok((StatusCode::CREATED, Json(CreatedToken { info: token_info(&token, now()), token: secret })))
+	}
+
+pub async fn revoke(State(state): State<Arc<ServerState>>, Extension(Peer(peer_addr)): Extension<Peer>, Extension(principal): Extension<Principal>, Path(id): Path<String>) -> Result<StatusCode, Failure> {
+	let revoked = id.clone();
+	blocking(&state, move |storage| match storage.revoke_token(&revoked)? {
+		true => Ok(()),
+		false => Err(Failure::not_found(format!("No enrollment token with ID {}", revoked)))
+		}).await?;
+	info!(target: SECURITY, "Enrollment token {} revoked by {}", id, principal);
+	audit(&state, Event::Enrollment, &principal, peer_addr, format!("Revoked enrollment token {}", id)).await;
+	Ok(StatusCode::NO_CONTENT)
+	}
+
+pub async fn endpoints(State(state): State<Arc<ServerState>>, Path(id): Path<String>, Query(paging): Query<Paging>) -> Result<Json<Page<EndpointInfo>>, Failure> {
+	let endpoints = blocking(&state, move |storage| {
+		if storage.find_token(&id)?.is_none() {
+			return Err(Failure::not_found(format!("No enrollment token with ID {}", id)));
+			}
+		Ok(storage.endpoints_by_token(&id)?)
+		}).await?;
+	Ok(Json(paging.page(endpoints.into_iter().map(endpoint_info).collect())?))
+	}
+
+fn token_info(token: &EnrollmentToken, now: i64) -> TokenInfo {
+	TokenInfo {
+		id: token.id.clone(),
+		description: token.description.clone(),
+		groups: token.groups.clone(),
+		created: token.created,
+		expires: token.expires,
+		max_uses: token.max_uses,
+		uses: token.uses,
+		status: token.status(now).to_string()
+		}
+	}
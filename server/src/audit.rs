@@ -0,0 +1,186 @@
+// Audit Log
+//
+// Administrative and security-relevant actions are recorded in a hash-chained log kept by
+// the storage backend: enrollments, identity mismatches on the data port, operator sign-ins,
+// questions, actions and configuration changes, and changes to endpoints, enrollment and
+// access. Recording never fails the action being recorded; a record that can't be written is
+// reported in the security log instead.
+
+use std::env;
+use std::fmt;
+use std::io::Write;
+use luminum_log::SECURITY;
+use luminum_proto::CommandKind;
+use luminum_proto::audit::{AuditRecord, AuditVerification, ChainVerifier};
+use tracing::error;
+use crate::enroll;
+use crate::storage::{Storage, StorageError};
+
+// Source of actions taken on the server host
+pub const LOCAL: &str = "local";
+// Actor for things the server does by itself, such as reloading its configuration
+pub const SERVER: &str = "server";
+// Records read from storage at a time when walking the chain
+const BATCH: u32 = 1000;
+// Longest actor and source stored; endpoints choose some of what goes in them
+const MAX_ACTOR_LEN: usize = 255;
+const MAX_SOURCE_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+	// An endpoint enrolled
+	Registration,
+	// An enrollment was refused
+	EnrollmentDenied,
+	// A message came from an endpoint whose credentials didn't match its claimed identity
+	IdentityMismatch,
+	SignIn,
+	SignInFailed,
+	SignOut,
+	// A question was asked, of one endpoint or many
+	Question,
+	// A client action was queued
+	Action,
+	// Client configuration or an Integrity watchlist changed
+	Configuration,
+	// An endpoint was revoked, deleted or regrouped
+	Endpoint,
+	// Enrollment tokens or keys changed
+	Enrollment,
+	// Operators, API keys or passwords changed
+	Access,
+	// The server reloaded its configuration
+	Server,
+	// The audit log was exported
+	Audit
+	}
+
+impl Event {
+	pub const ALL: [Event; 14] = [Event::Registration, Event::EnrollmentDenied, Event::IdentityMismatch, Event::SignIn, Event::SignInFailed, Event::SignOut,
+		Event::Question, Event::Action, Event::Configuration, Event::Endpoint, Event::Enrollment, Event::Access, Event::Server, Event::Audit];
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Event::Registration => "registration",
+			Event::EnrollmentDenied => "enrollment-denied",
+			Event::IdentityMismatch => "identity-mismatch",
+			Event::SignIn => "sign-in",
+			Event::SignInFailed => "sign-in-failed",
+			Event::SignOut => "sign-out",
+			Event::Question => "question",
+			Event::Action => "action",
+			Event::Configuration => "configuration",
+			Event::Endpoint => "endpoint",
+			Event::Enrollment => "enrollment",
+			Event::Access => "access",
+			Event::Server => "server",
+			Event::Audit => "audit"
+			}
+		}
+
+	pub fn parse(value: &str) -> Option<Event> {
+		Event::ALL.into_iter().find(|event| event.as_str() == value)
+		}
+
+	// The event for queueing a command
+	pub fn command(kind: &CommandKind) -> Event {
+		match kind {
+			CommandKind::Question(_) => Event::Question,
+			CommandKind::SetConfig { .. } => Event::Configuration,
+			CommandKind::Action { .. } => Event::Action
+			}
+		}
+	}
+
+impl fmt::Display for Event {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.as_str())
+		}
+	}
+
+pub fn record(storage: &dyn Storage, event: Event, actor: &str, source: &str, detail: &str) {
+	let record = AuditRecord {
+		at: enroll::now(),
+		event: event.to_string(),
+		actor: clip(actor, MAX_ACTOR_LEN),
+		source: clip(source, MAX_SOURCE_LEN),
+		detail: detail.to_string(),
+		..AuditRecord::default()
+		};
+	if let Err(err) = storage.append_audit(record) {
+		error!(target: SECURITY, "Unable to write audit record \"{}\" ({} by {} from {}): {}", detail, event, actor, source, err);
+		}
+	}
+
+fn clip(value: &str, max: usize) -> String {
+	match value.char_indices().nth(max) {
+		Some((end, _)) => value[..end].to_string(),
+		None => value.to_string()
+		}
+	}
+
+// Record an action taken with a command on the server host
+pub fn record_local(storage: &dyn Storage, event: Event, detail: &str) {
+	record(storage, event, &host_actor(), LOCAL, detail);
+	}
+
+// Who is running a command on the server host, as far as the environment says
+fn host_actor() -> String {
+	match env::var("SUDO_USER").or_else(|_| env::var("USER")) {
+		Ok(user) if !user.is_empty() => format!("{} on the server host", user),
+		_ => String::from("server host")
+		}
+	}
+
+pub fn describe_command(kind: &CommandKind) -> String {
+	match kind {
+		CommandKind::Question(question) => format!("question {}", question),
+		CommandKind::SetConfig { key, value } => format!("config {}={}", key, value),
+		CommandKind::Action { name, args } => format!("action {} {}", name, args.join(" ")).trim_end().to_string()
+		}
+	}
+
+// Check the whole chain from its first record
+pub fn verify(storage: &dyn Storage) -> Result<AuditVerification, StorageError> {
+	let mut verifier = ChainVerifier::new();
+	let mut broken = None;
+	each_record(storage, |record| {
+		broken = verifier.check(record).err();
+		broken.is_none()
+		})?;
+	Ok(AuditVerification { records: verifier.count(), head: verifier.head().to_string(), broken })
+	}
+
+// Write the whole chain as JSON lines, oldest first, returning the number of records written
+pub fn export<W: Write>(storage: &dyn Storage, output: &mut W) -> Result<u64, String> {
+	let mut count = 0;
+	let mut failure = None;
+	each_record(storage, |record| {
+		let written = serde_json::to_string(record).map_err(|err| err.to_string())
+			.and_then(|line| writeln!(output, "{}", line).map_err(|err| err.to_string()));
+		count += 1;
+		failure = written.err();
+		failure.is_none()
+		}).map_err(|err| err.to_string())?;
+	match failure {
+		Some(err) => Err(err),
+		None => Ok(count)
+		}
+	}
+
+// Visit records in order until the visitor returns false or the chain ends
+fn each_record<F: FnMut(&AuditRecord) -> bool>(storage: &dyn Storage, mut visit: F) -> Result<(), StorageError> {
+	let mut after = 0;
+	loop {
+		let batch = storage.audit_chain(after, BATCH)?;
+		for record in &batch {
+			if !visit(record) {
+				return Ok(());
+				}
+			}
+		match batch.last() {
+			Some(last) => { after = last.seq; },
+			None => { return Ok(()); }
+			}
+		}
+	}
@@ -0,0 +1,96 @@
+// Admin API key administration
+//
+// --create-api-key, --list-api-keys and --revoke-api-key.
+
+use std::process;
+use clap::ArgMatches;
+use crate::audit::{self, Event};
+use crate::storage::{self, Storage};
+use crate::{access, api, enroll, format_timestamp};
+
+// Run an admin API key administration command and exit
+pub fn run(storage: &dyn Storage, matches: &ArgMatches) {
+	let result = if matches.is_present("create-api-key") {
+		let scopes = match matches.value_of("scopes") {
+			Some(scopes) => access::parse_scopes(&scopes.split(',').collect::<Vec<&str>>()),
+			None => Ok(access::Scope::ALL.to_vec())
+			};
+		let scopes = scopes.unwrap_or_else(|err| {
+			println!("Error: {}", err);
+			process::exit(1);
+			});
+		let expires = match matches.value_of("expires") {
+			Some("never") | None => None,
+			Some(lifetime) => match enroll::parse_duration(lifetime) {
+				Some(seconds) => Some(enroll::now().saturating_add(seconds)),
+				None => {
+					println!("Error: Invalid key lifetime: {}", lifetime);
+					process::exit(1);
+					}
+				}
+			};
+		let owner = matches.value_of("owner");
+		if let Some(owner) = owner {
+			match storage.find_operator(owner) {
+				Ok(Some(_)) => {},
+				Ok(None) => {
+					println!("Error: No operator named {}", owner);
+					process::exit(1);
+					},
+				Err(err) => {
+					println!("Error: {}", err);
+					process::exit(1);
+					}
+				}
+			}
+		let (key, secret) = match api::generate_key(matches.value_of("description").unwrap_or(""), owner, scopes, expires) {
+			Ok(generated) => generated,
+			Err(err) => {
+				println!("Error: Could not generate API key: {}", err);
+				process::exit(1);
+				}
+			};
+		storage.add_api_key(&key).map(|_| {
+			audit::record_local(storage, Event::Access, &format!("Created API key {} with scopes {}", key.id, storage::encode_scopes(&key.scopes)));
+			println!("API key {} created.", key.id);
+			println!("Scopes: {}", storage::encode_scopes(&key.scopes));
+			println!("Expires: {}", format_timestamp(key.expires));
+			if let Some(owner) = &key.owner {
+				println!("Acts for operator {}, within their role and groups", owner);
+				}
+			println!("\nKey: {}\n", secret);
+			println!("NOTE: This will be the only time this key will be made available. Please make a note of it!");
+			})
+		}
+	else if matches.is_present("list-api-keys") {
+		storage.list_api_keys().map(|keys| {
+			let now = enroll::now();
+			println!("{:<10} {:<19} {:<19} {:<19} {:<10} {:<16} {:<24} DESCRIPTION", "ID", "CREATED", "EXPIRES", "LAST USED", "STATUS", "OWNER", "SCOPES");
+			for key in keys {
+				println!("{:<10} {:<19} {:<19} {:<19} {:<10} {:<16} {:<24} {}", key.id, format_timestamp(Some(key.created)), format_timestamp(key.expires), format_timestamp(key.last_used),
+					key.status(now), key.owner.as_deref().unwrap_or("-"), storage::encode_scopes(&key.scopes), key.description);
+				}
+			})
+		}
+	else {
+		let id = matches.value_of("revoke-api-key").unwrap_or_default();
+		storage.revoke_api_key(id).map(|found| {
+			if found {
+				audit::record_local(storage, Event::Access, &format!("Revoked API key {}", id));
+				println!("API key {} revoked.", id);
+				}
+			else {
+				println!("Error: No API key with ID {}", id);
+				process::exit(1);
+				}
+			})
+		};
+
+	match result {
+		Ok(_) => process::exit(0),
+		Err(err) => {
+			println!("Error: {}", err);
+			process::exit(1);
+			}
+		}
+	}
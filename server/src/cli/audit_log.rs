@@ -0,0 +1,43 @@
+// Audit log administration
+//
+// --export-audit and --verify-audit.
+
+use std::fs::File;
+use std::process;
+use clap::ArgMatches;
+use crate::audit::{self, Event};
+use crate::storage::Storage;
+
+// Export or check the audit log and exit
+pub fn run(storage: &dyn Storage, matches: &ArgMatches) {
+	if let Some(path) = matches.value_of("export-audit") {
+		// Log messages share standard output, so exports only go to files
+		audit::record_local(storage, Event::Audit, &format!("Exported the audit log to {}", path));
+		match File::create(path).map_err(|err| err.to_string()).and_then(|mut file| audit::export(storage, &mut file)) {
+			Ok(count) => println!("Exported {} audit records to {}.", count, path),
+			Err(err) => {
+				println!("Error: Could not export the audit log: {}", err);
+				process::exit(1);
+				}
+			}
+		process::exit(0);
+		}
+
+	match audit::verify(storage) {
+		Ok(verification) => match verification.broken {
+			None => {
+				println!("Audit log intact: {} records.", verification.records);
+				println!("Head: {}", verification.head);
+				process::exit(0);
+				},
+			Some(broken) => {
+				println!("Error: Audit log is broken at {}. The {} records before it are intact.", broken, verification.records);
+				process::exit(1);
+				}
+			},
+		Err(err) => {
+			println!("Error: {}", err);
+			process::exit(1);
+			}
+		}
+	}
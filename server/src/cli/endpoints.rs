@@ -0,0 +1,128 @@
+// Endpoint administration
+//
+// --revoke-endpoint, --endpoint-history, and --push and --commands for pushed commands.
+
+use std::process;
+use clap::ArgMatches;
+use luminum_proto::CommandKind;
+use crate::audit::{self, Event};
+use crate::storage::Storage;
+use crate::{format_timestamp, push};
+
+// Revoke an endpoint's registration, then exit
+pub fn revoke(storage: &dyn Storage, uid: &str) {
+	match storage.revoke_endpoint(uid) {
+		Ok(true) => {
+			audit::record_local(storage, Event::Endpoint, &format!("Revoked endpoint {}", uid));
+			println!("Endpoint {} revoked.", uid);
+			process::exit(0);
+			},
+		Ok(false) => {
+			println!("Error: No endpoint with UID {}", uid);
+			process::exit(1);
+			},
+		Err(err) => {
+			println!("Error: {}", err);
+			process::exit(1);
+			}
+		}
+	}
+
+// Queue a command for an endpoint or list its commands, then exit
+pub fn commands(storage: &dyn Storage, matches: &ArgMatches) {
+	let uid = matches.value_of("push").or(matches.value_of("commands")).unwrap_or_default();
+	match storage.find_endpoint(uid) {
+		Ok(Some(endpoint)) if endpoint.revoked => {
+			println!("Error: Endpoint {} has been revoked", uid);
+			process::exit(1);
+			},
+		Ok(Some(_)) => {},
+		Ok(None) => {
+			println!("Error: No endpoint with UID {}", uid);
+			process::exit(1);
+			},
+		Err(err) => {
+			println!("Error: {}", err);
+			process::exit(1);
+			}
+		}
+
+	let result = if matches.is_present("push") {
+		let kind = if let Some(question) = matches.value_of("question") {
+			CommandKind::Question(question.to_string())
+			}
+		else if let Some(setting) = matches.value_of("set-config") {
+			match setting.split_once('=') {
+				Some((key, value)) if !key.is_empty() => CommandKind::SetConfig { key: key.to_string(), value: value.to_string() },
+				_ => {
+					println!("Error: --set-config expects KEY=VALUE");
+					process::exit(1);
+					}
+				}
+			}
+		else if let Some(mut action) = matches.values_of("action") {
+			let name = action.next().unwrap_or_default().to_string();
+			CommandKind::Action { name, args: action.map(String::from).collect() }
+			}
+		else {
+			println!("Error: --push requires --question, --set-config or --action");
+			process::exit(1);
+			};
+		let command = push::new_command(uid, kind);
+		storage.queue_command(&command).map(|_| {
+			audit::record_local(storage, Event::command(&command.kind), &format!("Queued {} for endpoint {} as command {}", audit::describe_command(&command.kind), uid, command.id));
+			println!("Command {} queued for endpoint {}.", command.id, uid);
+			println!("It will be delivered the next time the endpoint is listening. Check its result with --commands {}.", uid);
+			})
+		}
+	else {
+		storage.endpoint_commands(uid).map(|commands| {
+			println!("{:<36} {:<19} {:<10} {:<32} OUTPUT", "ID", "CREATED", "STATE", "COMMAND");
+			for command in commands {
+				let description = audit::describe_command(&command.kind);
+				println!("{:<36} {:<19} {:<10} {:<32} {}", command.id, format_timestamp(Some(command.created)), command.state, description, command.output.unwrap_or_default());
+				}
+			})
+		};
+	match result {
+		Ok(_) => process::exit(0),
+		Err(err) => {
+			println!("Error: {}", err);
+			process::exit(1);
+			}
+		}
+	}
+
+// Print an endpoint's attribute changes and presence events, then exit
+pub fn history(storage: &dyn Storage, uid: &str) {
+	let endpoint = match storage.find_endpoint(uid) {
+		Ok(Some(endpoint)) => endpoint,
+		Ok(None) => {
+			println!("Error: No endpoint with UID {}", uid);
+			process::exit(1);
+			},
+		Err(err) => {
+			println!("Error: {}", err);
+			process::exit(1);
+			}
+		};
+	let history = storage.attribute_history(uid).and_then(|changes| storage.presence_events(uid).map(|events| (changes, events)));
+	match history {
+		Ok((changes, events)) => {
+			println!("Endpoint {} ({}): {}, last seen {}\n", endpoint.uid, endpoint.hostname, endpoint.presence, format_timestamp(Some(endpoint.last_seen)));
+			println!("{:<19} {:<10} {:<32} NEW VALUE", "CHANGED", "ATTRIBUTE", "OLD VALUE");
+			for change in changes {
+				println!("{:<19} {:<10} {:<32} {}", format_timestamp(Some(change.changed)), change.attribute, change.old_value, change.new_value);
+				}
+			println!("\n{:<19} {:<10} NEW STATE", "AT", "OLD STATE");
+			for event in events {
+				println!("{:<19} {:<10} {}", format_timestamp(Some(event.at)), event.old, event.new);
+				}
+			process::exit(0);
+			},
+		Err(err) => {
+			println!("Error: {}", err);
+			process::exit(1);
+			}
+		}
+	}
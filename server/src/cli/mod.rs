@@ -0,0 +1,12 @@
+// Command-line administration
+//
+// Administration commands run on the server host against the configured storage, then exit
+// instead of starting the server. Each prints its result to standard output and exits with
+// status 1 on error.
+
+pub mod api_keys;
+pub mod audit_log;
+pub mod endpoints;
+pub mod operators;
+pub mod rotation;
+pub mod tokens;
@@ -0,0 +1,125 @@
+// Operator administration
+//
+// --create-operator, --list-operators, --reset-password, --disable-operator, --enable-operator
+// and --delete-operator.
+
+use std::process;
+use clap::ArgMatches;
+use crate::audit::{self, Event};
+use crate::storage::{self, Storage};
+use crate::{access, enroll, format_timestamp, setup};
+
+// Run an operator administration command and exit
+pub fn run(storage: &dyn Storage, matches: &ArgMatches) {
+	let result = if let Some(username) = matches.value_of("create-operator") {
+		if !access::valid_username(username) {
+			println!("Error: Invalid username: {} (letters, digits and . _ - @, up to 64 characters)", username);
+			process::exit(1);
+			}
+		let role = matches.value_of("role").unwrap_or("viewer");
+		let Some(role) = access::Role::parse(role) else {
+			println!("Error: Unknown role: {} (expected viewer, operator or administrator)", role);
+			process::exit(1);
+			};
+		let groups = storage::split_groups(matches.value_of("groups").unwrap_or(""));
+		if let Some(group) = groups.iter().find(|group| !enroll::valid_group(group)) {
+			println!("Error: Invalid group name: {}", group);
+			process::exit(1);
+			}
+		let operator = storage::Operator {
+			username: username.to_string(),
+			password_hash: new_password_hash(matches),
+			role,
+			groups,
+			created: enroll::now(),
+			last_login: None,
+			disabled: false
+			};
+		storage.add_operator(&operator).map(|_| {
+			audit::record_local(storage, Event::Access, &format!("Created operator {} as {}", operator.username, operator.role));
+			println!("Operator {} created as {}.", operator.username, operator.role);
+			if !operator.groups.is_empty() {
+				println!("Limited to groups: {}", operator.groups.join(", "));
+				}
+			})
+		}
+	else if matches.is_present("list-operators") {
+		storage.list_operators().map(|operators| {
+			println!("{:<24} {:<14} {:<19} {:<19} {:<9} GROUPS", "USERNAME", "ROLE", "CREATED", "LAST LOGIN", "STATUS");
+			for operator in operators {
+				let status = if operator.disabled { "disabled" } else { "active" };
+				println!("{:<24} {:<14} {:<19} {:<19} {:<9} {}", operator.username, operator.role, format_timestamp(Some(operator.created)), format_timestamp(operator.last_login), status, operator.groups.join(","));
+				}
+			})
+		}
+	else if let Some(username) = matches.value_of("delete-operator") {
+		storage.delete_operator(username).map(|found| {
+			if found {
+				audit::record_local(storage, Event::Access, &format!("Deleted operator {}", username));
+				println!("Operator {} deleted and their API keys revoked.", username);
+				}
+			else {
+				println!("Error: No operator named {}", username);
+				process::exit(1);
+				}
+			})
+		}
+	else {
+		let (username, change) = match (matches.value_of("reset-password"), matches.value_of("disable-operator"), matches.value_of("enable-operator")) {
+			(Some(username), _, _) => (username, "password reset"),
+			(_, Some(username), _) => (username, "disabled"),
+			(_, _, Some(username)) => (username, "enabled"),
+			_ => unreachable!()
+			};
+		storage.find_operator(username).and_then(|operator| {
+			let Some(mut operator) = operator else {
+				println!("Error: No operator named {}", username);
+				process::exit(1);
+				};
+			match change {
+				"disabled" => { operator.disabled = true; },
+				"enabled" => { operator.disabled = false; },
+				_ => { operator.password_hash = new_password_hash(matches); }
+				}
+			storage.update_operator(&operator).map(|_| {
+				audit::record_local(storage, Event::Access, &format!("Operator {} {}", username, change));
+				println!("Operator {} {}.", username, change);
+				})
+			})
+		};
+
+	match result {
+		Ok(_) => process::exit(0),
+		Err(err) => {
+			println!("Error: {}", err);
+			process::exit(1);
+			}
+		}
+	}
+
+// Read a new operator password from --password-file, or prompt for it twice, and hash it
+fn new_password_hash(matches: &ArgMatches) -> String {
+	let password = match matches.value_of("password-file") {
+		Some(path) => setup::read_secret(path).unwrap_or_else(|err| {
+			println!("Error: {}", err);
+			process::exit(1);
+			}),
+		None => {
+			let password = rpassword::read_password_from_tty(Some("Enter operator password: ")).expect("Error reading password input");
+			let verify = rpassword::read_password_from_tty(Some("Verify operator password: ")).expect("Error reading verify password input");
+			if password != verify {
+				println!("Error: Passwords do not match");
+				process::exit(1);
+				}
+			password
+			}
+		};
+	if let Err(err) = access::check_password(&password) {
+		println!("Error: {}", err);
+		process::exit(1);
+		}
+	access::hash_password(&password).unwrap_or_else(|err| {
+		println!("Error: Could not hash password: {}", err);
+		process::exit(1);
+		})
+	}
@@ -0,0 +1,139 @@
+// Secret and certificate rotation
+//
+// --rotate-secrets and --renew-cert replace the master key and the server certificate. Both
+// run as root before the server switches users, and refuse to run alongside a running server.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use crate::config::{self, Backend, Paths};
+use crate::secrets::{self, KeySource, MasterKey};
+use crate::tls::{self, KeyType};
+use crate::{enroll, file_exists, format_timestamp, open_storage, setup, write_key_pair};
+
+// Seal the stored secrets with a new master key. The new key is staged next to the key file
+// and only replaces it once everything sealed with the old key has been resealed.
+pub fn rotate_secrets(paths: &Paths, backend: &Backend, serverconfig: &HashMap<String, String>, old_key: Option<&MasterKey>) -> Result<(), String> {
+	// A key from the environment or the credential store takes priority over the key file, so
+	// a new key file would be ignored and nothing sealed with it could be opened
+	if let Some(source @ (KeySource::Environment | KeySource::Credential(_))) = old_key.map(MasterKey::source) {
+		return Err(format!("The master key is loaded from {}, which would still take priority over a new key file. Copy it to {} (owned by root, mode 0600), remove the override and run --rotate-secrets again.", source, paths.master_key));
+		}
+
+	let passphrase = secrets::reveal(old_key, serverconfig, "PKPASS").map_err(|err| err.to_string())?;
+	let dbpass = secrets::reveal(old_key, serverconfig, "DBPASS").map_err(|err| err.to_string())?;
+
+	let new_key = MasterKey::generate(&paths.master_key).map_err(|err| err.to_string())?;
+	let staged = format!("{}.new", paths.master_key);
+	new_key.write(&staged).map_err(|err| err.to_string())?;
+	let interrupted = |err: String| format!("{} (the new master key is in {})", err, staged);
+
+	// Enrollment keys are kept in storage
+	let storage = open_storage(backend, &dbpass);
+	storage.migrate().map_err(|err| interrupted(format!("Unable to apply schema migrations: {}", err)))?;
+	let resealed = match old_key {
+		Some(old_key) => enroll::reseal_keys(storage.as_ref(), old_key, &new_key).map_err(interrupted)?,
+		None if storage.enrollment_keys().map_err(|err| interrupted(err.to_string()))?.is_empty() => 0,
+		None => { return Err(String::from("Enrollment keys exist, but there is no master key to unseal them")); }
+		};
+	println!("Resealed {} enrollment keys.", resealed);
+
+	let sealed = ["PKPASS", "DBPASS"].iter().zip([&passphrase, &dbpass]).map(|(name, value)| {
+		new_key.seal_str(name, value).map(|sealed| (name.to_string(), sealed))
+		}).collect::<Result<Vec<_>, _>>().map_err(|err| interrupted(err.to_string()))?;
+	setup::write_config(paths, &sealed, &[secrets::LEGACY_KEY]).map_err(|err| interrupted(format!("Unable to write configuration database {}: {}", paths.config_db, err)))?;
+	println!("Resealed the private key passphrase and database password.");
+
+	fs::rename(&staged, &paths.master_key).map_err(|err| interrupted(format!("Unable to replace {}: {}", paths.master_key, err)))?;
+	println!("Master key {} replaced.", paths.master_key);
+	Ok(())
+	}
+
+// Issue a new server certificate with the current subject. The certificate and identity it
+// replaces are kept for the rollover, along with the key pair if it's replaced too.
+pub fn renew_certificate(paths: &Paths, passphrase: &str, settings: &config::Settings, new_key: bool) -> Result<(), String> {
+	let policy = &settings.certificate;
+	let current = fs::read(&paths.certificate).map_err(|err| err.to_string()).and_then(|pem| X509::from_pem(&pem).map_err(|err| err.to_string()))
+		.map_err(|err| format!("Unable to read server certificate {}: {}", paths.certificate, err))?;
+	let key_pem = fs::read(&paths.private_key).map_err(|err| format!("Unable to read {}: {}", paths.private_key, err))?;
+	let key = PKey::private_key_from_pem_passphrase(&key_pem, passphrase.as_bytes()).map_err(|_| format!("The private key {} can't be decrypted with the stored passphrase", paths.private_key))?;
+
+	// Renewing again mid-rollover would drop the certificate endpoints may still rely on
+	let renewed = tls::unix_time(current.not_before()).map_err(|err| err.to_string())?;
+	let rollover_ends = renewed + i64::from(policy.rollover) * 86400;
+	if file_exists(&tls::previous(&paths.identity)) && enroll::now() < rollover_ends {
+		return Err(format!("The previous renewal is still rolling out until {}. Renew again after that.", format_timestamp(Some(rollover_ends))));
+		}
+
+	let key = if new_key {
+		tls::generate_key(policy.key_type).map_err(|err| format!("Unable to create server key pair: {}", err))?
+		}
+	else {
+		if KeyType::of(&key) != Some(policy.key_type) {
+			println!("NOTE: The server key is not a {} key. Use --new-key to replace it.", policy.key_type);
+			}
+		key
+		};
+	let listen: Vec<_> = settings.listen.iter().map(SocketAddr::ip).collect();
+	let common_name = current.subject_name().entries_by_nid(Nid::COMMONNAME).next().and_then(|entry| entry.data().to_string().ok()).map(|name| name.to_string()).unwrap_or_default();
+	let names = policy.alt_names(&listen, &common_name);
+	let certificate = tls::server_certificate(&key, current.subject_name(), &names, policy.validity).map_err(|err| format!("Unable to create server certificate: {}", err))?;
+
+	// The new files are written alongside the current ones, which stay in place until every
+	// new file has been written
+	let staged = Paths {
+		private_key: tls::staged(&paths.private_key),
+		public_key: tls::staged(&paths.public_key),
+		certificate: tls::staged(&paths.certificate),
+		identity: tls::staged(&paths.identity),
+		..paths.clone()
+		};
+	let mut replaced = vec![(&paths.certificate, &staged.certificate), (&paths.identity, &staged.identity)];
+	if new_key {
+		replaced.extend([(&paths.private_key, &staged.private_key), (&paths.public_key, &staged.public_key)]);
+		}
+	let written = if new_key { write_key_pair(&staged, &key, passphrase).map_err(|err| format!("Unable to write server key pair: {}", err)) } else { Ok(()) }
+		.and_then(|_| tls::write_identity(&staged, &key, &certificate, passphrase).map_err(|err| format!("Unable to write server certificate: {}", err)))
+		.and_then(|_| {
+			// Keep the files being replaced. Any older copies belong to a rollover that has ended.
+			for (path, _) in &replaced {
+				if file_exists(path) {
+					let previous = tls::previous(path);
+					let _ = fs::remove_file(&previous);
+					fs::hard_link(path, &previous).map_err(|err| format!("Unable to back up {}: {}", path, err))?;
+					}
+				}
+			Ok(())
+			});
+	if let Err(err) = written {
+		for (_, new) in &replaced {
+			let _ = fs::remove_file(new);
+			}
+		return Err(err);
+		}
+	for (path, new) in &replaced {
+		fs::rename(new, path).map_err(|err| format!("Unable to move {} into place: {}", new, err))?;
+		}
+	if new_key {
+		println!("Server key pair replaced with a new {} key.", policy.key_type);
+		}
+
+	let expires = tls::unix_time(certificate.not_after()).ok();
+	println!("Certificate written to {}", paths.certificate);
+	println!("Identity written to {}", paths.identity);
+	println!("Names: {}", names.join(", "));
+	println!("Expires: {}", format_timestamp(expires));
+	println!("\nRestart the server to begin the rollover. Endpoints are sent the new certificate with each heartbeat, and the server presents it from {}.", format_timestamp(Some(enroll::now() + i64::from(policy.rollover) * 86400)));
+	Ok(())
+	}
+
+// The first configured address another process is already listening on
+pub fn server_running(settings: &config::Settings) -> Option<SocketAddr> {
+	settings.listen.iter().chain(&settings.admin).chain(&settings.api).copied().find(|address| {
+		matches!(std::net::TcpListener::bind(address), Err(err) if err.kind() == io::ErrorKind::AddrInUse)
+		})
+	}
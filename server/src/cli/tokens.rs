@@ -0,0 +1,117 @@
+// Enrollment token administration
+//
+// --create-token, --list-tokens, --revoke-token, --token-endpoints and --rotate-enrollment-key.
+
+use std::process;
+use clap::ArgMatches;
+use crate::audit::{self, Event};
+use crate::secrets::MasterKey;
+use crate::storage::{self, Storage};
+use crate::{enroll, format_timestamp};
+
+// Run an enrollment token administration command and exit
+pub fn run(storage: &dyn Storage, master_key: &MasterKey, matches: &ArgMatches) {
+	let result = if matches.is_present("rotate-enrollment-key") {
+		match enroll::rotate_key(storage, master_key) {
+			Ok((version, retired)) => {
+				audit::record_local(storage, Event::Enrollment, &format!("Rotated to enrollment key {}", version));
+				println!("New enrollment tokens will use enrollment key {}.", version);
+				if !retired.is_empty() {
+					let retired: Vec<String> = retired.iter().map(u32::to_string).collect();
+					println!("Removed enrollment keys no longer needed by any usable token: {}", retired.join(", "));
+					}
+				Ok(())
+				},
+			Err(err) => {
+				println!("Error: {}", err);
+				process::exit(1);
+				}
+			}
+		}
+	else if matches.is_present("create-token") {
+		let expires = match matches.value_of("expires") {
+			Some("never") => None,
+			Some(lifetime) => match enroll::parse_duration(lifetime) {
+				Some(seconds) => Some(seconds),
+				None => {
+					println!("Error: Invalid token lifetime: {}", lifetime);
+					process::exit(1);
+					}
+				},
+			None => Some(enroll::DEFAULT_EXPIRY)
+			};
+		let max_uses = matches.value_of("max-uses").map(|count| match count.parse::<u32>() {
+			Ok(count) if count > 0 => count,
+			_ => {
+				println!("Error: Invalid maximum use count: {}", count);
+				process::exit(1);
+				}
+			});
+		let groups = storage::split_groups(matches.value_of("groups").unwrap_or(""));
+		if let Some(group) = groups.iter().find(|group| !enroll::valid_group(group)) {
+			println!("Error: Invalid group name: {}", group);
+			process::exit(1);
+			}
+		let description = matches.value_of("description").unwrap_or("");
+
+		let (token, secret) = match enroll::generate(storage, master_key, description, groups, expires, max_uses) {
+			Ok(generated) => generated,
+			Err(err) => {
+				println!("Error: Could not generate enrollment token: {}", err);
+				process::exit(1);
+				}
+			};
+		storage.add_token(&token).map(|_| {
+			audit::record_local(storage, Event::Enrollment, &format!("Created enrollment token {} ({})", token.id, token.description));
+			println!("Enrollment token {} created.", token.id);
+			println!("Expires: {}", format_timestamp(token.expires));
+			println!("Maximum uses: {}", token.max_uses.map(|count| count.to_string()).unwrap_or(String::from("unlimited")));
+			println!("Groups: {}", token.groups.join(", "));
+			println!("\nToken: {}\n", secret);
+			println!("NOTE: This will be the only time this token will be made available. Please make a note of it!");
+			})
+		}
+	else if matches.is_present("list-tokens") {
+		storage.list_tokens().map(|tokens| {
+			let now = enroll::now();
+			println!("{:<10} {:<19} {:<19} {:>9} {:<10} {:<24} DESCRIPTION", "ID", "CREATED", "EXPIRES", "USES", "STATUS", "GROUPS");
+			for token in tokens {
+				let uses = match token.max_uses {
+					Some(max_uses) => format!("{}/{}", token.uses, max_uses),
+					None => token.uses.to_string()
+					};
+				println!("{:<10} {:<19} {:<19} {:>9} {:<10} {:<24} {}", token.id, format_timestamp(Some(token.created)), format_timestamp(token.expires), uses, token.status(now), token.groups.join(","), token.description);
+				}
+			})
+		}
+	else if let Some(id) = matches.value_of("revoke-token") {
+		storage.revoke_token(id).map(|found| {
+			if found {
+				audit::record_local(storage, Event::Enrollment, &format!("Revoked enrollment token {}", id));
+				println!("Enrollment token {} revoked.", id);
+				}
+			else {
+				println!("Error: No enrollment token with ID {}", id);
+				process::exit(1);
+				}
+			})
+		}
+	else {
+		let id = matches.value_of("token-endpoints").unwrap_or_default();
+		storage.endpoints_by_token(id).map(|endpoints| {
+			println!("{:<36} {:<32} {:<8} IP ADDRESS", "UID", "HOSTNAME", "OS");
+			for endpoint in endpoints {
+				let address = if endpoint.ipv4.is_empty() { endpoint.ipv6 } else { endpoint.ipv4 };
+				println!("{:<36} {:<32} {:<8} {}", endpoint.uid, endpoint.hostname, endpoint.osplat, address);
+				}
+			})
+		};
+
+	match result {
+		Ok(_) => process::exit(0),
+		Err(err) => {
+			println!("Error: {}", err);
+			process::exit(1);
+			}
+		}
+	}
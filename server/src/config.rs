@@ -0,0 +1,676 @@
+// Server Configuration File
+//
+// Tunables live in a TOML file, server.toml by default. Every setting is optional: anything
+// left out falls back to the value --setup wrote to the CONFIG table, then to a built-in
+// default, and command-line flags override both. The file is validated at startup. On SIGHUP
+// it is read again and the settings that are safe to change on a running server (limits,
+// presence thresholds, modules and the log level) are applied. Connected endpoints keep
+// their sessions; the rest need a restart, which is logged.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Deserialize;
+use tokio::signal::unix::{signal, SignalKind};
+use luminum_log::{Format, LogConfig, Output, Rotation};
+use luminum_proto::DEFAULT_MAX_FRAME;
+use tracing::{debug, error, info, warn};
+use crate::audit::{self, Event};
+use crate::guard::{self, Protection};
+use crate::listener::{Limits, ServerState};
+use crate::presence::{self, Thresholds};
+use crate::shutdown::Shutdown;
+use crate::tls::{self, CertificatePolicy};
+use crate::{CFGPATH, DCPATH, DDBPATH, DIPATH, DKPATH, DMKPATH, DPORT, DPPATH, MYSQL_SOCKET, read_serverconfig};
+
+pub const DEFAULT_CONFIG_FILE: &str = "/opt/Luminum/LuminumServer/config/server.toml";
+const DEFAULT_MAX_CONNECTIONS: usize = 4096;
+const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+const DEFAULT_READ_TIMEOUT: u64 = 30;
+const DEFAULT_IDLE_TIMEOUT: u64 = 300;
+const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+const DEFAULT_DRAIN_TIMEOUT: u64 = 30;
+
+#[derive(Debug)]
+pub enum ConfigError {
+	Read(String, io::Error),
+	Parse(String, toml::de::Error),
+	Database(String, rusqlite::Error),
+	Invalid(String)
+	}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ConfigError::Read(path, err) => write!(f, "unable to read configuration file {}: {}", path, err),
+			ConfigError::Parse(path, err) => write!(f, "invalid configuration file {}: {}", path, err),
+			ConfigError::Database(path, err) => write!(f, "unable to read configuration database {}: {}", path, err),
+			ConfigError::Invalid(reason) => write!(f, "{}", reason)
+			}
+		}
+	}
+
+impl Error for ConfigError {}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+	paths: PathsSection,
+	listen: ListenSection,
+	admin: AdminSection,
+	api: ApiSection,
+	storage: StorageSection,
+	limits: LimitsSection,
+	presence: PresenceSection,
+	modules: ModulesSection,
+	protection: ProtectionSection,
+	logging: LoggingSection,
+	certificate: CertificateSection
+	}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PathsSection {
+	config_db: Option<String>,
+	private_key: Option<String>,
+	public_key: Option<String>,
+	certificate: Option<String>,
+	identity: Option<String>,
+	client_ca_key: Option<String>,
+	client_ca_certificate: Option<String>,
+	master_key: Option<String>
+	}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ListenSection {
+	// Socket addresses such as "10.0.0.5:10465" or "[fd00::5]:10465"
+	addresses: Option<Vec<String>>
+	}
+
+// The admin listener is plain HTTP and only runs when addresses are given
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AdminSection {
+	addresses: Option<Vec<String>>
+	}
+
+// The admin API is served over TLS with the server certificate and only runs when addresses are given
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ApiSection {
+	addresses: Option<Vec<String>>
+	}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StorageSection {
+	backend: Option<String>,
+	mysql_socket: Option<String>,
+	sqlite_path: Option<String>
+	}
+
+// Timeouts are in seconds
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+	max_frame: Option<usize>,
+	max_connections: Option<usize>,
+	max_in_flight: Option<usize>,
+	handshake_timeout: Option<u64>,
+	read_timeout: Option<u64>,
+	idle_timeout: Option<u64>,
+	drain_timeout: Option<u64>
+	}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PresenceSection {
+	stale_after: Option<i64>,
+	offline_after: Option<i64>
+	}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ModulesSection {
+	integrity: Option<bool>
+	}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProtectionSection {
+	max_connections_per_ip: Option<usize>,
+	max_handshakes_per_ip: Option<usize>,
+	connections_per_minute: Option<u32>,
+	max_registrations_per_ip: Option<usize>,
+	registrations_per_minute: Option<u32>,
+	ban_after_failures: Option<u32>,
+	failure_window: Option<u64>,
+	ban_duration: Option<u64>,
+	sign_ins_per_minute: Option<u32>
+	}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LoggingSection {
+	// Same as level = "debug"
+	debug: Option<bool>,
+	level: Option<String>,
+	format: Option<String>,
+	output: Option<String>,
+	file: Option<String>,
+	rotation: Option<String>
+	}
+
+// Periods are in days
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CertificateSection {
+	key_type: Option<String>,
+	hostnames: Option<Vec<String>>,
+	addresses: Option<Vec<String>>,
+	validity: Option<u32>,
+	rollover: Option<u32>,
+	expiry_warning: Option<u32>
+	}
+
+// Command-line options, which take precedence over the file
+#[derive(Clone, Default)]
+pub struct Overrides {
+	pub private_key: Option<String>,
+	pub public_key: Option<String>,
+	pub certificate: Option<String>,
+	pub identity: Option<String>,
+	pub address: Option<String>,
+	pub port: Option<String>,
+	pub ephemeral: bool,
+	pub debug: bool
+	}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Paths {
+	pub config_db: String,
+	pub private_key: String,
+	pub public_key: String,
+	pub certificate: String,
+	pub identity: String,
+	pub client_ca_key: String,
+	pub client_ca_certificate: String,
+	pub master_key: String
+	}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Backend {
+	Mysql { socket: String },
+	Sqlite { path: String },
+	Memory
+	}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Modules {
+	pub integrity: bool
+	}
+
+// Settings that can change while the server runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tunables {
+	pub limits: Limits,
+	pub presence: Thresholds,
+	pub modules: Modules,
+	pub protection: Protection
+	}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+	pub paths: Paths,
+	pub listen: Vec<SocketAddr>,
+	pub admin: Vec<SocketAddr>,
+	pub api: Vec<SocketAddr>,
+	pub backend: Backend,
+	pub certificate: CertificatePolicy,
+	pub logging: LogConfig,
+	pub tunables: Tunables
+	}
+
+impl ConfigFile {
+	// Read and parse the configuration file. A missing file is only an error if it was named
+	// explicitly; otherwise every setting takes its default.
+	pub fn load(path: &str, required: bool) -> Result<ConfigFile, ConfigError> {
+		match fs::read_to_string(path) {
+			Ok(contents) => toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.to_string(), err)),
+			Err(err) if err.kind() == io::ErrorKind::NotFound && !required => Ok(ConfigFile::default()),
+			Err(err) => Err(ConfigError::Read(path.to_string(), err))
+			}
+		}
+
+	pub fn paths(&self, overrides: &Overrides) -> Paths {
+		let pick = |flag: &Option<String>, file: &Option<String>, default: &str| {
+			flag.clone().or_else(|| file.clone()).unwrap_or_else(|| default.to_string())
+			};
+		Paths {
+			config_db: pick(&None, &self.paths.config_db, CFGPATH),
+			private_key: pick(&overrides.private_key, &self.paths.private_key, DKPATH),
+			public_key: pick(&overrides.public_key, &self.paths.public_key, DPPATH),
+			certificate: pick(&overrides.certificate, &self.paths.certificate, DCPATH),
+			identity: pick(&overrides.identity, &self.paths.identity, DIPATH),
+			client_ca_key: pick(&None, &self.paths.client_ca_key, tls::CAKPATH),
+			client_ca_certificate: pick(&None, &self.paths.client_ca_certificate, tls::CACPATH),
+			master_key: pick(&None, &self.paths.master_key, DMKPATH)
+			}
+		}
+
+	pub fn mysql_socket(&self) -> String {
+		self.storage.mysql_socket.clone().unwrap_or_else(|| MYSQL_SOCKET.to_string())
+		}
+
+	// Resolve and validate every setting against the CONFIG table
+	pub fn settings(&self, paths: Paths, serverconfig: &HashMap<String, String>, overrides: &Overrides) -> Result<Settings, ConfigError> {
+		Ok(Settings {
+			listen: self.listen(serverconfig, overrides)?,
+			admin: self.admin()?,
+			api: self.api()?,
+			backend: self.backend(serverconfig, overrides)?,
+			certificate: self.certificate()?,
+			logging: self.logging(overrides)?,
+			tunables: self.tunables(serverconfig)?,
+			paths
+			})
+		}
+
+	// Flags replace the configured addresses with a single one
+	fn listen(&self, serverconfig: &HashMap<String, String>, overrides: &Overrides) -> Result<Vec<SocketAddr>, ConfigError> {
+		if let (Some(addresses), None, None) = (&self.listen.addresses, &overrides.address, &overrides.port) {
+			if addresses.is_empty() {
+				return Err(ConfigError::Invalid(String::from("listen.addresses must not be empty")));
+				}
+			return addresses.iter().map(|address| {
+				address.parse::<SocketAddr>().map_err(|_| ConfigError::Invalid(format!("Invalid listen address: {} (expected IP:PORT)", address)))
+				}).collect();
+			}
+
+		let address = overrides.address.clone().or_else(|| serverconfig.get("IPADDR").cloned())
+			.ok_or(ConfigError::Invalid(String::from("No listen address configured. Set listen.addresses or run --setup.")))?;
+		let address = address.parse::<IpAddr>().map_err(|_| ConfigError::Invalid(format!("Invalid IP address: {}", address)))?;
+		let port = overrides.port.clone().or_else(|| serverconfig.get("PORT").cloned()).unwrap_or_else(|| DPORT.to_string());
+		let port = match port.parse::<u16>() {
+			Ok(port) if port > 0 => port,
+			_ => { return Err(ConfigError::Invalid(format!("Invalid port: {}", port))); }
+			};
+		Ok(vec![SocketAddr::new(address, port)])
+		}
+
+	fn admin(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+		self.admin.addresses.iter().flatten().map(|address| {
+			address.parse::<SocketAddr>().map_err(|_| ConfigError::Invalid(format!("Invalid admin address: {} (expected IP:PORT)", address)))
+			}).collect()
+		}
+
+	fn api(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+		self.api.addresses.iter().flatten().map(|address| {
+			address.parse::<SocketAddr>().map_err(|_| ConfigError::Invalid(format!("Invalid API address: {} (expected IP:PORT)", address)))
+			}).collect()
+		}
+
+	fn backend(&self, serverconfig: &HashMap<String, String>, overrides: &Overrides) -> Result<Backend, ConfigError> {
+		let name = self.storage.backend.clone().or_else(|| serverconfig.get("STORAGE").cloned()).unwrap_or_else(|| String::from("mysql"));
+		match name.as_str() {
+			"mysql" => Ok(Backend::Mysql { socket: self.mysql_socket() }),
+			"sqlite" => Ok(Backend::Sqlite { path: self.storage.sqlite_path.clone().or_else(|| serverconfig.get("DBPATH").cloned()).unwrap_or_else(|| DDBPATH.to_string()) }),
+			// Nothing in memory survives a restart, so it has to be asked for on the command line
+			"memory" if overrides.ephemeral => Ok(Backend::Memory),
+			"memory" => Err(ConfigError::Invalid(String::from("The memory storage backend loses all data on restart. Start the server with --ephemeral to use it."))),
+			other => Err(ConfigError::Invalid(format!("Unknown storage backend: {} (expected mysql, sqlite or memory)", other)))
+			}
+		}
+
+	// How server certificates are issued. Setup needs this before the CONFIG table exists.
+	pub fn certificate(&self) -> Result<CertificatePolicy, ConfigError> {
+		let section = &self.certificate;
+		let defaults = CertificatePolicy::default();
+		let key_type = match &section.key_type {
+			Some(key_type) => key_type.parse().map_err(ConfigError::Invalid)?,
+			None => defaults.key_type
+			};
+		let hostnames = section.hostnames.clone().unwrap_or_default();
+		if let Some(hostname) = hostnames.iter().find(|hostname| hostname.is_empty() || hostname.parse::<IpAddr>().is_ok()) {
+			return Err(ConfigError::Invalid(format!("Invalid certificate hostname: \"{}\" (put IP addresses in certificate.addresses)", hostname)));
+			}
+		let addresses = section.addresses.iter().flatten().map(|address| {
+			address.parse::<IpAddr>().map_err(|_| ConfigError::Invalid(format!("Invalid certificate address: {}", address)))
+			}).collect::<Result<Vec<IpAddr>, ConfigError>>()?;
+		let policy = CertificatePolicy {
+			key_type,
+			hostnames,
+			addresses,
+			validity: positive("certificate.validity", section.validity.unwrap_or(defaults.validity))?,
+			rollover: section.rollover.unwrap_or(defaults.rollover),
+			expiry_warning: section.expiry_warning.unwrap_or(defaults.expiry_warning)
+			};
+		if policy.rollover >= policy.validity {
+			return Err(ConfigError::Invalid(String::from("certificate.rollover must be shorter than certificate.validity")));
+			}
+		Ok(policy)
+		}
+
+	// Logging is set up before the CONFIG table is read, so it only comes from the file and flags
+	pub fn logging(&self, overrides: &Overrides) -> Result<LogConfig, ConfigError> {
+		let section = &self.logging;
+		let invalid = |err: luminum_log::LogError| ConfigError::Invalid(err.to_string());
+		let level = if overrides.debug || (section.level.is_none() && section.debug == Some(true)) {
+			String::from("debug")
+			}
+		else {
+			section.level.clone().unwrap_or_else(|| String::from(luminum_log::DEFAULT_LEVEL))
+			};
+		luminum_log::validate_level(&level).map_err(|_| ConfigError::Invalid(format!("Invalid logging.level: {}", level)))?;
+		let rotation = section.rotation.as_deref().map_or(Ok(Rotation::Daily), str::parse).map_err(invalid)?;
+		Ok(LogConfig {
+			level,
+			format: section.format.as_deref().map_or(Ok(Format::Text), str::parse).map_err(invalid)?,
+			output: Output::parse(section.output.as_deref().unwrap_or("stdout"), section.file.as_deref(), rotation).map_err(invalid)?
+			})
+		}
+
+	fn tunables(&self, serverconfig: &HashMap<String, String>) -> Result<Tunables, ConfigError> {
+		let limits = Limits {
+			max_frame: positive("limits.max_frame", setting(self.limits.max_frame, serverconfig, "MAXFRAME", DEFAULT_MAX_FRAME)?)?,
+			max_connections: positive("limits.max_connections", setting(self.limits.max_connections, serverconfig, "MAXCONN", DEFAULT_MAX_CONNECTIONS)?)?,
+			handshake_timeout: Duration::from_secs(positive("limits.handshake_timeout", setting(self.limits.handshake_timeout, serverconfig, "HSTIMEOUT", DEFAULT_HANDSHAKE_TIMEOUT)?)?),
+			read_timeout: Duration::from_secs(positive("limits.read_timeout", setting(self.limits.read_timeout, serverconfig, "READTIMEOUT", DEFAULT_READ_TIMEOUT)?)?),
+			idle_timeout: Duration::from_secs(positive("limits.idle_timeout", setting(self.limits.idle_timeout, serverconfig, "IDLETIMEOUT", DEFAULT_IDLE_TIMEOUT)?)?),
+			max_in_flight: positive("limits.max_in_flight", setting(self.limits.max_in_flight, serverconfig, "MAXINFLIGHT", DEFAULT_MAX_IN_FLIGHT)?)?,
+			drain_timeout: Duration::from_secs(positive("limits.drain_timeout", setting(self.limits.drain_timeout, serverconfig, "DRAINTIMEOUT", DEFAULT_DRAIN_TIMEOUT)?)?)
+			};
+
+		let thresholds = Thresholds {
+			stale_after: setting(self.presence.stale_after, serverconfig, "STALEAFTER", presence::DEFAULT_STALE_AFTER)?,
+			offline_after: setting(self.presence.offline_after, serverconfig, "OFFLINEAFTER", presence::DEFAULT_OFFLINE_AFTER)?
+			};
+		if thresholds.stale_after <= 0 || thresholds.offline_after <= thresholds.stale_after {
+			return Err(ConfigError::Invalid(String::from("Invalid presence thresholds: presence.offline_after must be greater than presence.stale_after, and both must be positive")));
+			}
+
+		let section = &self.protection;
+		let protection = Protection {
+			max_connections_per_ip: positive("protection.max_connections_per_ip", section.max_connections_per_ip.unwrap_or(guard::DEFAULT_MAX_CONNECTIONS_PER_IP))?,
+			max_handshakes_per_ip: positive("protection.max_handshakes_per_ip", section.max_handshakes_per_ip.unwrap_or(guard::DEFAULT_MAX_HANDSHAKES_PER_IP))?,
+			connections_per_minute: positive("protection.connections_per_minute", section.connections_per_minute.unwrap_or(guard::DEFAULT_CONNECTIONS_PER_MINUTE))?,
+			max_registrations_per_ip: positive("protection.max_registrations_per_ip", section.max_registrations_per_ip.unwrap_or(guard::DEFAULT_MAX_REGISTRATIONS_PER_IP))?,
+			registrations_per_minute: positive("protection.registrations_per_minute", section.registrations_per_minute.unwrap_or(guard::DEFAULT_REGISTRATIONS_PER_MINUTE))?,
+			ban_after_failures: positive("protection.ban_after_failures", section.ban_after_failures.unwrap_or(guard::DEFAULT_BAN_AFTER_FAILURES))?,
+			failure_window: Duration::from_secs(positive("protection.failure_window", section.failure_window.unwrap_or(guard::DEFAULT_FAILURE_WINDOW))?),
+			ban_duration: Duration::from_secs(positive("protection.ban_duration", section.ban_duration.unwrap_or(guard::DEFAULT_BAN_DURATION))?),
+			sign_ins_per_minute: positive("protection.sign_ins_per_minute", section.sign_ins_per_minute.unwrap_or(guard::DEFAULT_SIGN_INS_PER_MINUTE))?
+			};
+
+		Ok(Tunables {
+			limits,
+			presence: thresholds,
+			modules: Modules { integrity: self.modules.integrity.unwrap_or(true) },
+			protection
+			})
+		}
+	}
+
+impl Settings {
+	// Settings that differ from the running ones but only take effect after a restart
+	pub fn restart_required(&self, other: &Settings) -> Vec<&'static str> {
+		let mut changed = Vec::new();
+		if self.paths != other.paths { changed.push("paths"); }
+		if self.listen != other.listen { changed.push("listen.addresses"); }
+		if self.admin != other.admin { changed.push("admin.addresses"); }
+		if self.api != other.api { changed.push("api.addresses"); }
+		if self.backend != other.backend { changed.push("storage"); }
+		if self.certificate != other.certificate { changed.push("certificate"); }
+		if self.logging.format != other.logging.format || self.logging.output != other.logging.output { changed.push("logging"); }
+		if self.tunables.limits.max_connections != other.tunables.limits.max_connections { changed.push("limits.max_connections"); }
+		changed
+		}
+	}
+
+impl fmt::Display for Backend {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Backend::Mysql { socket } => write!(f, "mysql ({})", socket),
+			Backend::Sqlite { path } => write!(f, "sqlite ({})", path),
+			Backend::Memory => write!(f, "memory")
+			}
+		}
+	}
+
+// A value from the file, else from the CONFIG table, else the default
+fn setting<T: FromStr>(file: Option<T>, serverconfig: &HashMap<String, String>, key: &str, default: T) -> Result<T, ConfigError> {
+	if let Some(value) = file {
+		return Ok(value);
+		}
+	match serverconfig.get(key) {
+		Some(value) => value.parse::<T>().map_err(|_| ConfigError::Invalid(format!("Invalid {} value in configuration database: {}", key, value))),
+		None => Ok(default)
+		}
+	}
+
+fn positive<T: Default + PartialOrd>(name: &str, value: T) -> Result<T, ConfigError> {
+	if value > T::default() { Ok(value) }
+	else { Err(ConfigError::Invalid(format!("{} must be greater than 0", name))) }
+	}
+
+// Read the configuration file and the CONFIG table and resolve the settings
+pub fn load(path: &str, required: bool, overrides: &Overrides) -> Result<Settings, ConfigError> {
+	let file = ConfigFile::load(path, required)?;
+	let paths = file.paths(overrides);
+	let serverconfig = read_serverconfig(&paths.config_db).map_err(|err| ConfigError::Database(paths.config_db.clone(), err))?;
+	file.settings(paths, &serverconfig, overrides)
+	}
+
+// Log what a reload changed
+fn log_changes(old: &Tunables, new: &Tunables) {
+	if old.limits != new.limits {
+		debug!("Limits for new connections: max_frame {}, max_in_flight {}, timeouts {}s handshake, {}s read, {}s idle, {}s drain",
+			new.limits.max_frame, new.limits.max_in_flight, new.limits.handshake_timeout.as_secs(), new.limits.read_timeout.as_secs(), new.limits.idle_timeout.as_secs(),
+			new.limits.drain_timeout.as_secs());
+		}
+	if old.presence != new.presence {
+		debug!("Endpoints become stale after {} seconds and offline after {} seconds", new.presence.stale_after, new.presence.offline_after);
+		}
+	if old.modules != new.modules {
+		debug!("Integrity module {}", if new.modules.integrity { "enabled" } else { "disabled" });
+		}
+	if old.protection != new.protection {
+		let protection = &new.protection;
+		debug!("Per-source limits: {} connections, {} handshakes, {} connections a minute, {} registrations, {} registrations a minute, {} sign-ins a minute; ban for {}s after {} failures in {}s",
+			protection.max_connections_per_ip, protection.max_handshakes_per_ip, protection.connections_per_minute, protection.max_registrations_per_ip,
+			protection.registrations_per_minute, protection.sign_ins_per_minute, protection.ban_duration.as_secs(), protection.ban_after_failures, protection.failure_window.as_secs());
+		}
+	}
+
+// Apply the settings that can change on a running server. The rest keep their running
+// values, and the ones that changed are reported.
+fn apply(state: &ServerState, current: &mut Settings, settings: Settings) {
+	for name in current.restart_required(&settings) {
+		warn!("Configuration setting {} changed. Restart the server to apply it.", name);
+		}
+	if settings.logging.level != current.logging.level {
+		match luminum_log::set_level(&settings.logging.level) {
+			Ok(()) => {
+				info!("Log level changed to {}", settings.logging.level);
+				current.logging.level = settings.logging.level;
+				},
+			Err(err) => { error!("Unable to change log level: {}", err); }
+			}
+		}
+	let mut tunables = settings.tunables;
+	tunables.limits.max_connections = current.tunables.limits.max_connections;
+	log_changes(&current.tunables, &tunables);
+	state.set_tunables(tunables);
+	current.tunables = tunables;
+	state.set_settings(current.clone());
+	}
+
+// Reload the configuration file on SIGHUP until the server stops. An invalid file is
+// reported and the running configuration is kept.
+pub async fn reload_on_hangup(state: Arc<ServerState>, path: String, required: bool, overrides: Overrides, shutdown: Shutdown, mut current: Settings) {
+	let mut hangup = match signal(SignalKind::hangup()) {
+		Ok(hangup) => hangup,
+		Err(err) => {
+			error!("Unable to install SIGHUP handler: {}", err);
+			return;
+			}
+		};
+	loop {
+		tokio::select! {
+			received = hangup.recv() => if received.is_none() { break; },
+			_ = shutdown.stopped() => { break; }
+			}
+		info!("Received SIGHUP. Reloading configuration from {}...", path);
+		let reload_path = path.clone();
+		let reload_overrides = overrides.clone();
+		let settings = match tokio::task::spawn_blocking(move || load(&reload_path, required, &reload_overrides)).await {
+			Ok(Ok(settings)) => settings,
+			Ok(Err(err)) => {
+				error!("Configuration reload failed, keeping the running configuration: {}", err);
+				continue;
+				},
+			Err(err) => {
+				error!("Configuration reload failed: {}", err);
+				continue;
+				}
+			};
+
+		apply(&state, &mut current, settings);
+		info!("Configuration reloaded.");
+		let (audit_state, detail) = (state.clone(), format!("Reloaded the configuration from {}", path));
+		let _ = tokio::task::spawn_blocking(move || audit::record(audit_state.storage.as_ref(), Event::Server, audit::SERVER, audit::LOCAL, &detail)).await;
+		}
+	}
+
+#[cfg(test)]
+mod tests {
+	use uuid::Uuid;
+	use crate::testing::Fixture;
+	use super::*;
+
+	fn parse(contents: &str) -> Result<ConfigFile, toml::de::Error> {
+		toml::from_str(contents)
+		}
+
+	fn serverconfig(entries: &[(&str, &str)]) -> HashMap<String, String> {
+		entries.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+		}
+
+	fn settings(contents: &str, serverconfig: &HashMap<String, String>) -> Result<Settings, ConfigError> {
+		let file = parse(contents).unwrap();
+		file.settings(file.paths(&Overrides::default()), serverconfig, &Overrides::default())
+		}
+
+	#[test]
+	fn rejects_unknown_settings() {
+		assert!(parse("[limits]\nmax_frame = 1024").is_ok());
+		for contents in ["[limits]\nmax_frames = 1024", "[limit]\nmax_frame = 1024", "listen = true", "[protection]\nban_after = 3", "[limits]\nmax_frame = \"big\""] {
+			assert!(parse(contents).is_err(), "{}", contents);
+			}
+		}
+
+	#[test]
+	fn loads_files_only_when_present_or_required() {
+		let path = std::env::temp_dir().join(format!("luminum-test-{}.toml", Uuid::new_v4())).to_string_lossy().into_owned();
+		assert!(ConfigFile::load(&path, false).is_ok());
+		assert!(matches!(ConfigFile::load(&path, true), Err(ConfigError::Read(_, _))));
+
+		fs::write(&path, "[presence]\nstale_after = 60\noffline_after = 600\n").unwrap();
+		let file = ConfigFile::load(&path, true).unwrap();
+		assert_eq!(file.presence.stale_after, Some(60));
+		fs::write(&path, "[presence]\nstale = 60\n").unwrap();
+		assert!(matches!(ConfigFile::load(&path, false), Err(ConfigError::Parse(_, _))));
+		fs::remove_file(&path).unwrap();
+		}
+
+	#[test]
+	fn prefers_the_file_then_the_config_table_then_the_default() {
+		let table = serverconfig(&[("IPADDR", "192.0.2.1"), ("PORT", "10000"), ("MAXFRAME", "2048"), ("READTIMEOUT", "45"), ("STORAGE", "sqlite"), ("DBPATH", "/var/lib/luminum.db")]);
+
+		let defaults = settings("", &serverconfig(&[("IPADDR", "192.0.2.1")])).unwrap();
+		assert_eq!(defaults.listen, ["192.0.2.1:10465".parse().unwrap()]);
+		assert_eq!(defaults.tunables.limits.max_frame, DEFAULT_MAX_FRAME);
+		assert_eq!(defaults.backend, Backend::Mysql { socket: MYSQL_SOCKET.to_string() });
+
+		let from_table = settings("", &table).unwrap();
+		assert_eq!(from_table.listen, ["192.0.2.1:10000".parse().unwrap()]);
+		assert_eq!(from_table.tunables.limits.max_frame, 2048);
+		assert_eq!(from_table.tunables.limits.read_timeout, Duration::from_secs(45));
+		assert_eq!(from_table.tunables.limits.idle_timeout, Duration::from_secs(DEFAULT_IDLE_TIMEOUT));
+		assert_eq!(from_table.backend, Backend::Sqlite { path: String::from("/var/lib/luminum.db") });
+
+		let from_file = settings("[listen]\naddresses = [\"[fd00::5]:10465\"]\n[limits]\nmax_frame = 4096\n[storage]\nsqlite_path = \"/srv/luminum.db\"", &table).unwrap();
+		assert_eq!(from_file.listen, ["[fd00::5]:10465".parse().unwrap()]);
+		assert_eq!(from_file.tunables.limits.max_frame, 4096);
+		assert_eq!(from_file.tunables.limits.read_timeout, Duration::from_secs(45));
+		assert_eq!(from_file.backend, Backend::Sqlite { path: String::from("/srv/luminum.db") });
+
+		// Flags take precedence over both
+		let file = parse("[listen]\naddresses = [\"192.0.2.9:10465\"]").unwrap();
+		let overrides = Overrides { address: Some(String::from("192.0.2.7")), ..Overrides::default() };
+		assert_eq!(file.settings(file.paths(&overrides), &table, &overrides).unwrap().listen, ["192.0.2.7:10000".parse().unwrap()]);
+		}
+
+	#[test]
+	fn rejects_invalid_values() {
+		let table = serverconfig(&[("IPADDR", "192.0.2.1")]);
+		for contents in ["[limits]\nmax_frame = 0", "[presence]\nstale_after = 600\noffline_after = 60", "[protection]\nban_duration = 0", "[listen]\naddresses = []",
+			"[listen]\naddresses = [\"192.0.2.1\"]", "[storage]\nbackend = \"postgres\"", "[certificate]\nvalidity = 30\nrollover = 30", "[logging]\nlevel = \"luminum=loud\""] {
+			assert!(matches!(settings(contents, &table), Err(ConfigError::Invalid(_))), "{}", contents);
+			}
+		assert!(settings("", &serverconfig(&[("IPADDR", "192.0.2.1"), ("MAXFRAME", "big")])).is_err());
+		assert!(settings("", &HashMap::new()).is_err());
+		}
+
+	#[test]
+	fn reports_settings_that_need_a_restart() {
+		let table = serverconfig(&[("IPADDR", "192.0.2.1")]);
+		let running = settings("", &table).unwrap();
+		let reloaded = settings("[limits]\nmax_frame = 4096\nmax_connections = 10\n[presence]\nstale_after = 60\noffline_after = 120\n[logging]\nlevel = \"debug\"", &table).unwrap();
+		assert_eq!(running.restart_required(&reloaded), ["limits.max_connections"]);
+
+		let reloaded = settings("[listen]\naddresses = [\"192.0.2.1:10000\"]\n[api]\naddresses = [\"127.0.0.1:10467\"]\n[storage]\nbackend = \"sqlite\"\n[logging]\nformat = \"json\"", &table).unwrap();
+		assert_eq!(running.restart_required(&reloaded), ["listen.addresses", "api.addresses", "storage", "logging"]);
+		}
+
+	#[test]
+	fn applies_reloaded_tunables() {
+		let fixture = Fixture::new();
+		let mut current = fixture.state.settings();
+		let reloaded = settings("[limits]\nmax_frame = 4096\nmax_connections = 10\n[presence]\nstale_after = 60\noffline_after = 120\n[modules]\nintegrity = false\n[protection]\nsign_ins_per_minute = 3\n[listen]\naddresses = [\"192.0.2.1:10000\"]", &HashMap::new()).unwrap();
+		apply(&fixture.state, &mut current, reloaded);
+
+		let tunables = fixture.state.tunables();
+		assert_eq!(tunables.limits.max_frame, 4096);
+		assert_eq!(tunables.presence, Thresholds { stale_after: 60, offline_after: 120 });
+		assert!(!tunables.modules.integrity);
+		assert_eq!(tunables.protection.sign_ins_per_minute, 3);
+		// The connection limit and addresses need a restart
+		assert_eq!(tunables.limits.max_connections, DEFAULT_MAX_CONNECTIONS);
+		assert_eq!(fixture.state.settings().listen, ["127.0.0.1:10465".parse().unwrap()]);
+		assert_eq!(fixture.state.settings(), current);
+		}
+
+	#[test]
+	fn memory_backend_needs_ephemeral() {
+		let file: ConfigFile = toml::from_str("[storage]\nbackend = \"memory\"").unwrap();
+		let serverconfig = HashMap::new();
+		assert!(matches!(file.backend(&serverconfig, &Overrides::default()), Err(ConfigError::Invalid(_))));
+
+		let overrides = Overrides { ephemeral: true, ..Overrides::default() };
+		assert!(matches!(file.backend(&serverconfig, &overrides), Ok(Backend::Memory)));
+		}
+
+	#[test]
+	fn memory_backend_from_serverconfig_needs_ephemeral() {
+		let serverconfig = HashMap::from([(String::from("STORAGE"), String::from("memory"))]);
+		assert!(ConfigFile::default().backend(&serverconfig, &Overrides::default()).is_err());
+		}
+	}
@@ -0,0 +1,1005 @@
+// Luminum Console
+//
+// Single-page console built on the management API. Views are chosen by the URL fragment and
+// drawn from API responses; everything that came from an endpoint is inserted as text, never
+// as markup.
+
+'use strict';
+
+const API = '/api/v1';
+const KEY = 'luminum.apiKey';
+const PAGE = 50;
+// How often lists refresh, and how often a question's results are polled while answers are outstanding
+const REFRESH = 30000;
+const POLL = 2000;
+const QUESTIONS = ['hostname', 'osver', 'ipaddress', 'uptime', 'lumys'];
+const SCOPES = ['read', 'ask', 'configure', 'deploy', 'endpoints', 'enrollment', 'admin'];
+// Scopes the server only grants to principals that aren't limited to endpoint groups
+const UNRESTRICTED = ['enrollment', 'admin'];
+
+// Timer of the current view, stopped when the view changes
+let timer = null;
+// Who is signed in, from /me
+let principal = null;
+
+class ApiError extends Error {
+	constructor(status, message) {
+		super(message);
+		this.status = status;
+	}
+}
+
+// Build an element. Attributes starting with "on" are event handlers; children may be
+// strings, elements, arrays of either, or null.
+function el(tag, attributes, ...children) {
+	const element = document.createElement(tag);
+	for (const [name, value] of Object.entries(attributes || {})) {
+		if (value === null || value === undefined || value === false) {
+			continue;
+		}
+		if (name.startsWith('on')) {
+			element.addEventListener(name.slice(2), value);
+		}
+		else if (name in element && typeof value !== 'string') {
+			element[name] = value;
+		}
+		else {
+			element.setAttribute(name, value === true ? '' : value);
+		}
+	}
+	for (const child of children.flat(Infinity)) {
+		if (child !== null && child !== undefined && child !== false) {
+			element.append(child instanceof Node ? child : String(child));
+		}
+	}
+	return element;
+}
+
+async function api(method, path, body) {
+	const key = sessionStorage.getItem(KEY);
+	if (!key) {
+		location.hash = '#/login';
+		throw new ApiError(401, 'Sign in with an API key');
+	}
+	const options = { method, headers: { Authorization: 'Bearer ' + key } };
+	if (body !== undefined) {
+		options.headers['Content-Type'] = 'application/json';
+		options.body = JSON.stringify(body);
+	}
+	const response = await fetch(API + path, options);
+	if (!response.ok) {
+		let message = response.statusText;
+		try {
+			message = (await response.json()).error;
+		}
+		catch (_) {
+			// Not a JSON error body
+		}
+		if (response.status === 401) {
+			sessionStorage.removeItem(KEY);
+			principal = null;
+			location.hash = '#/login';
+		}
+		throw new ApiError(response.status, message);
+	}
+	return response.status === 204 ? null : response.json();
+}
+
+const get = (path, params) => api('GET', path + query(params));
+
+// Whether the signed in principal may use a scope, so the console can leave out what the
+// server would refuse
+function can(scope) {
+	return Boolean(principal && principal.scopes.includes(scope) && !(UNRESTRICTED.includes(scope) && principal.groups.length));
+}
+
+// Query string from the parameters that have a value
+function query(params) {
+	const search = new URLSearchParams();
+	for (const [name, value] of Object.entries(params || {})) {
+		if (value !== null && value !== undefined && value !== '') {
+			search.set(name, value);
+		}
+	}
+	const string = search.toString();
+	return string ? '?' + string : '';
+}
+
+const segment = encodeURIComponent;
+
+function time(seconds) {
+	return seconds ? new Date(seconds * 1000).toLocaleString() : 'never';
+}
+
+function clock(seconds) {
+	return new Date(seconds * 1000).toLocaleTimeString();
+}
+
+function badge(state) {
+	return el('span', { class: 'badge ' + state }, state);
+}
+
+function presence(endpoint) {
+	return badge(endpoint.revoked ? 'revoked' : endpoint.presence);
+}
+
+function address(endpoint) {
+	return endpoint.ipv4 || endpoint.ipv6;
+}
+
+function endpointLink(uid, text) {
+	return el('a', { href: '#/endpoints/' + segment(uid) }, text || uid);
+}
+
+function describeCommand(kind) {
+	switch (kind.type) {
+		case 'question': return 'question ' + kind.args;
+		case 'config': return 'config ' + kind.args.key + '=' + kind.args.value;
+		default: return ('action ' + kind.args.name + ' ' + kind.args.args.join(' ')).trim();
+	}
+}
+
+function table(headings, rows, empty) {
+	if (!rows.length) {
+		return el('div', { class: 'empty' }, empty || 'Nothing to show.');
+	}
+	return el('table', {},
+		el('thead', {}, el('tr', {}, headings.map(heading => el('th', {}, heading)))),
+		el('tbody', {}, rows.map(cells => el('tr', {}, cells.map(cell => cell instanceof Node && cell.tagName === 'TD' ? cell : el('td', {}, cell))))));
+}
+
+function mono(text) {
+	return el('td', { class: 'mono' }, text);
+}
+
+// Previous and next buttons for a page of a collection
+function pager(page, go) {
+	if (page.total <= page.items.length && page.offset === 0) {
+		return null;
+	}
+	const first = page.total ? page.offset + 1 : 0;
+	const last = page.offset + page.items.length;
+	return el('div', { class: 'pager' },
+		`${first}–${last} of ${page.total}`,
+		el('button', { type: 'button', class: 'secondary', disabled: page.offset === 0, onclick: () => go(Math.max(0, page.offset - page.limit)) }, 'Previous'),
+		el('button', { type: 'button', class: 'secondary', disabled: last >= page.total, onclick: () => go(page.offset + page.limit) }, 'Next'));
+}
+
+function failure(err) {
+	return el('div', { class: 'error' }, err.message || String(err));
+}
+
+// Replace the contents of a container, showing any error in place of them
+async function fill(container, build) {
+	try {
+		container.replaceChildren(...[await build()].flat().filter(Boolean));
+	}
+	catch (err) {
+		container.replaceChildren(failure(err));
+	}
+}
+
+// Run an action from a form or button, reporting failure in the given container
+async function act(status, action) {
+	status.replaceChildren();
+	try {
+		await action();
+	}
+	catch (err) {
+		status.replaceChildren(failure(err));
+	}
+}
+
+function confirmed(message) {
+	return window.confirm(message);
+}
+
+// Names from a comma-separated list
+function names(text) {
+	return text.split(',').map(name => name.trim()).filter(Boolean);
+}
+
+function repeat(interval, task) {
+	timer = setInterval(task, interval);
+	return timer;
+}
+
+// Sign in
+
+async function signIn(key) {
+	sessionStorage.setItem(KEY, key);
+	principal = await get('/me');
+	location.hash = '#/endpoints';
+}
+
+function login(view) {
+	const username = el('input', { type: 'text', name: 'username', autocomplete: 'username', required: true });
+	const password = el('input', { type: 'password', name: 'password', autocomplete: 'current-password', required: true });
+	const key = el('input', { type: 'password', name: 'key', placeholder: 'id.secret', autocomplete: 'off', required: true });
+	const status = el('div');
+	view.append(el('section', { class: 'login' },
+		el('h1', {}, 'Sign in'),
+		status,
+		el('form', {
+			onsubmit: event => {
+				event.preventDefault();
+				act(status, async () => {
+					const response = await fetch(API + '/login', {
+						method: 'POST',
+						headers: { 'Content-Type': 'application/json' },
+						body: JSON.stringify({ username: username.value.trim(), password: password.value })
+					});
+					password.value = '';
+					if (!response.ok) {
+						const messages = { 401: 'Wrong username or password', 429: 'Too many sign-in attempts. Try again later.' };
+						throw new ApiError(response.status, messages[response.status] || response.statusText);
+					}
+					await signIn((await response.json()).key);
+				});
+			}
+		},
+			el('label', {}, 'Username', username),
+			el('label', {}, 'Password', password),
+			el('button', { type: 'submit' }, 'Sign in')),
+		el('details', {},
+			el('summary', {}, 'Use an API key instead'),
+			el('p', {}, 'Enter an API key created on the server with --create-api-key or from the Access page. It is kept for this browser session only.'),
+			el('form', {
+				onsubmit: event => {
+					event.preventDefault();
+					act(status, () => signIn(key.value.trim()));
+				}
+			}, el('label', {}, 'API key', key), el('button', { type: 'submit' }, 'Sign in')))));
+	username.focus();
+}
+
+// Endpoint inventory
+
+function endpoints(view) {
+	const filters = { search: '', presence: '', group: '', offset: 0 };
+	const search = el('input', { type: 'search', placeholder: 'UID, hostname or address' });
+	const state = el('select', {}, el('option', { value: '' }, 'Any'), ['online', 'stale', 'offline'].map(value => el('option', { value }, value)));
+	const group = el('select', {}, el('option', { value: '' }, 'Any'));
+	const results = el('div');
+
+	const load = () => fill(results, async () => {
+		const page = await get('/endpoints', { search: filters.search, presence: filters.presence, group: filters.group, limit: PAGE, offset: filters.offset });
+		return [
+			table(['UID', 'Hostname', 'OS', 'State', 'Last seen', 'Groups', 'Address'], page.items.map(endpoint => [
+				el('td', { class: 'mono' }, endpointLink(endpoint.uid)),
+				endpoint.hostname,
+				endpoint.osplat + ' ' + endpoint.osver,
+				presence(endpoint),
+				time(endpoint.last_seen),
+				endpoint.groups.join(', '),
+				address(endpoint)
+			]), 'No endpoints match.'),
+			pager(page, offset => { filters.offset = offset; load(); })
+		];
+	});
+	const apply = event => {
+		event.preventDefault();
+		Object.assign(filters, { search: search.value.trim(), presence: state.value, group: group.value, offset: 0 });
+		load();
+	};
+
+	view.append(el('h1', {}, 'Endpoints'), el('section', {},
+		el('form', { class: 'filters', onsubmit: apply, onchange: apply },
+			el('label', {}, 'Search', search),
+			el('label', {}, 'State', state),
+			el('label', {}, 'Group', group),
+			el('button', { type: 'submit' }, 'Filter')),
+		results));
+	get('/groups', { limit: 1000 }).then(page => group.append(...page.items.map(item => el('option', { value: item.name }, `${item.name} (${item.endpoints})`)))).catch(() => {});
+	load();
+	repeat(REFRESH, load);
+}
+
+// Endpoint detail
+
+function endpoint(view, uid) {
+	const path = '/endpoints/' + segment(uid);
+	const heading = el('h1', {}, uid);
+	const summary = el('section');
+	const groups = el('section');
+	const watchlist = el('section');
+	const commands = el('section');
+	const events = el('section');
+	const history = el('section');
+	view.append(heading, el('div', { class: 'columns' }, summary, el('div', {}, groups, watchlist)), commands, events, history);
+
+	const load = () => fill(summary, async () => {
+		const detail = await get(path);
+		const endpoint = detail.endpoint;
+		heading.replaceChildren(endpoint.hostname || uid, ' ', presence(endpoint));
+		const status = el('div');
+		showCommands(detail.commands);
+		showHistory(detail);
+		return [
+			el('h2', {}, 'Endpoint'),
+			status,
+			el('dl', {},
+				el('dt', {}, 'UID'), el('dd', { class: 'mono' }, endpoint.uid),
+				el('dt', {}, 'Hostname'), el('dd', {}, endpoint.hostname),
+				el('dt', {}, 'OS'), el('dd', {}, endpoint.osplat + ' ' + endpoint.osver),
+				el('dt', {}, 'IPv4 address'), el('dd', {}, endpoint.ipv4),
+				el('dt', {}, 'IPv6 address'), el('dd', {}, endpoint.ipv6),
+				el('dt', {}, 'Last seen'), el('dd', {}, time(endpoint.last_seen)),
+				el('dt', {}, 'Enrolled with'), el('dd', { class: 'mono' }, endpoint.token_id || 'none'),
+				el('dt', {}, 'Certificate'), el('dd', { class: 'mono' }, endpoint.cert_fingerprint || 'none')),
+			can('endpoints') && el('div', { class: 'toolbar' },
+				el('button', {
+					type: 'button', class: 'danger', disabled: endpoint.revoked,
+					onclick: () => confirmed(`Revoke endpoint ${uid}? It will no longer be able to talk to the server.`)
+						&& act(status, async () => { await api('POST', path + '/revoke'); load(); })
+				}, endpoint.revoked ? 'Revoked' : 'Revoke'),
+				el('button', {
+					type: 'button', class: 'danger',
+					onclick: () => confirmed(`Delete endpoint ${uid} and everything stored about it?`)
+						&& act(status, async () => { await api('DELETE', path); location.hash = '#/endpoints'; })
+				}, 'Delete'))
+		];
+	});
+
+	fill(groups, async () => {
+		const current = await get(path + '/groups');
+		if (!can('endpoints')) {
+			return [el('h2', {}, 'Groups'), el('p', {}, current.groups.join(', ') || 'None')];
+		}
+		const input = el('input', { type: 'text', value: current.groups.join(', '), placeholder: 'web, production' });
+		const status = el('div');
+		return [
+			el('h2', {}, 'Groups'),
+			status,
+			el('form', {
+				class: 'toolbar',
+				onsubmit: event => {
+					event.preventDefault();
+					act(status, async () => {
+						const saved = await api('PUT', path + '/groups', { groups: names(input.value) });
+						input.value = saved.groups.join(', ');
+						status.replaceChildren(el('div', { class: 'notice' }, 'Groups saved.'));
+					});
+				}
+			}, input, el('button', { type: 'submit' }, 'Save'))
+		];
+	});
+
+	fill(watchlist, async () => {
+		const current = await get(path + '/watchlist');
+		const paths = el('textarea', { placeholder: '/etc/passwd', readOnly: !can('configure') });
+		paths.value = current.paths.join('\n');
+		const status = el('div');
+		if (!can('configure')) {
+			return [el('h2', {}, 'Integrity watchlist'), paths];
+		}
+		return [
+			el('h2', {}, 'Integrity watchlist'),
+			status,
+			el('form', {
+				onsubmit: event => {
+					event.preventDefault();
+					act(status, async () => {
+						const saved = await api('PUT', path + '/watchlist', { paths: paths.value.split('\n').map(line => line.trim()).filter(Boolean) });
+						paths.value = saved.paths.join('\n');
+						status.replaceChildren(el('div', { class: 'notice' }, `Watchlist saved with ${saved.paths.length} paths. The endpoint picks it up the next time its Integrity Lumy starts.`));
+					});
+				}
+			}, paths, el('div', { class: 'toolbar' }, el('span', {}, 'One path per line.'), el('button', { type: 'submit' }, 'Save')))
+		];
+	});
+
+	const commandList = el('div');
+	commandForm();
+
+	function commandForm() {
+		// Each kind of command needs its own scope
+		const kinds = [['question', 'ask'], ['config', 'configure'], ['action', 'deploy']].filter(([, scope]) => can(scope)).map(([kind]) => kind);
+		if (!kinds.length) {
+			commands.append(el('h2', {}, 'Commands'), commandList);
+			return;
+		}
+		const type = el('select', {}, kinds.map(value => el('option', { value }, value)));
+		const first = el('input', { type: 'text', list: 'questions', placeholder: 'hostname', required: true });
+		const second = el('input', { type: 'text', placeholder: 'arguments', hidden: true });
+		const status = el('div');
+		const chosen = () => {
+			first.placeholder = { question: 'hostname', config: 'key', action: 'name' }[type.value];
+			second.placeholder = type.value === 'config' ? 'value' : 'arguments';
+			second.hidden = type.value === 'question';
+		};
+		type.addEventListener('change', chosen);
+		chosen();
+		const kind = () => {
+			switch (type.value) {
+				case 'question': return { type: 'question', args: first.value.trim() };
+				case 'config': return { type: 'config', args: { key: first.value.trim(), value: second.value } };
+				default: return { type: 'action', args: { name: first.value.trim(), args: second.value.split(/\s+/).filter(Boolean) } };
+			}
+		};
+		commands.append(
+			el('h2', {}, 'Commands'),
+			status,
+			el('form', {
+				class: 'toolbar',
+				onsubmit: event => {
+					event.preventDefault();
+					act(status, async () => {
+						await api('POST', path + '/commands', kind());
+						first.value = second.value = '';
+						load();
+					});
+				}
+			}, type, first, second, el('button', { type: 'submit' }, 'Queue'),
+				el('datalist', { id: 'questions' }, QUESTIONS.map(value => el('option', { value })))),
+			commandList);
+	}
+
+	function showCommands(list) {
+		commandList.replaceChildren(table(['Created', 'Request', 'State', 'Completed', 'Output'], list.slice().reverse().map(command => [
+			time(command.created),
+			describeCommand(command.kind),
+			badge(command.state),
+			command.completed ? time(command.completed) : '',
+			mono(command.output || '')
+		]), 'No commands have been queued.'));
+	}
+
+	function showHistory(detail) {
+		history.replaceChildren(
+			el('h2', {}, 'History'),
+			el('div', { class: 'columns' },
+				el('div', {}, table(['Changed', 'Attribute', 'Old value', 'New value'], detail.attribute_changes.slice().reverse().map(change => [
+					time(change.changed), change.attribute, change.old_value, change.new_value
+				]), 'No attribute changes.')),
+				el('div', {}, table(['At', 'From', 'To'], detail.presence_events.slice().reverse().map(event => [
+					time(event.at), badge(event.old), badge(event.new)
+				]), 'No presence changes.'))));
+	}
+
+	const timelineView = eventTimeline(path + '/integrity/events', false);
+	events.append(el('h2', {}, 'Integrity events'), timelineView.element);
+
+	load();
+	repeat(REFRESH, () => { load(); timelineView.load(); });
+}
+
+// Integrity events
+
+// A filterable, paged timeline of file events from the given collection
+function eventTimeline(collection, showEndpoint) {
+	const filters = { uid: '', path: '', kind: '', since: '', offset: 0 };
+	const uid = el('input', { type: 'text', placeholder: 'Any endpoint' });
+	const prefix = el('input', { type: 'text', placeholder: '/etc' });
+	const kind = el('select', {}, el('option', { value: '' }, 'Any'), ['create', 'modify', 'remove', 'access', 'other'].map(value => el('option', { value }, value)));
+	const since = el('select', {},
+		[['', 'Any time'], ['3600', 'Last hour'], ['86400', 'Last day'], ['604800', 'Last week'], ['2592000', 'Last 30 days']].map(([value, text]) => el('option', { value }, text)));
+	const results = el('div');
+
+	const load = () => fill(results, async () => {
+		const page = await get(collection, {
+			uid: filters.uid,
+			path: filters.path,
+			kind: filters.kind,
+			since: filters.since && Math.floor(Date.now() / 1000) - Number(filters.since),
+			limit: PAGE,
+			offset: filters.offset
+		});
+		return [timeline(page.items, showEndpoint), pager(page, offset => { filters.offset = offset; load(); })];
+	});
+	const apply = event => {
+		event.preventDefault();
+		Object.assign(filters, { uid: uid.value.trim(), path: prefix.value.trim(), kind: kind.value, since: since.value, offset: 0 });
+		load();
+	};
+
+	const element = el('div', {},
+		el('form', { class: 'filters', onsubmit: apply, onchange: apply },
+			showEndpoint ? el('label', {}, 'Endpoint UID', uid) : null,
+			el('label', {}, 'Path prefix', prefix),
+			el('label', {}, 'Kind', kind),
+			el('label', {}, 'Since', since),
+			el('button', { type: 'submit' }, 'Filter')),
+		results);
+	load();
+	return { element, load };
+}
+
+// Events grouped by the day they happened on, newest first
+function timeline(events, showEndpoint) {
+	if (!events.length) {
+		return el('div', { class: 'empty' }, 'No file events match.');
+	}
+	const items = [];
+	let day = null;
+	for (const event of events) {
+		const date = new Date(event.at * 1000).toLocaleDateString();
+		if (date !== day) {
+			day = date;
+			items.push(el('li', { class: 'day' }, date));
+		}
+		const meta = [event.file_type, event.permissions, event.owner, event.size !== null && event.size !== undefined ? event.size + ' bytes' : null].filter(Boolean).join(' · ');
+		items.push(el('li', { class: 'event' },
+			el('span', {}, clock(event.at)),
+			el('span', {}, badge(event.kind)),
+			el('span', { class: 'mono' }, event.path),
+			el('span', { class: 'meta' }, showEndpoint ? [endpointLink(event.uid), meta ? ' · ' + meta : ''] : meta)));
+	}
+	return el('ol', { class: 'timeline' }, items);
+}
+
+function events(view) {
+	const timelineView = eventTimeline('/integrity/events', true);
+	view.append(el('h1', {}, 'Integrity events'), el('section', {}, timelineView.element));
+	repeat(REFRESH, timelineView.load);
+}
+
+// Questions
+
+function questions(view) {
+	const text = el('input', { type: 'text', list: 'questions', placeholder: 'hostname', required: true });
+	const target = el('select', {}, el('option', { value: 'all' }, 'All endpoints'), el('option', { value: 'group' }, 'A group'), el('option', { value: 'uids' }, 'Chosen endpoints'));
+	const group = el('select', { hidden: true });
+	const uids = el('textarea', { placeholder: 'One endpoint UID per line', hidden: true });
+	const status = el('div');
+	const list = el('div');
+	let offset = 0;
+
+	target.addEventListener('change', () => {
+		group.hidden = target.value !== 'group';
+		uids.hidden = target.value !== 'uids';
+	});
+
+	const load = () => fill(list, async () => {
+		const page = await get('/questions', { limit: PAGE, offset });
+		return [
+			table(['Asked', 'Question', 'Asked of', 'Answered'], page.items.map(question => [
+				time(question.created),
+				el('a', { href: '#/questions/' + segment(question.id) }, question.question),
+				question.target,
+				`${question.answered} of ${question.endpoints}`
+			]), 'No questions have been asked.'),
+			pager(page, next => { offset = next; load(); })
+		];
+	});
+
+	view.append(el('h1', {}, 'Questions'),
+		can('ask') && el('section', {},
+			el('h2', {}, 'Ask a question'),
+			status,
+			el('form', {
+				class: 'filters',
+				onsubmit: event => {
+					event.preventDefault();
+					act(status, async () => {
+						const request = { question: text.value.trim() };
+						if (target.value === 'group') {
+							request.group = group.value;
+						}
+						else if (target.value === 'uids') {
+							request.uids = uids.value.split(/\s+/).filter(Boolean);
+						}
+						const question = await api('POST', '/questions', request);
+						location.hash = '#/questions/' + segment(question.id);
+					});
+				}
+			},
+				el('label', {}, 'Question', text),
+				el('label', {}, 'Ask', target),
+				el('label', {}, ' ', group),
+				el('button', { type: 'submit' }, 'Ask'),
+				uids,
+				el('datalist', { id: 'questions' }, QUESTIONS.map(value => el('option', { value }))))),
+		el('section', {}, el('h2', {}, 'Asked'), list));
+	get('/groups', { limit: 1000 }).then(page => group.append(...page.items.map(item => el('option', { value: item.name }, `${item.name} (${item.endpoints})`)))).catch(() => {});
+	load();
+	repeat(REFRESH, load);
+}
+
+// Answers arrive as endpoints pick up their commands, so the results are polled until every
+// endpoint has answered
+function question(view, id) {
+	const path = '/questions/' + segment(id);
+	const summary = el('section');
+	const results = el('section');
+	const filters = { state: '', offset: 0 };
+	const state = el('select', {}, el('option', { value: '' }, 'Any'), ['queued', 'sent', 'succeeded', 'failed', 'cancelled'].map(value => el('option', { value }, value)));
+	const answers = el('div');
+	view.append(el('h1', {}, 'Question'), summary, results);
+	results.append(el('h2', {}, 'Answers'),
+		el('form', {
+			class: 'filters',
+			onchange: () => { filters.state = state.value; filters.offset = 0; load(); },
+			onsubmit: event => event.preventDefault()
+		}, el('label', {}, 'State', state)),
+		answers);
+
+	const load = async () => {
+		let complete = false;
+		await fill(summary, async () => {
+			const info = await get(path);
+			complete = info.answered >= info.endpoints;
+			const bar = el('div');
+			bar.style.width = (info.endpoints ? Math.round(100 * info.answered / info.endpoints) : 100) + '%';
+			return [
+				el('dl', {},
+					el('dt', {}, 'Question'), el('dd', { class: 'mono' }, info.question),
+					el('dt', {}, 'Asked of'), el('dd', {}, info.target),
+					el('dt', {}, 'Asked'), el('dd', {}, time(info.created) + ' by ' + info.asked_by),
+					el('dt', {}, 'Answered'), el('dd', {}, `${info.answered} of ${info.endpoints}` + (complete ? '' : ' — waiting for answers'))),
+				el('div', { class: 'progress' }, bar)
+			];
+		});
+		await fill(answers, async () => {
+			const page = await get(path + '/results', { state: filters.state, limit: PAGE, offset: filters.offset });
+			return [
+				table(['Endpoint', 'State', 'Answered', 'Answer'], page.items.map(answer => [
+					el('td', { class: 'mono' }, endpointLink(answer.uid)),
+					badge(answer.state),
+					answer.completed ? time(answer.completed) : '',
+					mono(answer.answer || '')
+				]), 'No answers match.'),
+				pager(page, offset => { filters.offset = offset; load(); })
+			];
+		});
+		if (complete) {
+			clearInterval(poll);
+		}
+	};
+	const poll = repeat(POLL, load);
+	load();
+}
+
+// Enrollment tokens
+
+function tokens(view) {
+	const description = el('input', { type: 'text', placeholder: 'Web servers' });
+	const groups = el('input', { type: 'text', placeholder: 'web, production' });
+	const expires = el('select', {}, [['1d', 'One day'], ['7d', 'Seven days'], ['30d', '30 days'], ['never', 'Never']].map(([value, text]) => el('option', { value, selected: value === '7d' }, text)));
+	const uses = el('input', { type: 'number', min: '1', placeholder: 'Unlimited' });
+	const status = el('div');
+	const list = el('div');
+	const members = el('section', { hidden: true });
+	let offset = 0;
+
+	const showMembers = (token, start) => fill(members, async () => {
+		members.hidden = false;
+		const page = await get('/tokens/' + segment(token.id) + '/endpoints', { limit: PAGE, offset: start });
+		return [
+			el('h2', {}, `Endpoints enrolled with ${token.id}`),
+			table(['UID', 'Hostname', 'State', 'Last seen'], page.items.map(endpoint => [
+				el('td', { class: 'mono' }, endpointLink(endpoint.uid)), endpoint.hostname, presence(endpoint), time(endpoint.last_seen)
+			]), 'No endpoints have enrolled with this token.'),
+			pager(page, next => showMembers(token, next))
+		];
+	});
+
+	const load = () => fill(list, async () => {
+		const page = await get('/tokens', { limit: PAGE, offset });
+		return [
+			table(['ID', 'Description', 'Created', 'Expires', 'Uses', 'Status', 'Groups', ''], page.items.map(token => [
+				mono(token.id),
+				token.description,
+				time(token.created),
+				time(token.expires),
+				token.max_uses ? `${token.uses} of ${token.max_uses}` : String(token.uses),
+				badge(token.status),
+				token.groups.join(', '),
+				el('td', {},
+					el('button', { type: 'button', class: 'secondary', onclick: () => showMembers(token, 0) }, 'Endpoints'), ' ',
+					token.status === 'active' ? el('button', {
+						type: 'button', class: 'danger',
+						onclick: () => confirmed(`Revoke enrollment token ${token.id}?`) && act(status, async () => { await api('POST', '/tokens/' + segment(token.id) + '/revoke'); load(); })
+					}, 'Revoke') : null)
+			]), 'No enrollment tokens.'),
+			pager(page, next => { offset = next; load(); })
+		];
+	});
+
+	view.append(el('h1', {}, 'Enrollment tokens'),
+		el('section', {},
+			el('h2', {}, 'Create a token'),
+			status,
+			el('form', {
+				class: 'filters',
+				onsubmit: event => {
+					event.preventDefault();
+					act(status, async () => {
+						const created = await api('POST', '/tokens', {
+							description: description.value.trim(),
+							groups: names(groups.value),
+							expires: expires.value,
+							max_uses: uses.value ? Number(uses.value) : null
+						});
+						status.replaceChildren(el('div', { class: 'notice' },
+							el('p', {}, `Enrollment token ${created.info.id} created. This is the only time the token is shown; make a note of it now.`),
+							el('div', { class: 'secret' }, created.token)));
+						description.value = groups.value = uses.value = '';
+						load();
+					});
+				}
+			},
+				el('label', {}, 'Description', description),
+				el('label', {}, 'Groups', groups),
+				el('label', {}, 'Expires', expires),
+				el('label', {}, 'Maximum uses', uses),
+				el('button', { type: 'submit' }, 'Create'))),
+		el('section', {}, list),
+		members);
+	load();
+}
+
+// Access: your account and API keys, and operators for administrators
+
+function access(view) {
+	view.append(el('h1', {}, 'Access'));
+	account(view);
+	apiKeys(view);
+	if (can('admin')) {
+		operators(view);
+	}
+}
+
+function account(view) {
+	const section = el('section', {},
+		el('h2', {}, 'Your account'),
+		el('dl', {},
+			el('dt', {}, 'Signed in as'), el('dd', {}, principal.operator ? 'Operator ' + principal.operator : 'API key ' + principal.key_id),
+			el('dt', {}, 'Role'), el('dd', {}, principal.role || 'none'),
+			el('dt', {}, 'Permissions'), el('dd', {}, principal.scopes.join(', ') || 'none'),
+			el('dt', {}, 'Endpoint groups'), el('dd', {}, principal.groups.length ? principal.groups.join(', ') : 'All endpoints')));
+	view.append(section);
+	if (!principal.operator) {
+		return;
+	}
+	const current = el('input', { type: 'password', autocomplete: 'current-password', required: true });
+	const password = el('input', { type: 'password', autocomplete: 'new-password', required: true });
+	const again = el('input', { type: 'password', autocomplete: 'new-password', required: true });
+	const status = el('div');
+	section.append(el('h2', {}, 'Change password'), status,
+		el('form', {
+			class: 'filters',
+			onsubmit: event => {
+				event.preventDefault();
+				act(status, async () => {
+					if (password.value !== again.value) {
+						throw new Error('The new passwords do not match');
+					}
+					await api('PUT', '/me/password', { current_password: current.value, new_password: password.value });
+					current.value = password.value = again.value = '';
+					status.replaceChildren(el('div', { class: 'notice' }, 'Password changed.'));
+				});
+			}
+		},
+			el('label', {}, 'Current password', current),
+			el('label', {}, 'New password', password),
+			el('label', {}, 'New password again', again),
+			el('button', { type: 'submit' }, 'Change')));
+}
+
+function apiKeys(view) {
+	const description = el('input', { type: 'text', placeholder: 'Monitoring script' });
+	const scopes = principal.scopes.filter(can).map(scope => el('input', { type: 'checkbox', value: scope, checked: scope === 'read' }));
+	const expires = el('select', {}, [['1d', 'One day'], ['30d', '30 days'], ['90d', '90 days'], ['never', 'Never']].map(([value, text]) => el('option', { value, selected: value === '90d' }, text)));
+	const status = el('div');
+	const list = el('div');
+	let offset = 0;
+
+	const load = () => fill(list, async () => {
+		const page = await get('/api-keys', { limit: PAGE, offset });
+		return [
+			table(['ID', 'Description', 'Owner', 'Permissions', 'Created', 'Expires', 'Last used', 'Status', ''], page.items.map(key => [
+				mono(key.id),
+				key.session ? 'Console session' : key.description,
+				key.owner || '',
+				key.scopes.join(', '),
+				time(key.created),
+				time(key.expires),
+				time(key.last_used),
+				badge(key.status),
+				el('td', {}, key.status === 'active' && key.id !== principal.key_id ? el('button', {
+					type: 'button', class: 'danger',
+					onclick: () => confirmed(`Revoke API key ${key.id}?`) && act(status, async () => { await api('POST', '/api-keys/' + segment(key.id) + '/revoke'); load(); })
+				}, 'Revoke') : null)
+			]), 'No API keys.'),
+			pager(page, next => { offset = next; load(); })
+		];
+	});
+
+	view.append(el('section', {},
+		el('h2', {}, 'API keys'),
+		status,
+		el('form', {
+			class: 'filters',
+			onsubmit: event => {
+				event.preventDefault();
+				act(status, async () => {
+					const chosen = scopes.filter(box => box.checked).map(box => box.value);
+					if (!chosen.length) {
+						throw new Error('Choose at least one permission');
+					}
+					const created = await api('POST', '/api-keys', { description: description.value.trim(), scopes: chosen, expires: expires.value });
+					status.replaceChildren(el('div', { class: 'notice' },
+						el('p', {}, `API key ${created.info.id} created. This is the only time the key is shown; make a note of it now.`),
+						el('div', { class: 'secret' }, created.key)));
+					description.value = '';
+					load();
+				});
+			}
+		},
+			el('label', {}, 'Description', description),
+			el('fieldset', {}, el('legend', {}, 'Permissions'), scopes.map(box => el('label', { class: 'inline' }, box, box.value))),
+			el('label', {}, 'Expires', expires),
+			el('button', { type: 'submit' }, 'Create')),
+		list));
+	load();
+}
+
+function operators(view) {
+	const roles = ['viewer', 'operator', 'administrator'];
+	const username = el('input', { type: 'text', autocomplete: 'off', required: true });
+	const password = el('input', { type: 'password', autocomplete: 'new-password', required: true });
+	const role = el('select', {}, roles.map(value => el('option', { value }, value)));
+	const groups = el('input', { type: 'text', placeholder: 'All endpoints' });
+	const status = el('div');
+	const list = el('div');
+	const editor = el('section', { hidden: true });
+	let offset = 0;
+
+	const update = (name, changes) => act(status, async () => { await api('PUT', '/operators/' + segment(name), changes); load(); });
+
+	const edit = operator => {
+		const newRole = el('select', {}, roles.map(value => el('option', { value, selected: value === operator.role }, value)));
+		const newGroups = el('input', { type: 'text', value: operator.groups.join(', '), placeholder: 'All endpoints' });
+		const newPassword = el('input', { type: 'password', autocomplete: 'new-password', placeholder: 'Unchanged' });
+		const editStatus = el('div');
+		editor.hidden = false;
+		editor.replaceChildren(el('h2', {}, `Edit operator ${operator.username}`), editStatus,
+			el('form', {
+				class: 'filters',
+				onsubmit: event => {
+					event.preventDefault();
+					act(editStatus, async () => {
+						const changes = { role: newRole.value, groups: names(newGroups.value) };
+						if (newPassword.value) {
+							changes.password = newPassword.value;
+						}
+						await api('PUT', '/operators/' + segment(operator.username), changes);
+						editor.hidden = true;
+						load();
+					});
+				}
+			},
+				el('label', {}, 'Role', newRole),
+				el('label', {}, 'Endpoint groups', newGroups),
+				el('label', {}, 'New password', newPassword),
+				el('button', { type: 'submit' }, 'Save'),
+				el('button', { type: 'button', class: 'secondary', onclick: () => { editor.hidden = true; } }, 'Cancel')));
+	};
+
+	const load = () => fill(list, async () => {
+		const page = await get('/operators', { limit: PAGE, offset });
+		return [
+			table(['Username', 'Role', 'Endpoint groups', 'Created', 'Last sign-in', 'Status', ''], page.items.map(operator => [
+				operator.username,
+				operator.role,
+				operator.groups.length ? operator.groups.join(', ') : 'All endpoints',
+				time(operator.created),
+				time(operator.last_login),
+				badge(operator.disabled ? 'disabled' : 'active'),
+				el('td', {}, operator.username === principal.operator ? null : [
+					el('button', { type: 'button', class: 'secondary', onclick: () => edit(operator) }, 'Edit'), ' ',
+					el('button', { type: 'button', class: 'secondary', onclick: () => update(operator.username, { disabled: !operator.disabled }) }, operator.disabled ? 'Enable' : 'Disable'), ' ',
+					el('button', {
+						type: 'button', class: 'danger',
+						onclick: () => confirmed(`Delete operator ${operator.username} and revoke their API keys?`)
+							&& act(status, async () => { await api('DELETE', '/operators/' + segment(operator.username)); load(); })
+					}, 'Delete')
+				])
+			]), 'No operators.'),
+			pager(page, next => { offset = next; load(); })
+		];
+	});
+
+	view.append(el('section', {},
+		el('h2', {}, 'Operators'),
+		status,
+		el('form', {
+			class: 'filters',
+			onsubmit: event => {
+				event.preventDefault();
+				act(status, async () => {
+					const created = await api('POST', '/operators', { username: username.value.trim(), password: password.value, role: role.value, groups: names(groups.value) });
+					status.replaceChildren(el('div', { class: 'notice' }, `Operator ${created.username} created.`));
+					username.value = password.value = groups.value = '';
+					load();
+				});
+			}
+		},
+			el('label', {}, 'Username', username),
+			el('label', {}, 'Password', password),
+			el('label', {}, 'Role', role),
+			el('label', {}, 'Endpoint groups', groups),
+			el('button', { type: 'submit' }, 'Create')),
+		list),
+		editor);
+	load();
+}
+
+// Navigation
+
+const routes = [
+	[/^#\/login$/, login, null],
+	[/^#\/endpoints$/, endpoints, 'endpoints'],
+	[/^#\/endpoints\/([^/]+)$/, endpoint, 'endpoints'],
+	[/^#\/events$/, events, 'events'],
+	[/^#\/questions$/, questions, 'questions'],
+	[/^#\/questions\/([^/]+)$/, question, 'questions'],
+	[/^#\/tokens$/, tokens, 'tokens'],
+	[/^#\/access$/, access, 'access']
+];
+
+async function route() {
+	clearInterval(timer);
+	timer = null;
+	if (sessionStorage.getItem(KEY) && !principal) {
+		try {
+			principal = await get('/me');
+		}
+		catch (_) {
+			sessionStorage.removeItem(KEY);
+		}
+	}
+	const signedIn = Boolean(principal);
+	let hash = location.hash || '#/endpoints';
+	if (!signedIn) {
+		hash = '#/login';
+	}
+	else if (hash === '#/login' || (hash === '#/endpoints' && !can('read'))) {
+		hash = can('read') ? '#/endpoints' : '#/access';
+	}
+	const [pattern, view, section] = routes.find(([pattern]) => pattern.test(hash)) || routes[1];
+	const args = (hash.match(pattern) || []).slice(1).map(decodeURIComponent);
+
+	document.getElementById('nav').hidden = !signedIn;
+	document.getElementById('signout').hidden = !signedIn;
+	const who = document.getElementById('principal');
+	who.hidden = !signedIn;
+	who.textContent = signedIn ? principal.operator || 'API key ' + principal.key_id : '';
+	for (const link of document.querySelectorAll('#nav a')) {
+		link.classList.toggle('active', link.dataset.section === section);
+		link.hidden = Boolean(link.dataset.scope) && !can(link.dataset.scope);
+	}
+	const container = document.getElementById('view');
+	container.replaceChildren();
+	try {
+		view(container, ...args);
+	}
+	catch (err) {
+		container.replaceChildren(failure(err));
+	}
+	// The server configuration is only shown to administrators
+	if (can('admin') && !document.getElementById('server').textContent) {
+		get('/config').then(config => {
+			document.getElementById('server').textContent = `Luminum Server ${config.version}`;
+		}).catch(() => {});
+	}
+}
+
+// Console sessions are revoked on the server; an API key is only forgotten
+async function signOut() {
+	if (principal && principal.session) {
+		await api('POST', '/logout').catch(() => {});
+	}
+	sessionStorage.removeItem(KEY);
+	principal = null;
+	document.getElementById('server').textContent = '';
+	location.hash = '#/login';
+	route();
+}
+
+document.addEventListener('DOMContentLoaded', () => {
+	document.getElementById('signout').addEventListener('click', signOut);
+	window.addEventListener('hashchange', route);
+	route();
+});
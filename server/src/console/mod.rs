@@ -0,0 +1,42 @@
+// Web console
+//
+// Browser interface to the management API, served alongside it on the API listeners. The
+// page, script and stylesheet are compiled into the binary. The files themselves are public:
+// operators sign in with their password (or an API key), the session key is kept for the
+// browser session and sent with every API request, so the console can do nothing the
+// operator could not do from luminumctl.
+
+use axum::Router;
+use axum::http::header;
+use axum::response::{IntoResponse, Redirect};
+use axum::routing::get;
+
+pub const CONSOLE_PATH: &str = "/console/";
+
+const INDEX: &str = include_str!("index.html");
+const SCRIPT: &str = include_str!("console.js");
+const STYLESHEET: &str = include_str!("console.css");
+
+// Only the console's own files may be loaded, and no other site may frame it
+const CSP: &str = "default-src 'self'; img-src 'self' data:; object-src 'none'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'";
+
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+	Router::new()
+		.route("/", get(|| async { Redirect::to(CONSOLE_PATH) }))
+		.route("/console", get(|| async { Redirect::to(CONSOLE_PATH) }))
+		.route(CONSOLE_PATH, get(|| async { asset("text/html; charset=utf-8", INDEX) }))
+		.route("/console/console.js", get(|| async { asset("text/javascript; charset=utf-8", SCRIPT) }))
+		.route("/console/console.css", get(|| async { asset("text/css; charset=utf-8", STYLESHEET) }))
+	}
+
+// Browsers revalidate each time, so an upgraded server's console is picked up straight away
+fn asset(content_type: &'static str, body: &'static str) -> impl IntoResponse {
+	([
+		(header::CONTENT_TYPE, content_type),
+		(header::CACHE_CONTROL, "no-cache"),
+		(header::CONTENT_SECURITY_POLICY, CSP),
+		(header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+		(header::X_FRAME_OPTIONS, "DENY"),
+		(header::REFERRER_POLICY, "no-referrer")
+		], body)
+	}
@@ -0,0 +1,200 @@
+// Enrollment Tokens
+//
+// Endpoints register with an enrollment token rather than a shared server key. Tokens are
+// handed out as "<id>.<secret>". Only an HMAC-SHA256 of the secret is stored, keyed with an
+// enrollment key that is itself sealed with the master key (see secrets.rs), so the database
+// alone can't be used to enroll endpoints or to check guesses at a secret. Tokens created
+// before enrollment keys existed keep their plain SHA-256 hash.
+//
+// Rotating the enrollment key adds a new key for new tokens. An older key is kept until no
+// usable token depends on it.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use crate::secrets::MasterKey;
+use crate::storage::{EnrollmentKey, EnrollmentToken, Storage};
+
+pub const DEFAULT_EXPIRY: i64 = 7 * 86400;
+// Longest lifetime accepted for a token or API key
+pub const MAX_LIFETIME: i64 = 10 * 365 * 86400;
+const ID_BYTES: usize = 4;
+const SECRET_BYTES: usize = 20;
+const KEY_BYTES: usize = 32;
+
+// Create a new token. Returns the stored token and the token string to give to the administrator.
+pub fn generate(storage: &dyn Storage, master_key: &MasterKey, description: &str, groups: Vec<String>, expires: Option<i64>, max_uses: Option<u32>) -> Result<(EnrollmentToken, String), String> {
+	let (key_version, key) = current_key(storage, master_key)?;
+	let id = random_hex(ID_BYTES).map_err(|err| err.to_string())?;
+	let secret = random_hex(SECRET_BYTES).map_err(|err| err.to_string())?;
+	let created = now();
+	let token = EnrollmentToken {
+		id: id.clone(),
+		hash: hash_secret(Some(&key), &secret)?,
+		description: description.to_string(),
+		groups,
+		created,
+		expires: expires.map(|lifetime| created.saturating_add(lifetime)),
+		max_uses,
+		uses: 0,
+		revoked: false,
+		key_version
+		};
+	Ok((token, format!("{}.{}", id, secret)))
+	}
+
+// Check a presented token. Returns the token if it can be used, or the reason it was
+// rejected. The use is only counted once the endpoint is stored (Storage::enroll_endpoint).
+pub fn check(storage: &dyn Storage, master_key: &MasterKey, presented: &str) -> Result<EnrollmentToken, String> {
+	let (id, secret) = presented.split_once('.').ok_or("malformed enrollment token")?;
+	let token = storage.find_token(id).map_err(|err| err.to_string())?.ok_or("unknown enrollment token")?;
+	let key = match token.key_version {
+		0 => None,
+		version => {
+			let keys = storage.enrollment_keys().map_err(|err| err.to_string())?;
+			let key = keys.iter().find(|key| key.version == version).ok_or(format!("enrollment key {} for token {} no longer exists", version, token.id))?;
+			Some(open_key(master_key, key)?)
+			}
+		};
+	let hash = hash_secret(key.as_deref(), secret)?;
+	if hash.len() != token.hash.len() || !memcmp::eq(hash.as_bytes(), token.hash.as_bytes()) {
+		return Err(format!("invalid secret for enrollment token {}", token.id));
+		}
+	if let Some(reason) = token.rejection(now()) {
+		return Err(format!("enrollment token {}: {}", token.id, reason));
+		}
+	Ok(token)
+	}
+
+// Start using a new enrollment key for new tokens and remove older keys that no usable token
+// needs. Returns the new key version and the versions removed.
+pub fn rotate_key(storage: &dyn Storage, master_key: &MasterKey) -> Result<(u32, Vec<u32>), String> {
+	let keys = storage.enrollment_keys().map_err(|err| err.to_string())?;
+	let version = keys.last().map_or(1, |key| key.version + 1);
+	add_key(storage, master_key, version)?;
+
+	let now = now();
+	let tokens = storage.list_tokens().map_err(|err| err.to_string())?;
+	let mut retired = Vec::new();
+	for key in keys {
+		if !tokens.iter().any(|token| token.key_version == key.version && token.rejection(now).is_none()) {
+			storage.delete_enrollment_key(key.version).map_err(|err| err.to_string())?;
+			retired.push(key.version);
+			}
+		}
+	Ok((version, retired))
+	}
+
+// Seal every enrollment key with a new master key. Returns the number of keys resealed.
+pub fn reseal_keys(storage: &dyn Storage, old: &MasterKey, new: &MasterKey) -> Result<usize, String> {
+	let keys = storage.enrollment_keys().map_err(|err| err.to_string())?;
+	for key in &keys {
+		let sealed = new.seal(&key_name(key.version), &open_key(old, key)?).map_err(|err| err.to_string())?;
+		storage.reseal_enrollment_key(key.version, &sealed).map_err(|err| err.to_string())?;
+		}
+	Ok(keys.len())
+	}
+
+// The newest enrollment key, creating the first one if there is none yet
+fn current_key(storage: &dyn Storage, master_key: &MasterKey) -> Result<(u32, Vec<u8>), String> {
+	match storage.enrollment_keys().map_err(|err| err.to_string())?.pop() {
+		Some(key) => Ok((key.version, open_key(master_key, &key)?)),
+		None => add_key(storage, master_key, 1)
+		}
+	}
+
+fn add_key(storage: &dyn Storage, master_key: &MasterKey, version: u32) -> Result<(u32, Vec<u8>), String> {
+	let mut key = vec![0u8; KEY_BYTES];
+	rand_bytes(&mut key).map_err(|err| err.to_string())?;
+	let sealed = master_key.seal(&key_name(version), &key).map_err(|err| err.to_string())?;
+	storage.add_enrollment_key(&EnrollmentKey { version, sealed, created: now() }).map_err(|err| err.to_string())?;
+	Ok((version, key))
+	}
+
+fn open_key(master_key: &MasterKey, key: &EnrollmentKey) -> Result<Vec<u8>, String> {
+	master_key.open(&key_name(key.version), &key.sealed).map_err(|err| err.to_string())
+	}
+
+// Name each sealed key is bound to
+fn key_name(version: u32) -> String {
+	format!("ENROLL_KEY {}", version)
+	}
+
+// Parse a lifetime such as "90m", "12h" or "30d", up to MAX_LIFETIME
+pub fn parse_duration(input: &str) -> Option<i64> {
+	let input = input.trim();
+	let (split, unit) = input.char_indices().last()?;
+	let value: i64 = input[..split].parse().ok().filter(|value| *value > 0)?;
+	let seconds = match unit {
+		's' => Some(value),
+		'm' => value.checked_mul(60),
+		'h' => value.checked_mul(3600),
+		'd' => value.checked_mul(86400),
+		_ => None
+		};
+	seconds.filter(|seconds| *seconds <= MAX_LIFETIME)
+	}
+
+// Group names are restricted so they can be stored as a comma-separated list
+pub fn valid_group(name: &str) -> bool {
+	!name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+	}
+
+pub fn now() -> i64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs() as i64).unwrap_or(0)
+	}
+
+fn hash_secret(key: Option<&[u8]>, secret: &str) -> Result<String, String> {
+	let key = match key {
+		Some(key) => key,
+		None => { return Ok(hex(&sha256(secret.as_bytes()))); }
+		};
+	let hmac = PKey::hmac(key)
+		.and_then(|key| Signer::new(MessageDigest::sha256(), &key).and_then(|mut signer| signer.sign_oneshot_to_vec(secret.as_bytes())))
+		.map_err(|err| err.to_string())?;
+	Ok(hex(&hmac))
+	}
+
+pub fn random_hex(len: usize) -> Result<String, openssl::error::ErrorStack> {
+	let mut bytes = vec![0u8; len];
+	rand_bytes(&mut bytes)?;
+	Ok(hex(&bytes))
+	}
+
+pub fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+	}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_durations() {
+		assert_eq!(parse_duration("90s"), Some(90));
+		assert_eq!(parse_duration("90m"), Some(5400));
+		assert_eq!(parse_duration(" 12h "), Some(43200));
+		assert_eq!(parse_duration("30d"), Some(2592000));
+		assert_eq!(parse_duration("3650d"), Some(MAX_LIFETIME));
+		}
+
+	#[test]
+	fn rejects_invalid_durations() {
+		for input in ["", "d", "30", "0d", "-5d", "5w", "5 d", "5é", "é", "1.5h"] {
+			assert_eq!(parse_duration(input), None, "{:?}", input);
+			}
+		}
+
+	#[test]
+	fn rejects_durations_too_long() {
+		assert_eq!(parse_duration("3651d"), None);
+		assert_eq!(parse_duration(&format!("{}s", MAX_LIFETIME + 1)), None);
+		assert_eq!(parse_duration(&format!("{}d", i64::MAX / 86400 + 1)), None);
+		assert_eq!(parse_duration(&format!("{}s", i64::MAX)), None);
+		assert_eq!(parse_duration("99999999999999999999d"), None);
+		}
+	}
@@ -0,0 +1,570 @@
+// Connection Guard
+//
+// Limits what one source address can ask of the data port. Open sessions, TLS handshakes in
+// progress and registrations in progress are capped per source, and new connections and
+// registration attempts are rate limited. Sources that keep failing (TLS handshake errors,
+// malformed data, rejected enrollment tokens or endpoint identities) are banned for a while.
+// Connections are refused before the TLS handshake, so a refusal costs next to nothing.
+//
+// Sign-ins to the admin API are rate limited per source as well, before the password is
+// checked, and failed sign-ins from one source are audited at most once a minute.
+//
+// IPv6 sources are grouped by /64, since one host can usually draw addresses from a whole /64.
+// Each kind of refusal is logged at most once a minute per source; every decision is counted
+// in metrics.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use luminum_log::SECURITY;
+use tracing::{debug, info, warn};
+use crate::metrics;
+
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 64;
+pub const DEFAULT_MAX_HANDSHAKES_PER_IP: usize = 8;
+pub const DEFAULT_CONNECTIONS_PER_MINUTE: u32 = 120;
+pub const DEFAULT_MAX_REGISTRATIONS_PER_IP: usize = 2;
+pub const DEFAULT_REGISTRATIONS_PER_MINUTE: u32 = 10;
+pub const DEFAULT_BAN_AFTER_FAILURES: u32 = 10;
+pub const DEFAULT_FAILURE_WINDOW: u64 = 300;
+pub const DEFAULT_BAN_DURATION: u64 = 900;
+pub const DEFAULT_SIGN_INS_PER_MINUTE: u32 = 10;
+// How often sources with nothing left to track are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// Least time between logged refusals for one source
+const REFUSAL_LOG_INTERVAL: Duration = Duration::from_secs(60);
+// Least time between audit records of failed sign-ins from one source
+const SIGN_IN_AUDIT_INTERVAL: Duration = Duration::from_secs(60);
+
+// Per-source limits, from the [protection] section
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Protection {
+	pub max_connections_per_ip: usize,
+	pub max_handshakes_per_ip: usize,
+	pub connections_per_minute: u32,
+	pub max_registrations_per_ip: usize,
+	pub registrations_per_minute: u32,
+	// Failures within failure_window that get a source banned for ban_duration
+	pub ban_after_failures: u32,
+	pub failure_window: Duration,
+	pub ban_duration: Duration,
+	// Sign-in attempts on the admin API
+	pub sign_ins_per_minute: u32
+	}
+
+// Something a source did wrong
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+	Handshake,
+	Malformed,
+	Enrollment,
+	Identity
+	}
+
+impl Failure {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Failure::Handshake => "handshake",
+			Failure::Malformed => "malformed",
+			Failure::Enrollment => "enrollment",
+			Failure::Identity => "identity"
+			}
+		}
+	}
+
+// Why a connection or registration was refused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+	Banned,
+	Connections,
+	Handshakes,
+	ConnectionRate,
+	Registrations,
+	RegistrationRate,
+	SignInRate
+	}
+
+impl Refusal {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Refusal::Banned => "banned",
+			Refusal::Connections => "connections",
+			Refusal::Handshakes => "handshakes",
+			Refusal::ConnectionRate => "connection-rate",
+			Refusal::Registrations => "registrations",
+			Refusal::RegistrationRate => "registration-rate",
+			Refusal::SignInRate => "sign-in-rate"
+			}
+		}
+	}
+
+impl fmt::Display for Refusal {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Refusal::Banned => write!(f, "source is banned"),
+			Refusal::Connections => write!(f, "too many open connections"),
+			Refusal::Handshakes => write!(f, "too many TLS handshakes in progress"),
+			Refusal::ConnectionRate => write!(f, "connecting too often"),
+			Refusal::Registrations => write!(f, "too many registrations in progress"),
+			Refusal::RegistrationRate => write!(f, "registering too often"),
+			Refusal::SignInRate => write!(f, "signing in too often")
+			}
+		}
+	}
+
+// Allows a steady number of events per minute, with bursts of up to a minute's worth
+#[derive(Clone, Copy)]
+struct Bucket {
+	used: f64,
+	updated: Instant
+	}
+
+impl Bucket {
+	fn new(now: Instant) -> Bucket {
+		Bucket { used: 0.0, updated: now }
+		}
+
+	fn refill(&mut self, per_minute: u32, now: Instant) {
+		let refilled = now.saturating_duration_since(self.updated).as_secs_f64() * f64::from(per_minute) / 60.0;
+		self.used = (self.used - refilled).max(0.0);
+		self.updated = now;
+		}
+
+	fn take(&mut self, per_minute: u32, now: Instant) -> bool {
+		self.refill(per_minute, now);
+		if self.used + 1.0 > f64::from(per_minute) {
+			return false;
+			}
+		self.used += 1.0;
+		true
+		}
+	}
+
+struct Source {
+	connections: usize,
+	handshakes: usize,
+	registrations: usize,
+	connection_rate: Bucket,
+	registration_rate: Bucket,
+	sign_in_rate: Bucket,
+	// When a failed sign-in was last audited, and how many have failed since
+	sign_in_failures: Option<(Instant, u32)>,
+	// Times of recent failures, oldest first
+	failures: VecDeque<Instant>,
+	banned_until: Option<Instant>,
+	last_refusal_logged: Option<(Refusal, Instant)>
+	}
+
+impl Source {
+	fn new(now: Instant) -> Source {
+		Source {
+			connections: 0,
+			handshakes: 0,
+			registrations: 0,
+			connection_rate: Bucket::new(now),
+			registration_rate: Bucket::new(now),
+			sign_in_rate: Bucket::new(now),
+			sign_in_failures: None,
+			failures: VecDeque::new(),
+			banned_until: None,
+			last_refusal_logged: None
+			}
+		}
+
+	// Whether the source is banned, lifting a ban that has run out
+	fn banned(&mut self, address: &IpAddr, now: Instant) -> bool {
+		match self.banned_until {
+			Some(until) if until > now => true,
+			Some(_) => {
+				self.banned_until = None;
+				metrics::BANNED_SOURCES.dec();
+				info!(target: SECURITY, "Ban on {} lifted", describe(address));
+				false
+				},
+			None => false
+			}
+		}
+
+	// Nothing open, banned or remembered that a fresh entry wouldn't have
+	fn idle(&mut self, protection: &Protection, now: Instant) -> bool {
+		self.connection_rate.refill(protection.connections_per_minute, now);
+		self.registration_rate.refill(protection.registrations_per_minute, now);
+		self.sign_in_rate.refill(protection.sign_ins_per_minute, now);
+		self.connections == 0 && self.handshakes == 0 && self.registrations == 0 && self.banned_until.is_none()
+			&& self.connection_rate.used == 0.0 && self.registration_rate.used == 0.0 && self.sign_in_rate.used == 0.0
+			&& self.sign_in_failures.is_none_or(|(audited, _)| now.saturating_duration_since(audited) >= SIGN_IN_AUDIT_INTERVAL)
+			&& self.failures.back().is_none_or(|last| now.saturating_duration_since(*last) >= protection.failure_window)
+		}
+
+	// Log a refusal unless the same one was logged for this source recently
+	fn log_refusal(&mut self, address: &IpAddr, what: &str, refusal: Refusal, now: Instant) {
+		if self.last_refusal_logged.is_some_and(|(last, logged)| last == refusal && now.saturating_duration_since(logged) < REFUSAL_LOG_INTERVAL) {
+			debug!(target: SECURITY, "Refused {} from {}: {}", what, describe(address), refusal);
+			return;
+			}
+		self.last_refusal_logged = Some((refusal, now));
+		warn!(target: SECURITY, "Refusing {} from {}: {}", what, describe(address), refusal);
+		}
+	}
+
+#[derive(Default)]
+struct Sources {
+	by_address: HashMap<IpAddr, Source>,
+	last_sweep: Option<Instant>
+	}
+
+#[derive(Clone, Default)]
+pub struct Guard {
+	sources: Arc<Mutex<Sources>>
+	}
+
+// Held for as long as an admitted connection is open
+pub struct Admission {
+	sources: Arc<Mutex<Sources>>,
+	address: IpAddr,
+	handshaking: bool
+	}
+
+// Held while a registration is handled
+pub struct Registration {
+	sources: Arc<Mutex<Sources>>,
+	address: IpAddr
+	}
+
+impl Guard {
+	fn lock(&self) -> MutexGuard<'_, Sources> {
+		lock(&self.sources)
+		}
+
+	// Admit a new connection from a peer, counting it as a TLS handshake in progress
+	pub fn admit(&self, peer: IpAddr, protection: &Protection) -> Result<Admission, Refusal> {
+		let address = source_address(peer);
+		let now = Instant::now();
+		let mut sources = self.lock();
+		sweep(&mut sources, protection, now);
+		let source = sources.by_address.entry(address).or_insert_with(|| Source::new(now));
+		let refusal = if source.banned(&address, now) { Some(Refusal::Banned) }
+			else if source.connections >= protection.max_connections_per_ip { Some(Refusal::Connections) }
+			else if source.handshakes >= protection.max_handshakes_per_ip { Some(Refusal::Handshakes) }
+			else if !source.connection_rate.take(protection.connections_per_minute, now) { Some(Refusal::ConnectionRate) }
+			else { None };
+		if let Some(refusal) = refusal {
+			source.log_refusal(&address, "connections", refusal, now);
+			metrics::GUARD_REFUSALS.with_label_values(&[refusal.as_str()]).inc();
+			return Err(refusal);
+			}
+		source.connections += 1;
+		source.handshakes += 1;
+		Ok(Admission { sources: self.sources.clone(), address, handshaking: true })
+		}
+
+	// Admit a registration attempt from a peer
+	pub fn register(&self, peer: IpAddr, protection: &Protection) -> Result<Registration, Refusal> {
+		let address = source_address(peer);
+		let now = Instant::now();
+		let mut sources = self.lock();
+		let source = sources.by_address.entry(address).or_insert_with(|| Source::new(now));
+		let refusal = if source.banned(&address, now) { Some(Refusal::Banned) }
+			else if source.registrations >= protection.max_registrations_per_ip { Some(Refusal::Registrations) }
+			else if !source.registration_rate.take(protection.registrations_per_minute, now) { Some(Refusal::RegistrationRate) }
+			else { None };
+		if let Some(refusal) = refusal {
+			source.log_refusal(&address, "registrations", refusal, now);
+			metrics::GUARD_REFUSALS.with_label_values(&[refusal.as_str()]).inc();
+			return Err(refusal);
+			}
+		source.registrations += 1;
+		Ok(Registration { sources: self.sources.clone(), address })
+		}
+
+	// Admit a sign-in attempt on the admin API from a peer
+	pub fn sign_in(&self, peer: IpAddr, protection: &Protection) -> Result<(), Refusal> {
+		let address = source_address(peer);
+		let now = Instant::now();
+		let mut sources = self.lock();
+		sweep(&mut sources, protection, now);
+		let source = sources.by_address.entry(address).or_insert_with(|| Source::new(now));
+		if !source.sign_in_rate.take(protection.sign_ins_per_minute, now) {
+			source.log_refusal(&address, "sign-ins", Refusal::SignInRate, now);
+			metrics::GUARD_REFUSALS.with_label_values(&[Refusal::SignInRate.as_str()]).inc();
+			return Err(Refusal::SignInRate);
+			}
+		Ok(())
+		}
+
+	// Note a failed sign-in. Returns how many earlier failures from the source went unaudited
+	// if this one should be audited, or None if it should be left out as well.
+	pub fn sign_in_failed(&self, peer: IpAddr) -> Option<u32> {
+		let address = source_address(peer);
+		let now = Instant::now();
+		let mut sources = self.lock();
+		let source = sources.by_address.entry(address).or_insert_with(|| Source::new(now));
+		match &mut source.sign_in_failures {
+			Some((audited, unaudited)) if now.saturating_duration_since(*audited) < SIGN_IN_AUDIT_INTERVAL => {
+				*unaudited += 1;
+				None
+				},
+			failures => {
+				let unaudited = failures.map_or(0, |(_, unaudited)| unaudited);
+				*failures = Some((now, 0));
+				Some(unaudited)
+				}
+			}
+		}
+
+	// Record a failure, banning the source once it has failed too often
+	pub fn fail(&self, peer: IpAddr, failure: Failure, protection: &Protection) {
+		let address = source_address(peer);
+		let now = Instant::now();
+		metrics::GUARD_FAILURES.with_label_values(&[failure.as_str()]).inc();
+		let mut sources = self.lock();
+		let source = sources.by_address.entry(address).or_insert_with(|| Source::new(now));
+		if source.banned(&address, now) {
+			return;
+			}
+		while source.failures.front().is_some_and(|first| now.saturating_duration_since(*first) >= protection.failure_window) {
+			source.failures.pop_front();
+			}
+		source.failures.push_back(now);
+		debug!(target: SECURITY, "Failure from {} ({}): {} of {} within {} seconds", describe(&address), failure.as_str(), source.failures.len(),
+			protection.ban_after_failures, protection.failure_window.as_secs());
+		if source.failures.len() >= protection.ban_after_failures as usize {
+			source.failures.clear();
+			source.banned_until = Some(now + protection.ban_duration);
+			metrics::BANS.inc();
+			metrics::BANNED_SOURCES.inc();
+			warn!(target: SECURITY, "Banning {} for {} seconds after {} failures within {} seconds (last: {})", describe(&address), protection.ban_duration.as_secs(),
+				protection.ban_after_failures, protection.failure_window.as_secs(), failure.as_str());
+			}
+		}
+	}
+
+impl Admission {
+	// The TLS handshake is over, successfully or not
+	pub fn handshake_done(&mut self) {
+		if self.handshaking {
+			self.handshaking = false;
+			if let Some(source) = lock(&self.sources).by_address.get_mut(&self.address) {
+				source.handshakes -= 1;
+				}
+			}
+		}
+	}
+
+impl Drop for Admission {
+	fn drop(&mut self) {
+		if let Some(source) = lock(&self.sources).by_address.get_mut(&self.address) {
+			source.connections -= 1;
+			if self.handshaking {
+				source.handshakes -= 1;
+				}
+			}
+		}
+	}
+
+impl Drop for Registration {
+	fn drop(&mut self) {
+		if let Some(source) = lock(&self.sources).by_address.get_mut(&self.address) {
+			source.registrations -= 1;
+			}
+		}
+	}
+
+fn lock(sources: &Mutex<Sources>) -> MutexGuard<'_, Sources> {
+	sources.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+	}
+
+// Forget sources with nothing left to track, at most once per SWEEP_INTERVAL
+fn sweep(sources: &mut Sources, protection: &Protection, now: Instant) {
+	if sources.last_sweep.is_some_and(|last| now.saturating_duration_since(last) < SWEEP_INTERVAL) {
+		return;
+		}
+	sources.last_sweep = Some(now);
+	sources.by_address.retain(|address, source| {
+		source.banned(address, now);
+		!source.idle(protection, now)
+		});
+	}
+
+// The address limits apply to: IPv4 addresses as they are, IPv6 addresses by /64
+fn source_address(peer: IpAddr) -> IpAddr {
+	match peer.to_canonical() {
+		IpAddr::V6(address) => IpAddr::V6(Ipv6Addr::from(u128::from(address) & !((1u128 << 64) - 1))),
+		address => address
+		}
+	}
+
+fn describe(address: &IpAddr) -> String {
+	match address {
+		IpAddr::V6(_) => format!("{}/64", address),
+		IpAddr::V4(_) => address.to_string()
+		}
+	}
+
+#[cfg(test)]
+mod tests {
+	use std::net::IpAddr;
+	use std::thread::sleep;
+	use std::time::{Duration, Instant};
+	use super::*;
+
+	fn protection() -> Protection {
+		Protection {
+			max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+			max_handshakes_per_ip: DEFAULT_MAX_HANDSHAKES_PER_IP,
+			connections_per_minute: DEFAULT_CONNECTIONS_PER_MINUTE,
+			max_registrations_per_ip: DEFAULT_MAX_REGISTRATIONS_PER_IP,
+			registrations_per_minute: DEFAULT_REGISTRATIONS_PER_MINUTE,
+			ban_after_failures: 3,
+			failure_window: Duration::from_secs(DEFAULT_FAILURE_WINDOW),
+			ban_duration: Duration::from_millis(50),
+			sign_ins_per_minute: 2
+			}
+		}
+
+	fn ip(address: &str) -> IpAddr {
+		address.parse().unwrap()
+		}
+
+	// Open connections, handshakes and registrations counted for an address
+	fn counts(guard: &Guard, address: &str) -> (usize, usize, usize) {
+		let sources = guard.lock();
+		let source = &sources.by_address[&source_address(ip(address))];
+		(source.connections, source.handshakes, source.registrations)
+		}
+
+	#[test]
+	fn bucket_allows_a_minute_of_burst_then_refills() {
+		let start = Instant::now();
+		let mut bucket = Bucket::new(start);
+		assert!((0..60).all(|_| bucket.take(60, start)));
+		assert!(!bucket.take(60, start));
+		assert!(!bucket.take(60, start + Duration::from_millis(500)));
+		assert!(bucket.take(60, start + Duration::from_secs(1)));
+		assert!(!bucket.take(60, start + Duration::from_secs(1)));
+		let later = start + Duration::from_secs(120);
+		assert!((0..60).all(|_| bucket.take(60, later)));
+		assert!(!bucket.take(60, later));
+		}
+
+	#[test]
+	fn admission_counts_return_to_zero() {
+		let guard = Guard::default();
+		let protection = protection();
+		let mut admission = guard.admit(ip("192.0.2.1"), &protection).unwrap();
+		assert_eq!(counts(&guard, "192.0.2.1"), (1, 1, 0));
+		admission.handshake_done();
+		assert_eq!(counts(&guard, "192.0.2.1"), (1, 0, 0));
+		admission.handshake_done();
+		assert_eq!(counts(&guard, "192.0.2.1"), (1, 0, 0));
+		drop(admission);
+		assert_eq!(counts(&guard, "192.0.2.1"), (0, 0, 0));
+
+		// A connection that never finishes its handshake
+		drop(guard.admit(ip("192.0.2.1"), &protection).unwrap());
+		assert_eq!(counts(&guard, "192.0.2.1"), (0, 0, 0));
+
+		let registration = guard.register(ip("192.0.2.1"), &protection).unwrap();
+		assert_eq!(counts(&guard, "192.0.2.1"), (0, 0, 1));
+		drop(registration);
+		assert_eq!(counts(&guard, "192.0.2.1"), (0, 0, 0));
+		}
+
+	#[test]
+	fn limits_handshakes_and_connections() {
+		let guard = Guard::default();
+		let protection = Protection { max_connections_per_ip: 2, max_handshakes_per_ip: 1, ..protection() };
+		let mut first = guard.admit(ip("192.0.2.1"), &protection).unwrap();
+		assert_eq!(guard.admit(ip("192.0.2.1"), &protection).err(), Some(Refusal::Handshakes));
+		first.handshake_done();
+		let mut second = guard.admit(ip("192.0.2.1"), &protection).unwrap();
+		second.handshake_done();
+		assert_eq!(guard.admit(ip("192.0.2.1"), &protection).err(), Some(Refusal::Connections));
+		assert!(guard.admit(ip("192.0.2.2"), &protection).is_ok());
+		drop(first);
+		assert!(guard.admit(ip("192.0.2.1"), &protection).is_ok());
+		}
+
+	#[test]
+	fn limits_registrations() {
+		let guard = Guard::default();
+		let protection = Protection { registrations_per_minute: 3, ..protection() };
+		let first = guard.register(ip("192.0.2.1"), &protection).unwrap();
+		let second = guard.register(ip("192.0.2.1"), &protection).unwrap();
+		assert_eq!(guard.register(ip("192.0.2.1"), &protection).err(), Some(Refusal::Registrations));
+		drop((first, second));
+		assert!(guard.register(ip("192.0.2.1"), &protection).is_ok());
+		assert_eq!(guard.register(ip("192.0.2.1"), &protection).err(), Some(Refusal::RegistrationRate));
+		}
+
+	#[test]
+	fn bans_after_repeated_failures_until_the_ban_expires() {
+		let guard = Guard::default();
+		let protection = protection();
+		guard.fail(ip("192.0.2.1"), Failure::Handshake, &protection);
+		guard.fail(ip("192.0.2.1"), Failure::Malformed, &protection);
+		assert!(guard.admit(ip("192.0.2.1"), &protection).is_ok());
+		guard.fail(ip("192.0.2.1"), Failure::Enrollment, &protection);
+		assert_eq!(guard.admit(ip("192.0.2.1"), &protection).err(), Some(Refusal::Banned));
+		assert_eq!(guard.register(ip("192.0.2.1"), &protection).err(), Some(Refusal::Banned));
+		assert!(guard.admit(ip("192.0.2.2"), &protection).is_ok());
+
+		sleep(protection.ban_duration + Duration::from_millis(20));
+		assert!(guard.admit(ip("192.0.2.1"), &protection).is_ok());
+		// The failures that led to the ban don't count towards the next one
+		guard.fail(ip("192.0.2.1"), Failure::Identity, &protection);
+		assert!(guard.admit(ip("192.0.2.1"), &protection).is_ok());
+		}
+
+	#[test]
+	fn forgets_failures_outside_the_window() {
+		let guard = Guard::default();
+		let protection = Protection { failure_window: Duration::from_millis(50), ..protection() };
+		guard.fail(ip("192.0.2.1"), Failure::Handshake, &protection);
+		guard.fail(ip("192.0.2.1"), Failure::Handshake, &protection);
+		sleep(protection.failure_window + Duration::from_millis(20));
+		guard.fail(ip("192.0.2.1"), Failure::Handshake, &protection);
+		guard.fail(ip("192.0.2.1"), Failure::Handshake, &protection);
+		assert!(guard.admit(ip("192.0.2.1"), &protection).is_ok());
+		}
+
+	#[test]
+	fn limits_sign_ins_and_their_audit_records() {
+		let guard = Guard::default();
+		let protection = protection();
+		assert!(guard.sign_in(ip("192.0.2.1"), &protection).is_ok());
+		assert!(guard.sign_in(ip("192.0.2.1"), &protection).is_ok());
+		assert_eq!(guard.sign_in(ip("192.0.2.1"), &protection).err(), Some(Refusal::SignInRate));
+		assert!(guard.sign_in(ip("192.0.2.2"), &protection).is_ok());
+
+		assert_eq!(guard.sign_in_failed(ip("192.0.2.1")), Some(0));
+		assert_eq!(guard.sign_in_failed(ip("192.0.2.1")), None);
+		assert_eq!(guard.sign_in_failed(ip("192.0.2.1")), None);
+		assert_eq!(guard.sign_in_failed(ip("192.0.2.2")), Some(0));
+		let mut sources = guard.lock();
+		let source = sources.by_address.get_mut(&ip("192.0.2.1")).unwrap();
+		assert_eq!(source.sign_in_failures.map(|(_, unaudited)| unaudited), Some(2));
+		source.sign_in_failures = Some((Instant::now() - SIGN_IN_AUDIT_INTERVAL, 2));
+		drop(sources);
+		assert_eq!(guard.sign_in_failed(ip("192.0.2.1")), Some(2));
+		assert_eq!(guard.sign_in_failed(ip("192.0.2.1")), None);
+		}
+
+	#[test]
+	fn groups_ipv6_sources_by_64() {
+		assert_eq!(source_address(ip("2001:db8:1:2:aaaa:bbbb:cccc:dddd")), ip("2001:db8:1:2::"));
+		assert_eq!(source_address(ip("2001:db8:1:2::1")), source_address(ip("2001:db8:1:2:ffff::1")));
+		assert_ne!(source_address(ip("2001:db8:1:2::1")), source_address(ip("2001:db8:1:3::1")));
+		assert_eq!(source_address(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+		assert_eq!(source_address(ip("192.0.2.1")), ip("192.0.2.1"));
+		assert_eq!(describe(&source_address(ip("2001:db8:1:2::1"))), "2001:db8:1:2::/64");
+
+		let guard = Guard::default();
+		let protection = Protection { max_connections_per_ip: 1, ..protection() };
+		let _first = guard.admit(ip("2001:db8:1:2::1"), &protection).unwrap();
+		assert_eq!(guard.admit(ip("2001:db8:1:2::2"), &protection).err(), Some(Refusal::Connections));
+		assert!(guard.admit(ip("2001:db8:1:3::1"), &protection).is_ok());
+		}
+	}
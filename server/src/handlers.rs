@@ -0,0 +1,478 @@
+// Message Handlers
+//
+// Handlers run on the blocking thread pool and return the response to send back to the client.
+
+use regex::Regex;
+use uuid::Uuid;
+use luminum_proto::{ClientMessage, ServerMessage, Request, Response, RegisterRequest, RegisterResponse, IntegrityConfigResponse, IntegrityEvent, IntegrityEventReceipt, Heartbeat, CommandResult, CommandReceipt, ListenResponse, Lumy, Status, PRODUCT_CLIENT, UID_NONE};
+use crate::listener::{ServerState, Session};
+use crate::storage::{AttributeChange, Endpoint, FileEvent, Presence, Storage, StorageError};
+use luminum_log::SECURITY;
+use tracing::{debug, info, warn};
+use crate::audit::{self, Event};
+use crate::guard::Failure;
+use crate::{enroll, metrics, presence, push, VER};
+
+// Longest attribute value accepted from a heartbeat
+const MAX_ATTRIBUTE_LEN: usize = 255;
+// Most Integrity events accepted in one message, and the longest path recorded
+const MAX_EVENTS: usize = 1000;
+const MAX_PATH_LEN: usize = 4096;
+
+// Dispatch a decoded client message to its handler
+pub fn handle_message(state: &ServerState, session: &Session, msg: ClientMessage) -> ServerMessage {
+	let peer_addr = session.peer_addr;
+	if msg.product != PRODUCT_CLIENT || !valid_uid(&msg.uid) {
+		warn!(peer = %peer_addr, "Invalid client identification");
+		state.guard.fail(peer_addr.ip(), Failure::Malformed, &state.protection());
+		return ServerMessage::error(VER,Status::Denied,"Invalid client identification");
+		}
+
+	// Everything except registration must come from the endpoint the session authenticated as
+	let mut verified = None;
+	if !matches!(msg.content.request, Request::Register(_)) {
+		match verify_client(state.storage.as_ref(), session, &msg.uid) {
+			Ok(endpoint) => { verified = Some(endpoint); },
+			Err(VerifyError::Denied(reason)) => {
+				security_event(state.storage.as_ref(), session, &msg.uid, &reason);
+				state.guard.fail(peer_addr.ip(), Failure::Identity, &state.protection());
+				return ServerMessage::error(VER,Status::Denied,"Endpoint verification failed");
+				},
+			Err(VerifyError::Storage(err)) => {
+				warn!(uid = %msg.uid, "Unable to verify endpoint: {}", err);
+				return ServerMessage::error(VER,Status::Error,"Unable to verify endpoint");
+				}
+			}
+		}
+
+	match msg.content.request {
+		Request::Heartbeat(data) => {
+			debug!(uid = %msg.uid, peer = %peer_addr, "Received heartbeat");
+			metrics::HEARTBEATS.inc();
+			client_heartbeat(state,verified.unwrap_or_default(),data)
+			},
+		Request::Register(data) if msg.uid == UID_NONE => {
+			debug!(peer = %peer_addr, "Received endpoint registration request");
+			let _registration = match state.guard.register(peer_addr.ip(), &state.protection()) {
+				Ok(registration) => registration,
+				Err(_) => {
+					metrics::REGISTRATIONS.with_label_values(&["limited"]).inc();
+					return ServerMessage::error(VER,Status::Denied,"Too many registration attempts. Try again later.");
+					}
+				};
+			let response = register_client(state,session,data);
+			let result = match response.content.status {
+				Status::Ok => "accepted",
+				Status::Denied => {
+					state.guard.fail(peer_addr.ip(), Failure::Enrollment, &state.protection());
+					"denied"
+					},
+				_ => "error"
+				};
+			metrics::REGISTRATIONS.with_label_values(&[result]).inc();
+			response
+			},
+		Request::Register(_) => {
+			warn!(uid = %msg.uid, peer = %peer_addr, "Registration request from an already registered endpoint");
+			audit::record(state.storage.as_ref(), Event::EnrollmentDenied, &format!("endpoint {}", msg.uid), &peer_addr.ip().to_string(), "Registration request from an already registered endpoint");
+			metrics::REGISTRATIONS.with_label_values(&["denied"]).inc();
+			ServerMessage::error(VER,Status::Denied,"Endpoint is already registered")
+			},
+		Request::IntegrityConfig(_) if !state.tunables().modules.integrity => {
+			warn!(uid = %msg.uid, lumy = ?Lumy::Integrity, "Integrity Lumy configuration requested, but the module is disabled");
+			ServerMessage::error(VER,Status::Denied,"The Integrity module is not enabled on this server")
+			},
+		Request::IntegrityConfig(_) => {
+			debug!(uid = %msg.uid, peer = %peer_addr, lumy = ?Lumy::Integrity, "Received Integrity Lumy configuration request");
+			integrity_config(state.storage.as_ref(),&msg.uid,msg.content.status)
+			},
+		// The listener switches the session to push mode once this is acknowledged
+		Request::Listen(_) => {
+			debug!(uid = %msg.uid, peer = %peer_addr, "Endpoint is listening for commands");
+			ServerMessage::new(VER,Lumy::ServerCore,Status::Ok,Response::Listen(ListenResponse { keepalive: push::KEEPALIVE.as_secs() }))
+			},
+		Request::CommandResult(result) => {
+			command_result(state.storage.as_ref(),&msg.uid,result)
+			},
+		Request::IntegrityEvents(_) if !state.tunables().modules.integrity => {
+			warn!(uid = %msg.uid, lumy = ?Lumy::Integrity, "Integrity events received, but the module is disabled");
+			ServerMessage::error(VER,Status::Denied,"The Integrity module is not enabled on this server")
+			},
+		Request::IntegrityEvents(data) => {
+			debug!(uid = %msg.uid, peer = %peer_addr, lumy = ?Lumy::Integrity, "Received {} Integrity events", data.events.len());
+			integrity_events(state.storage.as_ref(),&msg.uid,data.events)
+			}
+		}
+	}
+
+enum VerifyError {
+	Denied(String),
+	Storage(StorageError)
+	}
+
+// Verify a message against the session credentials: the client certificate must have been
+// issued to the claimed UID, and that UID must be a registered, unrevoked endpoint whose
+// certificate on record is the one presented.
+fn verify_client(storage: &dyn Storage, session: &Session, uid: &str) -> Result<Endpoint, VerifyError> {
+	let cert = session.certificate.as_ref().ok_or(VerifyError::Denied(String::from("no client certificate presented")))?;
+	if cert.uid != uid {
+		return Err(VerifyError::Denied(format!("certificate was issued to UID \"{}\"", cert.uid)));
+		}
+	match storage.find_endpoint(uid).map_err(VerifyError::Storage)? {
+		None => Err(VerifyError::Denied(String::from("UID is not registered"))),
+		Some(endpoint) if endpoint.revoked => Err(VerifyError::Denied(String::from("endpoint has been revoked"))),
+		Some(endpoint) if endpoint.cert_fingerprint.as_deref() != Some(cert.fingerprint.as_str()) => {
+			Err(VerifyError::Denied(format!("certificate {} is not the one on record", cert.fingerprint)))
+			},
+		Some(endpoint) => Ok(endpoint)
+		}
+	}
+
+// Security events are always logged, whatever the log level, and audited
+fn security_event(storage: &dyn Storage, session: &Session, uid: &str, reason: &str) {
+	warn!(target: SECURITY, uid = %uid, peer = %session.peer_addr, "Rejected message: {}", reason);
+	audit::record(storage, Event::IdentityMismatch, &format!("endpoint {}", uid), &session.peer_addr.ip().to_string(), &format!("Rejected message: {}", reason));
+	}
+
+// Record a heartbeat, updating any attributes the endpoint reports as changed. The response
+// carries the server certificates the endpoint should trust.
+fn client_heartbeat(state: &ServerState, mut endpoint: Endpoint, data: Heartbeat) -> ServerMessage {
+	let storage = state.storage.as_ref();
+	let now = enroll::now();
+	let mut changes = Vec::new();
+	let reported = [
+		("hostname", &mut endpoint.hostname, data.hostname),
+		("osplat", &mut endpoint.osplat, data.osplat),
+		("osver", &mut endpoint.osver, data.osver),
+		("ipv4", &mut endpoint.ipv4, data.ipv4),
+		("ipv6", &mut endpoint.ipv6, data.ipv6)
+		];
+	for (attribute, current, value) in reported {
+		let value = match value.as_deref().map(str::trim) {
+			Some(value) if !value.is_empty() && value != current.as_str() => value.to_string(),
+			_ => continue
+			};
+		if value.len() > MAX_ATTRIBUTE_LEN {
+			warn!(uid = %endpoint.uid, "Ignoring oversized {}", attribute);
+			continue;
+			}
+		debug!(uid = %endpoint.uid, "Endpoint {} changed from \"{}\" to \"{}\"", attribute, current, value);
+		changes.push(AttributeChange {
+			uid: endpoint.uid.clone(),
+			attribute: attribute.to_string(),
+			old_value: std::mem::replace(current, value.clone()),
+			new_value: value,
+			changed: now
+			});
+		}
+	endpoint.last_seen = now;
+
+	if let Err(err) = storage.record_heartbeat(&endpoint, &changes) {
+		warn!(uid = %endpoint.uid, "Failed to update heartbeat: {}", err);
+		return ServerMessage::error(VER,Status::Error,"Unable to record heartbeat");
+		}
+	match presence::transition(storage, &endpoint.uid, endpoint.presence, Presence::Online, now) {
+		Ok(Some(event)) => { presence::log_event(&event); },
+		Ok(None) => {},
+		Err(err) => { warn!(uid = %endpoint.uid, "Failed to record presence: {}", err); }
+		}
+	let acknowledgement = Heartbeat {
+		server_certificates: Some(state.identity.trusted_certificates(now).to_string()),
+		..Heartbeat::default()
+		};
+	ServerMessage::new(VER,Lumy::ServerCore,Status::Ok,Response::Heartbeat(acknowledgement))
+	}
+
+fn register_client(state: &ServerState, session: &Session, data: RegisterRequest) -> ServerMessage {
+	let storage = state.storage.as_ref();
+	let new_uid = Uuid::new_v4().to_string();
+	let source = session.peer_addr.ip().to_string();
+	let hostname = data.hostname.clone();
+	let denied = |reason: &str| audit::record(storage, Event::EnrollmentDenied, "unregistered endpoint", &source, &format!("Endpoint \"{}\": {}", hostname, reason));
+
+	// The token is checked before anything is signed, but only used up once the endpoint is stored
+	let token = match enroll::check(storage, &state.master_key, data.serverkey.as_deref().unwrap_or_default()) {
+		Ok(token) => token,
+		Err(reason) => {
+			warn!(target: SECURITY, peer = %session.peer_addr, "Enrollment of endpoint \"{}\" denied: {}", data.hostname, reason);
+			denied(&format!("Invalid enrollment token: {}", reason));
+			return ServerMessage::error(VER,Status::Denied,"Invalid enrollment token");
+			}
+		};
+
+	// Issue the endpoint's client certificate
+	let csr = match data.csr {
+		Some(csr) => csr,
+		None => {
+			warn!("Registration request for endpoint \"{}\" did not include a certificate signing request", data.hostname);
+			denied("No certificate signing request");
+			return ServerMessage::error(VER,Status::Denied,"Certificate signing request required");
+			}
+		};
+	let (certificate, fingerprint) = match state.client_ca.sign_csr(&csr, &new_uid) {
+		Ok(signed) => signed,
+		Err(err) => {
+			warn!("Unable to sign certificate request for endpoint \"{}\": {}", data.hostname, err);
+			denied(&format!("Invalid certificate signing request: {}", err));
+			return ServerMessage::error(VER,Status::Denied,"Invalid certificate signing request");
+			}
+		};
+
+	let endpoint = Endpoint {
+		uid: new_uid,
+		hostname: data.hostname,
+		ipv4: data.ipv4.unwrap_or_default(),
+		ipv6: data.ipv6.unwrap_or_default(),
+		osplat: data.osplat,
+		osver: data.osver,
+		cert_fingerprint: Some(fingerprint),
+		token_id: Some(token.id),
+		groups: token.groups,
+		revoked: false,
+		last_seen: enroll::now(),
+		presence: Presence::Online
+		};
+	let token_id = endpoint.token_id.as_deref().unwrap_or_default();
+	match storage.enroll_endpoint(&endpoint, enroll::now()) {
+		Ok(true) => {
+			info!(uid = %endpoint.uid, peer = %session.peer_addr, "Endpoint \"{}\" successfully registered with enrollment token {}", endpoint.hostname, token_id);
+			audit::record(storage, Event::Registration, &format!("endpoint {}", endpoint.uid), &source,
+				&format!("Endpoint \"{}\" registered with enrollment token {}", endpoint.hostname, token_id));
+			ServerMessage::new(VER,Lumy::ServerCore,Status::Ok,Response::Register(RegisterResponse { uid: endpoint.uid, certificate: Some(certificate) }))
+			},
+		// Another registration may have used the last slot, or the token was revoked, since it was checked
+		Ok(false) => {
+			warn!(target: SECURITY, peer = %session.peer_addr, "Enrollment of endpoint \"{}\" denied: enrollment token {} is no longer usable", endpoint.hostname, token_id);
+			denied(&format!("Invalid enrollment token: enrollment token {} is no longer usable", token_id));
+			ServerMessage::error(VER,Status::Denied,"Invalid enrollment token")
+			},
+		Err(err) => {
+			warn!("Failed to register endpoint \"{}\": {}", endpoint.hostname,err);
+			denied(&format!("Unable to save endpoint: {}", err));
+			ServerMessage::error(VER,Status::Error,"Registration failed")
+			}
+		}
+	}
+
+fn integrity_config(storage: &dyn Storage, uid: &str, status: Status) -> ServerMessage {
+	if status == Status::New {
+		if let Err(err) = storage.reset_watchlist(uid) {
+			warn!(uid = %uid, lumy = ?Lumy::Integrity, "Failed to save Integrity Lumy configuration: {}", err);
+			return ServerMessage::error(VER,Status::Error,"Unable to save Integrity configuration");
+			}
+		info!(uid = %uid, lumy = ?Lumy::Integrity, "Saved Integrity Lumy configuration");
+		}
+
+	match storage.watchlist(uid) {
+		Ok(paths) => { ServerMessage::new(VER,Lumy::Integrity,Status::Ok,Response::IntegrityConfig(IntegrityConfigResponse { paths })) },
+		Err(err) => {
+			warn!(uid = %uid, lumy = ?Lumy::Integrity, "Unable to retrieve saved Integrity configuration: {}", err);
+			ServerMessage::error(VER,Status::Error,"Unable to retrieve Integrity configuration")
+			}
+		}
+	}
+
+// Store the file changes an endpoint reports. Events with an unusable path are dropped
+// rather than failing the whole batch.
+fn integrity_events(storage: &dyn Storage, uid: &str, events: Vec<IntegrityEvent>) -> ServerMessage {
+	if events.len() > MAX_EVENTS {
+		warn!(uid = %uid, lumy = ?Lumy::Integrity, "Rejected {} Integrity events in one message", events.len());
+		return ServerMessage::error(VER,Status::Denied,"Too many Integrity events in one message");
+		}
+	let received = enroll::now();
+	let limit = |value: Option<String>| value.filter(|value| value.len() <= MAX_ATTRIBUTE_LEN);
+	let events: Vec<FileEvent> = events.into_iter()
+		.filter(|event| !event.path.is_empty() && event.path.len() <= MAX_PATH_LEN)
+		.map(|event| FileEvent {
+			uid: uid.to_string(),
+			kind: event.kind,
+			path: event.path,
+			file_type: event.file_type,
+			permissions: limit(event.permissions),
+			owner: limit(event.owner),
+			size: event.size,
+			at: event.at,
+			received
+			})
+		.collect();
+	match storage.add_file_events(&events) {
+		Ok(()) => ServerMessage::new(VER,Lumy::Integrity,Status::Ok,Response::IntegrityEvents(IntegrityEventReceipt { accepted: events.len() as u32 })),
+		Err(err) => {
+			warn!(uid = %uid, lumy = ?Lumy::Integrity, "Failed to record Integrity events: {}", err);
+			ServerMessage::error(VER,Status::Error,"Unable to record Integrity events")
+			}
+		}
+	}
+
+fn command_result(storage: &dyn Storage, uid: &str, result: CommandResult) -> ServerMessage {
+	match storage.complete_command(uid, &result.id, result.success, &result.output, enroll::now()) {
+		Ok(true) => {
+			let outcome = if result.success { "succeeded" } else { "failed" };
+			info!(uid = %uid, "Command {} {}", result.id, outcome);
+			ServerMessage::new(VER,Lumy::ServerCore,Status::Ok,Response::CommandResult(CommandReceipt { id: result.id }))
+			},
+		Ok(false) => {
+			warn!(uid = %uid, "Result received for unknown command {}", result.id);
+			ServerMessage::error(VER,Status::Denied,"Unknown command")
+			},
+		Err(err) => {
+			warn!(uid = %uid, "Failed to record result of command {}: {}", result.id, err);
+			ServerMessage::error(VER,Status::Error,"Unable to record command result")
+			}
+		}
+	}
+
+fn valid_uid(input: &str) -> bool {
+	let re = Regex::new(r"^[a-zA-Z0-9_-]+$").unwrap();
+	re.is_match(input)
+	}
+
+#[cfg(test)]
+mod tests {
+	use luminum_proto::{ClientMessage, Heartbeat, Lumy, RegisterRequest, Request, Response, ServerMessage, Status, UID_NONE};
+	use crate::testing::{self, Fixture};
+	use crate::storage::Storage;
+	use crate::VER;
+	use super::handle_message;
+
+	const PEER: &str = "192.0.2.10:40000";
+
+	fn register(fixture: &Fixture, token: &str, csr: Option<String>) -> ServerMessage {
+		let request = RegisterRequest {
+			serverkey: Some(token.to_string()),
+			hostname: String::from("host01"),
+			osplat: String::from("Linux"),
+			osver: String::from("6.1"),
+			ipv4: Some(String::from("192.0.2.10")),
+			csr,
+			..RegisterRequest::default()
+			};
+		let msg = ClientMessage::new(UID_NONE, VER, Lumy::ServerCore, Status::New, Request::Register(request));
+		handle_message(&fixture.state, &testing::session(PEER, None), msg)
+		}
+
+	// Register an endpoint, returning its UID and client certificate
+	fn registered(fixture: &Fixture) -> (String, String) {
+		let (_, presented) = fixture.token(|_| {});
+		match register(fixture, &presented, Some(testing::csr())).content.response {
+			Response::Register(response) => (response.uid, response.certificate.unwrap()),
+			other => panic!("registration failed: {:?}", other)
+			}
+		}
+
+	fn heartbeat(fixture: &Fixture, uid: &str, certificate: &str, data: Heartbeat) -> ServerMessage {
+		let msg = ClientMessage::new(uid, VER, Lumy::ServerCore, Status::Ok, Request::Heartbeat(data));
+		handle_message(&fixture.state, &testing::session(PEER, Some(certificate)), msg)
+		}
+
+	fn audit_events(storage: &dyn Storage) -> Vec<String> {
+		storage.audit_chain(0, 100).unwrap().into_iter().map(|record| record.event).collect()
+		}
+
+	#[test]
+	fn registers_with_a_valid_token() {
+		let fixture = Fixture::new();
+		let (token, presented) = fixture.token(|_| {});
+		let response = register(&fixture, &presented, Some(testing::csr()));
+		assert_eq!(response.content.status, Status::Ok);
+		let uid = match response.content.response {
+			Response::Register(response) => response.uid,
+			other => panic!("unexpected response: {:?}", other)
+			};
+
+		let endpoint = fixture.storage().find_endpoint(&uid).unwrap().unwrap();
+		assert_eq!(endpoint.hostname, "host01");
+		assert_eq!(endpoint.token_id.as_deref(), Some(token.id.as_str()));
+		assert_eq!(endpoint.groups, vec![String::from("web")]);
+		assert!(endpoint.cert_fingerprint.is_some());
+		assert_eq!(fixture.storage().find_token(&token.id).unwrap().unwrap().uses, 1);
+		assert_eq!(audit_events(fixture.storage()), vec!["registration"]);
+		}
+
+	#[test]
+	fn rejects_an_expired_token() {
+		let fixture = Fixture::new();
+		let (token, presented) = fixture.token(|token| { token.expires = Some(token.created - 1); });
+		assert_eq!(register(&fixture, &presented, Some(testing::csr())).content.status, Status::Denied);
+		assert!(fixture.storage().list_endpoints().unwrap().is_empty());
+		assert_eq!(fixture.storage().find_token(&token.id).unwrap().unwrap().uses, 0);
+		assert_eq!(audit_events(fixture.storage()), vec!["enrollment-denied"]);
+		}
+
+	#[test]
+	fn rejects_an_exhausted_token() {
+		let fixture = Fixture::new();
+		let (token, presented) = fixture.token(|token| { token.max_uses = Some(1); });
+		assert_eq!(register(&fixture, &presented, Some(testing::csr())).content.status, Status::Ok);
+		assert_eq!(register(&fixture, &presented, Some(testing::csr())).content.status, Status::Denied);
+		assert_eq!(fixture.storage().list_endpoints().unwrap().len(), 1);
+		assert_eq!(fixture.storage().find_token(&token.id).unwrap().unwrap().uses, 1);
+		}
+
+	#[test]
+	fn rejects_a_revoked_token() {
+		let fixture = Fixture::new();
+		let (token, presented) = fixture.token(|_| {});
+		fixture.storage().revoke_token(&token.id).unwrap();
+		assert_eq!(register(&fixture, &presented, Some(testing::csr())).content.status, Status::Denied);
+		assert!(fixture.storage().list_endpoints().unwrap().is_empty());
+		}
+
+	#[test]
+	fn checks_the_token_before_the_csr() {
+		let fixture = Fixture::new();
+		let (token, presented) = fixture.token(|_| {});
+		let wrong_secret = format!("{}.{}", token.id, "0".repeat(40));
+		match register(&fixture, &wrong_secret, Some(String::from("not a CSR"))).content.response {
+			Response::Error(error) => assert_eq!(error.message, "Invalid enrollment token"),
+			other => panic!("unexpected response: {:?}", other)
+			}
+		// A bad CSR with a good token doesn't use the token up
+		assert_eq!(register(&fixture, &presented, Some(String::from("not a CSR"))).content.status, Status::Denied);
+		assert_eq!(fixture.storage().find_token(&token.id).unwrap().unwrap().uses, 0);
+		}
+
+	#[test]
+	fn rejects_messages_from_another_endpoints_certificate() {
+		let fixture = Fixture::new();
+		let (uid, _) = registered(&fixture);
+		let (_, other_certificate) = registered(&fixture);
+		let response = heartbeat(&fixture, &uid, &other_certificate, Heartbeat::default());
+		assert_eq!(response.content.status, Status::Denied);
+		assert_eq!(audit_events(fixture.storage()).last().map(String::as_str), Some("identity-mismatch"));
+		}
+
+	#[test]
+	fn rejects_messages_from_a_revoked_endpoint() {
+		let fixture = Fixture::new();
+		let (uid, certificate) = registered(&fixture);
+		fixture.storage().revoke_endpoint(&uid).unwrap();
+		assert_eq!(heartbeat(&fixture, &uid, &certificate, Heartbeat::default()).content.status, Status::Denied);
+		}
+
+	#[test]
+	fn heartbeats_refresh_changed_attributes() {
+		let fixture = Fixture::new();
+		let (uid, certificate) = registered(&fixture);
+		let data = Heartbeat {
+			hostname: Some(String::from("host02")),
+			ipv4: Some(String::from("192.0.2.10")),
+			osver: Some(String::from(" ")),
+			..Heartbeat::default()
+			};
+		let response = heartbeat(&fixture, &uid, &certificate, data);
+		assert_eq!(response.content.status, Status::Ok);
+		match response.content.response {
+			Response::Heartbeat(acknowledgement) => assert!(acknowledgement.server_certificates.is_some()),
+			other => panic!("unexpected response: {:?}", other)
+			}
+
+		let endpoint = fixture.storage().find_endpoint(&uid).unwrap().unwrap();
+		assert_eq!(endpoint.hostname, "host02");
+		assert_eq!(endpoint.osver, "6.1");
+		// Only the hostname actually changed
+		let history = fixture.storage().attribute_history(&uid).unwrap();
+		assert_eq!(history.len(), 1);
+		assert_eq!((history[0].attribute.as_str(), history[0].old_value.as_str(), history[0].new_value.as_str()), ("hostname", "host01", "host02"));
+		}
+	}
@@ -0,0 +1,362 @@
+// Data Listener
+//
+// Accepts client connections on every listen address and serves each one on its own task. A
+// semaphore shared by all addresses caps the number of concurrent connections; when it is
+// exhausted the accept loops wait for a slot, leaving new connections in the kernel backlog
+// instead of starving existing sessions.
+//
+// Sessions are multiplexed: each request is handled on its own task and responses are written
+// as they complete, tagged with the request ID the client sent. Once an endpoint asks to
+// listen, its session also carries commands pushed by the server.
+//
+// When the server stops, the accept loops end and sessions stop reading new requests. The
+// requests already read are answered before the session closes.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::time::timeout;
+use openssl::ssl::{ErrorCode, Ssl};
+use tokio_openssl::SslStream;
+use luminum_proto::{ClientMessage, FrameError, Heartbeat, Lumy, Request, Response, ServerMessage, Status, read_message_async, write_message_async};
+use tracing::{debug, error, info, warn};
+use crate::VER;
+use crate::config::{Settings, Tunables};
+use crate::enroll::now;
+use crate::guard::{Admission, Failure, Guard, Protection};
+use crate::handlers::handle_message;
+use crate::metrics;
+use crate::presence::Thresholds;
+use crate::push::{self, Channels};
+use crate::secrets::MasterKey;
+use crate::shutdown::{Activity, Shutdown};
+use crate::storage::Storage;
+use crate::tls::{ClientCa, PeerCertificate, ServerIdentity, peer_certificate};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+	pub max_frame: usize,
+	pub max_connections: usize,
+	pub handshake_timeout: Duration,
+	pub read_timeout: Duration,
+	pub idle_timeout: Duration,
+	// Requests from one session handled at the same time
+	pub max_in_flight: usize,
+	// How long sessions get to finish when the server stops
+	pub drain_timeout: Duration
+	}
+
+pub struct ServerState {
+	pub storage: Box<dyn Storage>,
+	pub client_ca: ClientCa,
+	pub identity: ServerIdentity,
+	// Unseals enrollment keys
+	pub master_key: MasterKey,
+	pub channels: Channels,
+	// Per-source limits and bans on the data port
+	pub guard: Guard,
+	// Settings that can be reloaded while the server runs
+	pub tunables: RwLock<Tunables>,
+	// The running configuration, as reported by the admin API
+	pub settings: RwLock<Settings>
+	}
+
+impl ServerState {
+	pub fn tunables(&self) -> Tunables {
+		*self.tunables.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+		}
+
+	pub fn set_tunables(&self, tunables: Tunables) {
+		*self.tunables.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = tunables;
+		}
+
+	pub fn settings(&self) -> Settings {
+		self.settings.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+		}
+
+	pub fn set_settings(&self, settings: Settings) {
+		*self.settings.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = settings;
+		}
+
+	pub fn limits(&self) -> Limits {
+		self.tunables().limits
+		}
+
+	pub fn thresholds(&self) -> Thresholds {
+		self.tunables().presence
+		}
+
+	pub fn protection(&self) -> Protection {
+		self.tunables().protection
+		}
+	}
+
+// Responses and pushed messages waiting to be written to a single session
+const OUTGOING_DEPTH: usize = 64;
+
+// Connection details handed to message handlers
+pub struct Session {
+	pub peer_addr: SocketAddr,
+	pub certificate: Option<PeerCertificate>
+	}
+
+pub async fn run(listeners: Vec<TcpListener>, state: Arc<ServerState>, shutdown: Shutdown) {
+	// The connection cap is fixed when the server starts
+	let max_connections = state.limits().max_connections;
+	let slots = Arc::new(Semaphore::new(max_connections));
+
+	let accepting: Vec<_> = listeners.into_iter().map(|listener| {
+		tokio::spawn(accept_connections(listener, state.clone(), slots.clone(), max_connections, shutdown.clone()))
+		}).collect();
+	for task in accepting {
+		let _ = task.await;
+		}
+	}
+
+async fn accept_connections(listener: TcpListener, state: Arc<ServerState>, slots: Arc<Semaphore>, max_connections: usize, shutdown: Shutdown) {
+	while shutdown.is_running() {
+		// Wait for a free connection slot before accepting more work
+		if slots.available_permits() == 0 {
+			warn!("Connection limit ({}) reached. Deferring new connections.", max_connections);
+			}
+		let permit = tokio::select! {
+			permit = slots.clone().acquire_owned() => match permit {
+				Ok(permit) => permit,
+				Err(_) => { break; }
+				},
+			_ = shutdown.stopped() => { break; }
+			};
+
+		let accepted = tokio::select! {
+			accepted = listener.accept() => accepted,
+			_ = shutdown.stopped() => { break; }
+			};
+		match accepted {
+			Ok((stream, peer_addr)) => {
+				debug!(peer = %peer_addr, "Incoming connection");
+				// Refused connections are closed before the TLS handshake
+				let admission = match state.guard.admit(peer_addr.ip(), &state.protection()) {
+					Ok(admission) => admission,
+					Err(_) => { continue; }
+					};
+				metrics::CONNECTIONS.inc();
+				let state = state.clone();
+				let held = Held { _permit: permit, admission, _activity: shutdown.track() };
+				let shutdown = shutdown.clone();
+				tokio::spawn(async move {
+					handle_connection(stream, peer_addr, state, held, shutdown).await;
+					});
+				},
+			Err(err) => { warn!("Error accepting connection: {}", err); }
+			}
+		}
+	}
+
+async fn handle_connection(stream: TcpStream, peer_addr: SocketAddr, state: Arc<ServerState>, mut held: Held, shutdown: Shutdown) {
+	// Reloaded limits apply to new connections; this session keeps the ones it started with
+	let limits = state.limits();
+
+	// Accept TLS connection with the certificate currently presented
+	let mut tls_stream = match Ssl::new(state.identity.acceptor(now()).context()).and_then(|ssl| SslStream::new(ssl, stream)) {
+		Ok(tls_stream) => tls_stream,
+		Err(err) => {
+			warn!(peer = %peer_addr, "Error setting up TLS session: {}", err);
+			return;
+			}
+		};
+	let handshake = timeout(limits.handshake_timeout, Pin::new(&mut tls_stream).accept()).await;
+	held.admission.handshake_done();
+	match handshake {
+		Ok(Ok(())) => {
+			info!(peer = %peer_addr, "Connection established");
+			},
+		Ok(Err(err)) => {
+			warn!(peer = %peer_addr, "Error accepting TLS connection: {}", err);
+			metrics::HANDSHAKE_FAILURES.with_label_values(&["error"]).inc();
+			// Peers that close before finishing a handshake, such as TCP health checks, cost little
+			if !(err.code() == ErrorCode::SYSCALL && err.io_error().is_none()) {
+				state.guard.fail(peer_addr.ip(), Failure::Handshake, &state.protection());
+				}
+			return;
+			},
+		Err(_) => {
+			warn!(peer = %peer_addr, "TLS handshake timed out");
+			metrics::HANDSHAKE_FAILURES.with_label_values(&["timeout"]).inc();
+			// Stalled handshakes hold a slot for the whole timeout, so they count against the source
+			state.guard.fail(peer_addr.ip(), Failure::Handshake, &state.protection());
+			return;
+			}
+		}
+	let session = Arc::new(Session {
+		peer_addr,
+		certificate: peer_certificate(tls_stream.ssl())
+		});
+	if let Some(cert) = &session.certificate {
+		debug!(peer = %peer_addr, uid = %cert.uid, "Client certificate presented");
+		}
+	let (reader, writer) = tokio::io::split(tls_stream);
+	let mut reader = BufReader::new(reader);
+
+	// Everything sent to the client goes through the writer task
+	let (outgoing, queue) = mpsc::channel(OUTGOING_DEPTH);
+	let listening = Arc::new(AtomicBool::new(false));
+	tokio::spawn(write_messages(writer, queue, limits, peer_addr, listening.clone(), held));
+	let in_flight = Arc::new(Semaphore::new(limits.max_in_flight));
+	let mut channel: Option<(String, u64)> = None;
+
+	// Handle framed messages until the client closes the session or the server stops
+	loop {
+		// Wait for the start of the next message, then give the client a bounded time to send the rest.
+		// Listening sessions stay open indefinitely.
+		let idle_limit = if channel.is_some() { None } else { Some(limits.idle_timeout) };
+		let ready = tokio::select! {
+			_ = outgoing.closed() => { break; },
+			_ = shutdown.stopped() => {
+				debug!(peer = %peer_addr, "Closing session for shutdown");
+				break;
+				},
+			ready = async {
+				match idle_limit {
+					Some(idle_timeout) => timeout(idle_timeout, reader.fill_buf()).await.map(|result| result.map(|buf| buf.is_empty())),
+					None => Ok(reader.fill_buf().await.map(|buf| buf.is_empty()))
+					}
+				} => ready
+			};
+		match ready {
+			Ok(Ok(true)) => { break; },
+			Ok(Ok(false)) => {},
+			Ok(Err(err)) => {
+				warn!(peer = %peer_addr, "Error reading from stream: {}", err);
+				break;
+				},
+			Err(_) => {
+				debug!(peer = %peer_addr, "Session idle for {} seconds", limits.idle_timeout.as_secs());
+				break;
+				}
+			}
+
+		let msg = match timeout(limits.read_timeout, read_message_async::<_, ClientMessage>(&mut reader, limits.max_frame)).await {
+			Ok(Ok(Some(msg))) => msg,
+			Ok(Ok(None)) => { break; },
+			Ok(Err(FrameError::Io(err))) => {
+				warn!(peer = %peer_addr, "Error reading from stream: {}", err);
+				break;
+				},
+			Ok(Err(_)) => {
+				warn!(peer = %peer_addr, "Malformed data in stream");
+				metrics::MALFORMED_MESSAGES.inc();
+				state.guard.fail(peer_addr.ip(), Failure::Malformed, &state.protection());
+				break;
+				},
+			Err(_) => {
+				warn!(peer = %peer_addr, "Timed out reading message");
+				break;
+				}
+			};
+
+		// Requests to listen are handled in order, so pushed messages can't overtake the acknowledgement
+		if matches!(msg.content.request, Request::Listen(_)) {
+			let uid = msg.uid.clone();
+			let response = match respond(&state, &session, msg).await {
+				Some(response) => response,
+				None => { break; }
+				};
+			let accepted = matches!(response.content.response, Response::Listen(_));
+			if outgoing.send(response).await.is_err() { break; }
+			if accepted && channel.is_none() {
+				channel = Some((uid.clone(), state.channels.open(&uid, outgoing.clone())));
+				listening.store(true, Ordering::SeqCst);
+				}
+			continue;
+			}
+
+		// Everything else runs concurrently, up to the session's in-flight limit
+		let permit = match in_flight.clone().acquire_owned().await {
+			Ok(permit) => permit,
+			Err(_) => { break; }
+			};
+		let handler_state = state.clone();
+		let handler_session = session.clone();
+		let handler_outgoing = outgoing.clone();
+		tokio::spawn(async move {
+			if let Some(response) = respond(&handler_state, &handler_session, msg).await {
+				let _ = handler_outgoing.send(response).await;
+				}
+			drop(permit);
+			});
+		}
+	if let Some((uid, id)) = channel {
+		state.channels.close(&uid, id);
+		}
+	debug!(peer = %peer_addr, "Connection closed");
+	}
+
+// Run the handler for a message, tagging the response with the message's request ID
+async fn respond(state: &Arc<ServerState>, session: &Arc<Session>, msg: ClientMessage) -> Option<ServerMessage> {
+	let request_id = msg.request_id;
+	let lumy = msg.content.lumy;
+	let handler = metrics::handler(&msg.content.request);
+	let handler_state = state.clone();
+	let handler_session = session.clone();
+	// Database handlers are blocking, so keep them off the async worker threads
+	let handled = tokio::task::spawn_blocking(move || {
+		let started = Instant::now();
+		let response = handle_message(&handler_state, &handler_session, msg);
+		metrics::observe_handler(handler, started.elapsed());
+		response
+		});
+	match handled.await {
+		Ok(mut response) => {
+			response.request_id = request_id;
+			Some(response)
+			},
+		Err(err) => {
+			error!(peer = %session.peer_addr, lumy = ?lumy, "Message handler failed: {}", err);
+			None
+			}
+		}
+	}
+
+// What a connection holds until it closes: its connection slot, its place in the per-source
+// limits and its part in keeping the server from stopping
+struct Held {
+	_permit: OwnedSemaphorePermit,
+	admission: Admission,
+	_activity: Activity
+	}
+
+// Write queued messages to the client until every sender is gone or a write fails. Idle
+// listening sessions get a keepalive heartbeat. What the connection holds is released when
+// this ends.
+async fn write_messages(mut writer: WriteHalf<SslStream<TcpStream>>, mut queue: Receiver<ServerMessage>, limits: Limits, peer_addr: SocketAddr, listening: Arc<AtomicBool>, _held: Held) {
+	loop {
+		let msg = match timeout(push::KEEPALIVE, queue.recv()).await {
+			Ok(Some(msg)) => msg,
+			Ok(None) => { break; },
+			Err(_) if listening.load(Ordering::SeqCst) => ServerMessage::new(VER,Lumy::ServerCore,Status::Online,Response::Heartbeat(Heartbeat::default())),
+			Err(_) => { continue; }
+			};
+		if !send_message(&mut writer, &msg, &limits, peer_addr).await { break; }
+		}
+	}
+
+// Write a message to the client, returning false if the session should be closed
+async fn send_message(writer: &mut WriteHalf<SslStream<TcpStream>>, msg: &ServerMessage, limits: &Limits, peer_addr: SocketAddr) -> bool {
+	match timeout(limits.read_timeout, write_message_async(writer, msg, limits.max_frame)).await {
+		Ok(Ok(())) => true,
+		Ok(Err(err)) => {
+			warn!(peer = %peer_addr, "Failed to send message: {}", err);
+			false
+			},
+		Err(_) => {
+			warn!(peer = %peer_addr, "Timed out sending message");
+			false
+			}
+		}
+	}
@@ -1,89 +1,75 @@
 use chrono::Local;
-use chrono::format::strftime::StrftimeItems;
 use std::collections::HashMap;
 use std::env;
+use std::error::Error;
 use std::str;
 use std::fs::{self, File};
 use std::path::Path;
-use std::io::{self, BufRead, Read, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc};
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, RwLock};
 use std::process;
-use std::net::{TcpListener, SocketAddr, TcpStream, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, SocketAddr, Ipv4Addr, Ipv6Addr};
 use libc::setuid;
-use random_str;
-use magic_crypt::{new_magic_crypt, MagicCryptTrait};
 use clap::{Arg, App};
 use regex::Regex;
-use colored::Colorize;
-use rpassword;
 use rusqlite::{params, Connection, Result};
-use mysql::*;
-use mysql::prelude::Queryable;
 use uuid::Uuid;
-use native_tls::{Identity, TlsAcceptor};
-use openssl::bn::BigNum;
-use openssl::rsa::Rsa;
 use openssl::pkey::PKey;
 use openssl::symm::Cipher;
-use openssl::error::ErrorStack;
-use openssl::pkcs12::Pkcs12;
-use openssl::x509::{X509NameBuilder, X509};
-use openssl::x509::extension::SubjectAlternativeName;
-use openssl::hash::MessageDigest;
-use openssl::asn1::Asn1Time;
+use openssl::x509::X509NameBuilder;
 use openssl::nid::Nid;
-use serde::{Deserialize, Serialize};
-use rmp_serde::{from_read, to_vec_named};
-
-const VER: &str = "0.0.1";
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio::time::timeout_at;
+use tracing::{debug, error, info, warn};
+use config::{Backend, ConfigFile, Overrides, Paths};
+use listener::ServerState;
+use secrets::{MasterKey, SecretError};
+use tls::{CertificatePolicy, ClientCa, KeyType, ServerIdentity};
+use setup::Subject;
+use shutdown::Shutdown;
+use storage::{MemoryStorage, MysqlStorage, SqliteStorage, Storage};
+
+mod access;
+mod api;
+mod audit;
+mod cli;
+mod config;
+mod console;
+mod enroll;
+mod guard;
+mod handlers;
+mod listener;
+mod metrics;
+mod presence;
+mod push;
+mod secrets;
+mod setup;
+mod shutdown;
+mod storage;
+#[cfg(test)]
+mod testing;
+mod tls;
+
+pub const VER: &str = "0.0.1";
 const CFGPATH: &str = "/opt/Luminum/LuminumServer/config/server.conf.db";
 const DKPATH: &str = "/opt/Luminum/LuminumServer/config/luminum.key";
 const DPPATH: &str = "/opt/Luminum/LuminumServer/config/luminum.pub";
 const DCPATH: &str = "/opt/Luminum/LuminumServer/config/luminum.crt";
 const DIPATH: &str = "/opt/Luminum/LuminumServer/config/luminum.pfx";
+const DDBPATH: &str = "/opt/Luminum/LuminumServer/config/luminum.db";
+const DMKPATH: &str = "/opt/Luminum/LuminumServer/config/master.key";
 const DPORT: &str = "10465";
+const MYSQL_SOCKET: &str = "/var/run/mysqld/mysqld.sock";
 
 struct Config {
 	key: String,
 	value: String
 	}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ServerMessage {
-	version: String,
-	content: MessageContent
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct ClientMessage {
-	uid: String,
-	product: String,
-	version: String,
-	content: MessageContent
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct MessageContent {
-	lumy: String,
-	status: String,
-	action: String,
-	data: MessageData
-	}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct MessageData {
-	serverkey: Option<String>,
-	hostname: Option<String>,
-	uid: Option<String>,
-	osplat: Option<String>,
-	osver: Option<String>,
-	ipv4: Option<String>,
-	ipv6: Option<String>,
-	info: Option<Vec<String>>
-	}
-
-fn main() {
+#[tokio::main]
+async fn main() {
 	// Parse command-line arguments
 	let matches = App::new("Luminum Server Daemon")
 		.version(VER)
@@ -124,12 +110,275 @@ fn main() {
 		.value_name("PORT")
 		.help("Specifies the network data port to use")
 		.takes_value(true))
+	.arg(Arg::with_name("config")
+		.long("config")
+		.value_name("CONFIG_FILE")
+		.help("Specifies the path to the server configuration file")
+		.takes_value(true))
 	.arg(Arg::with_name("setup")
 		.short('s')
 		.long("setup")
 		.value_name("SETUP")
 		.help("Set daemon configuration parameters")
 		.takes_value(false))
+	.arg(Arg::with_name("non-interactive")
+		.long("non-interactive")
+		.help("Run setup without prompting, using flags, LUMINUM_* environment variables and an optional setup file")
+		.requires("setup")
+		.takes_value(false))
+	.arg(Arg::with_name("setup-file")
+		.long("setup-file")
+		.value_name("FILE")
+		.help("JSON file of setup settings (implies --non-interactive)")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("dry-run")
+		.long("dry-run")
+		.help("Show what non-interactive setup would create or change without doing it")
+		.requires("setup")
+		.takes_value(false))
+	.arg(Arg::with_name("output")
+		.long("output")
+		.value_name("FORMAT")
+		.help("Output format for non-interactive setup")
+		.possible_values(["text", "json"])
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("storage")
+		.long("storage")
+		.value_name("BACKEND")
+		.help("Storage backend for setup (mysql, sqlite)")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("dbpath")
+		.long("dbpath")
+		.value_name("FILE")
+		.help("SQLite database path for setup")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("key-passphrase-file")
+		.long("key-passphrase-file")
+		.value_name("FILE")
+		.help("File containing the private key passphrase for setup")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("cert-country")
+		.long("cert-country")
+		.value_name("CODE")
+		.help("Two-letter country code for the server certificate")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("cert-state")
+		.long("cert-state")
+		.value_name("STATE")
+		.help("State or province for the server certificate")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("cert-locality")
+		.long("cert-locality")
+		.value_name("CITY")
+		.help("City or locality for the server certificate")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("cert-organization")
+		.long("cert-organization")
+		.value_name("ORG")
+		.help("Organization for the server certificate")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("cert-cn")
+		.long("cert-cn")
+		.value_name("NAME")
+		.help("Common name for the server certificate")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("mysql-admin")
+		.long("mysql-admin")
+		.value_name("USER")
+		.help("MySQL administrative user for setup")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("mysql-admin-password-file")
+		.long("mysql-admin-password-file")
+		.value_name("FILE")
+		.help("File containing the MySQL administrative password for setup")
+		.requires("setup")
+		.takes_value(true))
+	.arg(Arg::with_name("migrate")
+		.short('m')
+		.long("migrate")
+		.value_name("MIGRATE")
+		.help("Apply pending database schema migrations and exit")
+		.takes_value(false))
+	.arg(Arg::with_name("rotate-secrets")
+		.long("rotate-secrets")
+		.help("Create a new master key, re-encrypt the stored secrets with it and exit. The server must be stopped first.")
+		.takes_value(false))
+	.arg(Arg::with_name("rotate-enrollment-key")
+		.long("rotate-enrollment-key")
+		.help("Start hashing new enrollment tokens with a new key and exit")
+		.takes_value(false))
+	.arg(Arg::with_name("renew-cert")
+		.long("renew-cert")
+		.help("Issue a new server certificate and identity, rolled out to endpoints before it is presented, and exit")
+		.takes_value(false))
+	.arg(Arg::with_name("new-key")
+		.long("new-key")
+		.help("Replace the server key pair when renewing the certificate, using the configured key type")
+		.requires("renew-cert")
+		.takes_value(false))
+	.arg(Arg::with_name("create-token")
+		.long("create-token")
+		.help("Create an enrollment token and exit")
+		.takes_value(false))
+	.arg(Arg::with_name("expires")
+		.long("expires")
+		.value_name("LIFETIME")
+		.help("Lifetime of a new enrollment token or API key, e.g. 12h or 30d, or \"never\" [default: 7d for tokens, never for keys]")
+		.takes_value(true))
+	.arg(Arg::with_name("max-uses")
+		.long("max-uses")
+		.value_name("COUNT")
+		.help("Number of endpoints a new enrollment token may enroll [default: unlimited]")
+		.takes_value(true))
+	.arg(Arg::with_name("groups")
+		.long("groups")
+		.value_name("GROUPS")
+		.help("Comma-separated groups assigned to endpoints enrolled with a new token, or that a new operator is limited to")
+		.takes_value(true))
+	.arg(Arg::with_name("description")
+		.long("description")
+		.value_name("TEXT")
+		.help("Description of a new enrollment token or API key")
+		.takes_value(true))
+	.arg(Arg::with_name("list-tokens")
+		.long("list-tokens")
+		.help("List enrollment tokens and exit")
+		.takes_value(false))
+	.arg(Arg::with_name("revoke-token")
+		.long("revoke-token")
+		.value_name("TOKEN_ID")
+		.help("Revoke an enrollment token and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("token-endpoints")
+		.long("token-endpoints")
+		.value_name("TOKEN_ID")
+		.help("List endpoints enrolled with an enrollment token and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("create-api-key")
+		.long("create-api-key")
+		.help("Create an admin API key for luminumctl and exit")
+		.takes_value(false))
+	.arg(Arg::with_name("list-api-keys")
+		.long("list-api-keys")
+		.help("List admin API keys and exit")
+		.takes_value(false))
+	.arg(Arg::with_name("revoke-api-key")
+		.long("revoke-api-key")
+		.value_name("KEY_ID")
+		.help("Revoke an admin API key and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("scopes")
+		.long("scopes")
+		.value_name("SCOPES")
+		.help("Comma-separated permissions of a new API key: read, ask, configure, deploy, endpoints, enrollment, admin [default: all]")
+		.requires("create-api-key")
+		.takes_value(true))
+	.arg(Arg::with_name("owner")
+		.long("owner")
+		.value_name("USERNAME")
+		.help("Operator a new API key acts for, limiting it to the operator's role and groups")
+		.requires("create-api-key")
+		.takes_value(true))
+	.arg(Arg::with_name("create-operator")
+		.long("create-operator")
+		.value_name("USERNAME")
+		.help("Create an operator account for the admin API and web console and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("role")
+		.long("role")
+		.value_name("ROLE")
+		.help("Role of a new operator: viewer, operator or administrator [default: viewer]")
+		.requires("create-operator")
+		.takes_value(true))
+	.arg(Arg::with_name("password-file")
+		.long("password-file")
+		.value_name("FILE")
+		.help("File containing an operator's password, instead of prompting for it")
+		.takes_value(true))
+	.arg(Arg::with_name("list-operators")
+		.long("list-operators")
+		.help("List operator accounts and exit")
+		.takes_value(false))
+	.arg(Arg::with_name("reset-password")
+		.long("reset-password")
+		.value_name("USERNAME")
+		.help("Set a new password for an operator and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("disable-operator")
+		.long("disable-operator")
+		.value_name("USERNAME")
+		.help("Stop an operator from signing in or using their API keys and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("enable-operator")
+		.long("enable-operator")
+		.value_name("USERNAME")
+		.help("Re-enable a disabled operator and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("delete-operator")
+		.long("delete-operator")
+		.value_name("USERNAME")
+		.help("Delete an operator, revoking their API keys, and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("revoke-endpoint")
+		.long("revoke-endpoint")
+		.value_name("UID")
+		.help("Revoke a registered endpoint and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("endpoint-history")
+		.long("endpoint-history")
+		.value_name("UID")
+		.help("Show attribute changes and presence events for an endpoint and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("push")
+		.long("push")
+		.value_name("UID")
+		.help("Queue a command for an endpoint (with --question, --set-config or --action) and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("question")
+		.long("question")
+		.value_name("QUESTION")
+		.help("Question to ask an endpoint, such as hostname, osver, ipaddress, uptime or lumys")
+		.takes_value(true))
+	.arg(Arg::with_name("set-config")
+		.long("set-config")
+		.value_name("KEY=VALUE")
+		.help("Client configuration value to change on an endpoint")
+		.takes_value(true))
+	.arg(Arg::with_name("action")
+		.long("action")
+		.value_name("ACTION")
+		.help("Client action to run on an endpoint, followed by any arguments")
+		.takes_value(true)
+		.multiple_values(true))
+	.arg(Arg::with_name("commands")
+		.long("commands")
+		.value_name("UID")
+		.help("List commands queued for an endpoint and their results and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("export-audit")
+		.long("export-audit")
+		.value_name("FILE")
+		.help("Write the audit log to a file as JSON lines and exit")
+		.takes_value(true))
+	.arg(Arg::with_name("verify-audit")
+		.long("verify-audit")
+		.help("Check the audit log's hash chain and exit")
+		.takes_value(false))
+	.arg(Arg::with_name("ephemeral")
+		.long("ephemeral")
+		.help("Allows the memory storage backend, which keeps nothing across restarts")
+		.takes_value(false))
 	.arg(Arg::with_name("debug")
 		.short('d')
 		.long("debug")
@@ -138,400 +387,450 @@ fn main() {
 		.takes_value(false))
 	.get_matches();
 
-	// Set variables based on command-line arguments or use defaults
-	let key_file = matches.value_of("key").unwrap_or(DKPATH);
-	let pub_file = matches.value_of("pubkey").unwrap_or(DPPATH);
-	let cert_file = matches.value_of("certificate").unwrap_or(DCPATH);
-	let identity_file = matches.value_of("identity").unwrap_or(DIPATH);
-	let mut address = matches.value_of("address").unwrap_or("");
-	let mut port = matches.value_of("port").unwrap_or("");
+	// Command-line arguments take precedence over the configuration file
+	let overrides = Overrides {
+		private_key: matches.value_of("key").map(String::from),
+		public_key: matches.value_of("pubkey").map(String::from),
+		certificate: matches.value_of("certificate").map(String::from),
+		identity: matches.value_of("identity").map(String::from),
+		address: matches.value_of("address").map(String::from),
+		port: matches.value_of("port").map(String::from),
+		ephemeral: matches.is_present("ephemeral"),
+		debug: matches.is_present("debug")
+		};
+	let config_file = matches.value_of("config").unwrap_or(config::DEFAULT_CONFIG_FILE).to_string();
+	let config_required = matches.is_present("config");
 	let setup = matches.is_present("setup");
-	let debug = matches.is_present("debug");
+	let migrate = matches.is_present("migrate");
+
+	// Load the configuration file. Logging and file paths are needed before setup can run.
+	let configfile = match ConfigFile::load(&config_file, config_required) {
+		Ok(configfile) => configfile,
+		Err(err) => {
+			eprintln!("Error: {}", err);
+			process::exit(1);
+			}
+		};
+	let log_guard = match configfile.logging(&overrides).map_err(|err| err.to_string()).and_then(|logging| luminum_log::init(&logging).map_err(|err| err.to_string())) {
+		Ok(guard) => guard,
+		Err(err) => {
+			eprintln!("Unable to start logging: {}", err);
+			process::exit(1);
+			}
+		};
 
-	dbout(debug,0,format!("Starting Luminum Server Daemon v{}...",VER).as_str());
+	info!("Starting Luminum Server Daemon v{}...",VER);
 
 	// Figure out location on disk
 	match env::current_exe() {
 		Ok(current_exe) => {
 			let path_string = current_exe.to_string_lossy().into_owned();
-			dbout(debug,4,format!("Run location: {}",path_string).as_str());
+			debug!("Run location: {}",path_string);
 			}
 		Err(err) => {
-			dbout(debug,1,format!("Unable to determine run location: {}",err).as_str());
+			error!("Unable to determine run location: {}",err);
 			}
 		}
 
 	// Check if the standard installation paths exist
 	if fs::metadata("/opt/Luminum").is_err() || fs::metadata("/opt/Luminum/LuminumServer").is_err() || fs::metadata("/opt/Luminum/LuminumServer/config/").is_err() {
-		dbout(debug,1,format!("Luminum Server install paths are missing. Is the software installed correctly?").as_str());
+		error!("Luminum Server install paths are missing. Is the software installed correctly?");
 		process::exit(1);
 		}
 
-	// Set up break handler
-	let running = Arc::new(AtomicBool::new(true));
-	let r = running.clone();
-	ctrlc::set_handler(move || {
-		r.store(false, Ordering::SeqCst);
-		print!("\r\x1B[K");
-		dbout(debug,0,"Received BREAK signal. Terminating Luminum Server...");
-		process::exit(1);
-		}).expect("Error creating break handler");
+	let paths = configfile.paths(&overrides);
+	let certificate_policy = match configfile.certificate() {
+		Ok(policy) => policy,
+		Err(err) => {
+			error!("Invalid configuration: {}", err);
+			process::exit(1);
+			}
+		};
 
 	// Check if setup flag is specified and run setup routine if true
 	if setup {
-		dbout(debug,4,format!("Starting daemon setup.").as_str());
-		if fs::metadata(CFGPATH).is_err() {
-			daemonsetup();
+		debug!("Starting daemon setup.");
+		if ["non-interactive","setup-file","dry-run","output"].iter().any(|arg| matches.is_present(arg)) {
+			setup::run(&matches, &paths, &configfile.mysql_socket(), &certificate_policy);
+			}
+		else if fs::metadata(&paths.config_db).is_err() {
+			let dbpath = matches.value_of("dbpath").map(String::from).or_else(|| env::var("LUMINUM_DBPATH").ok()).unwrap_or_else(|| DDBPATH.to_string());
+			daemonsetup(&paths, &configfile.mysql_socket(), &dbpath, &certificate_policy);
 			}
 		else {
-			dbout(debug,1,format!("Server configuration already exists. Aborting.").as_str());
+			error!("Server configuration already exists. Aborting.");
 			process::exit(1);
 			}
 		}
 
 	// Import server configuration
-	let mut serverconfig: HashMap<String, String> = HashMap::new();
-	if fs::metadata(CFGPATH).is_ok() {
-		let confconn = Connection::open(CFGPATH).expect("Error: Could not open configuration database.");
-		let mut stmt = confconn.prepare("select KEY,VALUE from CONFIG").unwrap();
-		let cfg_iter = stmt.query_map(params![], |row| {
-			Ok(Config {
-				key: row.get(0)?,
-				value: row.get(1)?
-				})
-			}).expect("Error: Failed to parse configuration values");
-		for cfg_result in cfg_iter {
-			if let Ok(cfg) = cfg_result {
-				serverconfig.insert(cfg.key.to_string(),cfg.value.to_string());
-				}
-			}
-
-		if address == "" {
-			address = serverconfig.get("IPADDR").expect("Error: Could not set server IP address from configuration database.");
-			}
-		if port == "" {
-			port = serverconfig.get("PORT").expect("Error: Could not set server port from configuration database.");
-			}
-		}
-	else {
-		dbout(debug,1,format!("Configuration database not found. (Run with --setup)").as_str());
+	if fs::metadata(&paths.config_db).is_err() {
+		error!("Configuration database not found. (Run with --setup)");
 		process::exit(1);
 		}
-
-	// Network options sanity checking
-	if contains_no_numbers(port) {
-		dbout(debug,1,format!("Invalid port specified: {}", port).as_str());
-		process::exit(1);
-		}
-
-	let addr_str = format!("{}:{}", address,port);
-	let addr: SocketAddr = addr_str.parse().expect("Invalid socket address");
+	let serverconfig = read_serverconfig(&paths.config_db).expect("Error: Could not read configuration database.");
+	let settings = match configfile.settings(paths.clone(), &serverconfig, &overrides) {
+		Ok(settings) => settings,
+		Err(err) => {
+			error!("Invalid configuration: {}", err);
+			process::exit(1);
+			}
+		};
+	debug!("Configuration file: {}", config_file);
 
 	// Check if necessary encryption files exist
-	if !file_exists(key_file) {
-		dbout(debug,1,format!("Private key file ({}) does not exist.", key_file).as_str());
+	if !file_exists(&paths.private_key) {
+		error!("Private key file ({}) does not exist.", paths.private_key);
 		process::exit(1);
 		}
 	else {
-		dbout(debug,3,format!("Using private key: {}",key_file).as_str());
+		info!("Using private key: {}",paths.private_key);
 		}
 
-	if !file_exists(pub_file) {
-		dbout(debug,1,format!("Public key file ({}) does not exist.", pub_file).as_str());
+	if !file_exists(&paths.public_key) {
+		error!("Public key file ({}) does not exist.", paths.public_key);
 		process::exit(1);
 		}
 	else {
-		dbout(debug,3,format!("Using public key: {}",pub_file).as_str());
+		info!("Using public key: {}",paths.public_key);
 		}
 
-	if !file_exists(cert_file) {
-		dbout(debug,1,format!("Certificate file ({}) does not exist.", cert_file).as_str());
+	if !file_exists(&paths.certificate) {
+		error!("Certificate file ({}) does not exist.", paths.certificate);
 		process::exit(1);
 		}
 	else {
-		dbout(debug,3,format!("Using certificate: {}",cert_file).as_str());
+		info!("Using certificate: {}",paths.certificate);
 		}
 
-	if !file_exists(identity_file) {
-		dbout(debug,1,format!("Identity file ({}) does not exist.", identity_file).as_str());
+	if !file_exists(&paths.identity) {
+		error!("Identity file ({}) does not exist.", paths.identity);
 		process::exit(1);
 		}
 	else {
-		dbout(debug,3,format!("Using identity: {}",identity_file).as_str());
+		info!("Using identity: {}",paths.identity);
+		}
+
+	// The master key file is only readable by root, so load it before switching users
+	let legacy = secrets::is_legacy(&serverconfig);
+	let master_key = match MasterKey::load(&paths.master_key) {
+		Ok(master_key) => {
+			info!("Using master key from {}",master_key.source());
+			Some(master_key)
+			},
+		// Configurations from older versions get their first master key from --rotate-secrets
+		Err(SecretError::NoMasterKey(_)) if legacy => None,
+		Err(err) => {
+			error!("Error loading master key: {}", err);
+			process::exit(1);
+			}
+		};
+	if legacy {
+		warn!("Stored secrets are encrypted with the legacy server key (SVRKEY). Run with --rotate-secrets to seal them with a master key.");
+		}
+
+	if matches.is_present("rotate-secrets") {
+		// A running server keeps the old master key and couldn't open the resealed enrollment keys
+		if let Some(address) = cli::rotation::server_running(&settings) {
+			println!("Error: The server is running (it is listening on {}). Stop it before rotating secrets.", address);
+			process::exit(1);
+			}
+		match cli::rotation::rotate_secrets(&paths, &settings.backend, &serverconfig, master_key.as_ref()) {
+			Ok(()) => process::exit(0),
+			Err(err) => {
+				println!("Error: {}", err);
+				process::exit(1);
+				}
+			}
+		}
+	let master_key = match master_key {
+		Some(master_key) => master_key,
+		None => {
+			error!("No master key found at {}. Run with --rotate-secrets to create one.", paths.master_key);
+			process::exit(1);
+			}
+		};
+
+	// Use private key passphrase and database password from server configuration
+	let (passphrase, dbpass) = match secrets::reveal(Some(&master_key), &serverconfig, "PKPASS").and_then(|passphrase| secrets::reveal(Some(&master_key), &serverconfig, "DBPASS").map(|dbpass| (passphrase, dbpass))) {
+		Ok(secrets) => secrets,
+		Err(err) => {
+			error!("Error reading stored secrets: {}", err);
+			process::exit(1);
+			}
+		};
+
+	// Certificate renewal writes files owned by root, so it runs before switching users
+	if matches.is_present("renew-cert") {
+		match cli::rotation::renew_certificate(&paths, &passphrase, &settings, matches.is_present("new-key")) {
+			Ok(()) => process::exit(0),
+			Err(err) => {
+				println!("Error: {}", err);
+				process::exit(1);
+				}
+			}
 		}
 
 	// Check if the "luminum" system user exists and switch process to that user
 	let (user_exists,user_uid) = sysuser_info("luminum");
 	if user_exists {
-		let parse_uid: Result<u32, _> = user_uid.unwrap_or_else(|| String::new()).parse();
+		let parse_uid: Result<u32, _> = user_uid.unwrap_or_else(String::new).parse();
 		match parse_uid {
 			Ok(run_uid) => {
 				if unsafe { setuid(run_uid) } != 0 {
-					dbout(debug,1,format!("Could not assign process to \"luminum\" system user.").as_str());
+					error!("Could not assign process to \"luminum\" system user.");
 					process::exit(1);
 					}
 				},
 			Err(err) => {
-				dbout(debug,1,format!("Could not assign process to \"luminum\" system user: {}", err).as_str());
+				error!("Could not assign process to \"luminum\" system user: {}", err);
 				process::exit(1);
 				}
 			}
 		}
 	else {
-		dbout(debug,1,format!("The \"luminum\" system user does not exist.").as_str());
+		error!("The \"luminum\" system user does not exist.");
 		process::exit(1);
 		}
 
-	// Connect to MySQL server
-	if !file_exists("/var/run/mysqld/mysqld.sock") {
-		dbout(debug,1,format!("Database socket (/var/run/mysqld/mysqld.sock) is missing.").as_str());
-		process::exit(1);
+	// Open the configured storage backend
+	let storage = open_storage(&settings.backend, &dbpass);
+
+	// Bring the database schema up to date
+	match storage.migrate() {
+		Ok(applied) => {
+			for version in &applied {
+				info!("Applied schema migration {}", version);
+				}
+			if migrate {
+				info!("Database schema is up to date. ({} migrations applied)", applied.len());
+				process::exit(0);
+				}
+			},
+		Err(err) => {
+			error!("Error applying schema migrations: {}", err);
+			process::exit(1);
+			}
 		}
 
-	let server_key = serverconfig.get("SVRKEY").unwrap();
-	let mc = new_magic_crypt!(&server_key, 256);
-	let encrypted_dbpass = serverconfig.get("DBPASS").unwrap();
-	let dbpass = mc.decrypt_base64_to_string(&encrypted_dbpass).unwrap();
+	// Endpoint administration
+	if let Some(uid) = matches.value_of("revoke-endpoint") {
+		cli::endpoints::revoke(storage.as_ref(), uid);
+		}
 
-	let socket_path = "/var/run/mysqld/mysqld.sock";
+	if let Some(uid) = matches.value_of("endpoint-history") {
+		cli::endpoints::history(storage.as_ref(), uid);
+		}
 
-	// Open CLIENTS database
-	let copts = Opts::from(OptsBuilder::new()
-		.socket(Some(socket_path))
-		.user(Some("luminum"))
-		.pass(Some(dbpass.clone()))
-		.db_name(Some("CLIENTS")));
-	let clients_db_pool = Arc::new(Pool::new(copts).unwrap());
+	// Pushed commands
+	if matches.is_present("push") || matches.is_present("commands") {
+		cli::endpoints::commands(storage.as_ref(), &matches);
+		}
 
-/*
-	let clientsconn = match clients_db_pool.get_conn() {
-		Ok(cconn) => {
-			dbout(debug,3,format!("Connected to MySQL database: CLIENTS").as_str());
-			cconn
-			}
-		Err(err) => {
-			dbout(debug,1,format!("Error connecting to MySQL database: {}", err).as_str());
-			std::process::exit(1);
-			}
-		};
-*/
-	// TODO - Need to create a check for configured modules so we're not creating connections for modules not enabled
-
-	// Open INTEGRITY database
-	let liopts = Opts::from(OptsBuilder::new()
-		.socket(Some(socket_path))
-		.user(Some("luminum"))
-		.pass(Some(dbpass.clone()))
-		.db_name(Some("INTEGRITY")));
-	let integrity_db_pool = Arc::new(Pool::new(liopts).unwrap());
-
-/*
-	let liconn = match integrity_db_pool.get_conn() {
-		Ok(conn) => {
-			dbout(debug,3,format!("Connected to MySQL database: INTEGRITY").as_str());
-			conn
-			}
+	// Enrollment token administration
+	if ["create-token","list-tokens","revoke-token","token-endpoints","rotate-enrollment-key"].iter().any(|arg| matches.is_present(arg)) {
+		cli::tokens::run(storage.as_ref(), &master_key, &matches);
+		}
+
+	// Admin API key administration
+	if ["create-api-key","list-api-keys","revoke-api-key"].iter().any(|arg| matches.is_present(arg)) {
+		cli::api_keys::run(storage.as_ref(), &matches);
+		}
+
+	// Operator administration
+	if ["create-operator","list-operators","reset-password","disable-operator","enable-operator","delete-operator"].iter().any(|arg| matches.is_present(arg)) {
+		cli::operators::run(storage.as_ref(), &matches);
+		}
+
+	// Audit log
+	if matches.is_present("export-audit") || matches.is_present("verify-audit") {
+		cli::audit_log::run(storage.as_ref(), &matches);
+		}
+
+	// Load the client certificate authority, creating it on first start
+	let client_ca = match ClientCa::load(&paths, &passphrase) {
+		Ok(ca) => ca,
+		Err(_) if !file_exists(&paths.client_ca_certificate) => {
+			info!("Client CA ({}) does not exist. Creating...", paths.client_ca_certificate);
+			match ClientCa::create(&paths, &passphrase, settings.certificate.key_type) {
+				Ok(ca) => ca,
+				Err(err) => {
+					error!("Error creating client CA: {}", err);
+					process::exit(1);
+					}
+				}
+			},
 		Err(err) => {
-			dbout(debug,1,format!("Error connecting to MySQL database: {}", err).as_str());
-			std::process::exit(1);
+			error!("Error loading client CA: {}", err);
+			process::exit(1);
 			}
 		};
-*/
-	// Use private key passphrase from server configuration and load PKE identity file
-	let encrypted_passphrase = serverconfig.get("PKPASS").unwrap();
-	let passphrase = mc.decrypt_base64_to_string(&encrypted_passphrase).unwrap();
 
-	let identity = match Identity::from_pkcs12(&fs::read(identity_file).unwrap(), &passphrase) {
+	// Create TLS handler. Client certificates are verified against the client CA.
+	let identity = match ServerIdentity::load(&paths, &passphrase, &client_ca, &settings.certificate) {
 		Ok(identity) => identity,
 		Err(err) => {
-			dbout(debug,1,format!("Error loading TLS identity: {}", err).as_str());
-			return;
+			error!("Error creating TLS handler: {}", err);
+			process::exit(1);
 			}
 		};
+	if identity.rolling_over(enroll::now()) {
+		debug!("Presenting the previous server certificate until {} while endpoints pick up the renewed one", format_timestamp(Some(identity.switch_at())));
+		}
 
-	// Create TLS handler
-	let acceptor = match TlsAcceptor::new(identity) {
-		// TODO: Probably want to set up the connection to require client certificates
-		Ok(acceptor) => acceptor,
-		Err(err) => {
-			dbout(debug,1,format!("Error creating TLS handler: {}", err).as_str());
-			return;
-			}
-		};
+	// Connection handling limits and presence thresholds
+	let tunables = settings.tunables;
+	debug!("Maximum message size: {} bytes", tunables.limits.max_frame);
+	debug!("Maximum concurrent connections: {}", tunables.limits.max_connections);
+	debug!("Endpoints become stale after {} seconds and offline after {} seconds", tunables.presence.stale_after, tunables.presence.offline_after);
+	if !tunables.modules.integrity {
+		debug!("Integrity module is disabled.");
+		}
 
-	// Start the data listener service
-	let listener = match TcpListener::bind(addr) {
-		Ok(listener) => listener,
-		Err(err) => {
-			dbout(debug,1,format!("Failed to bind to port {port}: {}", err).as_str());
-			return;
+	let state = Arc::new(ServerState {
+		storage,
+		client_ca,
+		identity,
+		master_key,
+		channels: push::Channels::default(),
+		guard: guard::Guard::default(),
+		tunables: RwLock::new(tunables),
+		settings: RwLock::new(settings.clone())
+		});
+
+	// Start the data listener service on every configured address
+	let mut listeners = Vec::new();
+	for addr in &settings.listen {
+		match TcpListener::bind(addr).await {
+			Ok(listener) => { listeners.push(listener); },
+			Err(err) => {
+				error!("Failed to bind to {}: {}", addr, err);
+				process::exit(1);
+				}
 			}
-		};
+		}
 
-	// Finished Startup
-	dbout(debug,3,format!("Luminum Server Daemon started on {}...",addr_str).as_str());
-
-	// Listen for incoming connections
-	while running.load(Ordering::SeqCst) {
-		match listener.accept() {
-			Ok((stream, peer_addr)) => {
-				dbout(debug,4,format!("Incoming connection from {}", peer_addr).as_str());
-				// Accept TLS connection
-				let mut tls_stream = match acceptor.accept(stream) {
-					Ok(stream) => {
-						dbout(debug,3,format!("Connection established with {}", peer_addr).as_str());
-						stream
-						},
-					Err(err) => {
-						dbout(debug,2,format!("Error accepting TLS connection: {}", err).as_str());
-						continue;
-						}
-					};
-				// Handle the connection
-				let mut buffer = [0; 1024];
-
-				match tls_stream.read(&mut buffer) {
-					Ok(n) => {
-						if n > 0 {
-							match from_read::<_, ClientMessage>(&buffer[..n]) {
-								Ok(msg) => {
-									//println!("Received from client: {:?}",msg);
-									if msg.product == "Luminum Client" && valid_uid(&msg.uid) {
-										let uid = msg.uid.to_string();
-										if msg.content.action == "heartbeat" {
-											let mut conn = clients_db_pool.get_conn().unwrap();
-											conn.exec_drop(format!("update STATUS set LASTSEEN = now() where UID = '{}'",uid),());
-											dbout(debug,4,format!("Received heartbeat from UID \"{}\"", &uid).as_str());
-											}
-										else if msg.uid == "NONE" && msg.content.action == "register" {
-											dbout(debug,4,format!("Received endpoint registration request from {}",&peer_addr).as_str());
-											if msg.content.data.serverkey == Some(String::from(server_key)) {
-												register_client(&clients_db_pool,msg.content.data,&mut tls_stream,debug);
-												}
-											else {
-												dbout(debug,2,format!("An invalid server key was provided by {} during registration.", &peer_addr).as_str());
-												}
-											}
-										else if msg.content.lumy == "Integrity" {
-											if msg.content.action == "newconfig" {
-												dbout(debug,4,format!("Received Integrity Lumy configuration request from {}",&peer_addr).as_str());
-												integrity_config(&integrity_db_pool,msg.uid,&mut tls_stream,"new".to_string(),debug);
-												}
-											}
-										}
-									},
-								Err(_) => {
-									dbout(debug,2,format!("Malformed data in stream from {}", peer_addr).as_str());
-									}
-								}
-							}
+	// Metrics are served on the admin addresses, if any
+	let mut admin_listeners = Vec::new();
+	for addr in &settings.admin {
+		match TcpListener::bind(addr).await {
+			Ok(listener) => { admin_listeners.push(listener); },
+			Err(err) => {
+				error!("Failed to bind admin listener to {}: {}", addr, err);
+				process::exit(1);
+				}
+			}
+		}
+	metrics::serve(admin_listeners).await;
 
-						},
-					Err(err) => {
-						dbout(debug,2,format!("Error reading from stream: {}", err).as_str());
-						}
-					}
-				},
-			Err(err) => { dbout(debug,2,format!("Error accepting connection: {}", err).as_str()); }
+	// The admin API is served on the API addresses, if any
+	let mut api_listeners = Vec::new();
+	for addr in &settings.api {
+		match TcpListener::bind(addr).await {
+			Ok(listener) => { api_listeners.push(listener); },
+			Err(err) => {
+				error!("Failed to bind API listener to {}: {}", addr, err);
+				process::exit(1);
+				}
 			}
 		}
+	// SIGTERM and SIGINT stop the daemon once it is serving
+	let shutdown = Shutdown::new();
+	tokio::spawn(shutdown::on_signal(shutdown.clone()));
 
-	dbout(debug,0,format!("Luminum server daemon stopped.").as_str());
-	}
+	// Tasks that use the storage backend, stopped before it is closed
+	let mut tasks = JoinSet::new();
+	api::serve(api_listeners, state.clone(), shutdown.clone(), &mut tasks).await;
 
-fn handle_msg(pool: &Arc<Pool>, peer_addr: String, data: &str, stream: &mut native_tls::TlsStream<TcpStream>, debug: bool) {
-	let conn = pool.get_conn().unwrap();
-	}
+	// Finished Startup
+	let addresses: Vec<String> = settings.listen.iter().map(SocketAddr::to_string).collect();
+	info!("Luminum Server Daemon started on {}...",addresses.join(", "));
+	shutdown::notify(luminum_log::systemd::READY);
+	tokio::spawn(shutdown::watchdog());
+
+	// Apply configuration changes on SIGHUP
+	tasks.spawn(config::reload_on_hangup(state.clone(), config_file, config_required, overrides, shutdown.clone(), settings));
+
+	// Track endpoint presence in the background
+	tasks.spawn(presence::monitor(state.clone(), shutdown.clone()));
+
+	// Deliver queued commands to listening endpoints
+	tasks.spawn(push::dispatch(state.clone(), shutdown.clone()));
+
+	// Warn about certificates close to expiry
+	tasks.spawn(tls::monitor(state.clone(), shutdown.clone()));
+
+	// Listen for incoming connections until a shutdown signal
+	listener::run(listeners, state.clone(), shutdown.clone()).await;
+
+	// Let sessions, API requests and background tasks in progress finish
+	let drain_timeout = state.limits().drain_timeout;
+	let deadline = Instant::now() + drain_timeout;
+	if shutdown.active() > 0 {
+		info!("Waiting up to {} seconds for open sessions to finish ({} remaining)...", drain_timeout.as_secs(), shutdown.active());
+		}
+	if !shutdown.drained(drain_timeout).await {
+		warn!("Abandoning open sessions after {} seconds ({} remaining).", drain_timeout.as_secs(), shutdown.active());
+		}
+	if timeout_at(deadline.into(), async { while tasks.join_next().await.is_some() {} }).await.is_err() {
+		warn!("Background tasks did not stop in time.");
+		}
+	drop(tasks);
+
+	// Close the storage backend. Handlers abandoned above may still hold it, in which case
+	// its connections close with the process.
+	match Arc::try_unwrap(state) {
+		Ok(state) => drop(state),
+		Err(_) => { debug!("Storage still in use; leaving it to close on exit."); }
+		}
 
-fn verify_client(pool: &Arc<Pool>, peer_addr: String, data: &str, stream: &mut native_tls::TlsStream<TcpStream>, debug: bool) {
-	let conn = pool.get_conn().unwrap();
-	let mut vstat = String::new();
+	info!("Luminum server daemon stopped.");
+	drop(log_guard);
+	process::exit(0);
 	}
 
-fn register_client(pool: &Arc<Pool>, data: MessageData, stream: &mut native_tls::TlsStream<TcpStream>, debug: bool) {
-	let mut conn = pool.get_conn().unwrap();
-	let new_uid = Uuid::new_v4();
-
-	let hostname = data.hostname.unwrap();
-	let osplat = data.osplat.unwrap();
-	let osver = data.osver.unwrap();
-	let ipv4 = data.ipv4.unwrap_or("".to_string());
-	let ipv6 = data.ipv6.unwrap_or("".to_string());
-
-	let query = format!("insert into STATUS (UID,HOSTNAME,IPV4,IPV6,OSPLAT,OSVER,REGDATE,LASTSEEN) VALUES ('{}', '{}', '{}', '{}', '{}', '{}',now(),now())", new_uid, hostname, ipv4, ipv6, osplat, osver);
-	match conn.query_drop(query) {
-		Ok(_) => {
-			let response_data = MessageData {
-				uid: Some(String::from(new_uid)),
-				serverkey: None,
-				hostname: None,
-				osplat: None,
-				osver: None,
-				ipv4: None,
-				ipv6: None,
-				info: None
-				};
-			let response_content = MessageContent {
-				lumy: String::from("Luminum Core"),
-				status: String::from("OK"),
-				action: String::from("register"),
-				data: response_data
-				};
-			let response = ServerMessage {
-				version: String::from(VER),
-				content: response_content
-				};
-			let serialized_data = to_vec_named(&response).expect("Error: Failed to serialize message to client.");
-			stream.write_all(&serialized_data).expect("Error: Failed to send response to client.");
-			dbout(debug,3,format!("Endpoint \"{}\" successfully registered. (UID {})", hostname,new_uid).as_str());
+// Open the configured storage backend, exiting if it isn't available
+fn open_storage(backend: &Backend, dbpass: &str) -> Box<dyn Storage> {
+	match backend {
+		Backend::Mysql { socket } => {
+			if !file_exists(socket) {
+				error!("Database socket ({}) is missing.", socket);
+				process::exit(1);
+				}
+			match MysqlStorage::connect(socket, "luminum", dbpass) {
+				Ok(storage) => {
+					info!("Connected to MySQL databases: CLIENTS, INTEGRITY");
+					Box::new(storage)
+					},
+				Err(err) => {
+					error!("Error connecting to MySQL database: {}", err);
+					process::exit(1);
+					}
+				}
 			},
-		Err(err) => {
-			dbout(debug,2,format!("Failed to register endpoint \"{}\": {}", hostname,err).as_str());
+		Backend::Sqlite { path } => {
+			match SqliteStorage::open(path) {
+				Ok(storage) => {
+					info!("Using SQLite database: {}", path);
+					Box::new(storage)
+					},
+				Err(err) => {
+					error!("Error opening SQLite database {}: {}", path, err);
+					process::exit(1);
+					}
+				}
+			},
+		Backend::Memory => {
+			warn!("Using in-memory storage. Registrations will not persist across restarts.");
+			Box::new(MemoryStorage::new())
 			}
 		}
 	}
 
-fn integrity_config(pool: &Arc<Pool>, uid: String, stream: &mut native_tls::TlsStream<TcpStream>, action: String, debug: bool) {
-	let mut conn = pool.get_conn().unwrap();
-	if action == "new" {
-		match conn.exec_drop(format!("delete from WATCHLIST where ID = (select ID from CLIENTS.STATUS where UID = '{}')",uid),()) {
-			Ok(_) => { dbout(debug,3,format!("Deleted saved Integrity Lumy configuration for UID \"{}\"", uid).as_str()); }
-			Err(err) => { dbout(debug,2,format!("Error deleting saved Integrity Lumy configuration for UID \"{}\": {}", uid, err).as_str()); }
-			}
-		let query = format!("insert into WATCHLIST (ID, OS, PATH) SELECT status.ID, status.OSPLAT, wd.PATH from (SELECT ID, OSPLAT FROM CLIENTS.STATUS WHERE UID = '{}') AS status CROSS JOIN (SELECT PATH FROM WATCH_DEFAULT WHERE OS = (SELECT OSPLAT FROM CLIENTS.STATUS WHERE UID = '{}')) AS wd", uid, uid);
-		match conn.query_drop(query) {
-			Ok(_) => {
-				let query = format!("select PATH FROM WATCHLIST WHERE ID = (select ID from CLIENTS.STATUS where UID = '{}')", uid);
-				let results: Vec<String> = conn.exec(query, ()).expect("Error: Unable to retrieve saved Integrity configuration for endpoint");
-
-				let response_data = MessageData {
-					uid: None,
-					serverkey: None,
-					hostname: None,
-					osplat: None,
-					osver: None,
-					ipv4: None,
-					ipv6: None,
-					info: Some(results)
-					};
-				let response_content = MessageContent {
-					lumy: String::from("Integrity"),
-					status: String::from("OK"),
-					action: String::from("newconfig"),
-					data: response_data
-					};
-				let response = ServerMessage {
-					version: String::from(VER),
-					content: response_content
-					};
-				let serialized_data = to_vec_named(&response).expect("Error: Failed to serialize message to client.");
-				stream.write_all(&serialized_data).expect("Error: Failed to send response to client.");
-				dbout(debug,3,format!("Saved Integrity Lumy configuration for UID \"{}\"", uid).as_str());			
-				}
-	                Err(err) => {
-	                        dbout(debug,2,format!("Failed to save Integrity Lumy configuration: {}", err).as_str());
-				}
-                        }
+fn format_timestamp(timestamp: Option<i64>) -> String {
+	match timestamp.and_then(|secs| chrono::DateTime::from_timestamp(secs, 0)) {
+		Some(time) => time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string(),
+		None => String::from("never")
 		}
 	}
 
@@ -539,9 +838,21 @@ fn file_exists(path: &str) -> bool {
 	fs::metadata(path).is_ok()
 	}
 
-fn contains_no_numbers(variable: &str) -> bool {
-	let re = Regex::new(r"\d").unwrap();
-	!re.is_match(variable)
+// Read the CONFIG table of the configuration database
+fn read_serverconfig(path: &str) -> Result<HashMap<String, String>> {
+	let confconn = Connection::open(path)?;
+	let mut stmt = confconn.prepare("select KEY,VALUE from CONFIG")?;
+	let cfg_iter = stmt.query_map(params![], |row| {
+		Ok(Config {
+			key: row.get(0)?,
+			value: row.get(1)?
+			})
+		})?;
+	let mut serverconfig = HashMap::new();
+	for cfg in cfg_iter.flatten() {
+		serverconfig.insert(cfg.key,cfg.value);
+		}
+	Ok(serverconfig)
 	}
 
 fn contains_only_numbers(input: &str) -> bool {
@@ -549,24 +860,16 @@ fn contains_only_numbers(input: &str) -> bool {
 	re.is_match(input)
 	}
 
-fn valid_uid(input: &str) -> bool {
-	let re = Regex::new(r"^[a-zA-Z0-9_-]+$").unwrap();
-	re.is_match(input)
-	}
-
-fn valid_product(input: &str) -> bool {
-	let re = Regex::new(r"^[a-zA-Z\s]+$").unwrap();
-	re.is_match(input)
-	}
-
 // Daemon Setup
-fn daemonsetup() {
+fn daemonsetup(paths: &Paths, mysql_socket: &str, default_dbpath: &str, policy: &CertificatePolicy) {
 	println!("Luminum Server Daemon\nby Christopher R. Curzio <ccurzio@accipiter.org>\n");
 	println!("Daemon Configuration\n--------------------");
 
-	let mut setup_address = String::new();
-	let mut setup_port = String::new();
-	let mut setup_passphrase = String::new();
+	let setup_address: String;
+	let setup_port: String;
+	let setup_storage: String;
+	let mut setup_dbpath = default_dbpath.to_string();
+	let mut setup_passphrase: String;
 
 	loop {
 		let mut ui_address = String::new();
@@ -590,7 +893,7 @@ fn daemonsetup() {
 	loop {
 		let mut ui_port = String::new();
 
-		print!("Enter server port [{}]: ", DPORT.to_string());
+		print!("Enter server port [{}]: ", DPORT);
 		io::stdout().flush().unwrap();
 
 		io::stdin().read_line(&mut ui_port).unwrap();
@@ -612,7 +915,35 @@ fn daemonsetup() {
 			}
 		}
 
-	if fs::metadata(DKPATH).is_err() {
+	loop {
+		let mut ui_storage = String::new();
+
+		print!("Enter storage backend (mysql, sqlite) [mysql]: ");
+		io::stdout().flush().unwrap();
+
+		io::stdin().read_line(&mut ui_storage).unwrap();
+		let ui_storage = ui_storage.trim();
+
+		match ui_storage {
+			"" | "mysql" => { setup_storage = String::from("mysql"); break; },
+			"sqlite" => { setup_storage = String::from("sqlite"); break; },
+			_ => { println!("Invalid storage backend: {}\n", ui_storage); }
+			}
+		}
+
+	if setup_storage == "sqlite" {
+		let mut ui_dbpath = String::new();
+		print!("Enter SQLite database path [{}]: ", setup_dbpath);
+		io::stdout().flush().unwrap();
+
+		io::stdin().read_line(&mut ui_dbpath).unwrap();
+		let ui_dbpath = ui_dbpath.trim();
+		if !ui_dbpath.is_empty() {
+			setup_dbpath = ui_dbpath.to_string();
+			}
+		}
+
+	if fs::metadata(&paths.private_key).is_err() {
 		println!("\nServer key pair does not exist. Creating...");
 		loop {
 			let keypass = rpassword::read_password_from_tty(Some("Enter PEM passphrase for private key: ")).expect("Error reading passphrase input");
@@ -622,7 +953,10 @@ fn daemonsetup() {
 				continue;
 				}
 			else {
-				let _ = generate_private_key(keypass.as_str());
+				if let Err(err) = generate_private_key(paths, keypass.as_str(), policy.key_type) {
+					println!("Error creating server key pair: {}", err);
+					process::exit(1);
+					}
 				setup_passphrase = keypass;
 				break;
 				}
@@ -630,32 +964,29 @@ fn daemonsetup() {
 		}
 	else {
 		let mut ui_exkey = String::new();
-		println!("\nA private key was found at {}", DKPATH);
+		println!("\nA private key was found at {}", paths.private_key);
 		loop {
 			print!("Do you want to use this key? [Y/n]: ");
 			io::stdout().flush().unwrap();
 			io::stdin().read_line(&mut ui_exkey).expect("Error reading user input");
 			let ui_exkey = ui_exkey.trim();
 			if ui_exkey == "Y" || ui_exkey == "y" || ui_exkey.is_empty() {
-				loop {
-					let ui_passphrase = rpassword::read_password_from_tty(Some("Enter PEM passphrase for private key: ")).expect("Error reading passphrase input");
-					setup_passphrase = ui_passphrase.trim().to_string();
-					break;
-					}
+				let ui_passphrase = rpassword::read_password_from_tty(Some("Enter PEM passphrase for private key: ")).expect("Error reading passphrase input");
+				setup_passphrase = ui_passphrase.trim().to_string();
 				break;
 				}
 			else {
-				let oldkey = format!("{DKPATH}.old");
-				let oldpub = format!("{DPPATH}.old");
-				let oldcrt = format!("{DCPATH}.old");
-				let oldpfx = format!("{DIPATH}.old");
+				let oldkey = format!("{}.old", paths.private_key);
+				let oldpub = format!("{}.old", paths.public_key);
+				let oldcrt = format!("{}.old", paths.certificate);
+				let oldpfx = format!("{}.old", paths.identity);
 
 				if file_exists(&oldkey) { fs::remove_file(&oldkey).expect("Error: Could not delete existing private key backup file"); }
 				if file_exists(&oldpub) { fs::remove_file(&oldpub).expect("Error: Could not delete existing private key backup file"); }
 				if file_exists(&oldcrt) { fs::remove_file(&oldcrt).expect("Error: Could not delete existing certificate backup file"); }
 				if file_exists(&oldpfx) { fs::remove_file(&oldpfx).expect("Error: Could not delete existing identity backup file"); }
 
-				match fs::rename(DKPATH, &oldkey) {
+				match fs::rename(&paths.private_key, &oldkey) {
 					Ok(()) => {
 						println!("Backed up existing private key to {}", oldkey);
 						},
@@ -664,7 +995,7 @@ fn daemonsetup() {
 						process::exit(1);
 						}
 					}
-				match fs::rename(DPPATH, &oldpub) {
+				match fs::rename(&paths.public_key, &oldpub) {
 					Ok(()) => {
 						println!("Backed up existing public key to {}", oldpub);
 						},
@@ -673,7 +1004,7 @@ fn daemonsetup() {
 						process::exit(1);
 						}
 					}
-				match fs::rename(DCPATH, &oldcrt) {
+				match fs::rename(&paths.certificate, &oldcrt) {
 					Ok(()) => {
 						println!("Backed up existing certificate to {}", oldcrt);
 						},
@@ -682,7 +1013,7 @@ fn daemonsetup() {
 						process::exit(1);
 						}
 					}
-				match fs::rename(DIPATH, &oldpfx) {
+				match fs::rename(&paths.identity, &oldpfx) {
 					Ok(()) => {
 						println!("Backed up existing identity file to {}", oldpfx);
 						},
@@ -700,7 +1031,10 @@ fn daemonsetup() {
 						continue;
 						}
 					else {
-						let _ = generate_private_key(keypass.as_str());
+						if let Err(err) = generate_private_key(paths, keypass.as_str(), policy.key_type) {
+							println!("Error creating server key pair: {}", err);
+							process::exit(1);
+							}
 						setup_passphrase = keypass;
 						break;
 						}
@@ -711,177 +1045,201 @@ fn daemonsetup() {
 			}
 		}
 
-	if fs::metadata(DCPATH).is_err() {
+	if fs::metadata(&paths.certificate).is_err() {
 		println!("\nServer certificate does not exist. Creating...");
-		let _ = generate_certificate(setup_passphrase.as_str());
+		let subject = prompt_subject();
+		let listen: Vec<IpAddr> = setup_address.parse().into_iter().collect();
+		let names = policy.alt_names(&listen, &subject.common_name);
+		match generate_certificate(paths, setup_passphrase.as_str(), &subject, &names, policy.validity) {
+			Ok(()) => {
+				println!("Certificate written to {}", paths.certificate);
+				println!("Identity written to {}", paths.identity);
+				},
+			Err(err) => {
+				println!("Error creating server certificate: {}", err);
+				process::exit(1);
+				}
+			}
+		}
+
+	if fs::metadata(&paths.client_ca_certificate).is_err() {
+		println!("\nClient certificate authority does not exist. Creating...");
+		if let Err(err) = ClientCa::create(paths, setup_passphrase.as_str(), policy.key_type) {
+			println!("Error creating client certificate authority: {}", err);
+			process::exit(1);
+			}
 		}
 
+	// Stored secrets are sealed with the master key, which is kept out of the configuration database
+	let master_key = match MasterKey::load(&paths.master_key) {
+		Ok(master_key) => {
+			println!("\nUsing master key from {}", master_key.source());
+			master_key
+			},
+		Err(SecretError::NoMasterKey(_)) => {
+			let master_key = MasterKey::generate(&paths.master_key).expect("Error: Could not generate master key");
+			if let Err(err) = master_key.write(&paths.master_key) {
+				println!("Error: Could not write master key: {}", err);
+				process::exit(1);
+				}
+			println!("\nCreated master key {}", paths.master_key);
+			master_key
+			},
+		Err(err) => {
+			println!("Error: {}", err);
+			process::exit(1);
+			}
+		};
+
 	let sid = Uuid::new_v4().to_string();
-	let new_server_key = random_str::get_string(32, true, true, true, false);
-	let mc = new_magic_crypt!(&new_server_key, 256);
-	let encoded_crypt = mc.encrypt_str_to_base64(setup_passphrase);
+	let encoded_crypt = master_key.seal_str("PKPASS", &setup_passphrase).expect("Error: Could not encrypt private key passphrase");
 	let dbpass = random_str::get_string(16, true, true, true, true);
-	let encoded_dbpass = mc.encrypt_str_to_base64(dbpass.clone());
+	let encoded_dbpass = master_key.seal_str("DBPASS", &dbpass).expect("Error: Could not encrypt database password");
 
-	let confconn = Connection::open(CFGPATH).expect("Error: Could not initialize configuration database");
+	let confconn = Connection::open(&paths.config_db).expect("Error: Could not initialize configuration database");
 	confconn.execute("create table if not exists CONFIG ( KEY text not null, VALUE text not null )",[]).expect("Error: Could not create CONFIG table in configuration database");
-	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",&[&"SID",sid.as_str()]).expect("Error: Could not insert SID into CONFIG table.");
-	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",&[&"SVRKEY",new_server_key.as_str()]).expect("Error: Could not insert SVRKEY into CONFIG table.");
-	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",&[&"IPADDR",setup_address.as_str()]).expect("Error: Could not insert IPADDR into CONFIG table.");
-	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",&[&"PORT",setup_port.as_str()]).expect("Error: Could not insert PORT into CONFIG table.");
-	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",&[&"PKPASS",encoded_crypt.as_str()]).expect("Error: Could not insert PKPASS into CONFIG table.");
-	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",&[&"DBPASS",encoded_dbpass.as_str()]).expect("Error: Could not insert DBPASS into CONFIG table.");
+	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["SID",sid.as_str()]).expect("Error: Could not insert SID into CONFIG table.");
+	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["IPADDR",setup_address.as_str()]).expect("Error: Could not insert IPADDR into CONFIG table.");
+	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["PORT",setup_port.as_str()]).expect("Error: Could not insert PORT into CONFIG table.");
+	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["PKPASS",encoded_crypt.as_str()]).expect("Error: Could not insert PKPASS into CONFIG table.");
+	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["DBPASS",encoded_dbpass.as_str()]).expect("Error: Could not insert DBPASS into CONFIG table.");
+	confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["STORAGE",setup_storage.as_str()]).expect("Error: Could not insert STORAGE into CONFIG table.");
+	if setup_storage == "sqlite" {
+		confconn.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)",["DBPATH",setup_dbpath.as_str()]).expect("Error: Could not insert DBPATH into CONFIG table.");
+		}
 	confconn.close().unwrap();
 
+	// Create the databases and apply the schema
+	let storage: Box<dyn Storage> = if setup_storage == "mysql" {
+		print!("\nEnter MySQL administrative user [root]: ");
+		io::stdout().flush().unwrap();
+		let mut ui_admin = String::new();
+		io::stdin().read_line(&mut ui_admin).expect("Error reading user input");
+		let ui_admin = if ui_admin.trim().is_empty() { "root" } else { ui_admin.trim() };
+		let ui_adminpass = rpassword::read_password_from_tty(Some("Enter MySQL administrative password (blank for socket authentication): ")).expect("Error reading password input");
+
+		if let Err(err) = MysqlStorage::provision(mysql_socket, ui_admin, &ui_adminpass, "luminum", &dbpass) {
+			println!("Error: Could not create Luminum databases: {}", err);
+			process::exit(1);
+			}
+		println!("Created databases CLIENTS, INTEGRITY and database user \"luminum\"");
+		Box::new(MysqlStorage::connect(mysql_socket, "luminum", &dbpass).expect("Error: Could not connect to Luminum databases"))
+		}
+	else {
+		let sqlite = SqliteStorage::open(&setup_dbpath).expect("Error: Could not create SQLite database");
+		// The daemon runs as the "luminum" system user, so it needs to own the database file
+		if let (true, Some(user_uid)) = sysuser_info("luminum") {
+			let user_uid = user_uid.parse::<u32>().ok();
+			std::os::unix::fs::chown(&setup_dbpath, user_uid, None).expect("Error: Could not change ownership of SQLite database");
+			}
+		println!("Created SQLite database: {}", setup_dbpath);
+		Box::new(sqlite)
+		};
+	match storage.migrate() {
+		Ok(applied) => { println!("Applied {} schema migrations", applied.len()); },
+		Err(err) => {
+			println!("Error: Could not apply schema migrations: {}", err);
+			process::exit(1);
+			}
+		}
+
 	println!("Server IP address: {}", setup_address);
 	println!("Server Port: {}", setup_port);
-	println!("Private Key: {}", DKPATH);
-	println!("Public Key: {}", DPPATH);
-	println!("Certificate: {}", DCPATH);
-	println!("Database password for \"luminum\" user: {}", dbpass);
-	println!("\nNOTE: This will be the only time the database password for the \"luminum\" user will be made available. Please make a note of it!\n\n");
+	println!("Private Key: {}", paths.private_key);
+	println!("Public Key: {}", paths.public_key);
+	println!("Certificate: {}", paths.certificate);
+	println!("Client CA Certificate: {}", paths.client_ca_certificate);
+	println!("Master Key: {}", paths.master_key);
+	println!("Storage backend: {}", setup_storage);
+	if setup_storage == "mysql" {
+		println!("Database password for \"luminum\" user: {}", dbpass);
+		println!("\nNOTE: This will be the only time the database password for the \"luminum\" user will be made available. Please make a note of it!\n\n");
+		}
 	println!("Luminum Server setup is complete.");
+	println!("Create an enrollment token for new endpoints with --create-token.");
 	process::exit(0);
 	}
 
 // IPv4 Address Validation
 fn is_valid_ipv4_address(ip: &str) -> bool {
-	if ip.to_string() != "127.0.0.1" {
-		return ip.parse::<Ipv4Addr>().is_ok()
+	if ip != "127.0.0.1" {
+		ip.parse::<Ipv4Addr>().is_ok()
 		}
-	else { return false; }
+	else { false }
 	}
 
 // IPv6 Address Validation
 fn is_valid_ipv6_address(ip: &str) -> bool {
-	if ip.to_string() != "0:0:0:0:0:0:0:1" && ip.to_string() != "::1" {
+	if ip != "0:0:0:0:0:0:0:1" && ip != "::1" {
 		ip.parse::<Ipv6Addr>().is_ok()
 		}
-	else { return false; }
+	else { false }
 	}
 
 // Create Private/Public Key PEM Files
-fn generate_private_key(ui_keypass: &str) -> Result<(), ErrorStack> {
-	let rsa = Rsa::generate(2048).unwrap();
-	let pkey = PKey::from_rsa(rsa).unwrap();
-
-	let mut keyfile = File::create(DKPATH).expect("Could not create private key file");
-	let mut pubkeyfile = File::create(DPPATH).expect("Could not create public key file");
-
-	//let prv_key = pkey.private_key_to_pem_pkcs8().unwrap();
-	let pub_key = pkey.public_key_to_pem().unwrap();
-	let encrypted_key = pkey.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), ui_keypass.as_bytes()).expect("Failed to encrypt private key");
-	keyfile.write_all(str::from_utf8(encrypted_key.as_slice()).unwrap().as_bytes()).expect("Failed to write private key data to file");
-	pubkeyfile.write_all(str::from_utf8(pub_key.as_slice()).unwrap().as_bytes()).expect("Failed to write public key data to file");
+fn generate_private_key(paths: &Paths, ui_keypass: &str, key_type: KeyType) -> Result<(), Box<dyn Error>> {
+	let pkey = tls::generate_key(key_type)?;
+	write_key_pair(paths, &pkey, ui_keypass)
+	}
 
+// Write the private key, encrypted with the passphrase, and the public key
+fn write_key_pair(paths: &Paths, pkey: &PKey<openssl::pkey::Private>, ui_keypass: &str) -> Result<(), Box<dyn Error>> {
+	let encrypted_key = pkey.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), ui_keypass.as_bytes())?;
+	tls::write_private(&paths.private_key, &encrypted_key)?;
+	File::create(&paths.public_key)?.write_all(&pkey.public_key_to_pem()?)?;
 	Ok(())
 	}
 
+// Prompt for the server certificate subject
+fn prompt_subject() -> Subject {
+	let prompt = |label: &str| {
+		let mut input = String::new();
+		print!("{}", label);
+		io::stdout().flush().unwrap();
+		io::stdin()
+			.read_line(&mut input)
+			.expect("Error reading user input");
+		input.trim().to_string()
+		};
 
-fn generate_certificate(ui_keypass: &str) -> Result<(), ErrorStack> {
-	let mut cert_co = String::new();
-	let mut cert_st = String::new();
-	let mut cert_lc = String::new();
-	let mut cert_on = String::new();
-	let mut cert_cn = String::new();
-
-	let mut prv_key_file = File::open(DKPATH).expect("Unable to open private key file");
-	let mut prv_key_pem = String::new();
-	let mut pub_key_file = File::open(DPPATH).expect("Unable to open public key file");
-	let mut pub_key_pem = String::new();
-
-	prv_key_file.read_to_string(&mut prv_key_pem).expect("Unable to read private key file");
-	let prv_key = PKey::private_key_from_pem_passphrase(prv_key_pem.as_bytes(),ui_keypass.as_bytes()).expect("Unable to parse private key");
-	pub_key_file.read_to_string(&mut pub_key_pem).expect("Unable to read public key file");
-	let pub_key = PKey::public_key_from_pem_passphrase(pub_key_pem.as_bytes(),ui_keypass.as_bytes()).expect("Unable to parse public key");
-
-	print!("Two-letter country code: ");
-	io::stdout().flush().unwrap();
-	io::stdin()
-		.read_line(&mut cert_co)
-		.expect("Error reading user input");
-	let cert_co = cert_co.trim();
-
-	print!("State or province: ");
-	io::stdout().flush().unwrap();
-	io::stdin()
-		.read_line(&mut cert_st)
-		.expect("Error reading user input");
-	let cert_st = cert_st.trim();
-
-	print!("City or locality name: ");
-	io::stdout().flush().unwrap();
-	io::stdin()
-		.read_line(&mut cert_lc)
-		.expect("Error reading user input");
-	let cert_lc = cert_lc.trim();
-
-	print!("Organization: ");
-	io::stdout().flush().unwrap();
-	io::stdin()
-		.read_line(&mut cert_on)
-		.expect("Error reading user input");
-	let cert_on = cert_on.trim();
-
-	print!("Enter certificate common name (CN): ");
-	io::stdout().flush().unwrap();
-	io::stdin()
-		.read_line(&mut cert_cn)
-		.expect("Error reading user input");
-	let cert_cn = cert_cn.trim();
-
-	let mut serial_number = BigNum::new().unwrap();
-	serial_number.rand(159, openssl::bn::MsbOption::MAYBE_ZERO, false).unwrap();
-	let serial_number = serial_number.to_asn1_integer().unwrap();
-
-	let mut name_builder = X509NameBuilder::new().unwrap();
-	name_builder.append_entry_by_nid(Nid::COUNTRYNAME, cert_co).expect("Failed to add country to X509Name");
-	name_builder.append_entry_by_nid(Nid::STATEORPROVINCENAME, cert_st).expect("Failed to add state to X509Name");
-	name_builder.append_entry_by_nid(Nid::LOCALITYNAME, cert_lc).expect("Failed to add city to X509Name");
-	name_builder.append_entry_by_nid(Nid::ORGANIZATIONNAME, cert_on).expect("Failed to add organization to X509Name");
-	name_builder.append_entry_by_nid(Nid::COMMONNAME, cert_cn).expect("Failed to add CN to X509Name");
-
-	let mut x509 = X509::builder().expect("Failed to create X509 builder");
-	let name = name_builder.build();
-	x509.set_version(3).expect("Failed to set x509 version");
-	x509.set_serial_number(&serial_number).unwrap();
-	x509.set_subject_name(&name).expect("Failed to set x509 subject name");
-	x509.set_issuer_name(&name).expect("Failed to set x509 issuer name");
-	x509.set_pubkey(&pub_key).unwrap();
-
-	let not_before = Asn1Time::days_from_now(0).expect("Failed to set not before time");
-	let not_after = Asn1Time::days_from_now(365).expect("Failed to set not after time");
-
-	x509.set_not_before(&not_before).expect("Failed to set not before");
-	x509.set_not_after(&not_after).expect("Failed to set not after");
+	Subject {
+		country: prompt("Two-letter country code: "),
+		state: prompt("State or province: "),
+		locality: prompt("City or locality name: "),
+		organization: prompt("Organization: "),
+		common_name: prompt("Enter certificate common name (CN): ")
+		}
+	}
 
-	let san = SubjectAlternativeName::new()
-		.ip("192.168.1.10")
-		.dns(cert_cn)
-		.build(&x509.x509v3_context(None, None))
-		.unwrap();
-	x509.append_extension(san).unwrap();
+// Create the server certificate and PFX identity from the server key pair
+fn generate_certificate(paths: &Paths, ui_keypass: &str, subject: &Subject, names: &[String], days: u32) -> Result<(), Box<dyn Error>> {
+	let prv_key_pem = fs::read(&paths.private_key)?;
+	let prv_key = PKey::private_key_from_pem_passphrase(&prv_key_pem,ui_keypass.as_bytes())?;
 
-	x509.sign(&prv_key, MessageDigest::sha256()).unwrap();
+	// Only the common name is required
+	let mut name_builder = X509NameBuilder::new()?;
+	for (nid, value) in [(Nid::COUNTRYNAME, &subject.country), (Nid::STATEORPROVINCENAME, &subject.state), (Nid::LOCALITYNAME, &subject.locality), (Nid::ORGANIZATIONNAME, &subject.organization)] {
+		if !value.is_empty() {
+			name_builder.append_entry_by_nid(nid, value)?;
+			}
+		}
+	name_builder.append_entry_by_nid(Nid::COMMONNAME, &subject.common_name)?;
+	let name = name_builder.build();
 
-	let certificate = x509.build();
-	let cert_pem = certificate.to_pem().unwrap();
+	let certificate = tls::server_certificate(&prv_key, &name, names, days)?;
+	tls::write_identity(paths, &prv_key, &certificate, ui_keypass)
+	}
 
-	match std::fs::write(DCPATH, &cert_pem) {
-		Ok(_) => println!("Certificate written to {}",DCPATH),
-		Err(err) => eprintln!("Error writing certificate to file: {}", err)
+// Give a file only its owner can read to the "luminum" user, so the daemon can read it after
+// switching to that user. Only root can give files away.
+pub fn give_to_daemon(path: &str) -> io::Result<()> {
+	if unsafe { libc::geteuid() } != 0 {
+		return Ok(());
 		}
-
-	let mut pkcs12_builder = Pkcs12::builder();
-	//let pkcs12 = pkcs12_builder.build(&ui_keypass,"Luminum Server Key",&prv_key, &certificate).expect("Failed to build PKCS#12 structure");
-	let pkcs12 = pkcs12_builder.name("Luminum Server Key").pkey(&prv_key).cert(&certificate).build2(&ui_keypass).expect("Failed to build PKCS#12 structure");
-	let pkcs12_der = pkcs12.to_der().expect("Failed to convert PKCS#12 to DER format");
-	match std::fs::write(DIPATH, &pkcs12_der) {
-		Ok(_) => println!("Identity written to {}",DIPATH),
-		Err(err) => eprintln!("Error writing identity file: {}", err)
+	match sysuser_info("luminum") {
+		(true, Some(uid)) => std::os::unix::fs::chown(path, uid.parse::<u32>().ok(), None),
+		_ => Ok(())
 		}
-
-	Ok(())
 	}
 
 // See if a specific user exists on the system
@@ -890,36 +1248,14 @@ fn sysuser_info(username: &str) -> (bool, Option<String>) {
 
 	if let Ok(pwfile) = File::open(pwpath) {
 		let reader = io::BufReader::new(pwfile);
-		for line in reader.lines() {
-			if let Ok(line) = line {
-				let fields: Vec<&str> = line.split(':').collect();
-				if let (Some(user),Some(uid)) = (fields.get(0),fields.get(2)) {
-					if *user == username {
-						return (true, Some((*uid).to_string()));
-						}
+		for line in reader.lines().map_while(Result::ok) {
+			let fields: Vec<&str> = line.split(':').collect();
+			if let (Some(user),Some(uid)) = (fields.first(),fields.get(2)) {
+				if *user == username {
+					return (true, Some((*uid).to_string()));
 					}
 				}
 			}
 		}
-	return (false,None);
-	}
-
-// Debug Output
-pub fn dbout(debug: bool, outlvl: i32, output: &str) {
-	let dateformat = StrftimeItems::new("%Y-%m-%d %H:%M:%S");
-	let current_datetime = Local::now();
-	let formatted_datetime = current_datetime.format_with_items(dateformat).to_string();
-	let mut etype = String::new();
-
-	if debug {
-		if outlvl == 0 { etype = "PROC".cyan().to_string(); }
-		else if outlvl == 1 { etype = "FAIL".red().to_string(); }
-		else if outlvl == 2 { etype = "WARN".yellow().to_string(); }
-		else if outlvl == 3 { etype = " OK ".green().to_string(); }
-		else if outlvl == 4 { etype = "INFO".to_string(); }
-		println!("{} [{}] {}",formatted_datetime,etype,output);
-		}
-	else {
-		if outlvl == 1 { println!("{}",output); }
-		}
+	(false,None)
 	}
@@ -0,0 +1,150 @@
+// Metrics
+//
+// Counters, gauges and histograms in the Prometheus text format, served at /metrics on the
+// admin listener. The admin listener speaks plain HTTP and is only started when admin
+// addresses are configured, so keep it on a loopback or management address.
+
+use std::time::{Duration, Instant};
+use axum::Router;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use lazy_static::lazy_static;
+use prometheus::{Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder};
+use prometheus::{register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+use luminum_proto::Request;
+use crate::storage::Presence;
+
+// Buckets in seconds, from a cached lookup to a slow database
+const LATENCY_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+lazy_static! {
+	pub static ref CONNECTIONS: IntCounter = register_int_counter!("luminum_connections_accepted_total", "Connections accepted on the data port").unwrap();
+	pub static ref ACTIVE_CONNECTIONS: IntGauge = register_int_gauge!("luminum_connections_active", "Connections currently open on the data port").unwrap();
+	pub static ref HANDSHAKE_FAILURES: IntCounterVec = register_int_counter_vec!("luminum_tls_handshake_failures_total", "TLS handshakes that failed or timed out", &["reason"]).unwrap();
+	pub static ref GUARD_REFUSALS: IntCounterVec = register_int_counter_vec!("luminum_guard_refusals_total", "Connections and registrations refused by per-source limits and bans", &["reason"]).unwrap();
+	pub static ref GUARD_FAILURES: IntCounterVec = register_int_counter_vec!("luminum_guard_failures_total", "Failures counted towards banning their source", &["kind"]).unwrap();
+	pub static ref BANS: IntCounter = register_int_counter!("luminum_guard_bans_total", "Sources banned for repeated failures").unwrap();
+	pub static ref BANNED_SOURCES: IntGauge = register_int_gauge!("luminum_guard_banned_sources", "Sources currently banned").unwrap();
+	pub static ref MALFORMED_MESSAGES: IntCounter = register_int_counter!("luminum_malformed_messages_total", "Sessions closed because of malformed data in the stream").unwrap();
+	pub static ref REGISTRATIONS: IntCounterVec = register_int_counter_vec!("luminum_registrations_total", "Endpoint registration requests by result", &["result"]).unwrap();
+	pub static ref HEARTBEATS: IntCounter = register_int_counter!("luminum_heartbeats_total", "Heartbeats received from endpoints").unwrap();
+	pub static ref ENDPOINTS: IntGaugeVec = register_int_gauge_vec!("luminum_endpoints", "Unrevoked endpoints by presence state, as of the last presence check", &["presence"]).unwrap();
+	pub static ref DB_WAIT: HistogramVec = register_histogram_vec!("luminum_db_wait_seconds", "Time spent waiting for a database connection", &["database"], LATENCY_BUCKETS.to_vec()).unwrap();
+	pub static ref HANDLER_LATENCY: HistogramVec = register_histogram_vec!("luminum_handler_duration_seconds", "Time taken to handle a client request", &["handler"], LATENCY_BUCKETS.to_vec()).unwrap();
+	}
+
+// Register every metric, so families that haven't been updated yet are still scraped
+pub fn register() {
+	lazy_static::initialize(&CONNECTIONS);
+	lazy_static::initialize(&ACTIVE_CONNECTIONS);
+	lazy_static::initialize(&HANDSHAKE_FAILURES);
+	lazy_static::initialize(&GUARD_REFUSALS);
+	lazy_static::initialize(&GUARD_FAILURES);
+	lazy_static::initialize(&BANS);
+	lazy_static::initialize(&BANNED_SOURCES);
+	lazy_static::initialize(&MALFORMED_MESSAGES);
+	lazy_static::initialize(&REGISTRATIONS);
+	lazy_static::initialize(&HEARTBEATS);
+	lazy_static::initialize(&ENDPOINTS);
+	lazy_static::initialize(&DB_WAIT);
+	lazy_static::initialize(&HANDLER_LATENCY);
+	}
+
+// Handler label for a client request
+pub fn handler(request: &Request) -> &'static str {
+	match request {
+		Request::Register(_) => "register",
+		Request::Heartbeat(_) => "heartbeat",
+		Request::IntegrityConfig(_) => "integrity_config",
+		Request::Listen(_) => "listen",
+		Request::CommandResult(_) => "command_result",
+		Request::IntegrityEvents(_) => "integrity_events"
+		}
+	}
+
+pub fn observe_db_wait(database: &str, started: Instant) {
+	DB_WAIT.with_label_values(&[database]).observe(started.elapsed().as_secs_f64());
+	}
+
+pub fn observe_handler(handler: &str, elapsed: Duration) {
+	HANDLER_LATENCY.with_label_values(&[handler]).observe(elapsed.as_secs_f64());
+	}
+
+pub fn set_endpoints(online: usize, stale: usize, offline: usize) {
+	for (presence, count) in [(Presence::Online, online), (Presence::Stale, stale), (Presence::Offline, offline)] {
+		ENDPOINTS.with_label_values(&[presence.as_str()]).set(count as i64);
+		}
+	}
+
+async fn scrape() -> impl IntoResponse {
+	let encoder = TextEncoder::new();
+	let mut body = Vec::new();
+	match encoder.encode(&prometheus::gather(), &mut body) {
+		Ok(()) => (StatusCode::OK, [(header::CONTENT_TYPE, encoder.format_type().to_string())], body),
+		Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, [(header::CONTENT_TYPE, String::from("text/plain"))], err.to_string().into_bytes())
+		}
+	}
+
+// Serve the admin endpoints on the bound admin listeners
+pub async fn serve(listeners: Vec<TcpListener>) {
+	register();
+	let app = Router::new().route("/metrics", get(scrape));
+	for listener in listeners {
+		let address = listener.local_addr().map(|address| address.to_string()).unwrap_or_default();
+		info!("Serving metrics on http://{}/metrics", address);
+		let app = app.clone();
+		tokio::spawn(async move {
+			if let Err(err) = axum::serve(listener, app).await {
+				error!("Admin listener on {} failed: {}", address, err);
+				}
+			});
+		}
+	}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+	use axum::body::to_bytes;
+	use luminum_proto::{CommandResult, Heartbeat, IntegrityConfigRequest, IntegrityEvents, ListenRequest, RegisterRequest};
+	use super::*;
+
+	#[test]
+	fn every_request_has_its_own_handler_label() {
+		let requests = [
+			Request::Register(RegisterRequest::default()),
+			Request::Heartbeat(Heartbeat::default()),
+			Request::IntegrityConfig(IntegrityConfigRequest::default()),
+			Request::Listen(ListenRequest::default()),
+			Request::CommandResult(CommandResult::default()),
+			Request::IntegrityEvents(IntegrityEvents::default())
+			];
+		let labels: HashSet<&str> = requests.iter().map(handler).collect();
+		assert_eq!(labels.len(), requests.len());
+		assert!(labels.iter().all(|label| !label.is_empty() && label.chars().all(|c| c.is_ascii_lowercase() || c == '_')));
+		}
+
+	#[tokio::test]
+	async fn scrape_serves_the_registered_families_as_text() {
+		// Labelled families only appear once they have a labelled value
+		register();
+		set_endpoints(2, 1, 0);
+		observe_handler(handler(&Request::Heartbeat(Heartbeat::default())), Duration::from_millis(3));
+		observe_db_wait("sqlite", Instant::now());
+
+		let response = scrape().await.into_response();
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()[header::CONTENT_TYPE], TextEncoder::new().format_type());
+		let body = String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+		for family in ["luminum_connections_accepted_total", "luminum_connections_active", "luminum_heartbeats_total", "luminum_malformed_messages_total", "luminum_guard_bans_total", "luminum_guard_banned_sources"] {
+			assert!(body.contains(&format!("# TYPE {} ", family)), "{} missing from:\n{}", family, body);
+			}
+		assert!(body.contains("# TYPE luminum_handler_duration_seconds histogram"));
+		assert!(body.contains("luminum_handler_duration_seconds_bucket{handler=\"heartbeat\",le=\"0.005\"}"));
+		assert!(body.contains("luminum_endpoints{presence=\"online\"} 2"));
+		assert!(body.contains("luminum_endpoints{presence=\"stale\"} 1"));
+		assert!(body.contains("luminum_db_wait_seconds_count{database=\"sqlite\"}"));
+		}
+	}
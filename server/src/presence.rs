@@ -0,0 +1,168 @@
+// Endpoint Presence
+//
+// An endpoint is online while its heartbeats keep arriving, stale once it has been quiet for
+// longer than the STALEAFTER threshold, and offline after OFFLINEAFTER. Heartbeats bring an
+// endpoint back online; a monitor task moves quiet endpoints to stale and offline. Every
+// transition is recorded as a presence event.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use crate::enroll::now;
+use crate::listener::ServerState;
+use crate::metrics;
+use crate::shutdown::Shutdown;
+use crate::storage::{Presence, PresenceEvent, Storage, StorageError};
+
+pub const DEFAULT_STALE_AFTER: i64 = 600;
+pub const DEFAULT_OFFLINE_AFTER: i64 = 1800;
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// Seconds without a heartbeat before an endpoint changes state
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+	pub stale_after: i64,
+	pub offline_after: i64
+	}
+
+impl Thresholds {
+	// Presence of an endpoint last seen at the given time
+	pub fn presence(&self, last_seen: i64, now: i64) -> Presence {
+		let quiet = now - last_seen;
+		if quiet >= self.offline_after { Presence::Offline }
+		else if quiet >= self.stale_after { Presence::Stale }
+		else { Presence::Online }
+		}
+	}
+
+// Record a transition for the endpoint if its presence has changed
+pub fn transition(storage: &dyn Storage, uid: &str, old: Presence, new: Presence, at: i64) -> Result<Option<PresenceEvent>, StorageError> {
+	if old == new {
+		return Ok(None);
+		}
+	let event = PresenceEvent { uid: uid.to_string(), old, new, at };
+	storage.record_presence(&event)?;
+	Ok(Some(event))
+	}
+
+// Re-evaluate every unrevoked endpoint, returning the transitions that were recorded. The
+// endpoint counts by presence are published as metrics.
+pub fn check(storage: &dyn Storage, thresholds: &Thresholds, now: i64) -> Result<Vec<PresenceEvent>, StorageError> {
+	let mut events = Vec::new();
+	let (mut online, mut stale, mut offline) = (0, 0, 0);
+	for endpoint in storage.list_endpoints()?.into_iter().filter(|endpoint| !endpoint.revoked) {
+		let current = thresholds.presence(endpoint.last_seen, now);
+		match current {
+			Presence::Online => { online += 1; },
+			Presence::Stale => { stale += 1; },
+			Presence::Offline => { offline += 1; }
+			}
+		if let Some(event) = transition(storage, &endpoint.uid, endpoint.presence, current, now)? {
+			events.push(event);
+			}
+		}
+	metrics::set_endpoints(online, stale, offline);
+	Ok(events)
+	}
+
+pub fn log_event(event: &PresenceEvent) {
+	if event.new == Presence::Online {
+		info!(uid = %event.uid, "Endpoint is now {} (was {})", event.new, event.old);
+		}
+	else {
+		warn!(uid = %event.uid, "Endpoint is now {} (was {})", event.new, event.old);
+		}
+	}
+
+// Periodically check endpoint presence until the server stops
+pub async fn monitor(state: Arc<ServerState>, shutdown: Shutdown) {
+	let mut interval = tokio::time::interval(CHECK_INTERVAL);
+	loop {
+		tokio::select! {
+			_ = interval.tick() => {},
+			_ = shutdown.stopped() => { break; }
+			}
+		let check_state = state.clone();
+		match tokio::task::spawn_blocking(move || check(check_state.storage.as_ref(), &check_state.thresholds(), now())).await {
+			Ok(Ok(events)) => {
+				for event in &events {
+					log_event(event);
+					}
+				},
+			Ok(Err(err)) => { warn!("Unable to check endpoint presence: {}", err); },
+			Err(err) => { error!("Presence check failed: {}", err); }
+			}
+		}
+	}
+
+#[cfg(test)]
+mod tests {
+	use crate::storage::{Endpoint, EnrollmentToken, MemoryStorage};
+	use super::*;
+
+	const NOW: i64 = 1700000000;
+	const THRESHOLDS: Thresholds = Thresholds { stale_after: DEFAULT_STALE_AFTER, offline_after: DEFAULT_OFFLINE_AFTER };
+
+	// Storage holding online endpoints last seen at the given times
+	fn storage(endpoints: &[(&str, i64)]) -> MemoryStorage {
+		let storage = MemoryStorage::new();
+		storage.migrate().unwrap();
+		storage.add_token(&EnrollmentToken { id: String::from("token"), ..EnrollmentToken::default() }).unwrap();
+		for (uid, last_seen) in endpoints {
+			let endpoint = Endpoint { uid: uid.to_string(), token_id: Some(String::from("token")), last_seen: *last_seen, ..Endpoint::default() };
+			assert!(storage.enroll_endpoint(&endpoint, NOW).unwrap());
+			}
+		storage
+		}
+
+	fn transitions(mut events: Vec<PresenceEvent>) -> Vec<(String, Presence, Presence)> {
+		events.sort_by(|a, b| a.uid.cmp(&b.uid));
+		events.into_iter().map(|event| (event.uid, event.old, event.new)).collect()
+		}
+
+	#[test]
+	fn presence_changes_at_the_thresholds() {
+		assert_eq!(THRESHOLDS.presence(NOW, NOW), Presence::Online);
+		assert_eq!(THRESHOLDS.presence(NOW - DEFAULT_STALE_AFTER + 1, NOW), Presence::Online);
+		assert_eq!(THRESHOLDS.presence(NOW - DEFAULT_STALE_AFTER, NOW), Presence::Stale);
+		assert_eq!(THRESHOLDS.presence(NOW - DEFAULT_OFFLINE_AFTER + 1, NOW), Presence::Stale);
+		assert_eq!(THRESHOLDS.presence(NOW - DEFAULT_OFFLINE_AFTER, NOW), Presence::Offline);
+
+		// A clock that runs ahead of the server's doesn't take an endpoint offline
+		assert_eq!(THRESHOLDS.presence(NOW + 60, NOW), Presence::Online);
+		}
+
+	#[test]
+	fn transitions_are_recorded_only_when_presence_changes() {
+		let storage = storage(&[("host01", NOW)]);
+		assert_eq!(transition(&storage, "host01", Presence::Online, Presence::Online, NOW).unwrap(), None);
+		assert!(storage.presence_events("host01").unwrap().is_empty());
+
+		let event = transition(&storage, "host01", Presence::Online, Presence::Stale, NOW).unwrap().unwrap();
+		assert_eq!(event, PresenceEvent { uid: String::from("host01"), old: Presence::Online, new: Presence::Stale, at: NOW });
+		assert_eq!(storage.presence_events("host01").unwrap(), [event]);
+		assert_eq!(storage.find_endpoint("host01").unwrap().unwrap().presence, Presence::Stale);
+		}
+
+	#[test]
+	fn check_moves_quiet_endpoints_and_skips_revoked_ones() {
+		let storage = storage(&[("online", NOW - 10), ("stale", NOW - DEFAULT_STALE_AFTER), ("offline", NOW - DEFAULT_OFFLINE_AFTER), ("revoked", NOW - DEFAULT_OFFLINE_AFTER)]);
+		storage.revoke_endpoint("revoked").unwrap();
+
+		assert_eq!(transitions(check(&storage, &THRESHOLDS, NOW).unwrap()), [
+			(String::from("offline"), Presence::Online, Presence::Offline),
+			(String::from("stale"), Presence::Online, Presence::Stale)
+			]);
+		assert!(storage.presence_events("revoked").unwrap().is_empty());
+		assert_eq!(storage.find_endpoint("revoked").unwrap().unwrap().presence, Presence::Online);
+
+		// Nothing has changed since the last check
+		assert!(check(&storage, &THRESHOLDS, NOW).unwrap().is_empty());
+
+		assert_eq!(transitions(check(&storage, &THRESHOLDS, NOW + DEFAULT_OFFLINE_AFTER - DEFAULT_STALE_AFTER).unwrap()), [
+			(String::from("online"), Presence::Online, Presence::Stale),
+			(String::from("stale"), Presence::Stale, Presence::Offline)
+			]);
+		assert_eq!(storage.presence_events("stale").unwrap().len(), 2);
+		}
+	}
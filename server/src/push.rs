@@ -0,0 +1,224 @@
+// Push Channels
+//
+// Endpoints keep a listening session open so the server can send them commands without
+// waiting for the next poll. Each listening session registers a channel here, keyed by UID.
+// Commands are queued in storage, so other processes such as the command-line tools can
+// create them, and a dispatcher delivers queued commands to connected endpoints.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use uuid::Uuid;
+use luminum_proto::{Command, CommandKind, Lumy, Response, ServerMessage, Status};
+use tracing::{debug, error, warn};
+use crate::enroll::now;
+use crate::listener::ServerState;
+use crate::shutdown::Shutdown;
+use crate::storage::{CommandState, QueuedCommand, StorageError};
+use crate::VER;
+
+// Idle listening sessions get a heartbeat at this interval so both ends notice dead connections
+pub const KEEPALIVE: Duration = Duration::from_secs(60);
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+pub struct Channels {
+	next_id: AtomicU64,
+	sessions: Mutex<HashMap<String, (u64, Sender<ServerMessage>)>>
+	}
+
+impl Channels {
+	fn sessions(&self) -> MutexGuard<'_, HashMap<String, (u64, Sender<ServerMessage>)>> {
+		self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+		}
+
+	// Register a listening session for an endpoint, replacing any earlier session. Messages
+	// for the endpoint go to the given sender. Returns the ID to close the session with.
+	pub fn open(&self, uid: &str, sender: Sender<ServerMessage>) -> u64 {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		self.sessions().insert(uid.to_string(), (id, sender));
+		id
+		}
+
+	// Remove an endpoint's listening session, whichever it is, so nothing more is pushed to it
+	pub fn close_endpoint(&self, uid: &str) {
+		self.sessions().remove(uid);
+		}
+
+	// Remove a listening session, unless the endpoint has since opened a newer one
+	pub fn close(&self, uid: &str, id: u64) {
+		let mut sessions = self.sessions();
+		if sessions.get(uid).is_some_and(|(current, _)| *current == id) {
+			sessions.remove(uid);
+			}
+		}
+
+	pub fn is_connected(&self, uid: &str) -> bool {
+		self.sessions().get(uid).is_some_and(|(_, sender)| !sender.is_closed())
+		}
+
+	// Hand a message to the endpoint's session. Returns false if the endpoint isn't
+	// listening or its session is backed up.
+	pub fn send(&self, uid: &str, msg: ServerMessage) -> bool {
+		match self.sessions().get(uid) {
+			Some((_, sender)) => sender.try_send(msg).is_ok(),
+			None => false
+			}
+		}
+	}
+
+// A new command for an endpoint, ready to be queued
+pub fn new_command(uid: &str, kind: CommandKind) -> QueuedCommand {
+	QueuedCommand {
+		id: Uuid::new_v4().to_string(),
+		uid: uid.to_string(),
+		kind,
+		state: CommandState::Queued,
+		created: now(),
+		sent: None,
+		completed: None,
+		output: None,
+		question_id: None
+		}
+	}
+
+// Send queued commands to the endpoints that are listening. Revoked endpoints get nothing,
+// even if they were revoked by another process after the command was queued.
+fn deliver(state: &ServerState) -> Result<(), StorageError> {
+	let mut revoked = HashMap::new();
+	for queued in state.storage.queued_commands()? {
+		if !state.channels.is_connected(&queued.uid) {
+			continue;
+			}
+		if !revoked.contains_key(&queued.uid) {
+			let endpoint = state.storage.find_endpoint(&queued.uid)?;
+			revoked.insert(queued.uid.clone(), endpoint.is_none_or(|endpoint| endpoint.revoked));
+			}
+		if revoked[&queued.uid] {
+			state.channels.close_endpoint(&queued.uid);
+			continue;
+			}
+		// Marking first means a command is never sent twice. One that can't be handed over
+		// goes back in the queue.
+		if !state.storage.mark_command_sent(&queued.id, now())? {
+			continue;
+			}
+		let msg = ServerMessage::new(VER,Lumy::ServerCore,Status::Ok,Response::Command(Command { id: queued.id.clone(), kind: queued.kind }));
+		if state.channels.send(&queued.uid, msg) {
+			debug!(uid = %queued.uid, "Sent command {}", queued.id);
+			}
+		else {
+			state.storage.requeue_command(&queued.id)?;
+			}
+		}
+	Ok(())
+	}
+
+// Deliver queued commands until the server stops
+pub async fn dispatch(state: Arc<ServerState>, shutdown: Shutdown) {
+	let mut interval = tokio::time::interval(DISPATCH_INTERVAL);
+	loop {
+		tokio::select! {
+			_ = interval.tick() => {},
+			_ = shutdown.stopped() => { break; }
+			}
+		let dispatch_state = state.clone();
+		match tokio::task::spawn_blocking(move || deliver(&dispatch_state)).await {
+			Ok(Ok(())) => {},
+			Ok(Err(err)) => { warn!("Unable to deliver queued commands: {}", err); },
+			Err(err) => { error!("Command dispatcher failed: {}", err); }
+			}
+		}
+	}
+
+#[cfg(test)]
+mod tests {
+	use tokio::sync::mpsc::channel;
+	use crate::storage::Storage;
+	use crate::testing::Fixture;
+	use super::*;
+
+	fn question(uid: &str) -> QueuedCommand {
+		new_command(uid, CommandKind::Question(String::from("hostname")))
+		}
+
+	fn states(storage: &dyn Storage, uid: &str) -> Vec<CommandState> {
+		storage.endpoint_commands(uid).unwrap().into_iter().map(|command| command.state).collect()
+		}
+
+	#[test]
+	fn delivers_queued_commands_to_listening_endpoints() {
+		let fixture = Fixture::new();
+		fixture.endpoint("listening");
+		fixture.endpoint("offline");
+		let (sender, mut receiver) = channel(8);
+		fixture.state.channels.open("listening", sender);
+		fixture.storage().queue_command(&question("listening")).unwrap();
+		fixture.storage().queue_command(&question("offline")).unwrap();
+
+		deliver(&fixture.state).unwrap();
+		assert!(matches!(receiver.try_recv().unwrap().content.response, Response::Command(_)));
+		assert_eq!(states(fixture.storage(), "listening"), [CommandState::Sent]);
+		assert_eq!(states(fixture.storage(), "offline"), [CommandState::Queued]);
+
+		// Sent commands aren't sent again
+		deliver(&fixture.state).unwrap();
+		assert!(receiver.try_recv().is_err());
+		}
+
+	#[test]
+	fn requeues_commands_that_cannot_be_handed_over() {
+		let fixture = Fixture::new();
+		fixture.endpoint("endpoint");
+		let (sender, mut receiver) = channel(1);
+		fixture.state.channels.open("endpoint", sender.clone());
+		// The session is backed up
+		sender.try_send(ServerMessage::new(VER,Lumy::ServerCore,Status::Online,Response::Heartbeat(Default::default()))).unwrap();
+		fixture.storage().queue_command(&question("endpoint")).unwrap();
+
+		deliver(&fixture.state).unwrap();
+		let commands = fixture.storage().endpoint_commands("endpoint").unwrap();
+		assert_eq!((commands[0].state, commands[0].sent), (CommandState::Queued, None));
+
+		receiver.try_recv().unwrap();
+		deliver(&fixture.state).unwrap();
+		assert!(matches!(receiver.try_recv().unwrap().content.response, Response::Command(_)));
+		assert_eq!(states(fixture.storage(), "endpoint"), [CommandState::Sent]);
+		assert!(!fixture.storage().mark_command_sent(&commands[0].id, now()).unwrap());
+		}
+
+	#[test]
+	fn revoking_cancels_queued_commands() {
+		let fixture = Fixture::new();
+		fixture.endpoint("endpoint");
+		let (sender, mut receiver) = channel(8);
+		fixture.state.channels.open("endpoint", sender);
+		let sent = question("endpoint");
+		fixture.storage().queue_command(&sent).unwrap();
+		deliver(&fixture.state).unwrap();
+		assert!(receiver.try_recv().is_ok());
+		fixture.storage().queue_command(&question("endpoint")).unwrap();
+
+		assert!(fixture.storage().revoke_endpoint("endpoint").unwrap());
+		assert_eq!(states(fixture.storage(), "endpoint"), [CommandState::Sent, CommandState::Cancelled]);
+		assert!(!fixture.storage().revoke_endpoint("unknown").unwrap());
+		}
+
+	#[test]
+	fn revoked_endpoints_get_nothing_pushed() {
+		let fixture = Fixture::new();
+		fixture.endpoint("endpoint");
+		let (sender, mut receiver) = channel(8);
+		fixture.state.channels.open("endpoint", sender);
+		fixture.storage().revoke_endpoint("endpoint").unwrap();
+
+		// A command queued by another process after the endpoint was revoked
+		fixture.storage().queue_command(&question("endpoint")).unwrap();
+		deliver(&fixture.state).unwrap();
+		assert!(receiver.try_recv().is_err());
+		assert!(!fixture.state.channels.is_connected("endpoint"));
+		assert_eq!(states(fixture.storage(), "endpoint"), [CommandState::Queued]);
+		}
+	}
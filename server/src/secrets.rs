@@ -0,0 +1,298 @@
+// Secret Storage
+//
+// Secrets kept in the configuration database (the private key passphrase and the MySQL
+// password) and enrollment keys kept in storage are sealed with AES-256-GCM under a master
+// key that is never stored next to them. The master key comes from the LUMINUM_MASTER_KEY
+// environment variable, the systemd credential store, or a key file readable only by root,
+// in that order. Each sealed value is bound to its name, so values can't be swapped around.
+//
+// Configurations written by older versions encrypted their secrets with a key (SVRKEY) kept
+// in the same database. Those values are still read; --rotate-secrets seals them properly
+// and removes SVRKEY.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use magic_crypt::{new_magic_crypt, MagicCryptTrait};
+use openssl::error::ErrorStack;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+pub const MASTER_KEY_VAR: &str = "LUMINUM_MASTER_KEY";
+// Name of the credential under $CREDENTIALS_DIRECTORY (systemd LoadCredential=)
+pub const CREDENTIAL_NAME: &str = "luminum-master-key";
+// Configuration value holding the key used by older versions
+pub const LEGACY_KEY: &str = "SVRKEY";
+const SEALED_PREFIX: &str = "aead1:";
+const KEY_BYTES: usize = 32;
+const NONCE_BYTES: usize = 12;
+const TAG_BYTES: usize = 16;
+
+#[derive(Debug)]
+pub enum SecretError {
+	// No master key in the environment, the credential store or the key file
+	NoMasterKey(String),
+	InsecureKeyFile(String),
+	InvalidKey(String),
+	Io(String, io::Error),
+	Crypto(ErrorStack),
+	// A sealed value couldn't be opened with the master key
+	Unsealable(String),
+	Missing(String)
+	}
+
+impl fmt::Display for SecretError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SecretError::NoMasterKey(path) => write!(f, "no master key found (set {}, provide the {} credential or create {})", MASTER_KEY_VAR, CREDENTIAL_NAME, path),
+			SecretError::InsecureKeyFile(path) => write!(f, "master key file {} must be owned by root and not accessible to group or others", path),
+			SecretError::InvalidKey(source) => write!(f, "master key from {} is not a base64-encoded {}-byte key", source, KEY_BYTES),
+			SecretError::Io(path, err) => write!(f, "unable to access {}: {}", path, err),
+			SecretError::Crypto(err) => write!(f, "cryptographic error: {}", err),
+			SecretError::Unsealable(name) => write!(f, "{} can't be decrypted with the master key", name),
+			SecretError::Missing(name) => write!(f, "{} is not configured", name)
+			}
+		}
+	}
+
+impl Error for SecretError {}
+
+impl From<ErrorStack> for SecretError {
+	fn from(err: ErrorStack) -> Self { SecretError::Crypto(err) }
+	}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeySource {
+	Environment,
+	Credential(String),
+	File(String)
+	}
+
+impl fmt::Display for KeySource {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			KeySource::Environment => write!(f, "environment variable {}", MASTER_KEY_VAR),
+			KeySource::Credential(path) => write!(f, "credential {}", path),
+			KeySource::File(path) => write!(f, "key file {}", path)
+			}
+		}
+	}
+
+pub struct MasterKey {
+	key: [u8; KEY_BYTES],
+	source: KeySource
+	}
+
+impl MasterKey {
+	// Find the master key. The key file is checked last and must be readable only by root.
+	pub fn load(key_file: &str) -> Result<MasterKey, SecretError> {
+		if let Ok(encoded) = env::var(MASTER_KEY_VAR) {
+			return MasterKey::decode(&encoded, KeySource::Environment);
+			}
+		if let Ok(directory) = env::var("CREDENTIALS_DIRECTORY") {
+			let path = Path::new(&directory).join(CREDENTIAL_NAME).to_string_lossy().into_owned();
+			match fs::read_to_string(&path) {
+				Ok(encoded) => { return MasterKey::decode(&encoded, KeySource::Credential(path)); },
+				Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+				Err(err) => { return Err(SecretError::Io(path, err)); }
+				}
+			}
+		let metadata = match fs::metadata(key_file) {
+			Ok(metadata) => metadata,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => { return Err(SecretError::NoMasterKey(key_file.to_string())); },
+			Err(err) => { return Err(SecretError::Io(key_file.to_string(), err)); }
+			};
+		if metadata.uid() != 0 || metadata.mode() & 0o077 != 0 {
+			return Err(SecretError::InsecureKeyFile(key_file.to_string()));
+			}
+		let encoded = fs::read_to_string(key_file).map_err(|err| SecretError::Io(key_file.to_string(), err))?;
+		MasterKey::decode(&encoded, KeySource::File(key_file.to_string()))
+		}
+
+	// Create a new random key, to be written to the given key file
+	pub fn generate(key_file: &str) -> Result<MasterKey, SecretError> {
+		let mut key = [0u8; KEY_BYTES];
+		rand_bytes(&mut key)?;
+		Ok(MasterKey { key, source: KeySource::File(key_file.to_string()) })
+		}
+
+	fn decode(encoded: &str, source: KeySource) -> Result<MasterKey, SecretError> {
+		let bytes = STANDARD.decode(encoded.trim()).map_err(|_| SecretError::InvalidKey(source.to_string()))?;
+		let key: [u8; KEY_BYTES] = bytes.try_into().map_err(|_| SecretError::InvalidKey(source.to_string()))?;
+		Ok(MasterKey { key, source })
+		}
+
+	pub fn source(&self) -> &KeySource {
+		&self.source
+		}
+
+	// Write the key to a file only root can read. An existing file is replaced atomically.
+	pub fn write(&self, path: &str) -> Result<(), SecretError> {
+		let staging = format!("{}.tmp", path);
+		let _ = fs::remove_file(&staging);
+		let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&staging).map_err(|err| SecretError::Io(staging.clone(), err))?;
+		file.write_all(format!("{}\n", STANDARD.encode(self.key)).as_bytes())
+			.and_then(|_| file.sync_all())
+			.map_err(|err| SecretError::Io(staging.clone(), err))?;
+		fs::rename(&staging, path).map_err(|err| SecretError::Io(path.to_string(), err))
+		}
+
+	// Encrypt a value. The name is authenticated along with it.
+	pub fn seal(&self, name: &str, plaintext: &[u8]) -> Result<String, SecretError> {
+		let mut nonce = [0u8; NONCE_BYTES];
+		rand_bytes(&mut nonce)?;
+		let mut tag = [0u8; TAG_BYTES];
+		let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), &self.key, Some(&nonce), name.as_bytes(), plaintext, &mut tag)?;
+		let mut sealed = Vec::with_capacity(NONCE_BYTES + ciphertext.len() + TAG_BYTES);
+		sealed.extend_from_slice(&nonce);
+		sealed.extend_from_slice(&ciphertext);
+		sealed.extend_from_slice(&tag);
+		Ok(format!("{}{}", SEALED_PREFIX, STANDARD.encode(sealed)))
+		}
+
+	pub fn open(&self, name: &str, sealed: &str) -> Result<Vec<u8>, SecretError> {
+		let unsealable = || SecretError::Unsealable(name.to_string());
+		let encoded = sealed.strip_prefix(SEALED_PREFIX).ok_or_else(unsealable)?;
+		let bytes = STANDARD.decode(encoded).map_err(|_| unsealable())?;
+		if bytes.len() < NONCE_BYTES + TAG_BYTES {
+			return Err(unsealable());
+			}
+		let (nonce, rest) = bytes.split_at(NONCE_BYTES);
+		let (ciphertext, tag) = rest.split_at(rest.len() - TAG_BYTES);
+		decrypt_aead(Cipher::aes_256_gcm(), &self.key, Some(nonce), name.as_bytes(), ciphertext, tag).map_err(|_| unsealable())
+		}
+
+	pub fn seal_str(&self, name: &str, plaintext: &str) -> Result<String, SecretError> {
+		self.seal(name, plaintext.as_bytes())
+		}
+
+	pub fn open_str(&self, name: &str, sealed: &str) -> Result<String, SecretError> {
+		String::from_utf8(self.open(name, sealed)?).map_err(|_| SecretError::Unsealable(name.to_string()))
+		}
+	}
+
+// Whether a configuration still has secrets encrypted by an older version
+pub fn is_legacy(serverconfig: &HashMap<String, String>) -> bool {
+	serverconfig.contains_key(LEGACY_KEY)
+	}
+
+// Read a secret from the configuration database. Values sealed with the master key need it;
+// values from older versions are decrypted with SVRKEY.
+pub fn reveal(master_key: Option<&MasterKey>, serverconfig: &HashMap<String, String>, name: &str) -> Result<String, SecretError> {
+	let value = serverconfig.get(name).ok_or_else(|| SecretError::Missing(name.to_string()))?;
+	if value.starts_with(SEALED_PREFIX) {
+		return match master_key {
+			Some(master_key) => master_key.open_str(name, value),
+			None => Err(SecretError::Unsealable(name.to_string()))
+			};
+		}
+	let legacy_key = serverconfig.get(LEGACY_KEY).ok_or_else(|| SecretError::Unsealable(name.to_string()))?;
+	let mc = new_magic_crypt!(legacy_key, 256);
+	mc.decrypt_base64_to_string(value).map_err(|_| SecretError::Unsealable(name.to_string()))
+	}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::PermissionsExt;
+	use uuid::Uuid;
+	use super::*;
+
+	fn key() -> MasterKey {
+		MasterKey::generate("unused").unwrap()
+		}
+
+	#[test]
+	fn seals_and_opens() {
+		let key = key();
+		let sealed = key.seal_str("PKPASS", "secret passphrase").unwrap();
+		assert!(sealed.starts_with(SEALED_PREFIX));
+		assert!(!sealed.contains("secret passphrase"));
+		assert_eq!(key.open_str("PKPASS", &sealed).unwrap(), "secret passphrase");
+		// Every value gets its own nonce
+		assert_ne!(key.seal_str("PKPASS", "secret passphrase").unwrap(), sealed);
+		}
+
+	#[test]
+	fn binds_sealed_values_to_their_name() {
+		let key = key();
+		let sealed = key.seal_str("PKPASS", "secret passphrase").unwrap();
+		assert!(matches!(key.open("DBPASS", &sealed), Err(SecretError::Unsealable(name)) if name == "DBPASS"));
+		}
+
+	#[test]
+	fn rejects_other_keys_and_damaged_values() {
+		let sealed = key().seal_str("PKPASS", "secret passphrase").unwrap();
+		assert!(key().open("PKPASS", &sealed).is_err());
+
+		let mut damaged = STANDARD.decode(sealed.strip_prefix(SEALED_PREFIX).unwrap()).unwrap();
+		damaged[NONCE_BYTES] ^= 1;
+		let key = key();
+		for value in [format!("{}{}", SEALED_PREFIX, STANDARD.encode(damaged)), format!("{}AAAA", SEALED_PREFIX), String::from("aead1:not base64"), String::from("plaintext")] {
+			assert!(key.open("PKPASS", &value).is_err(), "{:?}", value);
+			}
+		}
+
+	#[test]
+	fn reveals_sealed_and_legacy_values() {
+		let key = key();
+		let legacy = new_magic_crypt!("legacy server key", 256);
+		let serverconfig = HashMap::from([
+			(String::from(LEGACY_KEY), String::from("legacy server key")),
+			(String::from("PKPASS"), key.seal_str("PKPASS", "sealed passphrase").unwrap()),
+			(String::from("DBPASS"), legacy.encrypt_str_to_base64("legacy password"))
+			]);
+		assert!(is_legacy(&serverconfig));
+		assert_eq!(reveal(Some(&key), &serverconfig, "PKPASS").unwrap(), "sealed passphrase");
+		assert_eq!(reveal(Some(&key), &serverconfig, "DBPASS").unwrap(), "legacy password");
+		assert_eq!(reveal(None, &serverconfig, "DBPASS").unwrap(), "legacy password");
+		assert!(matches!(reveal(None, &serverconfig, "PKPASS"), Err(SecretError::Unsealable(_))));
+		assert!(matches!(reveal(Some(&key), &serverconfig, "SVRPASS"), Err(SecretError::Missing(_))));
+
+		// Without SVRKEY, unsealed values can't be read
+		let mut serverconfig = serverconfig;
+		serverconfig.remove(LEGACY_KEY);
+		assert!(!is_legacy(&serverconfig));
+		assert!(matches!(reveal(Some(&key), &serverconfig, "DBPASS"), Err(SecretError::Unsealable(_))));
+		}
+
+	#[test]
+	fn loads_key_files_only_root_can_read() {
+		let dir = std::env::temp_dir().join(format!("luminum-test-{}", Uuid::new_v4()));
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("master.key").to_string_lossy().into_owned();
+		assert!(matches!(MasterKey::load(&path), Err(SecretError::NoMasterKey(_))));
+
+		let key = MasterKey::generate(&path).unwrap();
+		key.write(&path).unwrap();
+		assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+		let sealed = key.seal_str("PKPASS", "secret passphrase").unwrap();
+		match MasterKey::load(&path) {
+			Ok(loaded) => {
+				assert_eq!(loaded.source(), &KeySource::File(path.clone()));
+				assert_eq!(loaded.open_str("PKPASS", &sealed).unwrap(), "secret passphrase");
+				},
+			// Only files owned by root are accepted
+			Err(SecretError::InsecureKeyFile(_)) => assert_ne!(fs::metadata(&path).unwrap().uid(), 0),
+			Err(err) => panic!("{}", err)
+			}
+
+		for mode in [0o640, 0o604, 0o644] {
+			fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+			assert!(matches!(MasterKey::load(&path), Err(SecretError::InsecureKeyFile(_))), "{:o}", mode);
+			}
+
+		fs::write(&path, "not a key\n").unwrap();
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+		if fs::metadata(&path).unwrap().uid() == 0 {
+			assert!(matches!(MasterKey::load(&path), Err(SecretError::InvalidKey(_))));
+			}
+		fs::remove_dir_all(&dir).unwrap();
+		}
+	}
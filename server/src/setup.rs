@@ -0,0 +1,438 @@
+// Declarative Setup
+//
+// Non-interactive counterpart to the --setup prompts, for provisioning servers from
+// configuration management. Settings come from a JSON setup file, LUMINUM_* environment
+// variables and command-line flags, in increasing order of precedence. Anything not given
+// keeps its current configured value. Each step checks what already exists and only creates
+// or updates what is missing or different, so setup is safe to re-run. A dry run reports the
+// steps without performing any of them.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::process;
+use clap::ArgMatches;
+use openssl::pkey::PKey;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::secrets::{self, MasterKey, SecretError};
+use crate::storage::{migrations, MysqlStorage, SqliteStorage, Storage};
+use crate::tls::{CertificatePolicy, ClientCa};
+use crate::config::Paths;
+use crate::{DDBPATH, DPORT};
+use crate::{file_exists, generate_certificate, generate_private_key, is_valid_ipv4_address, is_valid_ipv6_address, read_serverconfig, sysuser_info};
+
+// Certificate subject for a new server certificate. Empty fields are left out, except the
+// common name, which is required.
+#[derive(Debug, Default)]
+pub struct Subject {
+	pub country: String,
+	pub state: String,
+	pub locality: String,
+	pub organization: String,
+	pub common_name: String
+	}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SetupFile {
+	address: Option<String>,
+	port: Option<u16>,
+	storage: Option<String>,
+	dbpath: Option<String>,
+	key_passphrase: Option<String>,
+	key_passphrase_file: Option<String>,
+	certificate: SubjectFile,
+	mysql_admin: Option<String>,
+	mysql_admin_password: Option<String>,
+	mysql_admin_password_file: Option<String>
+	}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SubjectFile {
+	country: Option<String>,
+	state: Option<String>,
+	locality: Option<String>,
+	organization: Option<String>,
+	common_name: Option<String>
+	}
+
+// Setup settings after merging every source
+struct Settings {
+	address: Option<String>,
+	port: Option<String>,
+	storage: Option<String>,
+	dbpath: Option<String>,
+	passphrase: Option<String>,
+	subject: Subject,
+	mysql_admin: String,
+	mysql_admin_password: String
+	}
+
+impl Settings {
+	fn resolve(matches: &ArgMatches) -> Result<Settings, String> {
+		let file: SetupFile = match matches.value_of("setup-file") {
+			Some(path) => {
+				let contents = fs::read_to_string(path).map_err(|err| format!("Unable to read setup file {}: {}", path, err))?;
+				serde_json::from_str(&contents).map_err(|err| format!("Invalid setup file {}: {}", path, err))?
+				},
+			None => SetupFile::default()
+			};
+		let pick = |flag: &str, var: &str, value: Option<String>| {
+			matches.value_of(flag).map(String::from).or_else(|| env::var(var).ok()).or(value)
+			};
+
+		Ok(Settings {
+			address: pick("address", "LUMINUM_ADDRESS", file.address),
+			port: pick("port", "LUMINUM_PORT", file.port.map(|port| port.to_string())),
+			storage: pick("storage", "LUMINUM_STORAGE", file.storage),
+			dbpath: pick("dbpath", "LUMINUM_DBPATH", file.dbpath),
+			passphrase: secret(matches.value_of("key-passphrase-file"), "LUMINUM_KEY_PASSPHRASE", file.key_passphrase, file.key_passphrase_file)?,
+			subject: Subject {
+				country: pick("cert-country", "LUMINUM_CERT_COUNTRY", file.certificate.country).unwrap_or_default(),
+				state: pick("cert-state", "LUMINUM_CERT_STATE", file.certificate.state).unwrap_or_default(),
+				locality: pick("cert-locality", "LUMINUM_CERT_LOCALITY", file.certificate.locality).unwrap_or_default(),
+				organization: pick("cert-organization", "LUMINUM_CERT_ORGANIZATION", file.certificate.organization).unwrap_or_default(),
+				common_name: pick("cert-cn", "LUMINUM_CERT_CN", file.certificate.common_name).unwrap_or_default()
+				},
+			mysql_admin: pick("mysql-admin", "LUMINUM_MYSQL_ADMIN", file.mysql_admin).unwrap_or_else(|| String::from("root")),
+			mysql_admin_password: secret(matches.value_of("mysql-admin-password-file"), "LUMINUM_MYSQL_ADMIN_PASSWORD", file.mysql_admin_password, file.mysql_admin_password_file)?.unwrap_or_default()
+			})
+		}
+	}
+
+// Secrets are never taken as flag values, since those are visible to other users. They come
+// from a file named by a flag, an environment variable, or the setup file.
+fn secret(flag_file: Option<&str>, var: &str, value: Option<String>, value_file: Option<String>) -> Result<Option<String>, String> {
+	if let Some(path) = flag_file {
+		return read_secret(path).map(Some);
+		}
+	if let Ok(value) = env::var(var) {
+		return Ok(Some(value));
+		}
+	if value.is_some() {
+		return Ok(value);
+		}
+	value_file.map(|path| read_secret(&path)).transpose()
+	}
+
+pub fn read_secret(path: &str) -> Result<String, String> {
+	match fs::read_to_string(path) {
+		Ok(contents) => Ok(contents.trim_end_matches(&['\r', '\n'][..]).to_string()),
+		Err(err) => Err(format!("Unable to read {}: {}", path, err))
+		}
+	}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+	Create,
+	Update,
+	Keep
+	}
+
+#[derive(Serialize)]
+struct Step {
+	action: Action,
+	item: String
+	}
+
+// What setup did, or would do on a dry run. The database password is only included once it
+// exists, so never on a dry run.
+#[derive(Serialize)]
+struct Report {
+	dry_run: bool,
+	changed: bool,
+	steps: Vec<Step>,
+	database_password: Option<String>
+	}
+
+impl Report {
+	fn step(&mut self, action: Action, item: String) {
+		if action != Action::Keep {
+			self.changed = true;
+			}
+		self.steps.push(Step { action, item });
+		}
+
+	fn print(&self) {
+		for step in &self.steps {
+			let action = match (step.action, self.dry_run) {
+				(Action::Create, false) => "Created",
+				(Action::Create, true) => "Would create",
+				(Action::Update, false) => "Updated",
+				(Action::Update, true) => "Would update",
+				(Action::Keep, _) => "Unchanged"
+				};
+			println!("{}: {}", action, step.item);
+			}
+		if let Some(dbpass) = &self.database_password {
+			println!("Database password for \"luminum\" user: {}", dbpass);
+			}
+		if self.dry_run {
+			println!("Dry run: no changes were made.");
+			}
+		else if self.changed {
+			println!("Luminum Server setup is complete.");
+			}
+		else {
+			println!("Luminum Server is already set up. Nothing to do.");
+			}
+		}
+	}
+
+// Run declarative setup and exit. Output is JSON with "--output json".
+pub fn run(matches: &ArgMatches, paths: &Paths, mysql_socket: &str, policy: &CertificatePolicy) {
+	let dry_run = matches.is_present("dry-run");
+	let json = matches.value_of("output") == Some("json");
+	match Settings::resolve(matches).and_then(|settings| apply(&settings, paths, mysql_socket, policy, dry_run)) {
+		Ok(report) => {
+			if json { println!("{}", serde_json::to_string_pretty(&report).unwrap()); }
+			else { report.print(); }
+			process::exit(0);
+			},
+		Err(err) => {
+			if json { println!("{}", serde_json::json!({ "error": err })); }
+			else { println!("Error: {}", err); }
+			process::exit(1);
+			}
+		}
+	}
+
+fn apply(settings: &Settings, paths: &Paths, mysql_socket: &str, policy: &CertificatePolicy, dry_run: bool) -> Result<Report, String> {
+	let mut report = Report { dry_run, changed: false, steps: Vec::new(), database_password: None };
+
+	// Settings that weren't given keep their configured values, then fall back to defaults
+	let existing = if file_exists(&paths.config_db) {
+		read_serverconfig(&paths.config_db).map_err(|err| format!("Unable to read configuration database {}: {}", paths.config_db, err))?
+		}
+	else {
+		HashMap::new()
+		};
+	let configured = existing.contains_key("PKPASS");
+	let legacy = secrets::is_legacy(&existing);
+	if !existing.is_empty() && !configured {
+		return Err(format!("Configuration database {} is incomplete. Move it aside and run setup again.", paths.config_db));
+		}
+
+	let address = settings.address.clone().or_else(|| existing.get("IPADDR").cloned()).ok_or("A server IP address is required")?;
+	if !is_valid_ipv4_address(&address) && !is_valid_ipv6_address(&address) {
+		return Err(format!("Invalid IP address: {}", address));
+		}
+	let port = settings.port.clone().or_else(|| existing.get("PORT").cloned()).unwrap_or_else(|| DPORT.to_string());
+	if port.parse::<u16>().map_or(true, |port| port == 0) {
+		return Err(format!("Invalid port: {}", port));
+		}
+	let storage = settings.storage.clone().or_else(|| existing.get("STORAGE").cloned()).unwrap_or_else(|| String::from("mysql"));
+	if storage != "mysql" && storage != "sqlite" {
+		return Err(format!("Invalid storage backend: {}", storage));
+		}
+	let dbpath = settings.dbpath.clone().or_else(|| existing.get("DBPATH").cloned()).unwrap_or_else(|| DDBPATH.to_string());
+
+	// Stored secrets are sealed with the master key. Secrets sealed with a master key that can't
+	// be found are an error; a new key is only made for new and legacy configurations.
+	let master_key = match MasterKey::load(&paths.master_key) {
+		Ok(master_key) => {
+			report.step(Action::Keep, format!("Master key from {}", master_key.source()));
+			master_key
+			},
+		Err(SecretError::NoMasterKey(_)) if !configured || legacy => {
+			report.step(Action::Create, format!("Master key {}", paths.master_key));
+			let master_key = MasterKey::generate(&paths.master_key).map_err(|err| format!("Unable to create master key: {}", err))?;
+			if !dry_run {
+				master_key.write(&paths.master_key).map_err(|err| format!("Unable to write master key: {}", err))?;
+				}
+			master_key
+			},
+		Err(err) => { return Err(format!("Unable to load master key: {}", err)); }
+		};
+
+	// Secrets already in the configuration are reused, so re-running setup never changes them
+	let stored = |key: &str| secrets::reveal(Some(&master_key), &existing, key).map_err(|err| format!("Unable to read stored secrets: {}", err));
+	let stored_passphrase = if configured { Some(stored("PKPASS")?) } else { None };
+	let passphrase = settings.passphrase.clone().or_else(|| stored_passphrase.clone()).ok_or("A private key passphrase is required")?;
+	let dbpass = if configured { stored("DBPASS")? } else { random_str::get_string(16, true, true, true, true) };
+	let seal = |key: &str, value: &str| master_key.seal_str(key, value).map_err(|err| format!("Unable to encrypt {}: {}", key, err));
+
+	// Server key pair
+	let new_key = !file_exists(&paths.private_key);
+	if new_key {
+		report.step(Action::Create, format!("Server key pair {} and {}", paths.private_key, paths.public_key));
+		if !dry_run {
+			generate_private_key(paths, &passphrase, policy.key_type).map_err(|err| format!("Unable to create server key pair: {}", err))?;
+			}
+		}
+	else {
+		let pem = fs::read(&paths.private_key).map_err(|err| format!("Unable to read {}: {}", paths.private_key, err))?;
+		let key = PKey::private_key_from_pem_passphrase(&pem, passphrase.as_bytes()).map_err(|_| format!("The private key {} can't be decrypted with the given passphrase", paths.private_key))?;
+		report.step(Action::Keep, format!("Server private key {}", paths.private_key));
+		if file_exists(&paths.public_key) {
+			report.step(Action::Keep, format!("Server public key {}", paths.public_key));
+			}
+		else {
+			report.step(Action::Create, format!("Server public key {}", paths.public_key));
+			if !dry_run {
+				let pub_key = key.public_key_to_pem().map_err(|err| format!("Unable to encode public key: {}", err))?;
+				fs::write(&paths.public_key, pub_key).map_err(|err| format!("Unable to write {}: {}", paths.public_key, err))?;
+				}
+			}
+		}
+
+	// Server certificate and identity. A new key pair needs a new certificate.
+	let have_certificate = file_exists(&paths.certificate) && file_exists(&paths.identity);
+	if have_certificate && !new_key {
+		report.step(Action::Keep, format!("Server certificate {} and identity {}", paths.certificate, paths.identity));
+		}
+	else {
+		if settings.subject.common_name.is_empty() {
+			return Err(String::from("A certificate common name is required to create the server certificate"));
+			}
+		if !settings.subject.country.is_empty() && (settings.subject.country.len() != 2 || !settings.subject.country.chars().all(|c| c.is_ascii_alphabetic())) {
+			return Err(format!("Invalid country code: {}", settings.subject.country));
+			}
+		let action = if have_certificate { Action::Update } else { Action::Create };
+		let listen: Vec<IpAddr> = address.parse().into_iter().collect();
+		let names = policy.alt_names(&listen, &settings.subject.common_name);
+		report.step(action, format!("Server certificate {} and identity {} for {}", paths.certificate, paths.identity, names.join(", ")));
+		if !dry_run {
+			generate_certificate(paths, &passphrase, &settings.subject, &names, policy.validity).map_err(|err| format!("Unable to create server certificate: {}", err))?;
+			}
+		}
+
+	// Client certificate authority
+	if file_exists(&paths.client_ca_certificate) {
+		ClientCa::load(paths, &passphrase).map_err(|err| format!("Unable to load client certificate authority: {}", err))?;
+		report.step(Action::Keep, format!("Client certificate authority {}", paths.client_ca_certificate));
+		}
+	else {
+		report.step(Action::Create, format!("Client certificate authority {}", paths.client_ca_certificate));
+		if !dry_run {
+			ClientCa::create(paths, &passphrase, policy.key_type).map_err(|err| format!("Unable to create client certificate authority: {}", err))?;
+			}
+		}
+
+	// Configuration database
+	let mut values = vec![("IPADDR", address), ("PORT", port), ("STORAGE", storage.clone())];
+	if storage == "sqlite" {
+		values.push(("DBPATH", dbpath.clone()));
+		}
+	let mut changes = Vec::new();
+	if configured {
+		for (key, value) in &values {
+			match existing.get(*key) {
+				Some(current) if current == value => {},
+				Some(current) => {
+					report.step(Action::Update, format!("Configuration {}: {} -> {}", key, current, value));
+					changes.push((key.to_string(), value.clone()));
+					},
+				None => {
+					report.step(Action::Create, format!("Configuration {}: {}", key, value));
+					changes.push((key.to_string(), value.clone()));
+					}
+				}
+			}
+		if legacy {
+			report.step(Action::Update, String::from("Configuration PKPASS and DBPASS, sealed with the master key"));
+			changes.push((String::from("PKPASS"), seal("PKPASS", &passphrase)?));
+			changes.push((String::from("DBPASS"), seal("DBPASS", &dbpass)?));
+			}
+		else if stored_passphrase.as_deref() != Some(passphrase.as_str()) {
+			report.step(Action::Update, String::from("Configuration PKPASS"));
+			changes.push((String::from("PKPASS"), seal("PKPASS", &passphrase)?));
+			}
+		if changes.is_empty() {
+			report.step(Action::Keep, format!("Configuration database {}", paths.config_db));
+			}
+		}
+	else {
+		report.step(Action::Create, format!("Configuration database {}", paths.config_db));
+		changes.push((String::from("SID"), Uuid::new_v4().to_string()));
+		changes.push((String::from("PKPASS"), seal("PKPASS", &passphrase)?));
+		changes.push((String::from("DBPASS"), seal("DBPASS", &dbpass)?));
+		changes.extend(values.iter().map(|(key, value)| (key.to_string(), value.clone())));
+		}
+	if !dry_run && !changes.is_empty() {
+		// Legacy secrets were resealed above, so the key kept beside them can go
+		write_config(paths, &changes, &[secrets::LEGACY_KEY]).map_err(|err| format!("Unable to write configuration database {}: {}", paths.config_db, err))?;
+		}
+
+	// Storage backend and schema
+	let database: Option<Box<dyn Storage>> = if storage == "mysql" {
+		if !file_exists(mysql_socket) {
+			return Err(format!("Database socket ({}) is missing.", mysql_socket));
+			}
+		match MysqlStorage::connect(mysql_socket, "luminum", &dbpass) {
+			Ok(mysql) => {
+				report.step(Action::Keep, String::from("MySQL databases CLIENTS, INTEGRITY and user \"luminum\""));
+				Some(Box::new(mysql))
+				},
+			Err(_) => {
+				report.step(Action::Create, String::from("MySQL databases CLIENTS, INTEGRITY and user \"luminum\""));
+				if dry_run {
+					None
+					}
+				else {
+					MysqlStorage::provision(mysql_socket, &settings.mysql_admin, &settings.mysql_admin_password, "luminum", &dbpass).map_err(|err| format!("Unable to create Luminum databases: {}", err))?;
+					Some(Box::new(MysqlStorage::connect(mysql_socket, "luminum", &dbpass).map_err(|err| format!("Unable to connect to Luminum databases: {}", err))?))
+					}
+				}
+			}
+		}
+	else if file_exists(&dbpath) {
+		report.step(Action::Keep, format!("SQLite database {}", dbpath));
+		Some(Box::new(SqliteStorage::open(&dbpath).map_err(|err| format!("Unable to open SQLite database {}: {}", dbpath, err))?))
+		}
+	else {
+		report.step(Action::Create, format!("SQLite database {}", dbpath));
+		if dry_run {
+			None
+			}
+		else {
+			let sqlite = SqliteStorage::open(&dbpath).map_err(|err| format!("Unable to create SQLite database {}: {}", dbpath, err))?;
+			// The daemon runs as the "luminum" system user, so it needs to own the database file
+			if let (true, Some(user_uid)) = sysuser_info("luminum") {
+				std::os::unix::fs::chown(&dbpath, user_uid.parse::<u32>().ok(), None).map_err(|err| format!("Unable to change ownership of {}: {}", dbpath, err))?;
+				}
+			Some(Box::new(sqlite))
+			}
+		};
+
+	let current = match &database {
+		Some(database) => database.schema_version().map_err(|err| format!("Unable to read schema version: {}", err))?,
+		None => 0
+		};
+	let pending: Vec<String> = migrations::pending(current).map(|migration| migration.version.to_string()).collect();
+	if pending.is_empty() {
+		report.step(Action::Keep, format!("Database schema (version {})", current));
+		}
+	else {
+		report.step(Action::Update, format!("Database schema migrations {}", pending.join(", ")));
+		if let (Some(database), false) = (&database, dry_run) {
+			database.migrate().map_err(|err| format!("Unable to apply schema migrations: {}", err))?;
+			}
+		}
+
+	if !dry_run {
+		report.database_password = Some(dbpass);
+		}
+	Ok(report)
+	}
+
+// Set configuration values, replacing any existing values for the same keys, and remove the
+// given keys
+pub fn write_config(paths: &Paths, values: &[(String, String)], removed: &[&str]) -> rusqlite::Result<()> {
+	let mut confconn = Connection::open(&paths.config_db)?;
+	let tx = confconn.transaction()?;
+	tx.execute("create table if not exists CONFIG ( KEY text not null, VALUE text not null )", [])?;
+	for (key, value) in values {
+		tx.execute("delete from CONFIG where KEY = ?1", [key])?;
+		tx.execute("insert into CONFIG (KEY,VALUE) values (?1, ?2)", [key, value])?;
+		}
+	for key in removed {
+		tx.execute("delete from CONFIG where KEY = ?1", [key])?;
+		}
+	tx.commit()
+	}
@@ -0,0 +1,144 @@
+// Shutdown
+//
+// SIGTERM and SIGINT stop the server in stages. The data and API listeners stop accepting
+// connections and background tasks end. Sessions stop reading new requests, finish the ones
+// they are handling and close. Sessions still open when limits.drain_timeout runs out are
+// abandoned. Last, the storage backend is dropped, closing its database connections. A second
+// signal exits at once.
+
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Notify, watch};
+use tokio::time::timeout;
+use luminum_log::systemd;
+use tracing::{error, info, warn};
+
+// Shared by everything that has to stop with the server
+#[derive(Clone)]
+pub struct Shutdown {
+	inner: Arc<Inner>
+	}
+
+struct Inner {
+	stopping: watch::Sender<bool>,
+	// Sessions and API connections still open
+	active: AtomicUsize,
+	idle: Notify
+	}
+
+// Held for as long as a session or connection is open
+pub struct Activity {
+	inner: Arc<Inner>
+	}
+
+impl Default for Shutdown {
+	fn default() -> Self {
+		Shutdown { inner: Arc::new(Inner { stopping: watch::Sender::new(false), active: AtomicUsize::new(0), idle: Notify::new() }) }
+		}
+	}
+
+impl Shutdown {
+	pub fn new() -> Shutdown {
+		Shutdown::default()
+		}
+
+	pub fn is_running(&self) -> bool {
+		!*self.inner.stopping.borrow()
+		}
+
+	pub fn stop(&self) {
+		self.inner.stopping.send_replace(true);
+		}
+
+	// Resolves once the server starts to stop
+	pub async fn stopped(&self) {
+		let mut stopping = self.inner.stopping.subscribe();
+		let _ = stopping.wait_for(|stopping| *stopping).await;
+		}
+
+	pub fn track(&self) -> Activity {
+		self.inner.active.fetch_add(1, Ordering::SeqCst);
+		Activity { inner: self.inner.clone() }
+		}
+
+	pub fn active(&self) -> usize {
+		self.inner.active.load(Ordering::SeqCst)
+		}
+
+	// Wait for every session and connection to close, returning false if some are still open
+	// at the deadline
+	pub async fn drained(&self, deadline: Duration) -> bool {
+		timeout(deadline, async {
+			loop {
+				let idle = self.inner.idle.notified();
+				if self.active() == 0 {
+					return;
+					}
+				idle.await;
+				}
+			}).await.is_ok()
+		}
+	}
+
+impl Drop for Activity {
+	fn drop(&mut self) {
+		if self.inner.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+			self.inner.idle.notify_waiters();
+			}
+		}
+	}
+
+// Start stopping the server on SIGTERM or SIGINT, and exit on a second signal
+pub async fn on_signal(shutdown: Shutdown) {
+	let (mut terminate, mut interrupt) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+		(Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+		(Err(err), _) | (_, Err(err)) => {
+			error!("Unable to install shutdown signal handlers: {}", err);
+			return;
+			}
+		};
+	let name = tokio::select! {
+		_ = terminate.recv() => "SIGTERM",
+		_ = interrupt.recv() => "SIGINT"
+		};
+	info!("Received {}. Stopping Luminum Server...", name);
+	notify(systemd::STOPPING);
+	shutdown.stop();
+
+	tokio::select! {
+		_ = terminate.recv() => {},
+		_ = interrupt.recv() => {}
+		};
+	warn!("Received a second signal. Exiting without waiting for sessions to finish.");
+	process::exit(1);
+	}
+
+// Ping the service manager's watchdog for as long as the async workers are responsive,
+// including while sessions drain
+pub async fn watchdog() {
+	let Some(interval) = systemd::watchdog_interval() else { return; };
+	let mut ticks = tokio::time::interval(interval);
+	let mut failing = false;
+	loop {
+		ticks.tick().await;
+		// Only the first of a run of failures is worth reporting
+		match systemd::notify(systemd::WATCHDOG) {
+			Err(err) if !failing => {
+				warn!("Unable to ping the service manager's watchdog: {}", err);
+				failing = true;
+				},
+			Err(_) => {},
+			Ok(_) => { failing = false; }
+			}
+		}
+	}
+
+// Tell the service manager about a state change, if there is one
+pub fn notify(state: &str) {
+	if let Err(err) = systemd::notify(state) {
+		warn!("Unable to notify the service manager ({}): {}", state, err);
+		}
+	}
@@ -0,0 +1,425 @@
+// In-memory storage backend
+//
+// Keeps everything in process memory. Nothing survives a restart, so this is meant for
+// evaluation and development rather than production use, and the server only selects it
+// when started with --ephemeral.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use luminum_proto::audit::AuditRecord;
+use super::{ApiKey, AttributeChange, AuditQuery, CommandState, Endpoint, EnrollmentKey, EnrollmentToken, FileEvent, FileEventQuery, Operator, PresenceEvent, Question, QueuedCommand, Storage, StorageError};
+use super::migrations;
+
+#[derive(Default)]
+pub struct MemoryStorage {
+	data: Mutex<MemoryData>
+	}
+
+#[derive(Default)]
+struct MemoryData {
+	schema_version: u32,
+	endpoints: HashMap<String, Endpoint>,
+	tokens: HashMap<String, EnrollmentToken>,
+	enrollment_keys: Vec<EnrollmentKey>,
+	history: Vec<AttributeChange>,
+	presence_events: Vec<PresenceEvent>,
+	commands: Vec<QueuedCommand>,
+	questions: Vec<Question>,
+	watchlists: HashMap<String, Vec<String>>,
+	file_events: Vec<FileEvent>,
+	api_keys: HashMap<String, ApiKey>,
+	operators: HashMap<String, Operator>,
+	audit_log: Vec<AuditRecord>,
+	// Default Integrity watch paths, keyed by OS platform
+	watch_defaults: HashMap<String, Vec<String>>
+	}
+
+impl MemoryStorage {
+	pub fn new() -> Self {
+		MemoryStorage::default()
+		}
+
+	fn data(&self) -> MutexGuard<'_, MemoryData> {
+		// The maps are always left consistent, so a panic elsewhere doesn't invalidate them
+		self.data.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+		}
+	}
+
+impl Storage for MemoryStorage {
+	// There is no schema to create, but seed data from the migrations still applies
+	fn migrate(&self) -> Result<Vec<u32>, StorageError> {
+		let mut data = self.data();
+		let mut applied = Vec::new();
+		for migration in migrations::pending(data.schema_version) {
+			for (os, path) in migration.watch_defaults {
+				data.watch_defaults.entry(os.to_string()).or_default().push(path.to_string());
+				}
+			data.schema_version = migration.version;
+			applied.push(migration.version);
+			}
+		Ok(applied)
+		}
+
+	fn schema_version(&self) -> Result<u32, StorageError> {
+		Ok(self.data().schema_version)
+		}
+
+	fn find_endpoint(&self, uid: &str) -> Result<Option<Endpoint>, StorageError> {
+		Ok(self.data().endpoints.get(uid).cloned())
+		}
+
+	fn list_endpoints(&self) -> Result<Vec<Endpoint>, StorageError> {
+		Ok(self.data().endpoints.values().cloned().collect())
+		}
+
+	fn endpoints_by_token(&self, token_id: &str) -> Result<Vec<Endpoint>, StorageError> {
+		Ok(self.data().endpoints.values().filter(|endpoint| endpoint.token_id.as_deref() == Some(token_id)).cloned().collect())
+		}
+
+	fn revoke_endpoint(&self, uid: &str) -> Result<bool, StorageError> {
+		let mut data = self.data();
+		match data.endpoints.get_mut(uid) {
+			Some(endpoint) => { endpoint.revoked = true; },
+			None => { return Ok(false); }
+			}
+		for command in data.commands.iter_mut().filter(|command| command.uid == uid && command.state == CommandState::Queued) {
+			command.state = CommandState::Cancelled;
+			}
+		Ok(true)
+		}
+
+	fn set_endpoint_groups(&self, uid: &str, groups: &[String]) -> Result<bool, StorageError> {
+		match self.data().endpoints.get_mut(uid) {
+			Some(endpoint) => { endpoint.groups = groups.to_vec(); Ok(true) },
+			None => Ok(false)
+			}
+		}
+
+	fn delete_endpoint(&self, uid: &str) -> Result<bool, StorageError> {
+		let mut data = self.data();
+		if data.endpoints.remove(uid).is_none() {
+			return Ok(false);
+			}
+		data.history.retain(|change| change.uid != uid);
+		data.presence_events.retain(|event| event.uid != uid);
+		data.commands.retain(|command| command.uid != uid);
+		data.watchlists.remove(uid);
+		data.file_events.retain(|event| event.uid != uid);
+		Ok(true)
+		}
+
+	fn record_heartbeat(&self, endpoint: &Endpoint, changes: &[AttributeChange]) -> Result<(), StorageError> {
+		let mut data = self.data();
+		if let Some(stored) = data.endpoints.get_mut(&endpoint.uid) {
+			stored.hostname = endpoint.hostname.clone();
+			stored.ipv4 = endpoint.ipv4.clone();
+			stored.ipv6 = endpoint.ipv6.clone();
+			stored.osplat = endpoint.osplat.clone();
+			stored.osver = endpoint.osver.clone();
+			stored.last_seen = endpoint.last_seen;
+			}
+		data.history.extend_from_slice(changes);
+		Ok(())
+		}
+
+	fn record_presence(&self, event: &PresenceEvent) -> Result<(), StorageError> {
+		let mut data = self.data();
+		if let Some(stored) = data.endpoints.get_mut(&event.uid) {
+			stored.presence = event.new;
+			}
+		data.presence_events.push(event.clone());
+		Ok(())
+		}
+
+	fn attribute_history(&self, uid: &str) -> Result<Vec<AttributeChange>, StorageError> {
+		Ok(self.data().history.iter().filter(|change| change.uid == uid).cloned().collect())
+		}
+
+	fn presence_events(&self, uid: &str) -> Result<Vec<PresenceEvent>, StorageError> {
+		Ok(self.data().presence_events.iter().filter(|event| event.uid == uid).cloned().collect())
+		}
+
+	fn queue_command(&self, command: &QueuedCommand) -> Result<(), StorageError> {
+		let mut data = self.data();
+		if data.commands.iter().any(|queued| queued.id == command.id) {
+			return Err(StorageError::Duplicate(command.id.clone()));
+			}
+		data.commands.push(command.clone());
+		Ok(())
+		}
+
+	fn queued_commands(&self) -> Result<Vec<QueuedCommand>, StorageError> {
+		Ok(self.data().commands.iter().filter(|command| command.state == CommandState::Queued).cloned().collect())
+		}
+
+	fn mark_command_sent(&self, id: &str, now: i64) -> Result<bool, StorageError> {
+		match self.data().commands.iter_mut().find(|command| command.id == id && command.state == CommandState::Queued) {
+			Some(command) => {
+				command.state = CommandState::Sent;
+				command.sent = Some(now);
+				Ok(true)
+				},
+			None => Ok(false)
+			}
+		}
+
+	fn requeue_command(&self, id: &str) -> Result<(), StorageError> {
+		if let Some(command) = self.data().commands.iter_mut().find(|command| command.id == id && command.state == CommandState::Sent) {
+			command.state = CommandState::Queued;
+			command.sent = None;
+			}
+		Ok(())
+		}
+
+	fn complete_command(&self, uid: &str, id: &str, success: bool, output: &str, now: i64) -> Result<bool, StorageError> {
+		let mut data = self.data();
+		match data.commands.iter_mut().find(|command| command.id == id && command.uid == uid && matches!(command.state, CommandState::Queued | CommandState::Sent)) {
+			Some(command) => {
+				command.state = if success { CommandState::Succeeded } else { CommandState::Failed };
+				command.completed = Some(now);
+				command.output = Some(output.to_string());
+				Ok(true)
+				},
+			None => Ok(false)
+			}
+		}
+
+	fn endpoint_commands(&self, uid: &str) -> Result<Vec<QueuedCommand>, StorageError> {
+		Ok(self.data().commands.iter().filter(|command| command.uid == uid).cloned().collect())
+		}
+
+	fn add_question(&self, question: &Question, commands: &[QueuedCommand]) -> Result<(), StorageError> {
+		let mut data = self.data();
+		if data.questions.iter().any(|existing| existing.id == question.id) {
+			return Err(StorageError::Duplicate(question.id.clone()));
+			}
+		if let Some(command) = commands.iter().find(|command| data.commands.iter().any(|queued| queued.id == command.id)) {
+			return Err(StorageError::Duplicate(command.id.clone()));
+			}
+		data.questions.push(question.clone());
+		data.commands.extend_from_slice(commands);
+		Ok(())
+		}
+
+	fn find_question(&self, id: &str) -> Result<Option<Question>, StorageError> {
+		Ok(self.data().questions.iter().find(|question| question.id == id).cloned())
+		}
+
+	fn list_questions(&self) -> Result<Vec<Question>, StorageError> {
+		let mut questions = self.data().questions.clone();
+		questions.sort_by_key(|question| std::cmp::Reverse(question.created));
+		Ok(questions)
+		}
+
+	fn question_commands(&self, id: &str) -> Result<Vec<QueuedCommand>, StorageError> {
+		Ok(self.data().commands.iter().filter(|command| command.question_id.as_deref() == Some(id)).cloned().collect())
+		}
+
+	fn add_token(&self, token: &EnrollmentToken) -> Result<(), StorageError> {
+		let mut data = self.data();
+		if data.tokens.contains_key(&token.id) {
+			return Err(StorageError::Duplicate(token.id.clone()));
+			}
+		data.tokens.insert(token.id.clone(), token.clone());
+		Ok(())
+		}
+
+	fn find_token(&self, id: &str) -> Result<Option<EnrollmentToken>, StorageError> {
+		Ok(self.data().tokens.get(id).cloned())
+		}
+
+	fn list_tokens(&self) -> Result<Vec<EnrollmentToken>, StorageError> {
+		let mut tokens: Vec<EnrollmentToken> = self.data().tokens.values().cloned().collect();
+		tokens.sort_by_key(|token| token.created);
+		Ok(tokens)
+		}
+
+	fn revoke_token(&self, id: &str) -> Result<bool, StorageError> {
+		match self.data().tokens.get_mut(id) {
+			Some(token) => { token.revoked = true; Ok(true) },
+			None => Ok(false)
+			}
+		}
+
+	fn enroll_endpoint(&self, endpoint: &Endpoint, now: i64) -> Result<bool, StorageError> {
+		let mut data = self.data();
+		if data.endpoints.contains_key(&endpoint.uid) {
+			return Err(StorageError::Duplicate(endpoint.uid.clone()));
+			}
+		match data.tokens.get_mut(endpoint.token_id.as_deref().unwrap_or_default()) {
+			Some(token) if token.rejection(now).is_none() => { token.uses += 1; },
+			_ => { return Ok(false); }
+			}
+		data.endpoints.insert(endpoint.uid.clone(), endpoint.clone());
+		Ok(true)
+		}
+
+	fn enrollment_keys(&self) -> Result<Vec<EnrollmentKey>, StorageError> {
+		Ok(self.data().enrollment_keys.clone())
+		}
+
+	fn add_enrollment_key(&self, key: &EnrollmentKey) -> Result<(), StorageError> {
+		let mut data = self.data();
+		if data.enrollment_keys.iter().any(|existing| existing.version == key.version) {
+			return Err(StorageError::Duplicate(key.version.to_string()));
+			}
+		data.enrollment_keys.push(key.clone());
+		data.enrollment_keys.sort_by_key(|key| key.version);
+		Ok(())
+		}
+
+	fn reseal_enrollment_key(&self, version: u32, sealed: &str) -> Result<bool, StorageError> {
+		match self.data().enrollment_keys.iter_mut().find(|key| key.version == version) {
+			Some(key) => { key.sealed = sealed.to_string(); Ok(true) },
+			None => Ok(false)
+			}
+		}
+
+	fn delete_enrollment_key(&self, version: u32) -> Result<bool, StorageError> {
+		let mut data = self.data();
+		let before = data.enrollment_keys.len();
+		data.enrollment_keys.retain(|key| key.version != version);
+		Ok(data.enrollment_keys.len() != before)
+		}
+
+	fn watchlist(&self, uid: &str) -> Result<Vec<String>, StorageError> {
+		Ok(self.data().watchlists.get(uid).cloned().unwrap_or_default())
+		}
+
+	fn reset_watchlist(&self, uid: &str) -> Result<(), StorageError> {
+		let mut data = self.data();
+		let paths = match data.endpoints.get(uid) {
+			Some(endpoint) => data.watch_defaults.get(&endpoint.osplat).cloned().unwrap_or_default(),
+			None => Vec::new()
+			};
+		data.watchlists.insert(uid.to_string(), paths);
+		Ok(())
+		}
+
+	fn set_watchlist(&self, uid: &str, paths: &[String]) -> Result<(), StorageError> {
+		let mut data = self.data();
+		if data.endpoints.contains_key(uid) {
+			data.watchlists.insert(uid.to_string(), paths.to_vec());
+			}
+		Ok(())
+		}
+
+	fn add_file_events(&self, events: &[FileEvent]) -> Result<(), StorageError> {
+		self.data().file_events.extend_from_slice(events);
+		Ok(())
+		}
+
+	fn file_events(&self, query: &FileEventQuery) -> Result<(Vec<FileEvent>, u64), StorageError> {
+		let data = self.data();
+		let mut matched: Vec<&FileEvent> = data.file_events.iter().rev().filter(|event| query.matches(event)).collect();
+		matched.sort_by_key(|event| std::cmp::Reverse(event.at));
+		let page = matched.iter().skip(query.offset as usize).take(query.limit as usize).map(|event| (*event).clone()).collect();
+		Ok((page, matched.len() as u64))
+		}
+
+	fn add_api_key(&self, key: &ApiKey) -> Result<(), StorageError> {
+		let mut data = self.data();
+		if data.api_keys.contains_key(&key.id) {
+			return Err(StorageError::Duplicate(key.id.clone()));
+			}
+		data.api_keys.insert(key.id.clone(), key.clone());
+		Ok(())
+		}
+
+	fn find_api_key(&self, id: &str) -> Result<Option<ApiKey>, StorageError> {
+		Ok(self.data().api_keys.get(id).cloned())
+		}
+
+	fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError> {
+		let mut keys: Vec<ApiKey> = self.data().api_keys.values().cloned().collect();
+		keys.sort_by_key(|key| key.created);
+		Ok(keys)
+		}
+
+	fn revoke_api_key(&self, id: &str) -> Result<bool, StorageError> {
+		match self.data().api_keys.get_mut(id) {
+			Some(key) => { key.revoked = true; Ok(true) },
+			None => Ok(false)
+			}
+		}
+
+	fn touch_api_key(&self, id: &str, now: i64) -> Result<(), StorageError> {
+		if let Some(key) = self.data().api_keys.get_mut(id) {
+			key.last_used = Some(now);
+			}
+		Ok(())
+		}
+
+	fn delete_stale_sessions(&self, now: i64) -> Result<(), StorageError> {
+		self.data().api_keys.retain(|_, key| !key.session || key.status(now) == "active");
+		Ok(())
+		}
+
+	fn add_operator(&self, operator: &Operator) -> Result<(), StorageError> {
+		let mut data = self.data();
+		if data.operators.contains_key(&operator.username) {
+			return Err(StorageError::Duplicate(operator.username.clone()));
+			}
+		data.operators.insert(operator.username.clone(), operator.clone());
+		Ok(())
+		}
+
+	fn find_operator(&self, username: &str) -> Result<Option<Operator>, StorageError> {
+		Ok(self.data().operators.get(username).cloned())
+		}
+
+	fn list_operators(&self) -> Result<Vec<Operator>, StorageError> {
+		let mut operators: Vec<Operator> = self.data().operators.values().cloned().collect();
+		operators.sort_by(|a, b| a.username.cmp(&b.username));
+		Ok(operators)
+		}
+
+	fn update_operator(&self, operator: &Operator) -> Result<bool, StorageError> {
+		match self.data().operators.get_mut(&operator.username) {
+			Some(existing) => {
+				existing.password_hash = operator.password_hash.clone();
+				existing.role = operator.role;
+				existing.groups = operator.groups.clone();
+				existing.disabled = operator.disabled;
+				Ok(true)
+				},
+			None => Ok(false)
+			}
+		}
+
+	fn delete_operator(&self, username: &str) -> Result<bool, StorageError> {
+		let mut data = self.data();
+		if data.operators.remove(username).is_none() {
+			return Ok(false);
+			}
+		for key in data.api_keys.values_mut().filter(|key| key.owner.as_deref() == Some(username)) {
+			key.revoked = true;
+			}
+		Ok(true)
+		}
+
+	fn touch_operator(&self, username: &str, now: i64) -> Result<(), StorageError> {
+		if let Some(operator) = self.data().operators.get_mut(username) {
+			operator.last_login = Some(now);
+			}
+		Ok(())
+		}
+
+	fn append_audit(&self, record: AuditRecord) -> Result<AuditRecord, StorageError> {
+		let mut data = self.data();
+		let record = record.seal(data.audit_log.last());
+		data.audit_log.push(record.clone());
+		Ok(record)
+		}
+
+	fn audit_records(&self, query: &AuditQuery) -> Result<(Vec<AuditRecord>, u64), StorageError> {
+		let data = self.data();
+		let matched: Vec<&AuditRecord> = data.audit_log.iter().rev().filter(|record| query.matches(record)).collect();
+		let page = matched.iter().skip(query.offset as usize).take(query.limit as usize).map(|record| (*record).clone()).collect();
+		Ok((page, matched.len() as u64))
+		}
+
+	fn audit_chain(&self, after: u64, limit: u32) -> Result<Vec<AuditRecord>, StorageError> {
+		Ok(self.data().audit_log.iter().filter(|record| record.seq > after).take(limit as usize).cloned().collect())
+		}
+	}
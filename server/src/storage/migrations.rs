@@ -0,0 +1,419 @@
+// Schema Migrations
+//
+// Ordered, append-only list of schema changes. Each backend records the versions it has
+// applied in a SCHEMA_VERSION table and applies anything newer on startup. Never edit a
+// migration once it has shipped; add a new one instead.
+
+pub struct Migration {
+	pub version: u32,
+	pub description: &'static str,
+	// Statements for the MySQL backend. Tables are qualified with their database name.
+	pub mysql: &'static [&'static str],
+	pub sqlite: &'static [&'static str],
+	// Rows to add to WATCH_DEFAULT as (OS platform, path)
+	pub watch_defaults: &'static [(&'static str, &'static str)]
+	}
+
+pub const MIGRATIONS: &[Migration] = &[
+	Migration {
+		version: 1,
+		description: "Create endpoint and Integrity watchlist tables",
+		mysql: &[
+			"create table if not exists CLIENTS.STATUS (
+				ID int unsigned not null auto_increment primary key,
+				UID varchar(64) not null unique,
+				HOSTNAME varchar(255) not null,
+				IPV4 varchar(15),
+				IPV6 varchar(45),
+				OSPLAT varchar(32) not null,
+				OSVER varchar(255) not null,
+				CERTFP varchar(128),
+				REGDATE datetime not null,
+				LASTSEEN datetime not null
+				)",
+			"create table if not exists INTEGRITY.WATCHLIST (
+				ID int unsigned not null,
+				OS varchar(32) not null,
+				PATH varchar(4096) not null,
+				index (ID)
+				)",
+			"create table if not exists INTEGRITY.WATCH_DEFAULT (
+				OS varchar(32) not null,
+				PATH varchar(4096) not null,
+				index (OS)
+				)"
+			],
+		sqlite: &[
+			"create table if not exists STATUS (
+				ID integer primary key autoincrement,
+				UID text not null unique,
+				HOSTNAME text not null,
+				IPV4 text,
+				IPV6 text,
+				OSPLAT text not null,
+				OSVER text not null,
+				CERTFP text,
+				REGDATE text not null,
+				LASTSEEN text not null
+				)",
+			"create table if not exists WATCHLIST (
+				ID integer not null,
+				OS text not null,
+				PATH text not null
+				)",
+			"create index if not exists WATCHLIST_ID on WATCHLIST (ID)",
+			"create table if not exists WATCH_DEFAULT (
+				OS text not null,
+				PATH text not null
+				)"
+			],
+		watch_defaults: &[]
+		},
+	Migration {
+		version: 2,
+		description: "Seed default Integrity watch paths",
+		mysql: &[],
+		sqlite: &[],
+		watch_defaults: &[
+			("Linux", "/etc"),
+			("Linux", "/bin"),
+			("Linux", "/sbin"),
+			("Linux", "/usr/bin"),
+			("Linux", "/usr/sbin"),
+			("Linux", "/boot"),
+			("macOS", "/etc"),
+			("macOS", "/bin"),
+			("macOS", "/sbin"),
+			("macOS", "/usr/bin"),
+			("macOS", "/usr/sbin"),
+			("macOS", "/Library/LaunchAgents"),
+			("macOS", "/Library/LaunchDaemons"),
+			("Windows", "C:\\Windows\\System32"),
+			("Windows", "C:\\Windows\\SysWOW64"),
+			("Windows", "C:\\ProgramData\\Microsoft\\Windows\\Start Menu\\Programs\\StartUp")
+			]
+		},
+	Migration {
+		version: 3,
+		description: "Add enrollment tokens and endpoint groups",
+		mysql: &[
+			"create table if not exists CLIENTS.ENROLL_TOKEN (
+				ID varchar(16) not null primary key,
+				HASH char(64) not null,
+				DESCRIPTION varchar(255) not null default '',
+				GRPS varchar(1024) not null default '',
+				CREATED bigint not null,
+				EXPIRES bigint,
+				MAXUSES int unsigned,
+				USES int unsigned not null default 0,
+				REVOKED tinyint(1) not null default 0
+				)",
+			"alter table CLIENTS.STATUS add column TOKENID varchar(16), add index (TOKENID)",
+			"create table if not exists CLIENTS.ENDPOINT_GROUP (
+				UID varchar(64) not null,
+				GRPNAME varchar(64) not null,
+				primary key (UID, GRPNAME),
+				index (GRPNAME)
+				)"
+			],
+		sqlite: &[
+			"create table if not exists ENROLL_TOKEN (
+				ID text not null primary key,
+				HASH text not null,
+				DESCRIPTION text not null default '',
+				GRPS text not null default '',
+				CREATED integer not null,
+				EXPIRES integer,
+				MAXUSES integer,
+				USES integer not null default 0,
+				REVOKED integer not null default 0
+				)",
+			"alter table STATUS add column TOKENID text",
+			"create index if not exists STATUS_TOKENID on STATUS (TOKENID)",
+			"create table if not exists ENDPOINT_GROUP (
+				UID text not null,
+				GRPNAME text not null,
+				primary key (UID, GRPNAME)
+				)",
+			"create index if not exists ENDPOINT_GROUP_NAME on ENDPOINT_GROUP (GRPNAME)"
+			],
+		watch_defaults: &[]
+		},
+	Migration {
+		version: 4,
+		description: "Add endpoint revocation",
+		mysql: &["alter table CLIENTS.STATUS add column REVOKED tinyint(1) not null default 0"],
+		sqlite: &["alter table STATUS add column REVOKED integer not null default 0"],
+		watch_defaults: &[]
+		},
+	Migration {
+		version: 5,
+		description: "Add endpoint presence and attribute history",
+		mysql: &[
+			"alter table CLIENTS.STATUS add column PRESENCE varchar(16) not null default 'online'",
+			"create table if not exists CLIENTS.ENDPOINT_HISTORY (
+				UID varchar(64) not null,
+				ATTR varchar(32) not null,
+				OLDVAL varchar(255) not null,
+				NEWVAL varchar(255) not null,
+				CHANGED bigint not null,
+				index (UID, CHANGED)
+				)",
+			"create table if not exists CLIENTS.PRESENCE_EVENT (
+				UID varchar(64) not null,
+				OLDSTATE varchar(16) not null,
+				NEWSTATE varchar(16) not null,
+				AT bigint not null,
+				index (UID, AT)
+				)"
+			],
+		sqlite: &[
+			"alter table STATUS add column PRESENCE text not null default 'online'",
+			"create table if not exists ENDPOINT_HISTORY (
+				UID text not null,
+				ATTR text not null,
+				OLDVAL text not null,
+				NEWVAL text not null,
+				CHANGED integer not null
+				)",
+			"create index if not exists ENDPOINT_HISTORY_UID on ENDPOINT_HISTORY (UID, CHANGED)",
+			"create table if not exists PRESENCE_EVENT (
+				UID text not null,
+				OLDSTATE text not null,
+				NEWSTATE text not null,
+				AT integer not null
+				)",
+			"create index if not exists PRESENCE_EVENT_UID on PRESENCE_EVENT (UID, AT)"
+			],
+		watch_defaults: &[]
+		},
+	Migration {
+		version: 6,
+		description: "Add pushed endpoint commands",
+		mysql: &[
+			"create table if not exists CLIENTS.COMMAND (
+				ID varchar(36) not null primary key,
+				UID varchar(64) not null,
+				BODY text not null,
+				STATE varchar(16) not null,
+				CREATED bigint not null,
+				SENT bigint,
+				COMPLETED bigint,
+				OUTPUT mediumtext,
+				index (STATE, CREATED),
+				index (UID, CREATED)
+				)"
+			],
+		sqlite: &[
+			"create table if not exists COMMAND (
+				ID text not null primary key,
+				UID text not null,
+				BODY text not null,
+				STATE text not null,
+				CREATED integer not null,
+				SENT integer,
+				COMPLETED integer,
+				OUTPUT text
+				)",
+			"create index if not exists COMMAND_STATE on COMMAND (STATE, CREATED)",
+			"create index if not exists COMMAND_UID on COMMAND (UID, CREATED)"
+			],
+		watch_defaults: &[]
+		},
+	Migration {
+		version: 7,
+		description: "Add keyed enrollment token hashes",
+		mysql: &[
+			"create table if not exists CLIENTS.ENROLL_KEY (
+				VERSION int unsigned not null primary key,
+				KEYDATA varchar(255) not null,
+				CREATED bigint not null
+				)",
+			"alter table CLIENTS.ENROLL_TOKEN add column KEYVER int unsigned not null default 0"
+			],
+		sqlite: &[
+			"create table if not exists ENROLL_KEY (
+				VERSION integer not null primary key,
+				KEYDATA text not null,
+				CREATED integer not null
+				)",
+			"alter table ENROLL_TOKEN add column KEYVER integer not null default 0"
+			],
+		watch_defaults: &[]
+		},
+	Migration {
+		version: 8,
+		description: "Add admin API keys",
+		mysql: &[
+			"create table if not exists CLIENTS.API_KEY (
+				ID varchar(16) not null primary key,
+				HASH char(64) not null,
+				DESCRIPTION varchar(255) not null default '',
+				CREATED bigint not null,
+				LASTUSED bigint,
+				REVOKED tinyint(1) not null default 0
+				)"
+			],
+		sqlite: &[
+			"create table if not exists API_KEY (
+				ID text not null primary key,
+				HASH text not null,
+				DESCRIPTION text not null default '',
+				CREATED integer not null,
+				LASTUSED integer,
+				REVOKED integer not null default 0
+				)"
+			],
+		watch_defaults: &[]
+		},
+	Migration {
+		version: 9,
+		description: "Add questions and Integrity file events",
+		mysql: &[
+			"create table if not exists CLIENTS.QUESTION (
+				ID varchar(36) not null primary key,
+				QUESTION varchar(255) not null,
+				TARGET varchar(255) not null,
+				ASKEDBY varchar(64) not null default '',
+				CREATED bigint not null,
+				index (CREATED)
+				)",
+			"alter table CLIENTS.COMMAND add column QUESTIONID varchar(36), add index (QUESTIONID)",
+			"create table if not exists INTEGRITY.FILE_EVENT (
+				ID bigint unsigned not null auto_increment primary key,
+				UID varchar(64) not null,
+				PATH varchar(4096) not null,
+				KIND varchar(16) not null,
+				FTYPE varchar(16),
+				PERMS varchar(16),
+				OWNER varchar(255),
+				SIZE bigint unsigned,
+				AT bigint not null,
+				RECEIVED bigint not null,
+				index (UID, AT),
+				index (AT)
+				)"
+			],
+		sqlite: &[
+			"create table if not exists QUESTION (
+				ID text not null primary key,
+				QUESTION text not null,
+				TARGET text not null,
+				ASKEDBY text not null default '',
+				CREATED integer not null
+				)",
+			"create index if not exists QUESTION_CREATED on QUESTION (CREATED)",
+			"alter table COMMAND add column QUESTIONID text",
+			"create index if not exists COMMAND_QUESTION on COMMAND (QUESTIONID)",
+			"create table if not exists FILE_EVENT (
+				ID integer primary key autoincrement,
+				UID text not null,
+				PATH text not null,
+				KIND text not null,
+				FTYPE text,
+				PERMS text,
+				OWNER text,
+				SIZE integer,
+				AT integer not null,
+				RECEIVED integer not null
+				)",
+			"create index if not exists FILE_EVENT_UID on FILE_EVENT (UID, AT)",
+			"create index if not exists FILE_EVENT_AT on FILE_EVENT (AT)"
+			],
+		watch_defaults: &[]
+		},
+	Migration {
+		version: 10,
+		description: "Add operators and scoped API keys",
+		// Existing keys keep every scope, so upgrading doesn't take access away
+		mysql: &[
+			"create table if not exists CLIENTS.OPERATOR (
+				USERNAME varchar(64) not null primary key,
+				PWHASH varchar(255) not null,
+				ROLE varchar(16) not null,
+				GRPS varchar(1024) not null default '',
+				CREATED bigint not null,
+				LASTLOGIN bigint,
+				DISABLED tinyint(1) not null default 0
+				)",
+			"alter table CLIENTS.API_KEY
+				add column OWNER varchar(64),
+				add column SCOPES varchar(255) not null default 'read,ask,configure,deploy,endpoints,enrollment,admin',
+				add column EXPIRES bigint,
+				add column SESSION tinyint(1) not null default 0,
+				add index (OWNER)"
+			],
+		sqlite: &[
+			"create table if not exists OPERATOR (
+				USERNAME text not null primary key,
+				PWHASH text not null,
+				ROLE text not null,
+				GRPS text not null default '',
+				CREATED integer not null,
+				LASTLOGIN integer,
+				DISABLED integer not null default 0
+				)",
+			"alter table API_KEY add column OWNER text",
+			"alter table API_KEY add column SCOPES text not null default 'read,ask,configure,deploy,endpoints,enrollment,admin'",
+			"alter table API_KEY add column EXPIRES integer",
+			"alter table API_KEY add column SESSION integer not null default 0",
+			"create index if not exists API_KEY_OWNER on API_KEY (OWNER)"
+			],
+		watch_defaults: &[]
+		},
+	Migration {
+		version: 11,
+		description: "Add the audit log",
+		// SQLite refuses changes to the log outright. MySQL only allows triggers like these to
+		// privileged users when binary logging is on, so there the hash chain alone shows changes.
+		mysql: &[
+			"create table if not exists CLIENTS.AUDIT_LOG (
+				SEQ bigint unsigned not null primary key,
+				AT bigint not null,
+				EVENT varchar(32) not null,
+				ACTOR varchar(255) not null,
+				SOURCE varchar(64) not null,
+				DETAIL text not null,
+				PREVHASH char(64) not null,
+				HASH char(64) not null,
+				index (AT),
+				index (EVENT)
+				)"
+			],
+		sqlite: &[
+			"create table if not exists AUDIT_LOG (
+				SEQ integer not null primary key,
+				AT integer not null,
+				EVENT text not null,
+				ACTOR text not null,
+				SOURCE text not null,
+				DETAIL text not null,
+				PREVHASH text not null,
+				HASH text not null
+				)",
+			"create index if not exists AUDIT_LOG_AT on AUDIT_LOG (AT)",
+			"create index if not exists AUDIT_LOG_EVENT on AUDIT_LOG (EVENT)",
+			"create trigger if not exists AUDIT_LOG_NO_UPDATE before update on AUDIT_LOG
+				begin select raise(abort, 'The audit log is append-only'); end",
+			"create trigger if not exists AUDIT_LOG_NO_DELETE before delete on AUDIT_LOG
+				begin select raise(abort, 'The audit log is append-only'); end"
+			],
+		watch_defaults: &[]
+		},
+	Migration {
+		version: 12,
+		description: "Add the audit log append lock",
+		// SQLite takes the database write lock for each append instead
+		mysql: &[
+			"create table if not exists CLIENTS.AUDIT_LOCK (ID tinyint unsigned not null primary key)",
+			"insert ignore into CLIENTS.AUDIT_LOCK (ID) values (1)"
+			],
+		sqlite: &[],
+		watch_defaults: &[]
+		}
+	];
+
+// Migrations newer than the given schema version, in order
+pub fn pending(current: u32) -> impl Iterator<Item = &'static Migration> {
+	MIGRATIONS.iter().filter(move |migration| migration.version > current)
+	}
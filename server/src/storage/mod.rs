@@ -0,0 +1,480 @@
+// Storage
+//
+// Everything the server persists goes through the Storage trait. Handlers only see the
+// trait, so backends can be swapped without touching message handling. Every backend must
+// pass client-supplied values as bound parameters, never by building statements with format!.
+
+use std::error::Error;
+use std::fmt;
+use luminum_proto::{CommandKind, FileEventKind, FileType};
+use luminum_proto::audit::AuditRecord;
+use crate::access::{Role, Scope};
+
+pub mod memory;
+pub mod migrations;
+pub mod mysqldb;
+pub mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use mysqldb::MysqlStorage;
+pub use sqlite::SqliteStorage;
+
+// A registered endpoint, as stored in CLIENTS.STATUS
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Endpoint {
+	pub uid: String,
+	pub hostname: String,
+	pub ipv4: String,
+	pub ipv6: String,
+	pub osplat: String,
+	pub osver: String,
+	pub cert_fingerprint: Option<String>,
+	// Enrollment token the endpoint registered with
+	pub token_id: Option<String>,
+	pub groups: Vec<String>,
+	// Revoked endpoints can no longer talk to the server
+	pub revoked: bool,
+	// Unix seconds of the last message from the endpoint
+	pub last_seen: i64,
+	// Presence state as of the last check
+	pub presence: Presence
+	}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Presence {
+	#[default]
+	Online,
+	Stale,
+	Offline
+	}
+
+impl Presence {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Presence::Online => "online",
+			Presence::Stale => "stale",
+			Presence::Offline => "offline"
+			}
+		}
+
+	pub fn parse(value: &str) -> Presence {
+		match value {
+			"stale" => Presence::Stale,
+			"offline" => Presence::Offline,
+			_ => Presence::Online
+			}
+		}
+	}
+
+impl fmt::Display for Presence {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(self.as_str()) }
+	}
+
+// A change to one of an endpoint's attributes, reported by a heartbeat
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttributeChange {
+	pub uid: String,
+	pub attribute: String,
+	pub old_value: String,
+	pub new_value: String,
+	pub changed: i64
+	}
+
+// A transition between presence states
+#[derive(Clone, Debug, PartialEq)]
+pub struct PresenceEvent {
+	pub uid: String,
+	pub old: Presence,
+	pub new: Presence,
+	pub at: i64
+	}
+
+// A command queued for delivery to an endpoint over its push session
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedCommand {
+	pub id: String,
+	pub uid: String,
+	pub kind: CommandKind,
+	pub state: CommandState,
+	// Timestamps are Unix seconds
+	pub created: i64,
+	pub sent: Option<i64>,
+	pub completed: Option<i64>,
+	pub output: Option<String>,
+	// Question the command was queued for, if any
+	pub question_id: Option<String>
+	}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandState {
+	Queued,
+	Sent,
+	Succeeded,
+	Failed,
+	// Dropped before delivery because the endpoint was revoked
+	Cancelled
+	}
+
+impl CommandState {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			CommandState::Queued => "queued",
+			CommandState::Sent => "sent",
+			CommandState::Succeeded => "succeeded",
+			CommandState::Failed => "failed",
+			CommandState::Cancelled => "cancelled"
+			}
+		}
+
+	pub fn parse(value: &str) -> CommandState {
+		match value {
+			"sent" => CommandState::Sent,
+			"succeeded" => CommandState::Succeeded,
+			"failed" => CommandState::Failed,
+			"cancelled" => CommandState::Cancelled,
+			_ => CommandState::Queued
+			}
+		}
+	}
+
+impl fmt::Display for CommandState {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(self.as_str()) }
+	}
+
+// A question asked of several endpoints at once. Each endpoint gets its own question
+// command, and the results of those commands are the answers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Question {
+	pub id: String,
+	pub question: String,
+	// Description of the endpoints asked, such as "group web"
+	pub target: String,
+	// API key the question was asked with
+	pub asked_by: String,
+	pub created: i64
+	}
+
+// A change to a watched path, reported by an endpoint's Integrity Lumy
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileEvent {
+	pub uid: String,
+	pub path: String,
+	pub kind: FileEventKind,
+	pub file_type: Option<FileType>,
+	pub permissions: Option<String>,
+	pub owner: Option<String>,
+	pub size: Option<u64>,
+	// When the endpoint saw the change and when the server received it, in Unix seconds
+	pub at: i64,
+	pub received: i64
+	}
+
+// Filter and page for file_events. Unset fields match every event.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileEventQuery {
+	pub uid: Option<String>,
+	// Only events from these endpoints, when given
+	pub uids: Option<Vec<String>>,
+	// Paths starting with this prefix
+	pub path: Option<String>,
+	pub kind: Option<FileEventKind>,
+	// Inclusive bounds on the event time
+	pub since: Option<i64>,
+	pub until: Option<i64>,
+	pub limit: u32,
+	pub offset: u32
+	}
+
+impl FileEventQuery {
+	pub fn matches(&self, event: &FileEvent) -> bool {
+		self.uid.as_ref().is_none_or(|uid| &event.uid == uid)
+			&& self.uids.as_ref().is_none_or(|uids| uids.contains(&event.uid))
+			&& self.path.as_ref().is_none_or(|path| event.path.starts_with(path.as_str()))
+			&& self.kind.is_none_or(|kind| event.kind == kind)
+			&& self.since.is_none_or(|since| event.at >= since)
+			&& self.until.is_none_or(|until| event.at <= until)
+		}
+	}
+
+// Enrollment token. Only a hash of the token secret is stored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnrollmentToken {
+	pub id: String,
+	pub hash: String,
+	pub description: String,
+	// Groups assigned to endpoints enrolled with this token
+	pub groups: Vec<String>,
+	// Timestamps are Unix seconds
+	pub created: i64,
+	pub expires: Option<i64>,
+	pub max_uses: Option<u32>,
+	pub uses: u32,
+	pub revoked: bool,
+	// Enrollment key the secret's hash was made with; 0 for an unkeyed SHA-256 hash
+	pub key_version: u32
+	}
+
+// Key for hashing enrollment token secrets, sealed with the master key
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnrollmentKey {
+	pub version: u32,
+	pub sealed: String,
+	pub created: i64
+	}
+
+impl EnrollmentToken {
+	// Reason the token can't be used at the given time, if any
+	pub fn rejection(&self, now: i64) -> Option<&'static str> {
+		if self.revoked { Some("token has been revoked") }
+		else if self.expires.is_some_and(|expires| expires <= now) { Some("token has expired") }
+		else if self.max_uses.is_some_and(|max_uses| self.uses >= max_uses) { Some("token has no uses remaining") }
+		else { None }
+		}
+
+	// "active", "revoked", "used" or "expired"
+	pub fn status(&self, now: i64) -> &'static str {
+		match self.rejection(now) {
+			None => "active",
+			Some(_) if self.revoked => "revoked",
+			Some(_) if self.max_uses.is_some_and(|max_uses| self.uses >= max_uses) => "used",
+			Some(_) => "expired"
+			}
+		}
+	}
+
+// Key for the admin API, handed out as "<id>.<secret>". Only a hash of the secret is stored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ApiKey {
+	pub id: String,
+	pub hash: String,
+	pub description: String,
+	// Operator the key acts for; keys created on the server host have none
+	pub owner: Option<String>,
+	pub scopes: Vec<Scope>,
+	// Timestamps are Unix seconds
+	pub created: i64,
+	pub expires: Option<i64>,
+	pub last_used: Option<i64>,
+	pub revoked: bool,
+	// Issued by signing in to the web console
+	pub session: bool
+	}
+
+impl ApiKey {
+	// "active", "revoked" or "expired"
+	pub fn status(&self, now: i64) -> &'static str {
+		match self.expires {
+			_ if self.revoked => "revoked",
+			Some(expires) if expires <= now => "expired",
+			_ => "active"
+			}
+		}
+	}
+
+// An operator account for the management API and web console
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Operator {
+	pub username: String,
+	// Argon2id hash in PHC string format
+	pub password_hash: String,
+	pub role: Role,
+	// Endpoint groups the operator is limited to; empty for every endpoint
+	pub groups: Vec<String>,
+	pub created: i64,
+	pub last_login: Option<i64>,
+	pub disabled: bool
+	}
+
+// Filter and page for audit_records. Unset fields match every record.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditQuery {
+	pub event: Option<String>,
+	// Actors containing this text
+	pub actor: Option<String>,
+	pub source: Option<String>,
+	// Inclusive bounds on the record time
+	pub since: Option<i64>,
+	pub until: Option<i64>,
+	pub limit: u32,
+	pub offset: u32
+	}
+
+impl AuditQuery {
+	pub fn matches(&self, record: &AuditRecord) -> bool {
+		self.event.as_ref().is_none_or(|event| &record.event == event)
+			&& self.actor.as_ref().is_none_or(|actor| record.actor.contains(actor.as_str()))
+			&& self.source.as_ref().is_none_or(|source| &record.source == source)
+			&& self.since.is_none_or(|since| record.at >= since)
+			&& self.until.is_none_or(|until| record.at <= until)
+		}
+	}
+
+pub fn encode_scopes(scopes: &[Scope]) -> String {
+	scopes.iter().map(Scope::as_str).collect::<Vec<&str>>().join(",")
+	}
+
+// Unknown scope names, from a newer server, are dropped rather than granted
+pub fn decode_scopes(scopes: &str) -> Vec<Scope> {
+	scopes.split(',').filter_map(|name| Scope::parse(name.trim())).collect()
+	}
+
+pub fn decode_role(username: &str, role: &str) -> Result<Role, StorageError> {
+	Role::parse(role).ok_or_else(|| StorageError::Corrupt(format!("operator {}: unknown role {}", username, role)))
+	}
+
+#[derive(Debug)]
+pub enum StorageError {
+	Mysql(mysql::Error),
+	Sqlite(rusqlite::Error),
+	Duplicate(String),
+	// A stored value could not be decoded
+	Corrupt(String)
+	}
+
+impl fmt::Display for StorageError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			StorageError::Mysql(err) => write!(f, "MySQL error: {}", err),
+			StorageError::Sqlite(err) => write!(f, "SQLite error: {}", err),
+			StorageError::Duplicate(key) => write!(f, "record already exists: {}", key),
+			StorageError::Corrupt(detail) => write!(f, "corrupt record: {}", detail)
+			}
+		}
+	}
+
+impl Error for StorageError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			StorageError::Mysql(err) => Some(err),
+			StorageError::Sqlite(err) => Some(err),
+			_ => None
+			}
+		}
+	}
+
+impl From<mysql::Error> for StorageError {
+	fn from(err: mysql::Error) -> Self { StorageError::Mysql(err) }
+	}
+
+impl From<rusqlite::Error> for StorageError {
+	fn from(err: rusqlite::Error) -> Self { StorageError::Sqlite(err) }
+	}
+
+// Groups are stored as a comma-separated list on enrollment tokens
+pub fn split_groups(groups: &str) -> Vec<String> {
+	groups.split(',').map(str::trim).filter(|group| !group.is_empty()).map(String::from).collect()
+	}
+
+// Commands are stored as JSON so new command types don't need schema changes
+pub fn encode_command(kind: &CommandKind) -> String {
+	serde_json::to_string(kind).unwrap_or_default()
+	}
+
+pub fn decode_command(id: &str, body: &str) -> Result<CommandKind, StorageError> {
+	serde_json::from_str(body).map_err(|err| StorageError::Corrupt(format!("command {}: {}", id, err)))
+	}
+
+pub trait Storage: Send + Sync {
+	// Bring the schema up to date, returning the migration versions that were applied
+	fn migrate(&self) -> Result<Vec<u32>, StorageError>;
+	// Latest migration version applied, or 0 for an empty database
+	fn schema_version(&self) -> Result<u32, StorageError>;
+
+	// Endpoints
+	fn find_endpoint(&self, uid: &str) -> Result<Option<Endpoint>, StorageError>;
+	fn list_endpoints(&self) -> Result<Vec<Endpoint>, StorageError>;
+	fn endpoints_by_token(&self, token_id: &str) -> Result<Vec<Endpoint>, StorageError>;
+	// Commands still queued for the endpoint are cancelled. Returns false if there is no such
+	// endpoint.
+	fn revoke_endpoint(&self, uid: &str) -> Result<bool, StorageError>;
+	// Replace an endpoint's groups. Returns false if there is no such endpoint.
+	fn set_endpoint_groups(&self, uid: &str, groups: &[String]) -> Result<bool, StorageError>;
+	// Remove an endpoint along with its groups, history, commands, watchlist and file
+	// events. Returns false if there is no such endpoint.
+	fn delete_endpoint(&self, uid: &str) -> Result<bool, StorageError>;
+
+	// Presence and attribute history
+	// Save the endpoint's current attributes and last-seen time, recording the given changes
+	fn record_heartbeat(&self, endpoint: &Endpoint, changes: &[AttributeChange]) -> Result<(), StorageError>;
+	fn record_presence(&self, event: &PresenceEvent) -> Result<(), StorageError>;
+	fn attribute_history(&self, uid: &str) -> Result<Vec<AttributeChange>, StorageError>;
+	fn presence_events(&self, uid: &str) -> Result<Vec<PresenceEvent>, StorageError>;
+
+	// Pushed commands
+	fn queue_command(&self, command: &QueuedCommand) -> Result<(), StorageError>;
+	// Commands waiting for delivery, oldest first
+	fn queued_commands(&self) -> Result<Vec<QueuedCommand>, StorageError>;
+	// Mark a command sent before it is handed to the endpoint. Returns false if the command
+	// is no longer queued, so it mustn't be sent.
+	fn mark_command_sent(&self, id: &str, now: i64) -> Result<bool, StorageError>;
+	// Put a command marked sent back in the queue, when it couldn't be handed over after all
+	fn requeue_command(&self, id: &str) -> Result<(), StorageError>;
+	// Record the result of a command for the given endpoint. Returns false if the endpoint
+	// has no such command awaiting a result.
+	fn complete_command(&self, uid: &str, id: &str, success: bool, output: &str, now: i64) -> Result<bool, StorageError>;
+	fn endpoint_commands(&self, uid: &str) -> Result<Vec<QueuedCommand>, StorageError>;
+
+	// Questions
+	// Save a question together with the commands that ask it
+	fn add_question(&self, question: &Question, commands: &[QueuedCommand]) -> Result<(), StorageError>;
+	fn find_question(&self, id: &str) -> Result<Option<Question>, StorageError>;
+	// Newest first
+	fn list_questions(&self) -> Result<Vec<Question>, StorageError>;
+	fn question_commands(&self, id: &str) -> Result<Vec<QueuedCommand>, StorageError>;
+
+	// Enrollment tokens
+	fn add_token(&self, token: &EnrollmentToken) -> Result<(), StorageError>;
+	fn find_token(&self, id: &str) -> Result<Option<EnrollmentToken>, StorageError>;
+	fn list_tokens(&self) -> Result<Vec<EnrollmentToken>, StorageError>;
+	// Returns false if there is no such token
+	fn revoke_token(&self, id: &str) -> Result<bool, StorageError>;
+	// Add an endpoint and count one use of its enrollment token in one step, if the token is
+	// still usable at the given time. Returns false, adding nothing, if the token was revoked,
+	// expired or used up in the meantime.
+	fn enroll_endpoint(&self, endpoint: &Endpoint, now: i64) -> Result<bool, StorageError>;
+
+	// Enrollment keys, oldest first
+	fn enrollment_keys(&self) -> Result<Vec<EnrollmentKey>, StorageError>;
+	fn add_enrollment_key(&self, key: &EnrollmentKey) -> Result<(), StorageError>;
+	// Replace the sealed key, when the master key changes. Returns false if there is no such key.
+	fn reseal_enrollment_key(&self, version: u32, sealed: &str) -> Result<bool, StorageError>;
+	fn delete_enrollment_key(&self, version: u32) -> Result<bool, StorageError>;
+
+	// Integrity watchlists
+	fn watchlist(&self, uid: &str) -> Result<Vec<String>, StorageError>;
+	// Replace an endpoint's watchlist with the default paths for its OS platform
+	fn reset_watchlist(&self, uid: &str) -> Result<(), StorageError>;
+	fn set_watchlist(&self, uid: &str, paths: &[String]) -> Result<(), StorageError>;
+	fn add_file_events(&self, events: &[FileEvent]) -> Result<(), StorageError>;
+	// Matching events, newest first, with the number of events matched before paging
+	fn file_events(&self, query: &FileEventQuery) -> Result<(Vec<FileEvent>, u64), StorageError>;
+
+	// Admin API keys
+	fn add_api_key(&self, key: &ApiKey) -> Result<(), StorageError>;
+	fn find_api_key(&self, id: &str) -> Result<Option<ApiKey>, StorageError>;
+	fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError>;
+	// Returns false if there is no such key
+	fn revoke_api_key(&self, id: &str) -> Result<bool, StorageError>;
+	fn touch_api_key(&self, id: &str, now: i64) -> Result<(), StorageError>;
+	// Remove console sessions that have expired or been signed out of
+	fn delete_stale_sessions(&self, now: i64) -> Result<(), StorageError>;
+
+	// Operators
+	fn add_operator(&self, operator: &Operator) -> Result<(), StorageError>;
+	fn find_operator(&self, username: &str) -> Result<Option<Operator>, StorageError>;
+	// By username
+	fn list_operators(&self) -> Result<Vec<Operator>, StorageError>;
+	// Save an operator's password hash, role, groups and state. Returns false if there is no such operator.
+	fn update_operator(&self, operator: &Operator) -> Result<bool, StorageError>;
+	// Remove an operator and revoke their API keys. Returns false if there is no such operator.
+	fn delete_operator(&self, username: &str) -> Result<bool, StorageError>;
+	fn touch_operator(&self, username: &str, now: i64) -> Result<(), StorageError>;
+
+	// Audit log. Appends are serialized and link each record to the last, so the chain can't
+	// fork; records are never changed or removed.
+	fn append_audit(&self, record: AuditRecord) -> Result<AuditRecord, StorageError>;
+	// Matching records, newest first, with the number of records matched before paging
+	fn audit_records(&self, query: &AuditQuery) -> Result<(Vec<AuditRecord>, u64), StorageError>;
+	// Up to limit records following the given sequence number, oldest first
+	fn audit_chain(&self, after: u64, limit: u32) -> Result<Vec<AuditRecord>, StorageError>;
+	}
@@ -0,0 +1,731 @@
+// MySQL storage backend
+//
+// Endpoints live in the CLIENTS database and Integrity Lumy data in the INTEGRITY database.
+
+use std::collections::HashMap;
+use std::time::Instant;
+use mysql::{Conn, Opts, OptsBuilder, Pool, PooledConn, Row, TxOpts, Value};
+use mysql::prelude::Queryable;
+use luminum_proto::{FileEventKind, FileType};
+use luminum_proto::audit::AuditRecord;
+use super::{ApiKey, AttributeChange, AuditQuery, CommandState, Endpoint, EnrollmentKey, EnrollmentToken, FileEvent, FileEventQuery, Operator, Presence, PresenceEvent, Question, QueuedCommand, Storage, StorageError, decode_command, decode_role, decode_scopes, encode_command, encode_scopes, split_groups};
+use super::migrations;
+use crate::metrics;
+
+const DATABASES: [&str; 2] = ["CLIENTS", "INTEGRITY"];
+// Columns read by endpoint_from_row
+const ENDPOINT_COLUMNS: &str = "UID,HOSTNAME,IPV4,IPV6,OSPLAT,OSVER,CERTFP,TOKENID,REVOKED,cast(unix_timestamp(LASTSEEN) as signed) as LASTSEEN,PRESENCE";
+// Columns read by command_from_row
+const COMMAND_COLUMNS: &str = "ID,UID,BODY,STATE,CREATED,SENT,COMPLETED,OUTPUT,QUESTIONID";
+// Columns read by file_event_from_row
+const FILE_EVENT_COLUMNS: &str = "UID,PATH,KIND,FTYPE,PERMS,OWNER,SIZE,AT,RECEIVED";
+// Columns read by api_key_from_row
+const API_KEY_COLUMNS: &str = "ID,HASH,DESCRIPTION,OWNER,SCOPES,CREATED,EXPIRES,LASTUSED,REVOKED,SESSION";
+// Columns read by operator_from_row
+const OPERATOR_COLUMNS: &str = "USERNAME,PWHASH,ROLE,GRPS,CREATED,LASTLOGIN,DISABLED";
+// Columns read by audit_record_from_row
+const AUDIT_COLUMNS: &str = "SEQ,AT,EVENT,ACTOR,SOURCE,DETAIL,PREVHASH,HASH";
+
+pub struct MysqlStorage {
+	clients: Pool,
+	integrity: Pool
+	}
+
+impl MysqlStorage {
+	// Open connection pools for the Luminum databases over the local MySQL socket
+	pub fn connect(socket_path: &str, user: &str, pass: &str) -> Result<MysqlStorage, StorageError> {
+		let opts = |db: &str| Opts::from(OptsBuilder::new()
+			.socket(Some(socket_path))
+			.user(Some(user))
+			.pass(Some(pass))
+			.db_name(Some(db)));
+		Ok(MysqlStorage {
+			clients: Pool::new(opts("CLIENTS"))?,
+			integrity: Pool::new(opts("INTEGRITY"))?
+			})
+		}
+
+	// Take a connection from a pool, recording how long it took
+	fn conn(&self, pool: &Pool, database: &str) -> Result<PooledConn, StorageError> {
+		let started = Instant::now();
+		let conn = pool.get_conn()?;
+		metrics::observe_db_wait(database, started);
+		Ok(conn)
+		}
+
+	// Create the Luminum databases and the service account, using an administrative account.
+	// Safe to run again; existing databases are left alone and the account password is reset.
+	pub fn provision(socket_path: &str, admin_user: &str, admin_pass: &str, user: &str, pass: &str) -> Result<(), StorageError> {
+		let opts = OptsBuilder::new()
+			.socket(Some(socket_path))
+			.user(Some(admin_user))
+			.pass(if admin_pass.is_empty() { None } else { Some(admin_pass) });
+		let mut conn = Conn::new(opts)?;
+		for db in DATABASES {
+			conn.query_drop(format!("create database if not exists {}", db))?;
+			}
+		// Account names and passwords can't be bound as parameters in these statements
+		let account = format!("{}@'localhost'", quote(user));
+		conn.query_drop(format!("create user if not exists {} identified by {}", account, quote(pass)))?;
+		conn.query_drop(format!("alter user {} identified by {}", account, quote(pass)))?;
+		for db in DATABASES {
+			conn.query_drop(format!("grant all privileges on {}.* to {}", db, account))?;
+			}
+		Ok(())
+		}
+	}
+
+impl Storage for MysqlStorage {
+	fn migrate(&self) -> Result<Vec<u32>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.query_drop("create table if not exists CLIENTS.SCHEMA_VERSION (
+			VERSION int unsigned not null primary key,
+			DESCRIPTION varchar(255) not null,
+			APPLIED datetime not null
+			)")?;
+		let current: Option<u32> = conn.query_first("select max(VERSION) from CLIENTS.SCHEMA_VERSION")?.flatten();
+
+		// MySQL commits DDL implicitly, so each migration is recorded as soon as it completes
+		let mut applied = Vec::new();
+		for migration in migrations::pending(current.unwrap_or(0)) {
+			for statement in migration.mysql {
+				conn.query_drop(*statement)?;
+				}
+			conn.exec_batch("insert into INTEGRITY.WATCH_DEFAULT (OS, PATH) values (?, ?)", migration.watch_defaults.iter())?;
+			conn.exec_drop("insert into CLIENTS.SCHEMA_VERSION (VERSION, DESCRIPTION, APPLIED) values (?, ?, now())", (migration.version, migration.description))?;
+			applied.push(migration.version);
+			}
+		Ok(applied)
+		}
+
+	fn schema_version(&self) -> Result<u32, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let tracked: Option<u64> = conn.query_first("select count(*) from information_schema.TABLES where TABLE_SCHEMA = 'CLIENTS' and TABLE_NAME = 'SCHEMA_VERSION'")?;
+		if tracked.unwrap_or(0) == 0 {
+			return Ok(0);
+			}
+		let current: Option<u32> = conn.query_first("select max(VERSION) from CLIENTS.SCHEMA_VERSION")?.flatten();
+		Ok(current.unwrap_or(0))
+		}
+
+	fn find_endpoint(&self, uid: &str) -> Result<Option<Endpoint>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let row: Option<Row> = conn.exec_first(format!("select {} from STATUS where UID = ?", ENDPOINT_COLUMNS), (uid,))?;
+		match row {
+			Some(row) => {
+				let mut endpoint = endpoint_from_row(row);
+				endpoint.groups = conn.exec("select GRPNAME from ENDPOINT_GROUP where UID = ? order by GRPNAME", (uid,))?;
+				Ok(Some(endpoint))
+				},
+			None => Ok(None)
+			}
+		}
+
+	fn list_endpoints(&self) -> Result<Vec<Endpoint>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let rows: Vec<Row> = conn.query(format!("select {} from STATUS order by REGDATE", ENDPOINT_COLUMNS))?;
+		with_groups(&mut conn, rows.into_iter().map(endpoint_from_row).collect())
+		}
+
+	fn endpoints_by_token(&self, token_id: &str) -> Result<Vec<Endpoint>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let rows: Vec<Row> = conn.exec(format!("select {} from STATUS where TOKENID = ? order by REGDATE", ENDPOINT_COLUMNS), (token_id,))?;
+		with_groups(&mut conn, rows.into_iter().map(endpoint_from_row).collect())
+		}
+
+	fn revoke_endpoint(&self, uid: &str) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		let found: Option<String> = tx.exec_first("select UID from STATUS where UID = ? for update", (uid,))?;
+		if found.is_none() {
+			return Ok(false);
+			}
+		tx.exec_drop("update STATUS set REVOKED = 1 where UID = ?", (uid,))?;
+		tx.exec_drop("update COMMAND set STATE = 'cancelled' where UID = ? and STATE = 'queued'", (uid,))?;
+		tx.commit()?;
+		Ok(true)
+		}
+
+	fn set_endpoint_groups(&self, uid: &str, groups: &[String]) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		let found: Option<String> = tx.exec_first("select UID from STATUS where UID = ?", (uid,))?;
+		if found.is_none() {
+			return Ok(false);
+			}
+		tx.exec_drop("delete from ENDPOINT_GROUP where UID = ?", (uid,))?;
+		tx.exec_batch("insert into ENDPOINT_GROUP (UID, GRPNAME) values (?, ?)", groups.iter().map(|group| (uid, group)))?;
+		tx.commit()?;
+		Ok(true)
+		}
+
+	fn delete_endpoint(&self, uid: &str) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		tx.exec_drop("delete from INTEGRITY.WATCHLIST where ID = (select ID from STATUS where UID = ?)", (uid,))?;
+		tx.exec_drop("delete from INTEGRITY.FILE_EVENT where UID = ?", (uid,))?;
+		for table in ["ENDPOINT_GROUP", "ENDPOINT_HISTORY", "PRESENCE_EVENT", "COMMAND"] {
+			tx.exec_drop(format!("delete from {} where UID = ?", table), (uid,))?;
+			}
+		tx.exec_drop("delete from STATUS where UID = ?", (uid,))?;
+		let deleted = tx.affected_rows() == 1;
+		tx.commit()?;
+		Ok(deleted)
+		}
+
+	fn record_heartbeat(&self, endpoint: &Endpoint, changes: &[AttributeChange]) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		tx.exec_drop(
+			"update STATUS set HOSTNAME = ?, IPV4 = ?, IPV6 = ?, OSPLAT = ?, OSVER = ?, LASTSEEN = from_unixtime(?) where UID = ?",
+			(&endpoint.hostname, &endpoint.ipv4, &endpoint.ipv6, &endpoint.osplat, &endpoint.osver, endpoint.last_seen, &endpoint.uid))?;
+		tx.exec_batch(
+			"insert into ENDPOINT_HISTORY (UID, ATTR, OLDVAL, NEWVAL, CHANGED) values (?, ?, ?, ?, ?)",
+			changes.iter().map(|change| (&change.uid, &change.attribute, &change.old_value, &change.new_value, change.changed)))?;
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn record_presence(&self, event: &PresenceEvent) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		tx.exec_drop("update STATUS set PRESENCE = ? where UID = ?", (event.new.as_str(), &event.uid))?;
+		tx.exec_drop(
+			"insert into PRESENCE_EVENT (UID, OLDSTATE, NEWSTATE, AT) values (?, ?, ?, ?)",
+			(&event.uid, event.old.as_str(), event.new.as_str(), event.at))?;
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn attribute_history(&self, uid: &str) -> Result<Vec<AttributeChange>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let changes = conn.exec_map(
+			"select UID,ATTR,OLDVAL,NEWVAL,CHANGED from ENDPOINT_HISTORY where UID = ? order by CHANGED",
+			(uid,),
+			|(uid, attribute, old_value, new_value, changed)| AttributeChange { uid, attribute, old_value, new_value, changed })?;
+		Ok(changes)
+		}
+
+	fn presence_events(&self, uid: &str) -> Result<Vec<PresenceEvent>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let events = conn.exec_map(
+			"select UID,OLDSTATE,NEWSTATE,AT from PRESENCE_EVENT where UID = ? order by AT",
+			(uid,),
+			|(uid, old, new, at): (String, String, String, i64)| PresenceEvent { uid, old: Presence::parse(&old), new: Presence::parse(&new), at })?;
+		Ok(events)
+		}
+
+	fn queue_command(&self, command: &QueuedCommand) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		insert_commands(&mut conn, std::slice::from_ref(command))
+		}
+
+	fn queued_commands(&self) -> Result<Vec<QueuedCommand>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let rows: Vec<Row> = conn.query(format!("select {} from COMMAND where STATE = 'queued' order by CREATED", COMMAND_COLUMNS))?;
+		rows.into_iter().map(command_from_row).collect()
+		}
+
+	fn mark_command_sent(&self, id: &str, now: i64) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.exec_drop("update COMMAND set STATE = 'sent', SENT = ? where ID = ? and STATE = 'queued'", (now, id))?;
+		Ok(conn.affected_rows() == 1)
+		}
+
+	fn requeue_command(&self, id: &str) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.exec_drop("update COMMAND set STATE = 'queued', SENT = null where ID = ? and STATE = 'sent'", (id,))?;
+		Ok(())
+		}
+
+	fn complete_command(&self, uid: &str, id: &str, success: bool, output: &str, now: i64) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let state = if success { CommandState::Succeeded } else { CommandState::Failed };
+		conn.exec_drop(
+			"update COMMAND set STATE = ?, COMPLETED = ?, OUTPUT = ? where ID = ? and UID = ? and STATE in ('queued', 'sent')",
+			(state.as_str(), now, output, id, uid))?;
+		Ok(conn.affected_rows() == 1)
+		}
+
+	fn endpoint_commands(&self, uid: &str) -> Result<Vec<QueuedCommand>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let rows: Vec<Row> = conn.exec(format!("select {} from COMMAND where UID = ? order by CREATED", COMMAND_COLUMNS), (uid,))?;
+		rows.into_iter().map(command_from_row).collect()
+		}
+
+	fn add_question(&self, question: &Question, commands: &[QueuedCommand]) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		tx.exec_drop(
+			"insert into QUESTION (ID,QUESTION,TARGET,ASKEDBY,CREATED) values (?, ?, ?, ?, ?)",
+			(&question.id, &question.question, &question.target, &question.asked_by, question.created))?;
+		insert_commands(&mut tx, commands)?;
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn find_question(&self, id: &str) -> Result<Option<Question>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let row: Option<Row> = conn.exec_first("select ID,QUESTION,TARGET,ASKEDBY,CREATED from QUESTION where ID = ?", (id,))?;
+		Ok(row.map(question_from_row))
+		}
+
+	fn list_questions(&self) -> Result<Vec<Question>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let rows: Vec<Row> = conn.query("select ID,QUESTION,TARGET,ASKEDBY,CREATED from QUESTION order by CREATED desc")?;
+		Ok(rows.into_iter().map(question_from_row).collect())
+		}
+
+	fn question_commands(&self, id: &str) -> Result<Vec<QueuedCommand>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let rows: Vec<Row> = conn.exec(format!("select {} from COMMAND where QUESTIONID = ? order by UID", COMMAND_COLUMNS), (id,))?;
+		rows.into_iter().map(command_from_row).collect()
+		}
+
+	fn add_token(&self, token: &EnrollmentToken) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.exec_drop(
+			"insert into ENROLL_TOKEN (ID,HASH,DESCRIPTION,GRPS,CREATED,EXPIRES,MAXUSES,USES,REVOKED,KEYVER) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+			(&token.id, &token.hash, &token.description, token.groups.join(","), token.created, token.expires, token.max_uses, token.uses, token.revoked, token.key_version))?;
+		Ok(())
+		}
+
+	fn find_token(&self, id: &str) -> Result<Option<EnrollmentToken>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let row: Option<Row> = conn.exec_first(
+			"select ID,HASH,DESCRIPTION,GRPS,CREATED,EXPIRES,MAXUSES,USES,REVOKED,KEYVER from ENROLL_TOKEN where ID = ?",
+			(id,))?;
+		Ok(row.map(token_from_row))
+		}
+
+	fn list_tokens(&self) -> Result<Vec<EnrollmentToken>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let rows: Vec<Row> = conn.query("select ID,HASH,DESCRIPTION,GRPS,CREATED,EXPIRES,MAXUSES,USES,REVOKED,KEYVER from ENROLL_TOKEN order by CREATED")?;
+		Ok(rows.into_iter().map(token_from_row).collect())
+		}
+
+	fn revoke_token(&self, id: &str) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		// Matched rather than changed rows, so revoking twice still finds the token
+		let found: Option<String> = conn.exec_first("select ID from ENROLL_TOKEN where ID = ?", (id,))?;
+		conn.exec_drop("update ENROLL_TOKEN set REVOKED = 1 where ID = ?", (id,))?;
+		Ok(found.is_some())
+		}
+
+	fn enroll_endpoint(&self, endpoint: &Endpoint, now: i64) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		tx.exec_drop(
+			"update ENROLL_TOKEN set USES = USES + 1 where ID = ? and REVOKED = 0 and (EXPIRES is null or EXPIRES > ?) and (MAXUSES is null or USES < MAXUSES)",
+			(&endpoint.token_id, now))?;
+		if tx.affected_rows() != 1 {
+			return Ok(false);
+			}
+		insert_endpoint(&mut tx, endpoint)?;
+		tx.commit()?;
+		Ok(true)
+		}
+
+	fn enrollment_keys(&self) -> Result<Vec<EnrollmentKey>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let keys = conn.query_map("select VERSION,KEYDATA,CREATED from ENROLL_KEY order by VERSION", |(version, sealed, created)| EnrollmentKey { version, sealed, created })?;
+		Ok(keys)
+		}
+
+	fn add_enrollment_key(&self, key: &EnrollmentKey) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.exec_drop("insert into ENROLL_KEY (VERSION,KEYDATA,CREATED) values (?, ?, ?)", (key.version, &key.sealed, key.created))?;
+		Ok(())
+		}
+
+	fn reseal_enrollment_key(&self, version: u32, sealed: &str) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.exec_drop("update ENROLL_KEY set KEYDATA = ? where VERSION = ?", (sealed, version))?;
+		Ok(conn.affected_rows() == 1)
+		}
+
+	fn delete_enrollment_key(&self, version: u32) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.exec_drop("delete from ENROLL_KEY where VERSION = ?", (version,))?;
+		Ok(conn.affected_rows() == 1)
+		}
+
+	fn watchlist(&self, uid: &str) -> Result<Vec<String>, StorageError> {
+		let mut conn = self.conn(&self.integrity, "integrity")?;
+		let paths = conn.exec("select PATH from WATCHLIST where ID = (select ID from CLIENTS.STATUS where UID = ?)", (uid,))?;
+		Ok(paths)
+		}
+
+	fn reset_watchlist(&self, uid: &str) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.integrity, "integrity")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		tx.exec_drop("delete from WATCHLIST where ID = (select ID from CLIENTS.STATUS where UID = ?)", (uid,))?;
+		tx.exec_drop(
+			"insert into WATCHLIST (ID, OS, PATH) select status.ID, status.OSPLAT, wd.PATH from CLIENTS.STATUS as status join WATCH_DEFAULT as wd on wd.OS = status.OSPLAT where status.UID = ?",
+			(uid,))?;
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn set_watchlist(&self, uid: &str, paths: &[String]) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.integrity, "integrity")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		tx.exec_drop("delete from WATCHLIST where ID = (select ID from CLIENTS.STATUS where UID = ?)", (uid,))?;
+		tx.exec_batch("insert into WATCHLIST (ID, OS, PATH) select ID, OSPLAT, ? from CLIENTS.STATUS where UID = ?", paths.iter().map(|path| (path, uid)))?;
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn add_file_events(&self, events: &[FileEvent]) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.integrity, "integrity")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		tx.exec_batch(
+			format!("insert into FILE_EVENT ({}) values (?, ?, ?, ?, ?, ?, ?, ?, ?)", FILE_EVENT_COLUMNS),
+			events.iter().map(|event| (&event.uid, &event.path, event.kind.as_str(), event.file_type.as_ref().map(FileType::as_str), &event.permissions, &event.owner, event.size, event.at, event.received)))?;
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn file_events(&self, query: &FileEventQuery) -> Result<(Vec<FileEvent>, u64), StorageError> {
+		let (conditions, mut values) = file_event_conditions(query);
+		let mut conn = self.conn(&self.integrity, "integrity")?;
+		let total: Option<u64> = conn.exec_first(format!("select count(*) from FILE_EVENT where {}", conditions), values.clone())?;
+		values.push(Value::from(query.limit));
+		values.push(Value::from(query.offset));
+		let rows: Vec<Row> = conn.exec(format!("select {} from FILE_EVENT where {} order by AT desc, ID desc limit ? offset ?", FILE_EVENT_COLUMNS, conditions), values)?;
+		Ok((rows.into_iter().map(file_event_from_row).collect(), total.unwrap_or(0)))
+		}
+
+	fn add_api_key(&self, key: &ApiKey) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.exec_drop(
+			format!("insert into API_KEY ({}) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", API_KEY_COLUMNS),
+			(&key.id, &key.hash, &key.description, &key.owner, encode_scopes(&key.scopes), key.created, key.expires, key.last_used, key.revoked, key.session))?;
+		Ok(())
+		}
+
+	fn find_api_key(&self, id: &str) -> Result<Option<ApiKey>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let row: Option<Row> = conn.exec_first(format!("select {} from API_KEY where ID = ?", API_KEY_COLUMNS), (id,))?;
+		Ok(row.map(api_key_from_row))
+		}
+
+	fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let rows: Vec<Row> = conn.query(format!("select {} from API_KEY order by CREATED", API_KEY_COLUMNS))?;
+		Ok(rows.into_iter().map(api_key_from_row).collect())
+		}
+
+	fn revoke_api_key(&self, id: &str) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		// Matched rather than changed rows, so revoking twice still finds the key
+		let found: Option<String> = conn.exec_first("select ID from API_KEY where ID = ?", (id,))?;
+		conn.exec_drop("update API_KEY set REVOKED = 1 where ID = ?", (id,))?;
+		Ok(found.is_some())
+		}
+
+	fn touch_api_key(&self, id: &str, now: i64) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.exec_drop("update API_KEY set LASTUSED = ? where ID = ?", (now, id))?;
+		Ok(())
+		}
+
+	fn delete_stale_sessions(&self, now: i64) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.exec_drop("delete from API_KEY where SESSION = 1 and (REVOKED = 1 or EXPIRES <= ?)", (now,))?;
+		Ok(())
+		}
+
+	fn add_operator(&self, operator: &Operator) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let existing: Option<String> = conn.exec_first("select USERNAME from OPERATOR where USERNAME = ?", (&operator.username,))?;
+		if existing.is_some() {
+			return Err(StorageError::Duplicate(operator.username.clone()));
+			}
+		conn.exec_drop(
+			format!("insert into OPERATOR ({}) values (?, ?, ?, ?, ?, ?, ?)", OPERATOR_COLUMNS),
+			(&operator.username, &operator.password_hash, operator.role.as_str(), operator.groups.join(","), operator.created, operator.last_login, operator.disabled))?;
+		Ok(())
+		}
+
+	fn find_operator(&self, username: &str) -> Result<Option<Operator>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let row: Option<Row> = conn.exec_first(format!("select {} from OPERATOR where USERNAME = ?", OPERATOR_COLUMNS), (username,))?;
+		row.map(operator_from_row).transpose()
+		}
+
+	fn list_operators(&self) -> Result<Vec<Operator>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let rows: Vec<Row> = conn.query(format!("select {} from OPERATOR order by USERNAME", OPERATOR_COLUMNS))?;
+		rows.into_iter().map(operator_from_row).collect()
+		}
+
+	fn update_operator(&self, operator: &Operator) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		// Matched rather than changed rows, so saving an unchanged operator still finds it
+		let found: Option<String> = conn.exec_first("select USERNAME from OPERATOR where USERNAME = ?", (&operator.username,))?;
+		conn.exec_drop(
+			"update OPERATOR set PWHASH = ?, ROLE = ?, GRPS = ?, DISABLED = ? where USERNAME = ?",
+			(&operator.password_hash, operator.role.as_str(), operator.groups.join(","), operator.disabled, &operator.username))?;
+		Ok(found.is_some())
+		}
+
+	fn delete_operator(&self, username: &str) -> Result<bool, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		tx.exec_drop("delete from OPERATOR where USERNAME = ?", (username,))?;
+		let deleted = tx.affected_rows() == 1;
+		tx.exec_drop("update API_KEY set REVOKED = 1 where OWNER = ?", (username,))?;
+		tx.commit()?;
+		Ok(deleted)
+		}
+
+	fn touch_operator(&self, username: &str, now: i64) -> Result<(), StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		conn.exec_drop("update OPERATOR set LASTLOGIN = ? where USERNAME = ?", (now, username))?;
+		Ok(())
+		}
+
+	// Locking the last record also locks the gap after it, so concurrent appends queue up
+	// behind this one instead of forking the chain
+	fn append_audit(&self, record: AuditRecord) -> Result<AuditRecord, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let mut tx = conn.start_transaction(TxOpts::default())?;
+		// Locking the last record leaves nothing to lock while the log is empty, so appends
+		// take turns on the lock row instead
+		tx.query_drop("select ID from AUDIT_LOCK where ID = 1 for update")?;
+		let last: Option<Row> = tx.query_first(format!("select {} from AUDIT_LOG order by SEQ desc limit 1", AUDIT_COLUMNS))?;
+		let record = record.seal(last.map(audit_record_from_row).as_ref());
+		tx.exec_drop(
+			format!("insert into AUDIT_LOG ({}) values (?, ?, ?, ?, ?, ?, ?, ?)", AUDIT_COLUMNS),
+			(record.seq, record.at, &record.event, &record.actor, &record.source, &record.detail, &record.prev_hash, &record.hash))?;
+		tx.commit()?;
+		Ok(record)
+		}
+
+	fn audit_records(&self, query: &AuditQuery) -> Result<(Vec<AuditRecord>, u64), StorageError> {
+		let (conditions, mut values) = audit_conditions(query);
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let total: Option<u64> = conn.exec_first(format!("select count(*) from AUDIT_LOG where {}", conditions), values.clone())?;
+		values.push(Value::from(query.limit));
+		values.push(Value::from(query.offset));
+		let rows: Vec<Row> = conn.exec(format!("select {} from AUDIT_LOG where {} order by SEQ desc limit ? offset ?", AUDIT_COLUMNS, conditions), values)?;
+		Ok((rows.into_iter().map(audit_record_from_row).collect(), total.unwrap_or(0)))
+		}
+
+	fn audit_chain(&self, after: u64, limit: u32) -> Result<Vec<AuditRecord>, StorageError> {
+		let mut conn = self.conn(&self.clients, "clients")?;
+		let rows: Vec<Row> = conn.exec(format!("select {} from AUDIT_LOG where SEQ > ? order by SEQ limit ?", AUDIT_COLUMNS), (after, limit))?;
+		Ok(rows.into_iter().map(audit_record_from_row).collect())
+		}
+	}
+
+// Build an Endpoint from a STATUS row. Optional columns may be NULL.
+fn endpoint_from_row(mut row: Row) -> Endpoint {
+	Endpoint {
+		uid: row.take("UID").unwrap_or_default(),
+		hostname: row.take("HOSTNAME").unwrap_or_default(),
+		ipv4: row.take::<Option<String>, _>("IPV4").flatten().unwrap_or_default(),
+		ipv6: row.take::<Option<String>, _>("IPV6").flatten().unwrap_or_default(),
+		osplat: row.take("OSPLAT").unwrap_or_default(),
+		osver: row.take("OSVER").unwrap_or_default(),
+		cert_fingerprint: row.take::<Option<String>, _>("CERTFP").flatten(),
+		token_id: row.take::<Option<String>, _>("TOKENID").flatten(),
+		groups: Vec::new(),
+		revoked: row.take("REVOKED").unwrap_or_default(),
+		last_seen: row.take::<Option<i64>, _>("LASTSEEN").flatten().unwrap_or_default(),
+		presence: Presence::parse(&row.take::<String, _>("PRESENCE").unwrap_or_default())
+		}
+	}
+
+// Endpoint rows don't carry their groups, so look them up for the whole list at once
+fn with_groups(conn: &mut PooledConn, mut endpoints: Vec<Endpoint>) -> Result<Vec<Endpoint>, StorageError> {
+	let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+	for (uid, group) in conn.query::<(String, String), _>("select UID,GRPNAME from ENDPOINT_GROUP order by GRPNAME")? {
+		groups.entry(uid).or_default().push(group);
+		}
+	for endpoint in &mut endpoints {
+		endpoint.groups = groups.remove(&endpoint.uid).unwrap_or_default();
+		}
+	Ok(endpoints)
+	}
+
+fn insert_endpoint<Q: Queryable>(conn: &mut Q, endpoint: &Endpoint) -> Result<(), StorageError> {
+	conn.exec_drop(
+		"insert into STATUS (UID,HOSTNAME,IPV4,IPV6,OSPLAT,OSVER,CERTFP,TOKENID,REGDATE,LASTSEEN) values (?, ?, ?, ?, ?, ?, ?, ?, now(), from_unixtime(?))",
+		(&endpoint.uid, &endpoint.hostname, &endpoint.ipv4, &endpoint.ipv6, &endpoint.osplat, &endpoint.osver, &endpoint.cert_fingerprint, &endpoint.token_id, endpoint.last_seen))?;
+	conn.exec_batch("insert into ENDPOINT_GROUP (UID, GRPNAME) values (?, ?)", endpoint.groups.iter().map(|group| (&endpoint.uid, group)))?;
+	Ok(())
+	}
+
+fn insert_commands<Q: Queryable>(conn: &mut Q, commands: &[QueuedCommand]) -> Result<(), StorageError> {
+	conn.exec_batch(
+		format!("insert into COMMAND ({}) values (?, ?, ?, ?, ?, ?, ?, ?, ?)", COMMAND_COLUMNS),
+		commands.iter().map(|command| (&command.id, &command.uid, encode_command(&command.kind), command.state.as_str(), command.created, command.sent, command.completed, &command.output, &command.question_id)))?;
+	Ok(())
+	}
+
+// Where clause and bound values for a file event query
+fn file_event_conditions(query: &FileEventQuery) -> (String, Vec<Value>) {
+	// An empty list of endpoints matches nothing
+	let in_uids = query.uids.as_ref().map(|uids| match uids.len() {
+		0 => "0 = 1".to_string(),
+		count => format!("UID in ({})", vec!["?"; count].join(", "))
+		});
+	let mut conditions = vec!["1 = 1"];
+	let mut values = Vec::new();
+	if let Some(uid) = &query.uid {
+		conditions.push("UID = ?");
+		values.push(Value::from(uid));
+		}
+	if let (Some(condition), Some(uids)) = (&in_uids, &query.uids) {
+		conditions.push(condition);
+		values.extend(uids.iter().map(Value::from));
+		}
+	if let Some(path) = &query.path {
+		conditions.push("left(PATH, char_length(?)) = ?");
+		values.push(Value::from(path));
+		values.push(Value::from(path));
+		}
+	if let Some(kind) = &query.kind {
+		conditions.push("KIND = ?");
+		values.push(Value::from(kind.as_str()));
+		}
+	if let Some(since) = query.since {
+		conditions.push("AT >= ?");
+		values.push(Value::from(since));
+		}
+	if let Some(until) = query.until {
+		conditions.push("AT <= ?");
+		values.push(Value::from(until));
+		}
+	(conditions.join(" and "), values)
+	}
+
+fn command_from_row(mut row: Row) -> Result<QueuedCommand, StorageError> {
+	let id: String = row.take("ID").unwrap_or_default();
+	let body: String = row.take("BODY").unwrap_or_default();
+	Ok(QueuedCommand {
+		kind: decode_command(&id, &body)?,
+		id,
+		uid: row.take("UID").unwrap_or_default(),
+		state: CommandState::parse(&row.take::<String, _>("STATE").unwrap_or_default()),
+		created: row.take("CREATED").unwrap_or_default(),
+		sent: row.take::<Option<i64>, _>("SENT").flatten(),
+		completed: row.take::<Option<i64>, _>("COMPLETED").flatten(),
+		output: row.take::<Option<String>, _>("OUTPUT").flatten(),
+		question_id: row.take::<Option<String>, _>("QUESTIONID").flatten()
+		})
+	}
+
+fn question_from_row(mut row: Row) -> Question {
+	Question {
+		id: row.take("ID").unwrap_or_default(),
+		question: row.take("QUESTION").unwrap_or_default(),
+		target: row.take("TARGET").unwrap_or_default(),
+		asked_by: row.take("ASKEDBY").unwrap_or_default(),
+		created: row.take("CREATED").unwrap_or_default()
+		}
+	}
+
+fn file_event_from_row(mut row: Row) -> FileEvent {
+	FileEvent {
+		uid: row.take("UID").unwrap_or_default(),
+		path: row.take("PATH").unwrap_or_default(),
+		kind: FileEventKind::parse(&row.take::<String, _>("KIND").unwrap_or_default()).unwrap_or_default(),
+		file_type: row.take::<Option<String>, _>("FTYPE").flatten().map(|file_type| FileType::parse(&file_type).unwrap_or_default()),
+		permissions: row.take::<Option<String>, _>("PERMS").flatten(),
+		owner: row.take::<Option<String>, _>("OWNER").flatten(),
+		size: row.take::<Option<u64>, _>("SIZE").flatten(),
+		at: row.take("AT").unwrap_or_default(),
+		received: row.take("RECEIVED").unwrap_or_default()
+		}
+	}
+
+fn token_from_row(mut row: Row) -> EnrollmentToken {
+	EnrollmentToken {
+		id: row.take("ID").unwrap_or_default(),
+		hash: row.take("HASH").unwrap_or_default(),
+		description: row.take("DESCRIPTION").unwrap_or_default(),
+		groups: split_groups(&row.take::<String, _>("GRPS").unwrap_or_default()),
+		created: row.take("CREATED").unwrap_or_default(),
+		expires: row.take::<Option<i64>, _>("EXPIRES").flatten(),
+		max_uses: row.take::<Option<u32>, _>("MAXUSES").flatten(),
+		uses: row.take("USES").unwrap_or_default(),
+		revoked: row.take("REVOKED").unwrap_or_default(),
+		key_version: row.take("KEYVER").unwrap_or_default()
+		}
+	}
+
+fn api_key_from_row(mut row: Row) -> ApiKey {
+	ApiKey {
+		id: row.take("ID").unwrap_or_default(),
+		hash: row.take("HASH").unwrap_or_default(),
+		description: row.take("DESCRIPTION").unwrap_or_default(),
+		owner: row.take::<Option<String>, _>("OWNER").flatten(),
+		scopes: decode_scopes(&row.take::<String, _>("SCOPES").unwrap_or_default()),
+		created: row.take("CREATED").unwrap_or_default(),
+		expires: row.take::<Option<i64>, _>("EXPIRES").flatten(),
+		last_used: row.take::<Option<i64>, _>("LASTUSED").flatten(),
+		revoked: row.take("REVOKED").unwrap_or_default(),
+		session: row.take("SESSION").unwrap_or_default()
+		}
+	}
+
+fn operator_from_row(mut row: Row) -> Result<Operator, StorageError> {
+	let username: String = row.take("USERNAME").unwrap_or_default();
+	Ok(Operator {
+		role: decode_role(&username, &row.take::<String, _>("ROLE").unwrap_or_default())?,
+		password_hash: row.take("PWHASH").unwrap_or_default(),
+		groups: split_groups(&row.take::<String, _>("GRPS").unwrap_or_default()),
+		created: row.take("CREATED").unwrap_or_default(),
+		last_login: row.take::<Option<i64>, _>("LASTLOGIN").flatten(),
+		disabled: row.take("DISABLED").unwrap_or_default(),
+		username
+		})
+	}
+
+// Where clause and bound values for an audit log query
+fn audit_conditions(query: &AuditQuery) -> (String, Vec<Value>) {
+	let mut conditions = vec!["1 = 1"];
+	let mut values = Vec::new();
+	if let Some(event) = &query.event {
+		conditions.push("EVENT = ?");
+		values.push(Value::from(event));
+		}
+	if let Some(actor) = &query.actor {
+		conditions.push("locate(?, ACTOR) > 0");
+		values.push(Value::from(actor));
+		}
+	if let Some(source) = &query.source {
+		conditions.push("SOURCE = ?");
+		values.push(Value::from(source));
+		}
+	if let Some(since) = query.since {
+		conditions.push("AT >= ?");
+		values.push(Value::from(since));
+		}
+	if let Some(until) = query.until {
+		conditions.push("AT <= ?");
+		values.push(Value::from(until));
+		}
+	(conditions.join(" and "), values)
+	}
+
+fn audit_record_from_row(mut row: Row) -> AuditRecord {
+	AuditRecord {
+		seq: row.take("SEQ").unwrap_or_default(),
+		at: row.take("AT").unwrap_or_default(),
+		event: row.take("EVENT").unwrap_or_default(),
+		actor: row.take("ACTOR").unwrap_or_default(),
+		source: row.take("SOURCE").unwrap_or_default(),
+		detail: row.take("DETAIL").unwrap_or_default(),
+		prev_hash: row.take("PREVHASH").unwrap_or_default(),
+		hash: row.take("HASH").unwrap_or_default()
+		}
+	}
+
+// Quote a string literal for statements that don't accept bound parameters
+fn quote(value: &str) -> String {
+	format!("'{}'", value.replace('\\', "\\\\").replace('\'', "''"))
+	}
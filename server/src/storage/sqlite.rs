@@ -0,0 +1,842 @@
+// SQLite storage backend
+//
+// Keeps the CLIENTS and INTEGRITY tables in a single database file, so the server can run
+// without an external database server.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row, TransactionBehavior};
+use rusqlite::types::Value;
+use luminum_proto::{FileEventKind, FileType};
+use luminum_proto::audit::AuditRecord;
+use super::{ApiKey, AttributeChange, AuditQuery, CommandState, Endpoint, EnrollmentKey, EnrollmentToken, FileEvent, FileEventQuery, Operator, Presence, PresenceEvent, Question, QueuedCommand, Storage, StorageError, decode_command, decode_role, decode_scopes, encode_command, encode_scopes, split_groups};
+use super::migrations;
+use crate::metrics;
+
+// Columns read by endpoint_from_row
+const ENDPOINT_COLUMNS: &str = "UID,HOSTNAME,IPV4,IPV6,OSPLAT,OSVER,CERTFP,TOKENID,REVOKED,cast(strftime('%s', LASTSEEN) as integer),PRESENCE";
+// Columns read by command_from_row
+const COMMAND_COLUMNS: &str = "ID,UID,BODY,STATE,CREATED,SENT,COMPLETED,OUTPUT,QUESTIONID";
+// Columns read by file_event_from_row
+const FILE_EVENT_COLUMNS: &str = "UID,PATH,KIND,FTYPE,PERMS,OWNER,SIZE,AT,RECEIVED";
+// Columns read by api_key_from_row
+const API_KEY_COLUMNS: &str = "ID,HASH,DESCRIPTION,OWNER,SCOPES,CREATED,EXPIRES,LASTUSED,REVOKED,SESSION";
+// Columns read by operator_from_row
+const OPERATOR_COLUMNS: &str = "USERNAME,PWHASH,ROLE,GRPS,CREATED,LASTLOGIN,DISABLED";
+// Columns read by audit_record_from_row
+const AUDIT_COLUMNS: &str = "SEQ,AT,EVENT,ACTOR,SOURCE,DETAIL,PREVHASH,HASH";
+
+pub struct SqliteStorage {
+	conn: Mutex<Connection>
+	}
+
+impl SqliteStorage {
+	// Open the database file, creating it if necessary. Tables are created by migrate().
+	pub fn open(path: &str) -> Result<SqliteStorage, StorageError> {
+		let conn = Connection::open(path)?;
+		Ok(SqliteStorage { conn: Mutex::new(conn) })
+		}
+
+	// Requests share one connection, so waiting for the lock is waiting for the database
+	fn conn(&self) -> MutexGuard<'_, Connection> {
+		let started = Instant::now();
+		let conn = self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+		metrics::observe_db_wait("sqlite", started);
+		conn
+		}
+	}
+
+impl Storage for SqliteStorage {
+	fn migrate(&self) -> Result<Vec<u32>, StorageError> {
+		let mut conn = self.conn();
+		conn.execute_batch("create table if not exists SCHEMA_VERSION (
+			VERSION integer not null primary key,
+			DESCRIPTION text not null,
+			APPLIED text not null
+			)")?;
+		let current: Option<u32> = conn.query_row("select max(VERSION) from SCHEMA_VERSION", [], |row| row.get(0))?;
+
+		// Each migration is applied and recorded atomically
+		let mut applied = Vec::new();
+		for migration in migrations::pending(current.unwrap_or(0)) {
+			let tx = conn.transaction()?;
+			for statement in migration.sqlite {
+				tx.execute_batch(statement)?;
+				}
+			for (os, path) in migration.watch_defaults {
+				tx.execute("insert into WATCH_DEFAULT (OS, PATH) values (?1, ?2)", params![os, path])?;
+				}
+			tx.execute("insert into SCHEMA_VERSION (VERSION, DESCRIPTION, APPLIED) values (?1, ?2, datetime('now'))", params![migration.version, migration.description])?;
+			tx.commit()?;
+			applied.push(migration.version);
+			}
+		Ok(applied)
+		}
+
+	fn schema_version(&self) -> Result<u32, StorageError> {
+		let conn = self.conn();
+		let tracked: bool = conn.query_row("select count(*) > 0 from sqlite_master where type = 'table' and name = 'SCHEMA_VERSION'", [], |row| row.get(0))?;
+		if !tracked {
+			return Ok(0);
+			}
+		let current: Option<u32> = conn.query_row("select max(VERSION) from SCHEMA_VERSION", [], |row| row.get(0))?;
+		Ok(current.unwrap_or(0))
+		}
+
+	fn find_endpoint(&self, uid: &str) -> Result<Option<Endpoint>, StorageError> {
+		let conn = self.conn();
+		let endpoint = conn.query_row(
+			&format!("select {} from STATUS where UID = ?1", ENDPOINT_COLUMNS),
+			params![uid],
+			endpoint_from_row).optional()?;
+		match endpoint {
+			Some(mut endpoint) => {
+				let mut stmt = conn.prepare("select GRPNAME from ENDPOINT_GROUP where UID = ?1 order by GRPNAME")?;
+				endpoint.groups = stmt.query_map(params![uid], |row| row.get(0))?.collect::<Result<Vec<String>, _>>()?;
+				Ok(Some(endpoint))
+				},
+			None => Ok(None)
+			}
+		}
+
+	fn list_endpoints(&self) -> Result<Vec<Endpoint>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare(&format!("select {} from STATUS order by REGDATE", ENDPOINT_COLUMNS))?;
+		let endpoints = stmt.query_map([], endpoint_from_row)?.collect::<Result<Vec<Endpoint>, _>>()?;
+		with_groups(&conn, endpoints)
+		}
+
+	fn endpoints_by_token(&self, token_id: &str) -> Result<Vec<Endpoint>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare(&format!("select {} from STATUS where TOKENID = ?1 order by REGDATE", ENDPOINT_COLUMNS))?;
+		let endpoints = stmt.query_map(params![token_id], endpoint_from_row)?.collect::<Result<Vec<Endpoint>, _>>()?;
+		with_groups(&conn, endpoints)
+		}
+
+	fn revoke_endpoint(&self, uid: &str) -> Result<bool, StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		if tx.execute("update STATUS set REVOKED = 1 where UID = ?1", params![uid])? != 1 {
+			return Ok(false);
+			}
+		tx.execute("update COMMAND set STATE = 'cancelled' where UID = ?1 and STATE = 'queued'", params![uid])?;
+		tx.commit()?;
+		Ok(true)
+		}
+
+	fn set_endpoint_groups(&self, uid: &str, groups: &[String]) -> Result<bool, StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		let exists: bool = tx.query_row("select count(*) > 0 from STATUS where UID = ?1", params![uid], |row| row.get(0))?;
+		if !exists {
+			return Ok(false);
+			}
+		tx.execute("delete from ENDPOINT_GROUP where UID = ?1", params![uid])?;
+		for group in groups {
+			tx.execute("insert into ENDPOINT_GROUP (UID, GRPNAME) values (?1, ?2)", params![uid, group])?;
+			}
+		tx.commit()?;
+		Ok(true)
+		}
+
+	fn delete_endpoint(&self, uid: &str) -> Result<bool, StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		tx.execute("delete from WATCHLIST where ID = (select ID from STATUS where UID = ?1)", params![uid])?;
+		for table in ["ENDPOINT_GROUP", "ENDPOINT_HISTORY", "PRESENCE_EVENT", "COMMAND", "FILE_EVENT"] {
+			tx.execute(&format!("delete from {} where UID = ?1", table), params![uid])?;
+			}
+		let deleted = tx.execute("delete from STATUS where UID = ?1", params![uid])?;
+		tx.commit()?;
+		Ok(deleted == 1)
+		}
+
+	fn record_heartbeat(&self, endpoint: &Endpoint, changes: &[AttributeChange]) -> Result<(), StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		tx.execute(
+			"update STATUS set HOSTNAME = ?2, IPV4 = ?3, IPV6 = ?4, OSPLAT = ?5, OSVER = ?6, LASTSEEN = datetime(?7, 'unixepoch') where UID = ?1",
+			params![endpoint.uid, endpoint.hostname, endpoint.ipv4, endpoint.ipv6, endpoint.osplat, endpoint.osver, endpoint.last_seen])?;
+		for change in changes {
+			tx.execute(
+				"insert into ENDPOINT_HISTORY (UID, ATTR, OLDVAL, NEWVAL, CHANGED) values (?1, ?2, ?3, ?4, ?5)",
+				params![change.uid, change.attribute, change.old_value, change.new_value, change.changed])?;
+			}
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn record_presence(&self, event: &PresenceEvent) -> Result<(), StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		tx.execute("update STATUS set PRESENCE = ?2 where UID = ?1", params![event.uid, event.new.as_str()])?;
+		tx.execute(
+			"insert into PRESENCE_EVENT (UID, OLDSTATE, NEWSTATE, AT) values (?1, ?2, ?3, ?4)",
+			params![event.uid, event.old.as_str(), event.new.as_str(), event.at])?;
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn attribute_history(&self, uid: &str) -> Result<Vec<AttributeChange>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare("select UID,ATTR,OLDVAL,NEWVAL,CHANGED from ENDPOINT_HISTORY where UID = ?1 order by CHANGED, rowid")?;
+		let changes = stmt.query_map(params![uid], |row| Ok(AttributeChange {
+			uid: row.get(0)?,
+			attribute: row.get(1)?,
+			old_value: row.get(2)?,
+			new_value: row.get(3)?,
+			changed: row.get(4)?
+			}))?.collect::<Result<Vec<AttributeChange>, _>>()?;
+		Ok(changes)
+		}
+
+	fn presence_events(&self, uid: &str) -> Result<Vec<PresenceEvent>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare("select UID,OLDSTATE,NEWSTATE,AT from PRESENCE_EVENT where UID = ?1 order by AT, rowid")?;
+		let events = stmt.query_map(params![uid], |row| Ok(PresenceEvent {
+			uid: row.get(0)?,
+			old: Presence::parse(&row.get::<_, String>(1)?),
+			new: Presence::parse(&row.get::<_, String>(2)?),
+			at: row.get(3)?
+			}))?.collect::<Result<Vec<PresenceEvent>, _>>()?;
+		Ok(events)
+		}
+
+	fn queue_command(&self, command: &QueuedCommand) -> Result<(), StorageError> {
+		let conn = self.conn();
+		insert_command(&conn, command)
+		}
+
+	fn queued_commands(&self) -> Result<Vec<QueuedCommand>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare(&format!("select {} from COMMAND where STATE = 'queued' order by CREATED", COMMAND_COLUMNS))?;
+		let rows = stmt.query_map([], command_from_row)?.collect::<Result<Vec<_>, _>>()?;
+		rows.into_iter().collect()
+		}
+
+	fn mark_command_sent(&self, id: &str, now: i64) -> Result<bool, StorageError> {
+		let conn = self.conn();
+		Ok(conn.execute("update COMMAND set STATE = 'sent', SENT = ?2 where ID = ?1 and STATE = 'queued'", params![id, now])? == 1)
+		}
+
+	fn requeue_command(&self, id: &str) -> Result<(), StorageError> {
+		let conn = self.conn();
+		conn.execute("update COMMAND set STATE = 'queued', SENT = null where ID = ?1 and STATE = 'sent'", params![id])?;
+		Ok(())
+		}
+
+	fn complete_command(&self, uid: &str, id: &str, success: bool, output: &str, now: i64) -> Result<bool, StorageError> {
+		let conn = self.conn();
+		let state = if success { CommandState::Succeeded } else { CommandState::Failed };
+		let updated = conn.execute(
+			"update COMMAND set STATE = ?3, COMPLETED = ?4, OUTPUT = ?5 where ID = ?1 and UID = ?2 and STATE in ('queued', 'sent')",
+			params![id, uid, state.as_str(), now, output])?;
+		Ok(updated == 1)
+		}
+
+	fn endpoint_commands(&self, uid: &str) -> Result<Vec<QueuedCommand>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare(&format!("select {} from COMMAND where UID = ?1 order by CREATED", COMMAND_COLUMNS))?;
+		let rows = stmt.query_map(params![uid], command_from_row)?.collect::<Result<Vec<_>, _>>()?;
+		rows.into_iter().collect()
+		}
+
+	fn add_question(&self, question: &Question, commands: &[QueuedCommand]) -> Result<(), StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		tx.execute(
+			"insert into QUESTION (ID,QUESTION,TARGET,ASKEDBY,CREATED) values (?1, ?2, ?3, ?4, ?5)",
+			params![question.id, question.question, question.target, question.asked_by, question.created])?;
+		for command in commands {
+			insert_command(&tx, command)?;
+			}
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn find_question(&self, id: &str) -> Result<Option<Question>, StorageError> {
+		let conn = self.conn();
+		let question = conn.query_row("select ID,QUESTION,TARGET,ASKEDBY,CREATED from QUESTION where ID = ?1", params![id], question_from_row).optional()?;
+		Ok(question)
+		}
+
+	fn list_questions(&self) -> Result<Vec<Question>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare("select ID,QUESTION,TARGET,ASKEDBY,CREATED from QUESTION order by CREATED desc, rowid desc")?;
+		let questions = stmt.query_map([], question_from_row)?.collect::<Result<Vec<Question>, _>>()?;
+		Ok(questions)
+		}
+
+	fn question_commands(&self, id: &str) -> Result<Vec<QueuedCommand>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare(&format!("select {} from COMMAND where QUESTIONID = ?1 order by UID", COMMAND_COLUMNS))?;
+		let rows = stmt.query_map(params![id], command_from_row)?.collect::<Result<Vec<_>, _>>()?;
+		rows.into_iter().collect()
+		}
+
+	fn add_token(&self, token: &EnrollmentToken) -> Result<(), StorageError> {
+		let conn = self.conn();
+		conn.execute(
+			"insert into ENROLL_TOKEN (ID,HASH,DESCRIPTION,GRPS,CREATED,EXPIRES,MAXUSES,USES,REVOKED,KEYVER) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+			params![token.id, token.hash, token.description, token.groups.join(","), token.created, token.expires, token.max_uses, token.uses, token.revoked, token.key_version])?;
+		Ok(())
+		}
+
+	fn find_token(&self, id: &str) -> Result<Option<EnrollmentToken>, StorageError> {
+		let conn = self.conn();
+		let token = conn.query_row(
+			"select ID,HASH,DESCRIPTION,GRPS,CREATED,EXPIRES,MAXUSES,USES,REVOKED,KEYVER from ENROLL_TOKEN where ID = ?1",
+			params![id],
+			token_from_row).optional()?;
+		Ok(token)
+		}
+
+	fn list_tokens(&self) -> Result<Vec<EnrollmentToken>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare("select ID,HASH,DESCRIPTION,GRPS,CREATED,EXPIRES,MAXUSES,USES,REVOKED,KEYVER from ENROLL_TOKEN order by CREATED")?;
+		let tokens = stmt.query_map([], token_from_row)?.collect::<Result<Vec<EnrollmentToken>, _>>()?;
+		Ok(tokens)
+		}
+
+	fn revoke_token(&self, id: &str) -> Result<bool, StorageError> {
+		let conn = self.conn();
+		Ok(conn.execute("update ENROLL_TOKEN set REVOKED = 1 where ID = ?1", params![id])? == 1)
+		}
+
+	fn enroll_endpoint(&self, endpoint: &Endpoint, now: i64) -> Result<bool, StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		let updated = tx.execute(
+			"update ENROLL_TOKEN set USES = USES + 1 where ID = ?1 and REVOKED = 0 and (EXPIRES is null or EXPIRES > ?2) and (MAXUSES is null or USES < MAXUSES)",
+			params![endpoint.token_id, now])?;
+		if updated != 1 {
+			return Ok(false);
+			}
+		insert_endpoint(&tx, endpoint)?;
+		tx.commit()?;
+		Ok(true)
+		}
+
+	fn enrollment_keys(&self) -> Result<Vec<EnrollmentKey>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare("select VERSION,KEYDATA,CREATED from ENROLL_KEY order by VERSION")?;
+		let keys = stmt.query_map([], |row| Ok(EnrollmentKey { version: row.get(0)?, sealed: row.get(1)?, created: row.get(2)? }))?
+			.collect::<Result<Vec<EnrollmentKey>, _>>()?;
+		Ok(keys)
+		}
+
+	fn add_enrollment_key(&self, key: &EnrollmentKey) -> Result<(), StorageError> {
+		let conn = self.conn();
+		conn.execute("insert into ENROLL_KEY (VERSION,KEYDATA,CREATED) values (?1, ?2, ?3)", params![key.version, key.sealed, key.created])?;
+		Ok(())
+		}
+
+	fn reseal_enrollment_key(&self, version: u32, sealed: &str) -> Result<bool, StorageError> {
+		let conn = self.conn();
+		Ok(conn.execute("update ENROLL_KEY set KEYDATA = ?1 where VERSION = ?2", params![sealed, version])? == 1)
+		}
+
+	fn delete_enrollment_key(&self, version: u32) -> Result<bool, StorageError> {
+		let conn = self.conn();
+		Ok(conn.execute("delete from ENROLL_KEY where VERSION = ?1", params![version])? == 1)
+		}
+
+	fn watchlist(&self, uid: &str) -> Result<Vec<String>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare("select PATH from WATCHLIST where ID = (select ID from STATUS where UID = ?1)")?;
+		let paths = stmt.query_map(params![uid], |row| row.get(0))?.collect::<Result<Vec<String>, _>>()?;
+		Ok(paths)
+		}
+
+	fn reset_watchlist(&self, uid: &str) -> Result<(), StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		tx.execute("delete from WATCHLIST where ID = (select ID from STATUS where UID = ?1)", params![uid])?;
+		tx.execute(
+			"insert into WATCHLIST (ID, OS, PATH) select status.ID, status.OSPLAT, wd.PATH from STATUS as status join WATCH_DEFAULT as wd on wd.OS = status.OSPLAT where status.UID = ?1",
+			params![uid])?;
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn set_watchlist(&self, uid: &str, paths: &[String]) -> Result<(), StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		tx.execute("delete from WATCHLIST where ID = (select ID from STATUS where UID = ?1)", params![uid])?;
+		for path in paths {
+			tx.execute("insert into WATCHLIST (ID, OS, PATH) select ID, OSPLAT, ?2 from STATUS where UID = ?1", params![uid, path])?;
+			}
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn add_file_events(&self, events: &[FileEvent]) -> Result<(), StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		for event in events {
+			tx.execute(
+				&format!("insert into FILE_EVENT ({}) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)", FILE_EVENT_COLUMNS),
+				params![event.uid, event.path, event.kind.as_str(), event.file_type.as_ref().map(FileType::as_str), event.permissions, event.owner, event.size.map(|size| size as i64), event.at, event.received])?;
+			}
+		tx.commit()?;
+		Ok(())
+		}
+
+	fn file_events(&self, query: &FileEventQuery) -> Result<(Vec<FileEvent>, u64), StorageError> {
+		let (conditions, mut values) = file_event_conditions(query);
+		let conn = self.conn();
+		let total: i64 = conn.query_row(&format!("select count(*) from FILE_EVENT where {}", conditions), params_from_iter(values.iter()), |row| row.get(0))?;
+		values.push(Value::Integer(query.limit as i64));
+		values.push(Value::Integer(query.offset as i64));
+		let mut stmt = conn.prepare(&format!("select {} from FILE_EVENT where {} order by AT desc, ID desc limit ? offset ?", FILE_EVENT_COLUMNS, conditions))?;
+		let events = stmt.query_map(params_from_iter(values.iter()), file_event_from_row)?.collect::<Result<Vec<FileEvent>, _>>()?;
+		Ok((events, total as u64))
+		}
+
+	fn add_api_key(&self, key: &ApiKey) -> Result<(), StorageError> {
+		let conn = self.conn();
+		conn.execute(
+			&format!("insert into API_KEY ({}) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)", API_KEY_COLUMNS),
+			params![key.id, key.hash, key.description, key.owner, encode_scopes(&key.scopes), key.created, key.expires, key.last_used, key.revoked, key.session])?;
+		Ok(())
+		}
+
+	fn find_api_key(&self, id: &str) -> Result<Option<ApiKey>, StorageError> {
+		let conn = self.conn();
+		let key = conn.query_row(&format!("select {} from API_KEY where ID = ?1", API_KEY_COLUMNS), params![id], api_key_from_row).optional()?;
+		Ok(key)
+		}
+
+	fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare(&format!("select {} from API_KEY order by CREATED", API_KEY_COLUMNS))?;
+		let keys = stmt.query_map([], api_key_from_row)?.collect::<Result<Vec<ApiKey>, _>>()?;
+		Ok(keys)
+		}
+
+	fn revoke_api_key(&self, id: &str) -> Result<bool, StorageError> {
+		let conn = self.conn();
+		Ok(conn.execute("update API_KEY set REVOKED = 1 where ID = ?1", params![id])? == 1)
+		}
+
+	fn touch_api_key(&self, id: &str, now: i64) -> Result<(), StorageError> {
+		let conn = self.conn();
+		conn.execute("update API_KEY set LASTUSED = ?2 where ID = ?1", params![id, now])?;
+		Ok(())
+		}
+
+	fn delete_stale_sessions(&self, now: i64) -> Result<(), StorageError> {
+		let conn = self.conn();
+		conn.execute("delete from API_KEY where SESSION = 1 and (REVOKED = 1 or EXPIRES <= ?1)", params![now])?;
+		Ok(())
+		}
+
+	fn add_operator(&self, operator: &Operator) -> Result<(), StorageError> {
+		let conn = self.conn();
+		if conn.query_row("select 1 from OPERATOR where USERNAME = ?1", params![operator.username], |_| Ok(())).optional()?.is_some() {
+			return Err(StorageError::Duplicate(operator.username.clone()));
+			}
+		conn.execute(
+			&format!("insert into OPERATOR ({}) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)", OPERATOR_COLUMNS),
+			params![operator.username, operator.password_hash, operator.role.as_str(), operator.groups.join(","), operator.created, operator.last_login, operator.disabled])?;
+		Ok(())
+		}
+
+	fn find_operator(&self, username: &str) -> Result<Option<Operator>, StorageError> {
+		let conn = self.conn();
+		let operator = conn.query_row(&format!("select {} from OPERATOR where USERNAME = ?1", OPERATOR_COLUMNS), params![username], operator_from_row).optional()?;
+		operator.transpose()
+		}
+
+	fn list_operators(&self) -> Result<Vec<Operator>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare(&format!("select {} from OPERATOR order by USERNAME", OPERATOR_COLUMNS))?;
+		let operators = stmt.query_map([], operator_from_row)?.collect::<Result<Vec<_>, _>>()?;
+		operators.into_iter().collect()
+		}
+
+	fn update_operator(&self, operator: &Operator) -> Result<bool, StorageError> {
+		let conn = self.conn();
+		let updated = conn.execute(
+			"update OPERATOR set PWHASH = ?2, ROLE = ?3, GRPS = ?4, DISABLED = ?5 where USERNAME = ?1",
+			params![operator.username, operator.password_hash, operator.role.as_str(), operator.groups.join(","), operator.disabled])?;
+		Ok(updated == 1)
+		}
+
+	fn delete_operator(&self, username: &str) -> Result<bool, StorageError> {
+		let mut conn = self.conn();
+		let tx = conn.transaction()?;
+		let deleted = tx.execute("delete from OPERATOR where USERNAME = ?1", params![username])?;
+		tx.execute("update API_KEY set REVOKED = 1 where OWNER = ?1", params![username])?;
+		tx.commit()?;
+		Ok(deleted == 1)
+		}
+
+	fn touch_operator(&self, username: &str, now: i64) -> Result<(), StorageError> {
+		let conn = self.conn();
+		conn.execute("update OPERATOR set LASTLOGIN = ?2 where USERNAME = ?1", params![username, now])?;
+		Ok(())
+		}
+
+	fn append_audit(&self, record: AuditRecord) -> Result<AuditRecord, StorageError> {
+		// Take the write lock before reading the last record, so appends from other processes
+		// wait their turn instead of failing partway through
+		let mut conn = self.conn();
+		let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+		let last = tx.query_row(&format!("select {} from AUDIT_LOG order by SEQ desc limit 1", AUDIT_COLUMNS), [], audit_record_from_row).optional()?;
+		let record = record.seal(last.as_ref());
+		tx.execute(
+			&format!("insert into AUDIT_LOG ({}) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)", AUDIT_COLUMNS),
+			params![record.seq as i64, record.at, record.event, record.actor, record.source, record.detail, record.prev_hash, record.hash])?;
+		tx.commit()?;
+		Ok(record)
+		}
+
+	fn audit_records(&self, query: &AuditQuery) -> Result<(Vec<AuditRecord>, u64), StorageError> {
+		let (conditions, mut values) = audit_conditions(query);
+		let conn = self.conn();
+		let total: i64 = conn.query_row(&format!("select count(*) from AUDIT_LOG where {}", conditions), params_from_iter(values.iter()), |row| row.get(0))?;
+		values.push(Value::Integer(query.limit as i64));
+		values.push(Value::Integer(query.offset as i64));
+		let mut stmt = conn.prepare(&format!("select {} from AUDIT_LOG where {} order by SEQ desc limit ? offset ?", AUDIT_COLUMNS, conditions))?;
+		let records = stmt.query_map(params_from_iter(values.iter()), audit_record_from_row)?.collect::<Result<Vec<AuditRecord>, _>>()?;
+		Ok((records, total as u64))
+		}
+
+	fn audit_chain(&self, after: u64, limit: u32) -> Result<Vec<AuditRecord>, StorageError> {
+		let conn = self.conn();
+		let mut stmt = conn.prepare(&format!("select {} from AUDIT_LOG where SEQ > ?1 order by SEQ limit ?2", AUDIT_COLUMNS))?;
+		let records = stmt.query_map(params![after as i64, limit], audit_record_from_row)?.collect::<Result<Vec<AuditRecord>, _>>()?;
+		Ok(records)
+		}
+	}
+
+fn endpoint_from_row(row: &Row) -> rusqlite::Result<Endpoint> {
+	Ok(Endpoint {
+		uid: row.get(0)?,
+		hostname: row.get(1)?,
+		ipv4: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+		ipv6: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+		osplat: row.get(4)?,
+		osver: row.get(5)?,
+		cert_fingerprint: row.get(6)?,
+		token_id: row.get(7)?,
+		groups: Vec::new(),
+		revoked: row.get(8)?,
+		last_seen: row.get::<_, Option<i64>>(9)?.unwrap_or(0),
+		presence: Presence::parse(&row.get::<_, String>(10)?)
+		})
+	}
+
+// Endpoint rows don't carry their groups, so look them up for the whole list at once
+fn with_groups(conn: &Connection, mut endpoints: Vec<Endpoint>) -> Result<Vec<Endpoint>, StorageError> {
+	let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+	let mut stmt = conn.prepare("select UID,GRPNAME from ENDPOINT_GROUP order by GRPNAME")?;
+	for row in stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))? {
+		let (uid, group) = row?;
+		groups.entry(uid).or_default().push(group);
+		}
+	for endpoint in &mut endpoints {
+		endpoint.groups = groups.remove(&endpoint.uid).unwrap_or_default();
+		}
+	Ok(endpoints)
+	}
+
+fn insert_endpoint(conn: &Connection, endpoint: &Endpoint) -> Result<(), StorageError> {
+	conn.execute(
+		"insert into STATUS (UID,HOSTNAME,IPV4,IPV6,OSPLAT,OSVER,CERTFP,TOKENID,REGDATE,LASTSEEN) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, datetime('now'), datetime(?9, 'unixepoch'))",
+		params![endpoint.uid, endpoint.hostname, endpoint.ipv4, endpoint.ipv6, endpoint.osplat, endpoint.osver, endpoint.cert_fingerprint, endpoint.token_id, endpoint.last_seen])?;
+	for group in &endpoint.groups {
+		conn.execute("insert into ENDPOINT_GROUP (UID, GRPNAME) values (?1, ?2)", params![endpoint.uid, group])?;
+		}
+	Ok(())
+	}
+
+fn insert_command(conn: &Connection, command: &QueuedCommand) -> Result<(), StorageError> {
+	conn.execute(
+		&format!("insert into COMMAND ({}) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)", COMMAND_COLUMNS),
+		params![command.id, command.uid, encode_command(&command.kind), command.state.as_str(), command.created, command.sent, command.completed, command.output, command.question_id])?;
+	Ok(())
+	}
+
+// Where clause and bound values for a file event query
+fn file_event_conditions(query: &FileEventQuery) -> (String, Vec<Value>) {
+	// An empty list of endpoints matches nothing
+	let in_uids = query.uids.as_ref().map(|uids| match uids.len() {
+		0 => "0 = 1".to_string(),
+		count => format!("UID in ({})", vec!["?"; count].join(", "))
+		});
+	let mut conditions = vec!["1 = 1"];
+	let mut values = Vec::new();
+	if let Some(uid) = &query.uid {
+		conditions.push("UID = ?");
+		values.push(Value::Text(uid.clone()));
+		}
+	if let (Some(condition), Some(uids)) = (&in_uids, &query.uids) {
+		conditions.push(condition);
+		values.extend(uids.iter().map(|uid| Value::Text(uid.clone())));
+		}
+	if let Some(path) = &query.path {
+		conditions.push("substr(PATH, 1, length(?)) = ?");
+		values.push(Value::Text(path.clone()));
+		values.push(Value::Text(path.clone()));
+		}
+	if let Some(kind) = &query.kind {
+		conditions.push("KIND = ?");
+		values.push(Value::Text(kind.to_string()));
+		}
+	if let Some(since) = query.since {
+		conditions.push("AT >= ?");
+		values.push(Value::Integer(since));
+		}
+	if let Some(until) = query.until {
+		conditions.push("AT <= ?");
+		values.push(Value::Integer(until));
+		}
+	(conditions.join(" and "), values)
+	}
+
+// The command body is decoded after the row is read, so a corrupt body is reported as a
+// storage error rather than a database error
+// Where clause and bound values for an audit log query
+fn audit_conditions(query: &AuditQuery) -> (String, Vec<Value>) {
+	let mut conditions = vec!["1 = 1"];
+	let mut values = Vec::new();
+	if let Some(event) = &query.event {
+		conditions.push("EVENT = ?");
+		values.push(Value::Text(event.clone()));
+		}
+	if let Some(actor) = &query.actor {
+		conditions.push("instr(ACTOR, ?) > 0");
+		values.push(Value::Text(actor.clone()));
+		}
+	if let Some(source) = &query.source {
+		conditions.push("SOURCE = ?");
+		values.push(Value::Text(source.clone()));
+		}
+	if let Some(since) = query.since {
+		conditions.push("AT >= ?");
+		values.push(Value::Integer(since));
+		}
+	if let Some(until) = query.until {
+		conditions.push("AT <= ?");
+		values.push(Value::Integer(until));
+		}
+	(conditions.join(" and "), values)
+	}
+
+fn command_from_row(row: &Row) -> rusqlite::Result<Result<QueuedCommand, StorageError>> {
+	let id: String = row.get(0)?;
+	let body: String = row.get(2)?;
+	let command = QueuedCommand {
+		kind: match decode_command(&id, &body) {
+			Ok(kind) => kind,
+			Err(err) => { return Ok(Err(err)); }
+			},
+		id,
+		uid: row.get(1)?,
+		state: CommandState::parse(&row.get::<_, String>(3)?),
+		created: row.get(4)?,
+		sent: row.get(5)?,
+		completed: row.get(6)?,
+		output: row.get(7)?,
+		question_id: row.get(8)?
+		};
+	Ok(Ok(command))
+	}
+
+fn question_from_row(row: &Row) -> rusqlite::Result<Question> {
+	Ok(Question {
+		id: row.get(0)?,
+		question: row.get(1)?,
+		target: row.get(2)?,
+		asked_by: row.get(3)?,
+		created: row.get(4)?
+		})
+	}
+
+fn file_event_from_row(row: &Row) -> rusqlite::Result<FileEvent> {
+	Ok(FileEvent {
+		uid: row.get(0)?,
+		path: row.get(1)?,
+		kind: FileEventKind::parse(&row.get::<_, String>(2)?).unwrap_or_default(),
+		file_type: row.get::<_, Option<String>>(3)?.map(|file_type| FileType::parse(&file_type).unwrap_or_default()),
+		permissions: row.get(4)?,
+		owner: row.get(5)?,
+		size: row.get::<_, Option<i64>>(6)?.map(|size| size as u64),
+		at: row.get(7)?,
+		received: row.get(8)?
+		})
+	}
+
+fn token_from_row(row: &Row) -> rusqlite::Result<EnrollmentToken> {
+	Ok(EnrollmentToken {
+		id: row.get(0)?,
+		hash: row.get(1)?,
+		description: row.get(2)?,
+		groups: split_groups(&row.get::<_, String>(3)?),
+		created: row.get(4)?,
+		expires: row.get(5)?,
+		max_uses: row.get(6)?,
+		uses: row.get(7)?,
+		revoked: row.get(8)?,
+		key_version: row.get(9)?
+		})
+	}
+
+fn api_key_from_row(row: &Row) -> rusqlite::Result<ApiKey> {
+	Ok(ApiKey {
+		id: row.get(0)?,
+		hash: row.get(1)?,
+		description: row.get(2)?,
+		owner: row.get(3)?,
+		scopes: decode_scopes(&row.get::<_, String>(4)?),
+		created: row.get(5)?,
+		expires: row.get(6)?,
+		last_used: row.get(7)?,
+		revoked: row.get(8)?,
+		session: row.get(9)?
+		})
+	}
+
+// The role is checked after the row is read, like command bodies
+fn operator_from_row(row: &Row) -> rusqlite::Result<Result<Operator, StorageError>> {
+	let username: String = row.get(0)?;
+	let role = match decode_role(&username, &row.get::<_, String>(2)?) {
+		Ok(role) => role,
+		Err(err) => { return Ok(Err(err)); }
+		};
+	Ok(Ok(Operator {
+		username,
+		password_hash: row.get(1)?,
+		role,
+		groups: split_groups(&row.get::<_, String>(3)?),
+		created: row.get(4)?,
+		last_login: row.get(5)?,
+		disabled: row.get(6)?
+		}))
+	}
+
+fn audit_record_from_row(row: &Row) -> rusqlite::Result<AuditRecord> {
+	Ok(AuditRecord {
+		seq: row.get::<_, i64>(0)? as u64,
+		at: row.get(1)?,
+		event: row.get(2)?,
+		actor: row.get(3)?,
+		source: row.get(4)?,
+		detail: row.get(5)?,
+		prev_hash: row.get(6)?,
+		hash: row.get(7)?
+		})
+	}
+
+#[cfg(test)]
+mod tests {
+	use luminum_proto::audit::AuditRecord;
+	use super::{migrations, SqliteStorage, Storage};
+	use crate::storage::{Endpoint, EnrollmentToken, Presence};
+
+	fn latest() -> u32 {
+		migrations::MIGRATIONS.last().unwrap().version
+		}
+
+	fn all_versions() -> Vec<u32> {
+		migrations::MIGRATIONS.iter().map(|migration| migration.version).collect()
+		}
+
+	// Enroll an endpoint and audit it, which touches most of the migrated tables
+	fn exercise(storage: &SqliteStorage) {
+		let token = EnrollmentToken { id: String::from("t1"), hash: String::from("00"), description: String::new(), groups: vec![String::from("web")], created: 1700000000, expires: None, max_uses: Some(1), uses: 0, revoked: false, key_version: 0 };
+		storage.add_token(&token).unwrap();
+		let endpoint = Endpoint { uid: String::from("new"), hostname: String::from("host02"), osplat: String::from("Linux"), token_id: Some(token.id.clone()), groups: token.groups.clone(), last_seen: 1700000000, ..Endpoint::default() };
+		assert!(storage.enroll_endpoint(&endpoint, 1700000001).unwrap());
+		assert_eq!(storage.find_endpoint("new").unwrap().unwrap().groups, vec![String::from("web")]);
+		assert_eq!(storage.find_token("t1").unwrap().unwrap().uses, 1);
+		// The token is used up, so nothing is added
+		let second = Endpoint { uid: String::from("second"), ..endpoint };
+		assert!(!storage.enroll_endpoint(&second, 1700000001).unwrap());
+		assert!(storage.find_endpoint("second").unwrap().is_none());
+		let record = storage.append_audit(AuditRecord { at: 1700000001, event: String::from("registration"), ..AuditRecord::default() }).unwrap();
+		assert_eq!(record.seq, 1);
+		}
+
+	#[test]
+	fn migrates_an_empty_database() {
+		let storage = SqliteStorage::open(":memory:").unwrap();
+		assert_eq!(storage.schema_version().unwrap(), 0);
+		assert_eq!(storage.migrate().unwrap(), all_versions());
+		assert_eq!(storage.schema_version().unwrap(), latest());
+		assert!(storage.migrate().unwrap().is_empty());
+		assert!(!watch_defaults(&storage, "Linux").is_empty());
+		exercise(&storage);
+		}
+
+	// Connections from the server and a command-line process append to the same log. Every
+	// append has to see the one before it, or the chain forks.
+	#[test]
+	fn concurrent_audit_appends_keep_one_chain() {
+		let path = std::env::temp_dir().join(format!("luminum-audit-{}.db", uuid::Uuid::new_v4()));
+		let path = path.to_string_lossy().into_owned();
+		SqliteStorage::open(&path).unwrap().migrate().unwrap();
+		let appenders: Vec<_> = (0..4).map(|appender| {
+			let path = path.clone();
+			std::thread::spawn(move || {
+				let storage = SqliteStorage::open(&path).unwrap();
+				for n in 0..25 {
+					storage.append_audit(AuditRecord { at: 1700000000, event: String::from("access"), detail: format!("{} {}", appender, n), ..AuditRecord::default() }).unwrap();
+					}
+				})
+			}).collect();
+		for appender in appenders {
+			appender.join().unwrap();
+			}
+
+		let storage = SqliteStorage::open(&path).unwrap();
+		let verification = crate::audit::verify(&storage).unwrap();
+		let _ = std::fs::remove_file(&path);
+		assert_eq!((verification.records, verification.broken), (100, None));
+		}
+
+	// Databases created before migrations existed already have the first tables, without a
+	// SCHEMA_VERSION table
+	#[test]
+	fn migrates_a_baseline_database() {
+		let storage = SqliteStorage::open(":memory:").unwrap();
+		storage.conn().execute_batch("
+			create table STATUS (
+				ID integer primary key autoincrement,
+				UID text not null unique,
+				HOSTNAME text not null,
+				IPV4 text,
+				IPV6 text,
+				OSPLAT text not null,
+				OSVER text not null,
+				CERTFP text,
+				REGDATE text not null,
+				LASTSEEN text not null
+				);
+			create table WATCHLIST (ID integer not null, OS text not null, PATH text not null);
+			create table WATCH_DEFAULT (OS text not null, PATH text not null);
+			insert into STATUS (UID,HOSTNAME,IPV4,IPV6,OSPLAT,OSVER,CERTFP,REGDATE,LASTSEEN) values ('old', 'host01', '192.0.2.10', '', 'Linux', '6.1', 'ab12', datetime('now'), datetime(1700000000, 'unixepoch'));
+			insert into WATCHLIST (ID, OS, PATH) values (1, 'Linux', '/srv');
+			").unwrap();
+		assert_eq!(storage.schema_version().unwrap(), 0);
+		assert_eq!(storage.migrate().unwrap(), all_versions());
+		assert_eq!(storage.schema_version().unwrap(), latest());
+
+		let endpoint = storage.find_endpoint("old").unwrap().unwrap();
+		assert_eq!((endpoint.hostname.as_str(), endpoint.cert_fingerprint.as_deref()), ("host01", Some("ab12")));
+		assert_eq!(endpoint.last_seen, 1700000000);
+		assert!(!endpoint.revoked && endpoint.token_id.is_none() && endpoint.groups.is_empty());
+		assert_eq!(endpoint.presence, Presence::Online);
+		exercise(&storage);
+		}
+
+	fn watch_defaults(storage: &SqliteStorage, os: &str) -> Vec<String> {
+		let conn = storage.conn();
+		let mut stmt = conn.prepare("select PATH from WATCH_DEFAULT where OS = ?1").unwrap();
+		let paths = stmt.query_map([os], |row| row.get(0)).unwrap().collect::<Result<Vec<String>, _>>().unwrap();
+		paths
+		}
+	}
@@ -0,0 +1,128 @@
+// Test Fixtures
+//
+// Server state for handler tests: MemoryStorage, with a client CA and server identity
+// generated into a scratch directory that is removed when the fixture is dropped.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::x509::{X509, X509NameBuilder, X509ReqBuilder};
+use uuid::Uuid;
+use crate::config::{ConfigFile, Overrides, Paths};
+use crate::enroll;
+use crate::guard::Guard;
+use crate::listener::{ServerState, Session};
+use crate::push::Channels;
+use crate::secrets::MasterKey;
+use crate::storage::{Endpoint, EnrollmentToken, MemoryStorage, Storage};
+use crate::tls::{self, CertificatePolicy, ClientCa, KeyType, PeerCertificate, ServerIdentity};
+
+const PASSPHRASE: &str = "fixture passphrase";
+
+pub struct Fixture {
+	pub state: ServerState,
+	dir: PathBuf
+	}
+
+impl Drop for Fixture {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.dir);
+		}
+	}
+
+impl Fixture {
+	pub fn new() -> Fixture {
+		let dir = std::env::temp_dir().join(format!("luminum-test-{}", Uuid::new_v4()));
+		fs::create_dir_all(&dir).unwrap();
+		let file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+		let paths = Paths {
+			config_db: file("config.db"),
+			private_key: file("luminum.key"),
+			public_key: file("luminum.pub"),
+			certificate: file("luminum.crt"),
+			identity: file("luminum.pfx"),
+			client_ca_key: file("clientca.key"),
+			client_ca_certificate: file("clientca.crt"),
+			master_key: file("master.key")
+			};
+
+		let client_ca = ClientCa::create(&paths, PASSPHRASE, KeyType::EcdsaP256).unwrap();
+		let key = tls::generate_key(KeyType::EcdsaP256).unwrap();
+		let mut subject = X509NameBuilder::new().unwrap();
+		subject.append_entry_by_nid(Nid::COMMONNAME, "luminum.test").unwrap();
+		let certificate = tls::server_certificate(&key, &subject.build(), &[String::from("127.0.0.1")], 30).unwrap();
+		tls::write_identity(&paths, &key, &certificate, PASSPHRASE).unwrap();
+		let identity = ServerIdentity::load(&paths, PASSPHRASE, &client_ca, &CertificatePolicy::default()).unwrap();
+
+		let serverconfig = HashMap::from([(String::from("IPADDR"), String::from("127.0.0.1"))]);
+		let settings = ConfigFile::default().settings(paths.clone(), &serverconfig, &Overrides::default()).unwrap();
+		let storage = MemoryStorage::new();
+		storage.migrate().unwrap();
+
+		let state = ServerState {
+			storage: Box::new(storage),
+			client_ca,
+			identity,
+			master_key: MasterKey::generate(&paths.master_key).unwrap(),
+			channels: Channels::default(),
+			guard: Guard::default(),
+			tunables: RwLock::new(settings.tunables),
+			settings: RwLock::new(settings)
+			};
+		Fixture { state, dir }
+		}
+
+	pub fn storage(&self) -> &dyn Storage {
+		self.state.storage.as_ref()
+		}
+
+	// Store a new enrollment token, changed as needed before it is saved. Returns the token
+	// string an endpoint presents.
+	pub fn token(&self, change: impl FnOnce(&mut EnrollmentToken)) -> (EnrollmentToken, String) {
+		let (mut token, presented) = enroll::generate(self.storage(), &self.state.master_key, "fixture", vec![String::from("web")], Some(enroll::DEFAULT_EXPIRY), None).unwrap();
+		change(&mut token);
+		self.storage().add_token(&token).unwrap();
+		(token, presented)
+		}
+
+	// Store a registered endpoint with the given UID
+	pub fn endpoint(&self, uid: &str) -> Endpoint {
+		let (token, _) = self.token(|_| {});
+		let endpoint = Endpoint {
+			uid: uid.to_string(),
+			hostname: String::from("host01"),
+			token_id: Some(token.id),
+			last_seen: enroll::now(),
+			..Endpoint::default()
+			};
+		assert!(self.storage().enroll_endpoint(&endpoint, enroll::now()).unwrap());
+		endpoint
+		}
+	}
+
+// A session from the given address, authenticated with the given client certificate
+pub fn session(peer: &str, certificate: Option<&str>) -> Session {
+	let peer_addr: SocketAddr = peer.parse().unwrap();
+	let certificate = certificate.map(|pem| {
+		let cert = X509::from_pem(pem.as_bytes()).unwrap();
+		let uid = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next().unwrap().data().to_string().unwrap();
+		PeerCertificate { uid, fingerprint: tls::fingerprint(&cert).unwrap() }
+		});
+	Session { peer_addr, certificate }
+	}
+
+// A PEM certificate signing request for a fresh endpoint key
+pub fn csr() -> String {
+	let key = tls::generate_key(KeyType::EcdsaP256).unwrap();
+	let mut name = X509NameBuilder::new().unwrap();
+	name.append_entry_by_nid(Nid::COMMONNAME, "endpoint").unwrap();
+	let mut request = X509ReqBuilder::new().unwrap();
+	request.set_subject_name(&name.build()).unwrap();
+	request.set_pubkey(&key).unwrap();
+	request.sign(&key, MessageDigest::sha256()).unwrap();
+	String::from_utf8(request.build().to_pem().unwrap()).unwrap()
+	}
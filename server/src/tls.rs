@@ -0,0 +1,481 @@
+// TLS and Client Certificate Authority
+//
+// The server keeps a small CA that signs the certificate signing request each client submits
+// during registration. Client certificates carry the endpoint UID as their common name, and
+// every connection is verified against this CA.
+//
+// Endpoints pin the server's self-signed certificate (server.crt on the client), so renewing
+// it is rolled out in two stages. --renew-cert writes the new certificate and identity and
+// keeps the old ones as *.prev. For the rollover period the server goes on presenting the
+// previous certificate while heartbeat responses hand endpoints both certificates to trust;
+// after that it presents the renewed one.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs12::{ParsedPkcs12_2, Pkcs12};
+use openssl::pkey::{HasParams, Id, PKey, PKeyRef, Private};
+use openssl::ssl::{SslAcceptor, SslMethod, SslRef, SslVerifyMode};
+use openssl::symm::Cipher;
+use openssl::x509::{X509, X509NameBuilder, X509NameRef, X509Req};
+use openssl::x509::extension::{AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName, SubjectKeyIdentifier};
+use crate::config::Paths;
+use crate::enroll::now;
+use crate::listener::ServerState;
+use crate::shutdown::Shutdown;
+use tracing::{error, info, warn};
+
+pub const CAKPATH: &str = "/opt/Luminum/LuminumServer/config/clientca.key";
+pub const CACPATH: &str = "/opt/Luminum/LuminumServer/config/clientca.crt";
+// Server certificate defaults, in days
+pub const DEFAULT_VALIDITY: u32 = 365;
+pub const DEFAULT_ROLLOVER: u32 = 14;
+pub const DEFAULT_EXPIRY_WARNING: u32 = 30;
+const CA_DAYS: u32 = 3650;
+const CLIENT_CERT_DAYS: u32 = 365;
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+const WARNING_INTERVAL: i64 = 86400;
+
+// Key types for new server and client CA keys. Existing RSA keys keep working.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyType {
+	EcdsaP256,
+	Ed25519
+	}
+
+impl FromStr for KeyType {
+	type Err = String;
+
+	fn from_str(input: &str) -> Result<KeyType, String> {
+		match input {
+			"ecdsa-p256" => Ok(KeyType::EcdsaP256),
+			"ed25519" => Ok(KeyType::Ed25519),
+			other => Err(format!("Unknown key type: {} (expected ecdsa-p256 or ed25519)", other))
+			}
+		}
+	}
+
+impl fmt::Display for KeyType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			KeyType::EcdsaP256 => write!(f, "ecdsa-p256"),
+			KeyType::Ed25519 => write!(f, "ed25519")
+			}
+		}
+	}
+
+impl KeyType {
+	// Key type of an existing key, if it's one that can be configured
+	pub fn of<T: HasParams>(key: &PKeyRef<T>) -> Option<KeyType> {
+		match key.id() {
+			Id::EC if key.ec_key().ok()?.group().curve_name() == Some(Nid::X9_62_PRIME256V1) => Some(KeyType::EcdsaP256),
+			Id::ED25519 => Some(KeyType::Ed25519),
+			_ => None
+			}
+		}
+	}
+
+// How server certificates are issued and renewed
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertificatePolicy {
+	pub key_type: KeyType,
+	// Names added to the server certificate besides the listen addresses and common name
+	pub hostnames: Vec<String>,
+	pub addresses: Vec<IpAddr>,
+	// Days a new server certificate is valid
+	pub validity: u32,
+	// Days the previous certificate is still presented after a renewal
+	pub rollover: u32,
+	// Days before expiry to start warning
+	pub expiry_warning: u32
+	}
+
+impl Default for CertificatePolicy {
+	fn default() -> Self {
+		CertificatePolicy {
+			key_type: KeyType::EcdsaP256,
+			hostnames: Vec::new(),
+			addresses: Vec::new(),
+			validity: DEFAULT_VALIDITY,
+			rollover: DEFAULT_ROLLOVER,
+			expiry_warning: DEFAULT_EXPIRY_WARNING
+			}
+		}
+	}
+
+impl CertificatePolicy {
+	// Subject alternative names for the server certificate: the configured hostnames and
+	// addresses, every specific listen address, and the common name
+	pub fn alt_names(&self, listen: &[IpAddr], common_name: &str) -> Vec<String> {
+		let mut names: Vec<String> = Vec::new();
+		let candidates = self.hostnames.iter().cloned()
+			.chain(self.addresses.iter().chain(listen.iter().filter(|address| !address.is_unspecified())).map(IpAddr::to_string))
+			.chain(Some(common_name.to_string()).filter(|name| !name.is_empty()));
+		for name in candidates {
+			if !names.contains(&name) {
+				names.push(name);
+				}
+			}
+		names
+		}
+	}
+
+pub struct ClientCa {
+	pub cert: X509,
+	key: PKey<Private>
+	}
+
+// Certificate details of a verified client connection
+#[derive(Clone, Debug, Default)]
+pub struct PeerCertificate {
+	pub uid: String,
+	pub fingerprint: String
+	}
+
+impl ClientCa {
+	// Load the client CA, encrypted with the server private key passphrase
+	pub fn load(paths: &Paths, passphrase: &str) -> Result<ClientCa, Box<dyn Error>> {
+		let key = PKey::private_key_from_pem_passphrase(&fs::read(&paths.client_ca_key)?, passphrase.as_bytes())?;
+		let cert = X509::from_pem(&fs::read(&paths.client_ca_certificate)?)?;
+		Ok(ClientCa { cert, key })
+		}
+
+	// Generate a new client CA and write it to disk
+	pub fn create(paths: &Paths, passphrase: &str, key_type: KeyType) -> Result<ClientCa, Box<dyn Error>> {
+		let key = generate_key(key_type)?;
+
+		let mut name = X509NameBuilder::new()?;
+		name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "Luminum")?;
+		name.append_entry_by_nid(Nid::COMMONNAME, "Luminum Client CA")?;
+		let name = name.build();
+
+		let mut x509 = X509::builder()?;
+		x509.set_version(2)?;
+		let serial = random_serial()?.to_asn1_integer()?;
+		x509.set_serial_number(&serial)?;
+		x509.set_subject_name(&name)?;
+		x509.set_issuer_name(&name)?;
+		x509.set_pubkey(&key)?;
+		let not_before = Asn1Time::days_from_now(0)?;
+		let not_after = Asn1Time::days_from_now(CA_DAYS)?;
+		x509.set_not_before(&not_before)?;
+		x509.set_not_after(&not_after)?;
+		x509.append_extension(BasicConstraints::new().critical().ca().pathlen(0).build()?)?;
+		x509.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build()?)?;
+		let ski = SubjectKeyIdentifier::new().build(&x509.x509v3_context(None, None))?;
+		x509.append_extension(ski)?;
+		x509.sign(&key, digest(&key))?;
+		let cert = x509.build();
+
+		let encrypted_key = key.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase.as_bytes())?;
+		write_private(&paths.client_ca_key, &encrypted_key)?;
+		crate::give_to_daemon(&paths.client_ca_key)?;
+		File::create(&paths.client_ca_certificate)?.write_all(&cert.to_pem()?)?;
+
+		Ok(ClientCa { cert, key })
+		}
+
+	// Sign a client CSR, binding the resulting certificate to the given UID.
+	// Returns the certificate PEM and its SHA-256 fingerprint.
+	pub fn sign_csr(&self, csr_pem: &str, uid: &str) -> Result<(String, String), Box<dyn Error>> {
+		let csr = X509Req::from_pem(csr_pem.as_bytes())?;
+		let pubkey = csr.public_key()?;
+		if !csr.verify(&pubkey)? {
+			return Err("CSR signature verification failed".into());
+			}
+
+		// The subject is chosen by the server, never taken from the CSR
+		let mut name = X509NameBuilder::new()?;
+		name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "Luminum Endpoint")?;
+		name.append_entry_by_nid(Nid::COMMONNAME, uid)?;
+		let name = name.build();
+
+		let mut x509 = X509::builder()?;
+		x509.set_version(2)?;
+		let serial = random_serial()?.to_asn1_integer()?;
+		x509.set_serial_number(&serial)?;
+		x509.set_subject_name(&name)?;
+		x509.set_issuer_name(self.cert.subject_name())?;
+		x509.set_pubkey(&pubkey)?;
+		let not_before = Asn1Time::days_from_now(0)?;
+		let not_after = Asn1Time::days_from_now(CLIENT_CERT_DAYS)?;
+		x509.set_not_before(&not_before)?;
+		x509.set_not_after(&not_after)?;
+		x509.append_extension(BasicConstraints::new().critical().build()?)?;
+		// Key encipherment only applies to RSA keys, but clients created before key types were
+		// configurable still submit those
+		x509.append_extension(KeyUsage::new().critical().digital_signature().key_encipherment().build()?)?;
+		x509.append_extension(ExtendedKeyUsage::new().client_auth().build()?)?;
+		let ski = SubjectKeyIdentifier::new().build(&x509.x509v3_context(Some(&self.cert), None))?;
+		x509.append_extension(ski)?;
+		let aki = AuthorityKeyIdentifier::new().keyid(false).build(&x509.x509v3_context(Some(&self.cert), None))?;
+		x509.append_extension(aki)?;
+		x509.sign(&self.key, digest(&self.key))?;
+		let cert = x509.build();
+
+		Ok((String::from_utf8(cert.to_pem()?)?, fingerprint(&cert)?))
+		}
+	}
+
+// The server's TLS identity, along with the previous one while a renewal is rolled out
+pub struct ServerIdentity {
+	current: SslAcceptor,
+	previous: Option<SslAcceptor>,
+	// When the renewed certificate starts being presented
+	switch_at: i64,
+	not_after: i64,
+	expiry_warning: u32,
+	// PEM certificates endpoints should trust, with and without the previous certificate
+	rollover_bundle: String,
+	bundle: String
+	}
+
+impl ServerIdentity {
+	pub fn load(paths: &Paths, passphrase: &str, ca: &ClientCa, policy: &CertificatePolicy) -> Result<ServerIdentity, Box<dyn Error>> {
+		let identity = read_identity(&paths.identity, passphrase)?;
+		let cert = identity.cert.as_ref().ok_or("Identity file has no certificate")?;
+		let bundle = String::from_utf8(cert.to_pem()?)?;
+		let switch_at = unix_time(cert.not_before())? + i64::from(policy.rollover) * 86400;
+
+		// The previous identity is only needed until the rollover ends or it expires
+		let previous_path = previous(&paths.identity);
+		let mut previous_identity = None;
+		if now() < switch_at && fs::metadata(&previous_path).is_ok() {
+			let identity = read_identity(&previous_path, passphrase)?;
+			let previous_cert = identity.cert.as_ref().ok_or("Previous identity file has no certificate")?;
+			if unix_time(previous_cert.not_after())? > now() {
+				previous_identity = Some((build_acceptor(&identity, ca)?, String::from_utf8(previous_cert.to_pem()?)?));
+				}
+			}
+		let (previous, rollover_bundle) = match previous_identity {
+			Some((acceptor, previous_pem)) => (Some(acceptor), format!("{}{}", bundle, previous_pem)),
+			None => (None, bundle.clone())
+			};
+		Ok(ServerIdentity {
+			current: build_acceptor(&identity, ca)?,
+			previous,
+			switch_at,
+			not_after: unix_time(cert.not_after())?,
+			expiry_warning: policy.expiry_warning,
+			rollover_bundle,
+			bundle
+			})
+		}
+
+	// Whether the previous certificate is still being presented
+	pub fn rolling_over(&self, now: i64) -> bool {
+		self.previous.is_some() && now < self.switch_at
+		}
+
+	pub fn switch_at(&self) -> i64 {
+		self.switch_at
+		}
+
+	pub fn acceptor(&self, now: i64) -> &SslAcceptor {
+		match &self.previous {
+			Some(previous) if self.rolling_over(now) => previous,
+			_ => &self.current
+			}
+		}
+
+	// Certificates endpoints should trust for the server
+	pub fn trusted_certificates(&self, now: i64) -> &str {
+		if self.rolling_over(now) { &self.rollover_bundle } else { &self.bundle }
+		}
+	}
+
+fn read_identity(path: &str, passphrase: &str) -> Result<ParsedPkcs12_2, Box<dyn Error>> {
+	Ok(Pkcs12::from_der(&fs::read(path)?)?.parse2(passphrase)?)
+	}
+
+// Build the TLS acceptor from a server identity. Client certificates are requested and
+// verified against the client CA when presented; unregistered clients may still connect to register.
+fn build_acceptor(identity: &ParsedPkcs12_2, ca: &ClientCa) -> Result<SslAcceptor, Box<dyn Error>> {
+	let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+	builder.set_private_key(identity.pkey.as_ref().ok_or("Identity file has no private key")?)?;
+	builder.set_certificate(identity.cert.as_ref().ok_or("Identity file has no certificate")?)?;
+	builder.check_private_key()?;
+	builder.cert_store_mut().add_cert(ca.cert.clone())?;
+	builder.add_client_ca(&ca.cert)?;
+	builder.set_verify(SslVerifyMode::PEER);
+	// Verifying peers without a session ID context makes OpenSSL fail resumed handshakes,
+	// which browsers and other HTTPS clients attempt routinely
+	builder.set_session_id_context(b"LuminumServer")?;
+	Ok(builder.build())
+	}
+
+// Generate a private key of the given type
+pub fn generate_key(key_type: KeyType) -> Result<PKey<Private>, ErrorStack> {
+	match key_type {
+		KeyType::EcdsaP256 => PKey::from_ec_key(EcKey::generate(&*EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)?),
+		KeyType::Ed25519 => PKey::generate_ed25519()
+		}
+	}
+
+// Issue a self-signed server certificate for the given names. Names that parse as IP
+// addresses become IP address entries, the rest DNS names.
+pub fn server_certificate(key: &PKey<Private>, subject: &X509NameRef, names: &[String], days: u32) -> Result<X509, Box<dyn Error>> {
+	let mut x509 = X509::builder()?;
+	x509.set_version(2)?;
+	let serial = random_serial()?.to_asn1_integer()?;
+	x509.set_serial_number(&serial)?;
+	x509.set_subject_name(subject)?;
+	x509.set_issuer_name(subject)?;
+	x509.set_pubkey(key)?;
+	let not_before = Asn1Time::days_from_now(0)?;
+	let not_after = Asn1Time::days_from_now(days)?;
+	x509.set_not_before(&not_before)?;
+	x509.set_not_after(&not_after)?;
+
+	if !names.is_empty() {
+		let mut san = SubjectAlternativeName::new();
+		for name in names {
+			if name.parse::<IpAddr>().is_ok() { san.ip(name); }
+			else { san.dns(name); }
+			}
+		let san = san.build(&x509.x509v3_context(None, None))?;
+		x509.append_extension(san)?;
+		}
+	let ski = SubjectKeyIdentifier::new().build(&x509.x509v3_context(None, None))?;
+	x509.append_extension(ski)?;
+	x509.sign(key, digest(key))?;
+	Ok(x509.build())
+	}
+
+// Write the server certificate and the PFX identity holding it and its key
+pub fn write_identity(paths: &Paths, key: &PKey<Private>, cert: &X509, passphrase: &str) -> Result<(), Box<dyn Error>> {
+	fs::write(&paths.certificate, cert.to_pem()?)?;
+	let pkcs12 = Pkcs12::builder().name("Luminum Server Key").pkey(key).cert(cert).build2(passphrase)?;
+	write_private(&paths.identity, &pkcs12.to_der()?)?;
+	crate::give_to_daemon(&paths.identity)?;
+	Ok(())
+	}
+
+// Write a file only its owner can read, such as a private key. An existing file loses any
+// wider permissions it had.
+pub fn write_private(path: &str, contents: &[u8]) -> io::Result<()> {
+	let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+	file.set_permissions(fs::Permissions::from_mode(0o600))?;
+	file.write_all(contents)
+	}
+
+// Where the file a renewal replaces is kept
+pub fn previous(path: &str) -> String {
+	format!("{}.prev", path)
+	}
+
+// Where a renewal writes a file before moving it into place
+pub fn staged(path: &str) -> String {
+	format!("{}.new", path)
+	}
+
+// Seconds since the epoch
+pub fn unix_time(time: &Asn1TimeRef) -> Result<i64, ErrorStack> {
+	let diff = Asn1Time::from_unix(0)?.diff(time)?;
+	Ok(i64::from(diff.days) * 86400 + i64::from(diff.secs))
+	}
+
+// Warn about certificates close to expiry and log the end of a rollover, until the server stops
+pub async fn monitor(state: Arc<ServerState>, shutdown: Shutdown) {
+	let mut interval = tokio::time::interval(CHECK_INTERVAL);
+	let mut rolling_over = state.identity.rolling_over(now());
+	let mut last_warning = 0;
+	loop {
+		tokio::select! {
+			_ = interval.tick() => {},
+			_ = shutdown.stopped() => { break; }
+			}
+		let now = now();
+		if rolling_over && !state.identity.rolling_over(now) {
+			info!("Certificate rollover complete. Now presenting the renewed server certificate.");
+			rolling_over = false;
+			}
+		if now - last_warning < WARNING_INTERVAL {
+			continue;
+			}
+		let warn_before = i64::from(state.identity.expiry_warning) * 86400;
+		let client_ca_expires = unix_time(state.client_ca.cert.not_after()).unwrap_or(i64::MAX);
+		for (name, expires, remedy) in [("Server certificate", state.identity.not_after, " Renew it with --renew-cert."), ("Client CA certificate", client_ca_expires, "")] {
+			let remaining = expires - now;
+			if remaining <= 0 {
+				error!("{} has expired.{}", name, remedy);
+				last_warning = now;
+				}
+			else if remaining <= warn_before {
+				warn!("{} expires in {} days.{}", name, remaining / 86400, remedy);
+				last_warning = now;
+				}
+			}
+		}
+	}
+
+// Extract the verified client certificate from an established session
+pub fn peer_certificate(ssl: &SslRef) -> Option<PeerCertificate> {
+	let cert = ssl.peer_certificate()?;
+	let uid = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()?.data().to_string().ok()?;
+	let fingerprint = fingerprint(&cert).ok()?;
+	Some(PeerCertificate { uid, fingerprint })
+	}
+
+pub fn fingerprint(cert: &X509) -> Result<String, Box<dyn Error>> {
+	let digest = cert.digest(MessageDigest::sha256())?;
+	Ok(digest.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(":"))
+	}
+
+// Ed25519 signs the message itself rather than a digest of it
+fn digest<T>(key: &PKeyRef<T>) -> MessageDigest {
+	if key.id() == Id::ED25519 { MessageDigest::null() } else { MessageDigest::sha256() }
+	}
+
+fn random_serial() -> Result<BigNum, Box<dyn Error>> {
+	let mut serial = BigNum::new()?;
+	serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+	Ok(serial)
+	}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::os::unix::fs::PermissionsExt;
+	use openssl::ec::{EcGroup, EcKey};
+	use openssl::nid::Nid;
+	use openssl::pkey::PKey;
+	use openssl::rsa::Rsa;
+	use uuid::Uuid;
+	use super::{KeyType, generate_key, write_private};
+
+	#[test]
+	fn identifies_configurable_key_types() {
+		assert_eq!(KeyType::of(&generate_key(KeyType::EcdsaP256).unwrap()), Some(KeyType::EcdsaP256));
+		assert_eq!(KeyType::of(&generate_key(KeyType::Ed25519).unwrap()), Some(KeyType::Ed25519));
+		let p384 = EcKey::generate(&EcGroup::from_curve_name(Nid::SECP384R1).unwrap()).unwrap();
+		assert_eq!(KeyType::of(&PKey::from_ec_key(p384).unwrap()), None);
+		assert_eq!(KeyType::of(&PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()), None);
+		}
+
+	#[test]
+	fn writes_private_files_for_the_owner_only() {
+		let path = std::env::temp_dir().join(format!("luminum-test-{}.key", Uuid::new_v4())).to_string_lossy().into_owned();
+		write_private(&path, b"first").unwrap();
+		assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+		// Replacing a file that was readable by others takes that away
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+		write_private(&path, b"second").unwrap();
+		assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		assert_eq!(fs::read(&path).unwrap(), b"second");
+		fs::remove_file(&path).unwrap();
+		}
+	}